@@ -0,0 +1,1300 @@
+//! Shared cache of upstream responses, following [RFC 9111].
+//!
+//! Responses are kept in an in-memory LRU with a size cap. When a disk directory is configured,
+//! entries evicted from memory are written there and brought back into memory when requested
+//! again.
+//!
+//! [RFC 9111]: https://www.rfc-editor.org/rfc/rfc9111
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, hash_map::DefaultHasher},
+    fs,
+    hash::{Hash as _, Hasher as _},
+    io::{self, Read as _, Write as _},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse,
+    http::{
+        Method, StatusCode,
+        header::{self, HeaderMap, HeaderName, HeaderValue, HttpDate},
+    },
+    web::{self, Bytes, BytesMut},
+};
+use futures_util::{Stream, StreamExt as _};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::headers;
+
+const X_CACHE: &str = "x-cache";
+
+/// Maximum number of variants, selected by `Vary`, kept for a single URL.
+const MAX_VARIANTS: usize = 8;
+
+/// Upper bound on freshness lifetimes guessed from `Last-Modified`.
+const MAX_HEURISTIC_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Extension of entry files in the disk tier.
+const ENTRY_EXTENSION: &str = "entry";
+
+/// Extension of files being written in the disk tier.
+const TMP_EXTENSION: &str = "tmp";
+
+/// Status codes whose responses may be given a heuristic freshness lifetime.
+///
+/// See [RFC 9110 §15.1](https://www.rfc-editor.org/rfc/rfc9110#section-15.1).
+const HEURISTICALLY_CACHEABLE: &[u16] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// Settings for the response cache.
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Maximum total size of entries held in memory, in bytes. Responses larger than an eighth
+    /// of this are not cached.
+    pub max_size: u64,
+
+    /// Directory that entries evicted from memory are moved to.
+    pub disk_dir: Option<PathBuf>,
+
+    /// Maximum total size of entries held on disk, in bytes.
+    pub disk_max_size: u64,
+}
+
+/// How a response was produced, as reported in the `X-Cache` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// Served from the cache without contacting the upstream.
+    Hit,
+
+    /// Fetched from the upstream.
+    Miss,
+
+    /// Served from the cache after the upstream confirmed it was still valid.
+    Revalidated,
+
+    /// Served from the cache while it is revalidated in the background.
+    Stale,
+}
+
+impl CacheStatus {
+    pub fn header(self) -> (&'static str, &'static str) {
+        let val = match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Revalidated => "REVALIDATED",
+            CacheStatus::Stale => "STALE",
+        };
+
+        (X_CACHE, val)
+    }
+}
+
+/// Parsed `Cache-Control` directives relevant to a shared cache.
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    stale_while_revalidate: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = Self::default();
+
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .filter_map(|val| val.to_str().ok())
+            .flat_map(|val| val.split(','));
+
+        for directive in directives {
+            let (name, val) = match directive.split_once('=') {
+                Some((name, val)) => (name.trim(), Some(val.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+
+            // invalid durations are treated as zero, making the response stale
+            let secs = || Some(val.and_then(|val| val.parse().ok()).unwrap_or(0));
+
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "must-revalidate" | "proxy-revalidate" => cc.must_revalidate = true,
+                "max-age" => cc.max_age = secs(),
+                "s-maxage" => cc.s_maxage = secs(),
+                "stale-while-revalidate" => cc.stale_while_revalidate = secs(),
+                _ => {}
+            }
+        }
+
+        cc
+    }
+}
+
+fn parse_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    headers
+        .get(name)?
+        .to_str()
+        .ok()?
+        .parse::<HttpDate>()
+        .ok()
+        .map(SystemTime::from)
+}
+
+/// Returns the cache key for a request; its host and target.
+fn key(req: &HttpRequest) -> String {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default();
+
+    format!("{host}{}", req.uri())
+}
+
+/// Values of the request headers named by `Vary`, which select between variants of a response.
+fn vary_values(response_headers: &HeaderMap, req: &HttpRequest) -> Vec<(String, Option<String>)> {
+    response_headers
+        .get_all(header::VARY)
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let vals = req
+                .headers()
+                .get_all(name.as_str())
+                .filter_map(|val| val.to_str().ok())
+                .collect::<Vec<_>>();
+
+            let vals = (!vals.is_empty()).then(|| vals.join(", "));
+            (name, vals)
+        })
+        .collect()
+}
+
+/// A stored response.
+#[derive(Debug)]
+pub struct CachedResponse {
+    key: String,
+    status: StatusCode,
+
+    /// End-to-end response headers, without `Age`.
+    headers: HeaderMap,
+
+    body: Bytes,
+
+    /// Request header values that this variant was selected by.
+    vary: Vec<(String, Option<String>)>,
+
+    /// Time the response was received.
+    response_time: SystemTime,
+
+    /// Age of the response when it was received.
+    initial_age: Duration,
+
+    freshness_lifetime: Duration,
+    stale_while_revalidate: Duration,
+
+    /// Whether the response must be revalidated before each use, or once stale.
+    no_cache: bool,
+    must_revalidate: bool,
+}
+
+impl CachedResponse {
+    fn new(
+        key: String,
+        status: StatusCode,
+        mut headers: HeaderMap,
+        body: Bytes,
+        vary: Vec<(String, Option<String>)>,
+        response_time: SystemTime,
+    ) -> Self {
+        let initial_age = initial_age(&headers, response_time);
+        headers.remove(header::AGE);
+
+        let mut entry = Self {
+            key,
+            status,
+            headers,
+            body,
+            vary,
+            response_time,
+            initial_age,
+            freshness_lifetime: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+            no_cache: false,
+            must_revalidate: false,
+        };
+
+        entry.update_policy();
+        entry
+    }
+
+    /// Recomputes freshness from the stored headers.
+    fn update_policy(&mut self) {
+        let cc = CacheControl::parse(&self.headers);
+        let date = parse_date(&self.headers, header::DATE).unwrap_or(self.response_time);
+
+        let explicit = cc
+            .s_maxage
+            .or(cc.max_age)
+            .map(Duration::from_secs)
+            .or_else(|| {
+                let expires = parse_date(&self.headers, header::EXPIRES)?;
+                Some(expires.duration_since(date).unwrap_or_default())
+            });
+
+        let heuristic = || {
+            if !HEURISTICALLY_CACHEABLE.contains(&self.status.as_u16()) {
+                return None;
+            }
+
+            let last_modified = parse_date(&self.headers, header::LAST_MODIFIED)?;
+            let since_modified = date.duration_since(last_modified).ok()?;
+            Some((since_modified / 10).min(MAX_HEURISTIC_LIFETIME))
+        };
+
+        self.freshness_lifetime = explicit.or_else(heuristic).unwrap_or_default();
+        self.stale_while_revalidate =
+            Duration::from_secs(cc.stale_while_revalidate.unwrap_or_default());
+        self.no_cache = cc.no_cache;
+        self.must_revalidate = cc.must_revalidate;
+    }
+
+    fn age(&self, now: SystemTime) -> Duration {
+        self.initial_age + now.duration_since(self.response_time).unwrap_or_default()
+    }
+
+    fn matches(&self, req: &HttpRequest) -> bool {
+        self.vary.iter().all(|(name, val)| {
+            let vals = req
+                .headers()
+                .get_all(name.as_str())
+                .filter_map(|val| val.to_str().ok())
+                .collect::<Vec<_>>();
+
+            val.as_deref() == (!vals.is_empty()).then(|| vals.join(", ")).as_deref()
+        })
+    }
+
+    fn size(&self) -> u64 {
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, val)| name.as_str().len() + val.len())
+            .sum::<usize>();
+
+        (self.key.len() + headers + self.body.len()) as u64
+    }
+
+    /// Returns true if the entry can still be used, either directly or after revalidation.
+    fn is_usable(&self, now: SystemTime) -> bool {
+        self.has_validators()
+            || self.age(now) < self.freshness_lifetime + self.stale_while_revalidate
+    }
+
+    fn has_validators(&self) -> bool {
+        self.headers.contains_key(header::ETAG) || self.headers.contains_key(header::LAST_MODIFIED)
+    }
+
+    /// Returns headers that make a request conditional on the entry having changed.
+    pub fn validators(&self) -> HeaderMap {
+        let mut validators = HeaderMap::new();
+
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            validators.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+
+        if let Some(last_modified) = self.headers.get(header::LAST_MODIFIED) {
+            validators.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+
+        validators
+    }
+
+    /// Returns true if the request's `If-None-Match` matches the entry's `ETag`.
+    fn etag_matches(&self, req: &HttpRequest) -> bool {
+        let Some(etag) = self
+            .headers
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+        else {
+            return false;
+        };
+
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+        let etag = opaque(etag);
+
+        req.headers()
+            .get_all(header::IF_NONE_MATCH)
+            .filter_map(|val| val.to_str().ok())
+            .flat_map(|val| val.split(','))
+            .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+    }
+
+    /// Builds a response to `req` from the entry.
+    pub fn response(&self, req: &HttpRequest, status: CacheStatus) -> HttpResponse {
+        let age = self.age(SystemTime::now()).as_secs();
+
+        if self.etag_matches(req) {
+            let mut res = HttpResponse::NotModified();
+
+            for name in [
+                header::CACHE_CONTROL,
+                header::CONTENT_LOCATION,
+                header::DATE,
+                header::ETAG,
+                header::EXPIRES,
+                header::VARY,
+            ] {
+                for val in self.headers.get_all(&name) {
+                    res.append_header((name.clone(), val.clone()));
+                }
+            }
+
+            return res
+                .insert_header((header::AGE, age))
+                .insert_header(status.header())
+                .finish();
+        }
+
+        let mut res = HttpResponse::build(self.status);
+
+        for (name, val) in &self.headers {
+            res.append_header((name.clone(), val.clone()));
+        }
+
+        res.insert_header((header::AGE, age))
+            .insert_header(status.header())
+            .body(self.body.clone())
+    }
+
+    /// Returns a copy of the entry updated with the headers of a `304 Not Modified` response.
+    fn revalidated(&self, not_modified: &HeaderMap) -> Self {
+        let mut headers = self.headers.clone();
+
+        for (name, _) in headers::end_to_end(not_modified) {
+            if *name != header::CONTENT_LENGTH {
+                headers.remove(name);
+            }
+        }
+
+        for (name, val) in headers::end_to_end(not_modified) {
+            if *name != header::CONTENT_LENGTH {
+                headers.append(name.clone(), val.clone());
+            }
+        }
+
+        let now = SystemTime::now();
+
+        // without a new date, the old one would make the entry look as old as the original response
+        if !not_modified.contains_key(header::DATE) {
+            if let Ok(date) = HeaderValue::from_str(&HttpDate::from(now).to_string()) {
+                headers.insert(header::DATE, date);
+            }
+        }
+
+        Self::new(
+            self.key.clone(),
+            self.status,
+            headers,
+            self.body.clone(),
+            self.vary.clone(),
+            now,
+        )
+    }
+}
+
+/// Computes the age of a response when it was received, from its `Age` and `Date` headers.
+///
+/// See [RFC 9111 §4.2.3](https://www.rfc-editor.org/rfc/rfc9111#section-4.2.3).
+fn initial_age(headers: &HeaderMap, response_time: SystemTime) -> Duration {
+    let age_value = headers
+        .get(header::AGE)
+        .and_then(|age| age.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+
+    let apparent_age = parse_date(headers, header::DATE)
+        .and_then(|date| response_time.duration_since(date).ok())
+        .unwrap_or_default();
+
+    age_value.max(apparent_age)
+}
+
+/// Result of looking up a request in the cache.
+#[derive(Debug)]
+pub enum Lookup {
+    /// The request must not be served from, or stored in, the cache.
+    Bypass,
+
+    /// There is no usable entry.
+    Miss,
+
+    /// The entry is fresh and can be served.
+    Fresh(Arc<CachedResponse>),
+
+    /// The entry is stale, but can be served while it is revalidated in the background.
+    StaleWhileRevalidate(Arc<CachedResponse>),
+
+    /// The entry must be revalidated before it is served.
+    Stale(Arc<CachedResponse>),
+}
+
+/// Size-capped map that evicts its least recently used entries.
+#[derive(Debug)]
+struct Lru<V> {
+    entries: HashMap<String, LruEntry<V>>,
+
+    /// Keys by last use.
+    order: BTreeMap<u64, String>,
+
+    tick: u64,
+    size: u64,
+    max_size: u64,
+}
+
+#[derive(Debug)]
+struct LruEntry<V> {
+    value: V,
+    size: u64,
+    tick: u64,
+}
+
+impl<V> Lru<V> {
+    fn new(max_size: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    fn get(&mut self, key: &str) -> Option<&V> {
+        let entry = self.entries.get_mut(key)?;
+
+        self.order.remove(&entry.tick);
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.to_owned());
+
+        Some(&entry.value)
+    }
+
+    /// Inserts an entry, returning those evicted to make room for it.
+    fn insert(&mut self, key: String, value: V, size: u64) -> Vec<(String, V)> {
+        let mut evicted = Vec::new();
+
+        if let Some(old) = self.remove(&key) {
+            drop(old);
+        }
+
+        if size > self.max_size {
+            evicted.push((key, value));
+            return evicted;
+        }
+
+        while self.size + size > self.max_size {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.size -= entry.size;
+            evicted.push((oldest, entry.value));
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.size += size;
+        self.entries.insert(
+            key,
+            LruEntry {
+                value,
+                size,
+                tick: self.tick,
+            },
+        );
+
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.size -= entry.size;
+        Some(entry.value)
+    }
+}
+
+/// Entry metadata as written to disk.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredMeta {
+    key: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    vary: Vec<(String, Option<String>)>,
+
+    /// Milliseconds since the Unix epoch.
+    response_time: u64,
+
+    initial_age: u64,
+}
+
+/// Directory of entries evicted from memory.
+///
+/// Each file holds the variants for one key, as a sequence of length-prefixed JSON metadata and
+/// body pairs.
+#[derive(Debug)]
+struct DiskTier {
+    dir: PathBuf,
+
+    /// Sizes of files in the directory, by file name.
+    index: Mutex<Lru<()>>,
+
+    /// Source of unique names for temporary files.
+    next_tmp: AtomicU64,
+}
+
+impl DiskTier {
+    /// Opens the directory, indexing any entries left by a previous run.
+    fn open(dir: PathBuf, max_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let mut index = Lru::new(max_size);
+        let mut evicted = Vec::new();
+
+        for file in fs::read_dir(&dir)? {
+            let file = file?;
+            let path = file.path();
+
+            if path.extension().is_some_and(|ext| ext == ENTRY_EXTENSION) {
+                let name = file.file_name().to_string_lossy().into_owned();
+                evicted.extend(index.insert(name, (), file.metadata()?.len()));
+            } else if path.extension().is_some_and(|ext| ext == TMP_EXTENSION) {
+                // left behind by an interrupted write
+                let _ = fs::remove_file(path);
+            }
+        }
+
+        for (name, ()) in evicted {
+            let _ = fs::remove_file(dir.join(name));
+        }
+
+        Ok(Self {
+            dir,
+            index: Mutex::new(index),
+            next_tmp: AtomicU64::new(0),
+        })
+    }
+
+    fn file_name(key: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        format!("{:016x}.{ENTRY_EXTENSION}", hasher.finish())
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.index.lock().unwrap().contains(&Self::file_name(key))
+    }
+
+    /// Writes the variants of `key` to disk, evicting older files if needed.
+    fn store(&self, key: &str, variants: &[Arc<CachedResponse>]) -> io::Result<()> {
+        let mut buf = Vec::new();
+
+        for entry in variants {
+            let meta = StoredMeta {
+                key: entry.key.clone(),
+                status: entry.status.as_u16(),
+                headers: entry
+                    .headers
+                    .iter()
+                    .filter_map(|(name, val)| {
+                        Some((name.to_string(), val.to_str().ok()?.to_owned()))
+                    })
+                    .collect(),
+                vary: entry.vary.clone(),
+                response_time: entry
+                    .response_time
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+                initial_age: entry.initial_age.as_secs(),
+            };
+
+            let meta = serde_json::to_vec(&meta)?;
+            buf.extend_from_slice(&(meta.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&meta);
+            buf.extend_from_slice(&(entry.body.len() as u64).to_be_bytes());
+            buf.extend_from_slice(&entry.body);
+        }
+
+        let name = Self::file_name(key);
+        let path = self.dir.join(&name);
+
+        // write to a temporary file first so that readers never see a partial entry; its name is
+        // unique so that concurrent writes of the same key do not interleave
+        let tmp = self.dir.join(format!(
+            "{name}.{}.{TMP_EXTENSION}",
+            self.next_tmp.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let written = fs::File::create(&tmp)
+            .and_then(|mut file| file.write_all(&buf))
+            .and_then(|()| fs::rename(&tmp, &path));
+
+        if let Err(err) = written {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+
+        let evicted = self
+            .index
+            .lock()
+            .unwrap()
+            .insert(name, (), buf.len() as u64);
+
+        for (name, ()) in evicted {
+            let _ = fs::remove_file(self.dir.join(name));
+        }
+
+        Ok(())
+    }
+
+    /// Reads and removes the variants of `key` from disk.
+    fn take(&self, key: &str) -> io::Result<Vec<CachedResponse>> {
+        let name = Self::file_name(key);
+        let path = self.dir.join(&name);
+
+        self.index.lock().unwrap().remove(&name);
+
+        let mut buf = Vec::new();
+        fs::File::open(&path)?.read_to_end(&mut buf)?;
+        let _ = fs::remove_file(&path);
+
+        let mut variants = Vec::new();
+        let mut rest = buf.as_slice();
+
+        while !rest.is_empty() {
+            let meta = read_chunk::<4>(&mut rest)?;
+            let meta = serde_json::from_slice::<StoredMeta>(meta)?;
+            let body = read_chunk::<8>(&mut rest)?;
+
+            // guard against hash collisions
+            if meta.key != key {
+                continue;
+            }
+
+            let headers = meta
+                .headers
+                .into_iter()
+                .filter_map(|(name, val)| {
+                    Some((
+                        HeaderName::try_from(name).ok()?,
+                        HeaderValue::try_from(val).ok()?,
+                    ))
+                })
+                .fold(HeaderMap::new(), |mut headers, (name, val)| {
+                    headers.append(name, val);
+                    headers
+                });
+
+            let mut entry = CachedResponse::new(
+                meta.key,
+                StatusCode::from_u16(meta.status).map_err(io::Error::other)?,
+                headers,
+                Bytes::copy_from_slice(body),
+                meta.vary,
+                UNIX_EPOCH + Duration::from_millis(meta.response_time),
+            );
+            entry.initial_age = Duration::from_secs(meta.initial_age);
+
+            variants.push(entry);
+        }
+
+        Ok(variants)
+    }
+
+    fn remove(&self, key: &str) {
+        let name = Self::file_name(key);
+
+        if self.index.lock().unwrap().remove(&name).is_some() {
+            let _ = fs::remove_file(self.dir.join(name));
+        }
+    }
+}
+
+/// Reads a chunk prefixed by its big-endian length, `N` bytes wide.
+fn read_chunk<'a, const N: usize>(buf: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "truncated cache entry");
+
+    let (len, rest) = buf.split_first_chunk::<N>().ok_or_else(invalid)?;
+    let mut len_bytes = [0; 8];
+    len_bytes[8 - N..].copy_from_slice(len);
+    let len = u64::from_be_bytes(len_bytes) as usize;
+
+    if rest.len() < len {
+        return Err(invalid());
+    }
+
+    let (chunk, rest) = rest.split_at(len);
+    *buf = rest;
+    Ok(chunk)
+}
+
+/// Cache of upstream responses.
+#[derive(Debug)]
+pub struct Cache {
+    memory: Mutex<Lru<Vec<Arc<CachedResponse>>>>,
+    disk: Option<Arc<DiskTier>>,
+    max_entry_size: u64,
+
+    /// Keys currently being revalidated in the background.
+    revalidating: Mutex<HashSet<String>>,
+
+    /// Keys whose evicted entries are still being written to disk.
+    writes: Arc<PendingWrites>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> io::Result<Self> {
+        let disk = config
+            .disk_dir
+            .map(|dir| DiskTier::open(dir, config.disk_max_size))
+            .transpose()?
+            .map(Arc::new);
+
+        Ok(Self {
+            memory: Mutex::new(Lru::new(config.max_size)),
+            disk,
+            max_entry_size: config.max_size / 8,
+            revalidating: Mutex::new(HashSet::new()),
+            writes: Arc::default(),
+        })
+    }
+
+    /// Looks up a stored response for `req`.
+    pub async fn lookup(&self, req: &HttpRequest) -> Lookup {
+        if req.method() != Method::GET || req.headers().contains_key(header::AUTHORIZATION) {
+            return Lookup::Bypass;
+        }
+
+        let cc = CacheControl::parse(req.headers());
+
+        if cc.no_store {
+            return Lookup::Bypass;
+        }
+
+        let no_cache = cc.no_cache
+            || (!req.headers().contains_key(header::CACHE_CONTROL)
+                && req
+                    .headers()
+                    .get(header::PRAGMA)
+                    .is_some_and(|pragma| pragma == "no-cache"));
+
+        let Some(entry) = self.find(&key(req), req).await else {
+            return Lookup::Miss;
+        };
+
+        let age = entry.age(SystemTime::now());
+        let max_age = cc.max_age.map(Duration::from_secs);
+
+        let fresh = !no_cache
+            && !entry.no_cache
+            && age < entry.freshness_lifetime
+            && max_age.is_none_or(|max_age| age <= max_age);
+
+        let may_serve_stale = !no_cache
+            && max_age.is_none()
+            && !entry.no_cache
+            && !entry.must_revalidate
+            && age < entry.freshness_lifetime + entry.stale_while_revalidate;
+
+        if fresh {
+            Lookup::Fresh(entry)
+        } else if may_serve_stale {
+            Lookup::StaleWhileRevalidate(entry)
+        } else if entry.has_validators() {
+            Lookup::Stale(entry)
+        } else {
+            Lookup::Miss
+        }
+    }
+
+    async fn find(&self, key: &str, req: &HttpRequest) -> Option<Arc<CachedResponse>> {
+        {
+            let mut memory = self.memory.lock().unwrap();
+
+            if let Some(variants) = memory.get(key) {
+                return variants.iter().find(|entry| entry.matches(req)).cloned();
+            }
+        }
+
+        let disk = self.disk.as_ref()?;
+
+        // an entry that has just been evicted is only found once it has been written
+        self.writes.wait(key).await;
+
+        if !disk.contains(key) {
+            return None;
+        }
+
+        let variants = web::block({
+            let disk = Arc::clone(disk);
+            let key = key.to_owned();
+            move || disk.take(&key)
+        })
+        .await
+        .ok()?
+        .inspect_err(|err| log::warn!("failed to read cache entry for {key}: {err}"))
+        .ok()?;
+
+        let now = SystemTime::now();
+        let mut found = None;
+
+        for entry in variants {
+            if entry.is_usable(now) {
+                let entry = self.insert(entry);
+
+                if found.is_none() && entry.matches(req) {
+                    found = Some(entry);
+                }
+            }
+        }
+
+        found
+    }
+
+    /// Stores an entry in memory, moving any evicted entries to disk.
+    fn insert(&self, entry: CachedResponse) -> Arc<CachedResponse> {
+        let entry = Arc::new(entry);
+
+        let evicted = {
+            let mut memory = self.memory.lock().unwrap();
+
+            let mut variants = memory.remove(&entry.key).unwrap_or_default();
+            variants.retain(|variant| variant.vary != entry.vary);
+            variants.push(Arc::clone(&entry));
+
+            if variants.len() > MAX_VARIANTS {
+                variants.remove(0);
+            }
+
+            let size = variants.iter().map(|variant| variant.size()).sum();
+            memory.insert(entry.key.clone(), variants, size)
+        };
+
+        self.spill(evicted);
+        entry
+    }
+
+    fn spill(&self, evicted: Vec<(String, Vec<Arc<CachedResponse>>)>) {
+        let Some(disk) = &self.disk else {
+            return;
+        };
+
+        let now = SystemTime::now();
+
+        for (key, mut variants) in evicted {
+            variants.retain(|entry| entry.is_usable(now));
+
+            if variants.is_empty() {
+                continue;
+            }
+
+            let disk = Arc::clone(disk);
+            let write = PendingWrites::begin(&self.writes, &key);
+
+            actix_web::rt::spawn(async move {
+                let _write = write;
+
+                let res = web::block(move || {
+                    disk.store(&key, &variants).inspect_err(|err| {
+                        log::warn!("failed to write cache entry for {key}: {err}")
+                    })
+                })
+                .await;
+
+                if let Err(err) = res {
+                    log::warn!("failed to write cache entry: {err}");
+                }
+            });
+        }
+    }
+
+    /// Removes all stored responses for the request's URL, after an unsafe request to it.
+    pub fn invalidate(&self, req: &HttpRequest) {
+        let key = key(req);
+        self.memory.lock().unwrap().remove(&key);
+
+        if let Some(disk) = &self.disk {
+            disk.remove(&key);
+        }
+    }
+
+    /// Updates an entry after the upstream responded to a revalidation request with
+    /// `304 Not Modified`.
+    pub fn revalidated(
+        &self,
+        entry: &CachedResponse,
+        not_modified: &HeaderMap,
+    ) -> Arc<CachedResponse> {
+        self.insert(entry.revalidated(not_modified))
+    }
+
+    /// Marks an entry as being revalidated in the background, returning false if it already is.
+    pub fn begin_revalidation(&self, entry: &CachedResponse) -> bool {
+        self.revalidating.lock().unwrap().insert(entry.key.clone())
+    }
+
+    pub fn end_revalidation(&self, entry: &CachedResponse) {
+        self.revalidating.lock().unwrap().remove(&entry.key);
+    }
+}
+
+/// Keys of entries being written to disk.
+#[derive(Debug, Default)]
+struct PendingWrites {
+    /// Number of writes in progress, by key.
+    keys: Mutex<HashMap<String, usize>>,
+
+    /// Notified whenever a write finishes.
+    done: Notify,
+}
+
+impl PendingWrites {
+    /// Records a write of `key`, which lasts until the returned guard is dropped.
+    fn begin(this: &Arc<Self>, key: &str) -> PendingWrite {
+        *this.keys.lock().unwrap().entry(key.to_owned()).or_default() += 1;
+
+        PendingWrite {
+            writes: Arc::clone(this),
+            key: key.to_owned(),
+        }
+    }
+
+    /// Waits until no write of `key` is in progress.
+    async fn wait(&self, key: &str) {
+        loop {
+            // registered before checking so that a write finishing in between is not missed
+            let done = self.done.notified();
+
+            if !self.keys.lock().unwrap().contains_key(key) {
+                return;
+            }
+
+            done.await;
+        }
+    }
+}
+
+/// Write of an entry to disk, finished when dropped.
+#[derive(Debug)]
+struct PendingWrite {
+    writes: Arc<PendingWrites>,
+    key: String,
+}
+
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        let mut keys = self.writes.keys.lock().unwrap();
+
+        if let Some(count) = keys.get_mut(&self.key) {
+            *count -= 1;
+
+            if *count == 0 {
+                keys.remove(&self.key);
+            }
+        }
+
+        drop(keys);
+        self.writes.done.notify_waiters();
+    }
+}
+
+/// Wraps the body of an upstream response to `req` so that it is stored once fully streamed, if
+/// it may be cached.
+///
+/// Returns the body unchanged if the response is not storable.
+pub fn fill<S>(
+    cache: &web::Data<Cache>,
+    req: &HttpRequest,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: S,
+) -> Result<CacheFill<S>, S> {
+    let cc = CacheControl::parse(headers);
+
+    let has_explicit_freshness =
+        cc.max_age.is_some() || cc.s_maxage.is_some() || headers.contains_key(header::EXPIRES);
+
+    let has_validators =
+        headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED);
+
+    let vary_all = headers
+        .get_all(header::VARY)
+        .filter_map(|val| val.to_str().ok())
+        .any(|val| val.split(',').any(|name| name.trim() == "*"));
+
+    let too_large = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|len| len > cache.max_entry_size);
+
+    let storable = !cc.no_store
+        && !cc.private
+        && !vary_all
+        && !too_large
+        && !headers.contains_key(header::SET_COOKIE)
+        && !status.is_informational()
+        && status != StatusCode::PARTIAL_CONTENT
+        && status != StatusCode::NOT_MODIFIED
+        && (has_explicit_freshness
+            || (has_validators && HEURISTICALLY_CACHEABLE.contains(&status.as_u16())));
+
+    if !storable {
+        return Err(body);
+    }
+
+    let headers = headers::end_to_end(headers).fold(HeaderMap::new(), |mut map, (name, val)| {
+        map.append(name.clone(), val.clone());
+        map
+    });
+
+    Ok(CacheFill {
+        body,
+        pending: Some(PendingEntry {
+            cache: cache.clone(),
+            key: key(req),
+            status,
+            vary: vary_values(&headers, req),
+            headers,
+            response_time: SystemTime::now(),
+            buf: BytesMut::new(),
+        }),
+    })
+}
+
+/// Response being received, to be stored once complete.
+#[derive(Debug)]
+struct PendingEntry {
+    cache: web::Data<Cache>,
+    key: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    vary: Vec<(String, Option<String>)>,
+    response_time: SystemTime,
+    buf: BytesMut,
+}
+
+/// Response body stream that stores the response in the cache once it has been fully received.
+#[derive(Debug)]
+pub struct CacheFill<S> {
+    body: S,
+    pending: Option<PendingEntry>,
+}
+
+impl<S, E> Stream for CacheFill<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = ready!(this.body.poll_next_unpin(cx));
+
+        match &item {
+            Some(Ok(chunk)) => {
+                if let Some(pending) = &mut this.pending {
+                    if (pending.buf.len() + chunk.len()) as u64 > pending.cache.max_entry_size {
+                        this.pending = None;
+                    } else {
+                        pending.buf.extend_from_slice(chunk);
+                    }
+                }
+            }
+
+            // incomplete responses are not stored
+            Some(Err(_)) => this.pending = None,
+
+            None => {
+                if let Some(pending) = this.pending.take() {
+                    let entry = CachedResponse::new(
+                        pending.key,
+                        pending.status,
+                        pending.headers,
+                        pending.buf.freeze(),
+                        pending.vary,
+                        pending.response_time,
+                    );
+
+                    pending.cache.insert(entry);
+                }
+            }
+        }
+
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use futures_util::stream;
+
+    use super::*;
+
+    fn cache(max_size: u64, disk_dir: Option<PathBuf>) -> web::Data<Cache> {
+        web::Data::new(
+            Cache::new(CacheConfig {
+                max_size,
+                disk_dir,
+                disk_max_size: 1024 * 1024,
+            })
+            .unwrap(),
+        )
+    }
+
+    async fn store(cache: &web::Data<Cache>, req: &HttpRequest, headers: &[(&str, &str)]) {
+        let headers = headers
+            .iter()
+            .fold(HeaderMap::new(), |mut map, (name, val)| {
+                map.append(
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(val).unwrap(),
+                );
+                map
+            });
+
+        let body = stream::iter([Ok::<_, ()>(Bytes::from_static(b"hello"))]);
+
+        let fill = fill(cache, req, StatusCode::OK, &headers, body)
+            .unwrap_or_else(|_| panic!("response should be storable"));
+        fill.collect::<Vec<_>>().await;
+    }
+
+    #[actix_web::test]
+    async fn serves_fresh_and_revalidates_stale() {
+        let cache = cache(1024 * 1024, None);
+        let req = TestRequest::get().uri("/a").to_http_request();
+
+        assert!(matches!(cache.lookup(&req).await, Lookup::Miss));
+
+        store(&cache, &req, &[("cache-control", "max-age=60")]).await;
+        assert!(matches!(cache.lookup(&req).await, Lookup::Fresh(_)));
+
+        let no_cache = TestRequest::get()
+            .uri("/a")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .to_http_request();
+        // stale entries without validators cannot be revalidated
+        assert!(matches!(cache.lookup(&no_cache).await, Lookup::Miss));
+
+        store(
+            &cache,
+            &req,
+            &[
+                ("cache-control", "max-age=0, stale-while-revalidate=30"),
+                ("etag", "\"v1\""),
+            ],
+        )
+        .await;
+        assert!(matches!(
+            cache.lookup(&req).await,
+            Lookup::StaleWhileRevalidate(_)
+        ));
+
+        let Lookup::Stale(entry) = cache.lookup(&no_cache).await else {
+            panic!("entry should need revalidation");
+        };
+        assert_eq!(
+            entry.validators().get(header::IF_NONE_MATCH).unwrap(),
+            "\"v1\""
+        );
+
+        let mut not_modified = HeaderMap::new();
+        not_modified.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("max-age=60"),
+        );
+        cache.revalidated(&entry, &not_modified);
+        assert!(matches!(cache.lookup(&req).await, Lookup::Fresh(_)));
+    }
+
+    #[actix_web::test]
+    async fn selects_variant_by_vary() {
+        let cache = cache(1024 * 1024, None);
+
+        let gzip = TestRequest::get()
+            .uri("/v")
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_http_request();
+        let identity = TestRequest::get().uri("/v").to_http_request();
+
+        let headers = [("cache-control", "max-age=60"), ("vary", "Accept-Encoding")];
+        store(&cache, &gzip, &headers).await;
+
+        assert!(matches!(cache.lookup(&gzip).await, Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup(&identity).await, Lookup::Miss));
+
+        store(&cache, &identity, &headers).await;
+        assert!(matches!(cache.lookup(&gzip).await, Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup(&identity).await, Lookup::Fresh(_)));
+    }
+
+    #[actix_web::test]
+    async fn moves_evicted_entries_to_disk() {
+        let dir = std::env::temp_dir().join(format!("http-proxy-cache-{}", std::process::id()));
+        let cache = cache(60, Some(dir.clone()));
+
+        let first = TestRequest::get().uri("/first").to_http_request();
+        let second = TestRequest::get().uri("/second").to_http_request();
+
+        store(&cache, &first, &[("cache-control", "max-age=60")]).await;
+        store(&cache, &second, &[("cache-control", "max-age=60")]).await;
+
+        // the lookup waits for the evicted entry to be written
+        assert!(!cache.memory.lock().unwrap().contains(&key(&first)));
+
+        let Lookup::Fresh(entry) = cache.lookup(&first).await else {
+            panic!("entry should be read back from disk");
+        };
+        assert_eq!(entry.body, "hello");
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn parses_cache_control() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=\"30\", S-MaxAge=60, max-stale"),
+        );
+
+        let cc = CacheControl::parse(&headers);
+        assert_eq!(cc.max_age, Some(30));
+        assert_eq!(cc.s_maxage, Some(60));
+        assert!(!cc.no_store);
+    }
+}
@@ -0,0 +1,287 @@
+//! Forwarding and hop-by-hop header handling.
+//!
+//! See [RFC 7239] for the `Forwarded` header and [RFC 9110 §7.6.1] for hop-by-hop headers.
+//!
+//! [RFC 7239]: https://www.rfc-editor.org/rfc/rfc7239
+//! [RFC 9110 §7.6.1]: https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1
+
+use std::net::IpAddr;
+
+use actix_web::{
+    HttpRequest,
+    http::header::{self, HeaderMap, HeaderName, HeaderValue},
+};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// Headers describing the path of a request through proxies, which are set from [`Forwarded`]
+/// rather than copied from the request.
+const FORWARDING: &[&str] = &[
+    "forwarded",
+    X_FORWARDED_FOR,
+    X_FORWARDED_PROTO,
+    X_FORWARDED_HOST,
+];
+
+/// Headers that only apply to a single connection and must not be forwarded.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Peers whose forwarding headers are trusted and extended, rather than replaced.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpAddr>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<IpAddr>) -> Self {
+        Self(proxies)
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        self.0.contains(&addr)
+    }
+}
+
+/// Lists header names nominated as hop-by-hop by the `Connection` header(s).
+fn connection_tokens<'a>(values: impl Iterator<Item = &'a [u8]>) -> Vec<String> {
+    values
+        .filter_map(|val| std::str::from_utf8(val).ok())
+        .flat_map(|val| val.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+/// Returns true if the header named `name` (in lowercase) must not be forwarded.
+fn is_hop_by_hop(name: &str, connection_tokens: &[String]) -> bool {
+    HOP_BY_HOP.contains(&name)
+        || name.starts_with("proxy-")
+        || connection_tokens.iter().any(|token| token == name)
+}
+
+/// Returns true if the header named `name` (in lowercase) is set from [`Forwarded`] instead of
+/// being copied from the request.
+pub fn is_forwarding(name: &str) -> bool {
+    FORWARDING.contains(&name)
+}
+
+/// Iterates over the end-to-end headers in `headers`; i.e., those that should be forwarded.
+pub fn end_to_end(headers: &HeaderMap) -> impl Iterator<Item = (&HeaderName, &HeaderValue)> {
+    let tokens = connection_tokens(
+        headers
+            .get_all(header::CONNECTION)
+            .map(HeaderValue::as_bytes),
+    );
+
+    headers
+        .iter()
+        .filter(move |(name, _)| !is_hop_by_hop(name.as_str(), &tokens))
+}
+
+/// Removes hop-by-hop headers from `headers`.
+pub fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let hop_by_hop = {
+        let tokens = connection_tokens(
+            headers
+                .get_all(header::CONNECTION)
+                .map(HeaderValue::as_bytes),
+        );
+
+        headers
+            .keys()
+            .filter(|name| is_hop_by_hop(name.as_str(), &tokens))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    for name in hop_by_hop {
+        headers.remove(name);
+    }
+}
+
+/// Forwarding headers to be set on a proxied request.
+#[derive(Debug, Clone)]
+pub struct Forwarded {
+    forwarded: String,
+    x_forwarded_for: String,
+    x_forwarded_proto: String,
+    x_forwarded_host: Option<String>,
+}
+
+impl Forwarded {
+    /// Builds forwarding headers for `req`.
+    ///
+    /// If the peer is a trusted proxy, the existing `Forwarded` and `X-Forwarded-For` chains are
+    /// extended and its `X-Forwarded-Proto`/`X-Forwarded-Host` are kept. Otherwise, any forwarding
+    /// headers it sent are discarded and new chains are started.
+    pub fn new(req: &HttpRequest, trusted: &TrustedProxies) -> Self {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let is_trusted = peer.is_some_and(|ip| trusted.contains(ip));
+        let headers = req.headers();
+
+        let trusted_value = |name: &str| {
+            let vals = headers
+                .get_all(name)
+                .filter_map(|val| val.to_str().ok())
+                .collect::<Vec<_>>();
+
+            (is_trusted && !vals.is_empty()).then(|| vals.join(", "))
+        };
+
+        // `X-Forwarded-Proto` and `X-Forwarded-Host` name a single value; if a chain of proxies
+        // sent a list, the last element is the one set by the trusted peer
+        let trusted_last = |name: &str| {
+            trusted_value(name)
+                .and_then(|vals| vals.rsplit(',').next().map(|val| val.trim().to_owned()))
+                .filter(|val| !val.is_empty())
+        };
+
+        let proto = trusted_last(X_FORWARDED_PROTO).unwrap_or_else(|| {
+            if req.app_config().secure() {
+                "https".to_owned()
+            } else {
+                "http".to_owned()
+            }
+        });
+
+        let host = trusted_last(X_FORWARDED_HOST).or_else(|| {
+            headers
+                .get(header::HOST)
+                .and_then(|val| val.to_str().ok())
+                .map(str::to_owned)
+        });
+
+        let node = match peer {
+            Some(IpAddr::V4(ip)) => ip.to_string(),
+            Some(IpAddr::V6(ip)) => format!("\"[{ip}]\""),
+            None => "unknown".to_owned(),
+        };
+
+        let mut element = format!("for={node};proto={proto}");
+        if let Some(host) = &host {
+            element.push_str(&format!(";host={}", quote_if_needed(host)));
+        }
+
+        let append = |existing: Option<String>, new: &str| match existing {
+            Some(existing) => format!("{existing}, {new}"),
+            None => new.to_owned(),
+        };
+
+        let peer = peer.map_or_else(|| "unknown".to_owned(), |ip| ip.to_string());
+
+        Self {
+            forwarded: append(trusted_value(header::FORWARDED.as_str()), &element),
+            x_forwarded_for: append(trusted_value(X_FORWARDED_FOR), &peer),
+            x_forwarded_proto: proto,
+            x_forwarded_host: host,
+        }
+    }
+
+    /// Iterates over header name-value pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            (header::FORWARDED.as_str(), Some(self.forwarded.as_str())),
+            (X_FORWARDED_FOR, Some(self.x_forwarded_for.as_str())),
+            (X_FORWARDED_PROTO, Some(self.x_forwarded_proto.as_str())),
+            (X_FORWARDED_HOST, self.x_forwarded_host.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, val)| Some((name, val?)))
+    }
+}
+
+/// Quotes a `Forwarded` parameter value unless it is a valid token.
+fn quote_if_needed(val: &str) -> String {
+    let is_token = !val.is_empty()
+        && val
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+
+    if is_token {
+        val.to_owned()
+    } else {
+        format!("\"{}\"", val.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let req = TestRequest::default()
+            .insert_header((header::CONNECTION, "close, x-custom"))
+            .insert_header(("x-custom", "1"))
+            .insert_header(("keep-alive", "timeout=5"))
+            .insert_header(("proxy-authorization", "Basic Zm9vOmJhcg=="))
+            .insert_header((header::TE, "trailers"))
+            .insert_header((header::ACCEPT, "*/*"))
+            .to_http_request();
+
+        let names = end_to_end(req.headers())
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, ["accept"]);
+
+        let mut headers = req.headers().clone();
+        remove_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key(header::ACCEPT));
+    }
+
+    #[test]
+    fn extends_chain_from_trusted_proxy() {
+        let proxy: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let trusted = TrustedProxies::new(vec![proxy.ip()]);
+
+        let req = TestRequest::default()
+            .peer_addr(proxy)
+            .insert_header((header::HOST, "example.com"))
+            .insert_header((header::FORWARDED, "for=192.0.2.60;proto=https"))
+            .insert_header((X_FORWARDED_FOR, "192.0.2.60"))
+            .insert_header((X_FORWARDED_PROTO, "http, https"))
+            .to_http_request();
+
+        let fwd = Forwarded::new(&req, &trusted);
+        assert_eq!(
+            fwd.forwarded,
+            "for=192.0.2.60;proto=https, for=10.0.0.1;proto=https;host=example.com"
+        );
+        assert_eq!(fwd.x_forwarded_for, "192.0.2.60, 10.0.0.1");
+        assert_eq!(fwd.x_forwarded_proto, "https");
+    }
+
+    #[test]
+    fn replaces_chain_from_untrusted_peer() {
+        let peer: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+
+        let req = TestRequest::default()
+            .peer_addr(peer)
+            .insert_header((header::HOST, "example.com:8080"))
+            .insert_header((header::FORWARDED, "for=1.2.3.4"))
+            .insert_header((X_FORWARDED_FOR, "1.2.3.4"))
+            .to_http_request();
+
+        let fwd = Forwarded::new(&req, &TrustedProxies::default());
+        assert_eq!(
+            fwd.forwarded,
+            r#"for="[2001:db8::1]";proto=http;host="example.com:8080""#
+        );
+        assert_eq!(fwd.x_forwarded_for, "2001:db8::1");
+        assert_eq!(fwd.x_forwarded_host.as_deref(), Some("example.com:8080"));
+    }
+}
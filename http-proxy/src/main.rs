@@ -1,116 +1,546 @@
-use std::{io, net::ToSocketAddrs as _};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    io, iter,
+    net::{IpAddr, ToSocketAddrs as _},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use actix_web::{
-    App, Error, HttpRequest, HttpResponse, HttpServer, dev::PeerAddr, error, middleware, web,
+    App, Error, HttpRequest, HttpResponse, HttpServer, Responder, error,
+    http::{StatusCode, header},
+    middleware,
+    web::{self, Bytes},
+};
+use awc::{
+    Client, Connector,
+    error::{ConnectError, PayloadError, SendRequestError},
 };
-use awc::Client;
 use clap::Parser;
-use futures_util::StreamExt as _;
+use futures_util::{StreamExt as _, future, stream::LocalBoxStream};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use url::Url;
 
+mod cache;
+mod headers;
+mod retry;
+mod routing;
+mod tunnel;
+mod upstream;
+
+use self::{
+    cache::{Cache, CacheConfig, CacheStatus, CachedResponse, Lookup},
+    headers::{Forwarded, TrustedProxies},
+    retry::{RequestBody, RetryPolicy},
+    routing::Router,
+    tunnel::TunnelConfig,
+    upstream::{Balance, EjectionConfig, Timeouts, UpstreamGuard, UpstreamPool},
+};
+
 const REQWEST_PREFIX: &str = "/using-reqwest";
+const STATUS_PATH: &str = "/_proxy/status";
 
-/// Forwards the incoming HTTP request using `awc`.
-async fn forward(
-    req: HttpRequest,
-    payload: web::Payload,
-    peer_addr: Option<PeerAddr>,
-    url: web::Data<Url>,
-    client: web::Data<Client>,
-) -> Result<HttpResponse, Error> {
-    let mut new_url = (**url).clone();
-    new_url.set_path(req.uri().path());
-    new_url.set_query(req.uri().query());
-
-    let forwarded_req = client
-        .request_from(new_url.as_str(), req.head())
-        .no_decompress();
-
-    // TODO: This forwarded implementation is incomplete as it only handles the unofficial
-    // X-Forwarded-For header but not the official Forwarded one.
-    let forwarded_req = match peer_addr {
-        Some(PeerAddr(addr)) => {
-            forwarded_req.insert_header(("x-forwarded-for", addr.ip().to_string()))
+/// Selects an upstream for the request, balancing by client IP where needed.
+fn select_upstream(req: &HttpRequest, pool: &UpstreamPool) -> Result<UpstreamGuard, Error> {
+    let client_ip = req
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+
+    pool.select(&client_ip)
+        .ok_or_else(|| error::ErrorServiceUnavailable("no healthy upstream available"))
+}
+
+/// Returns the host the request was sent to, from the `Host` header or request target.
+fn request_host(req: &HttpRequest) -> &str {
+    req.headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().host())
+        .unwrap_or_default()
+}
+
+/// State shared by the request handlers of a worker.
+struct Proxy {
+    router: web::Data<Router>,
+    trusted_proxies: TrustedProxies,
+    retry_policy: RetryPolicy,
+    tunnel_config: TunnelConfig,
+    cache: Option<web::Data<Cache>>,
+
+    /// `awc` clients for each set of upstream timeouts, created on first use.
+    awc_clients: RefCell<HashMap<Timeouts, Client>>,
+
+    /// `reqwest` clients for each set of upstream timeouts, created on first use.
+    reqwest_clients: RefCell<HashMap<Timeouts, reqwest::Client>>,
+}
+
+impl Proxy {
+    /// Resolves `req` to the pool of upstreams that should serve it, forwarding it to `path`.
+    fn resolve(&self, req: &HttpRequest, path: &str) -> UpstreamRequest {
+        let (pool, path) = self.router.table().resolve(request_host(req), path);
+
+        UpstreamRequest {
+            req: req.clone(),
+            pool,
+            path,
+            forwarded: Forwarded::new(req, &self.trusted_proxies),
         }
-        None => forwarded_req,
-    };
+    }
+
+    fn awc_client(&self, timeouts: Timeouts) -> Client {
+        self.awc_clients
+            .borrow_mut()
+            .entry(timeouts)
+            .or_insert_with(|| {
+                Client::builder()
+                    .connector(Connector::new().timeout(timeouts.connect))
+                    .timeout(timeouts.read)
+                    .finish()
+            })
+            .clone()
+    }
+
+    fn reqwest_client(&self, timeouts: Timeouts) -> Result<reqwest::Client, Error> {
+        if let Some(client) = self.reqwest_clients.borrow().get(&timeouts) {
+            return Ok(client.clone());
+        }
+
+        let client = reqwest::Client::builder()
+            .connect_timeout(timeouts.connect)
+            .read_timeout(timeouts.read)
+            .build()
+            .map_err(error::ErrorInternalServerError)?;
 
-    let res = forwarded_req
-        .send_stream(payload)
-        .await
-        .map_err(error::ErrorInternalServerError)?;
+        self.reqwest_clients
+            .borrow_mut()
+            .insert(timeouts, client.clone());
 
-    let mut client_resp = HttpResponse::build(res.status());
-    // Remove `Connection` as per
-    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Connection#Directives
-    for (header_name, header_value) in res.headers().iter().filter(|(h, _)| *h != "connection") {
-        client_resp.insert_header((header_name.clone(), header_value.clone()));
+        Ok(client)
     }
+}
 
-    Ok(client_resp.streaming(res))
+/// Shows the state of the upstream pools of each route.
+async fn status(proxy: web::Data<Proxy>) -> impl Responder {
+    web::Json(proxy.router.table().status())
 }
 
-/// Same as `forward` but uses `reqwest` as the client used to forward the request.
-async fn forward_reqwest(
+/// Builds the URL of the request on the upstream.
+fn upstream_url(req: &HttpRequest, upstream: &UpstreamGuard, path: &str) -> Url {
+    let mut url = upstream.url_for(path);
+    url.set_query(req.uri().query());
+    url
+}
+
+/// A request resolved to a pool of upstreams.
+#[derive(Clone)]
+struct UpstreamRequest {
     req: HttpRequest,
-    mut payload: web::Payload,
-    method: actix_web::http::Method,
-    peer_addr: Option<PeerAddr>,
-    url: web::Data<Url>,
-    client: web::Data<reqwest::Client>,
-) -> Result<HttpResponse, Error> {
-    let path = req
-        .uri()
-        .path()
-        .strip_prefix(REQWEST_PREFIX)
-        .unwrap_or(req.uri().path());
+    pool: Arc<UpstreamPool>,
+    path: String,
+    forwarded: Forwarded,
+}
+
+impl UpstreamRequest {
+    /// Tunnels an upgraded connection to an upstream.
+    async fn tunnel(
+        &self,
+        payload: web::Payload,
+        config: &TunnelConfig,
+    ) -> Result<HttpResponse, Error> {
+        let upstream = select_upstream(&self.req, &self.pool)?;
+        let url = upstream_url(&self.req, &upstream, &self.path);
+
+        tunnel::open(&self.req, payload, upstream, &url, &self.forwarded, config).await
+    }
+
+    /// Sends the request to an upstream with `send`, retrying against another upstream after a
+    /// connection error or timeout until `attempts_left` runs out.
+    ///
+    /// `send` is given the URL of the request on the selected upstream. Upstreams are reported as
+    /// failing after an error or a response for which `is_server_error` returns true.
+    async fn send_with_retries<T, F, Fut>(
+        &self,
+        mut attempts_left: u32,
+        mut send: F,
+        is_server_error: impl Fn(&T) -> bool,
+    ) -> Result<(T, UpstreamGuard), Error>
+    where
+        F: FnMut(Url) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut last_error = None;
+
+        let (res, upstream) = loop {
+            let upstream = match select_upstream(&self.req, &self.pool) {
+                Ok(upstream) => upstream,
+                // retrying may have ejected every upstream; report why the last attempt failed
+                Err(err) => return Err(last_error.unwrap_or(err)),
+            };
+
+            match send(upstream_url(&self.req, &upstream, &self.path)).await {
+                Ok(res) => break (res, upstream),
+                Err(err) => {
+                    upstream.report_failure();
 
-    let mut new_url = (**url).clone();
-    new_url.set_path(path);
-    new_url.set_query(req.uri().query());
+                    if attempts_left == 1 {
+                        return Err(err);
+                    }
 
-    let (tx, rx) = mpsc::unbounded_channel();
+                    log::warn!(
+                        "retrying request after upstream {} failed: {err}",
+                        upstream.url()
+                    );
+                    attempts_left -= 1;
+                    last_error = Some(err);
+                }
+            }
+        };
+
+        if is_server_error(&res) {
+            upstream.report_failure();
+        } else {
+            upstream.report_success();
+        }
+
+        Ok((res, upstream))
+    }
+
+    /// Sends the request using `client`.
+    ///
+    /// `extra_headers` replace any request headers of the same name.
+    async fn send(
+        &self,
+        proxy: &Proxy,
+        client: ClientKind,
+        body: RequestBody,
+        extra_headers: &header::HeaderMap,
+    ) -> Result<UpstreamResponse, Error> {
+        match client {
+            ClientKind::Awc => self.send_awc(proxy, body, extra_headers).await,
+            ClientKind::Reqwest => self.send_reqwest(proxy, body, extra_headers).await,
+        }
+    }
+
+    async fn send_awc(
+        &self,
+        proxy: &Proxy,
+        body: RequestBody,
+        extra_headers: &header::HeaderMap,
+    ) -> Result<UpstreamResponse, Error> {
+        let client = proxy.awc_client(self.pool.timeouts());
+        let attempts = proxy.retry_policy.attempts(&body);
+        let (buffered, mut streaming) = body.into_parts();
+
+        let send = |url: Url| {
+            let mut forwarded_req = client
+                .request_from(url.as_str(), self.req.head())
+                .no_decompress();
+
+            headers::remove_hop_by_hop(forwarded_req.headers_mut());
+            forwarded_req
+                .headers_mut()
+                .retain(|name, _| !headers::is_forwarding(name.as_str()));
+
+            for header in self.forwarded.iter() {
+                forwarded_req = forwarded_req.insert_header(header);
+            }
+
+            for (name, val) in extra_headers {
+                forwarded_req = forwarded_req.insert_header((name.clone(), val.clone()));
+            }
+
+            let sent = match streaming.take() {
+                Some(stream) => forwarded_req.send_stream(stream),
+                None if buffered.is_empty() => forwarded_req.send(),
+                None => forwarded_req.send_body(buffered.clone()),
+            };
+
+            async move {
+                sent.await.map_err(|err| {
+                    let timed_out = matches!(
+                        err,
+                        SendRequestError::Timeout
+                            | SendRequestError::Connect(ConnectError::Timeout)
+                    );
+                    retry::gateway_error(err, timed_out)
+                })
+            }
+        };
+
+        let (res, upstream) = self
+            .send_with_retries(attempts, send, |res| res.status().is_server_error())
+            .await?;
+
+        Ok(UpstreamResponse {
+            status: res.status(),
+            headers: res.headers().clone(),
+            body: res
+                .map(move |chunk| {
+                    let _upstream = &upstream;
+                    chunk
+                })
+                .boxed_local(),
+        })
+    }
+
+    async fn send_reqwest(
+        &self,
+        proxy: &Proxy,
+        body: RequestBody,
+        extra_headers: &header::HeaderMap,
+    ) -> Result<UpstreamResponse, Error> {
+        let client = proxy.reqwest_client(self.pool.timeouts())?;
+        let attempts = proxy.retry_policy.attempts(&body);
+        let (buffered, streaming) = body.into_parts();
+
+        let method = reqwest::Method::from_bytes(self.req.method().as_str().as_bytes())
+            .map_err(error::ErrorBadRequest)?;
+
+        // reqwest needs a `Send` body, so the payload is passed to it through a channel
+        let mut streaming = streaming.map(|mut stream| {
+            let (tx, rx) = mpsc::unbounded_channel();
+
+            actix_web::rt::spawn(async move {
+                while let Some(chunk) = stream.next().await {
+                    if tx.send(chunk).is_err() {
+                        // upstream request was abandoned
+                        break;
+                    }
+                }
+            });
+
+            reqwest::Body::wrap_stream(UnboundedReceiverStream::new(rx))
+        });
+
+        let send = |url: Url| {
+            let mut forwarded_req = client.request(method.clone(), url);
+
+            match streaming.take() {
+                Some(body) => forwarded_req = forwarded_req.body(body),
+                None if buffered.is_empty() => {}
+                None => forwarded_req = forwarded_req.body(buffered.clone()),
+            }
+
+            for (header_name, header_value) in headers::end_to_end(self.req.headers())
+                .filter(|(name, _)| {
+                    !headers::is_forwarding(name.as_str()) && !extra_headers.contains_key(*name)
+                })
+                .chain(extra_headers)
+            {
+                forwarded_req = forwarded_req.header(header_name.as_str(), header_value.as_bytes());
+            }
+
+            for (header_name, header_value) in self.forwarded.iter() {
+                forwarded_req = forwarded_req.header(header_name, header_value);
+            }
+
+            async move {
+                forwarded_req.send().await.map_err(|err| {
+                    let timed_out = err.is_timeout();
+                    retry::gateway_error(err, timed_out)
+                })
+            }
+        };
+
+        let (res, upstream) = self
+            .send_with_retries(attempts, send, |res| res.status().is_server_error())
+            .await?;
+
+        let status = StatusCode::from_u16(res.status().as_u16()).map_err(error::ErrorBadGateway)?;
+
+        let mut headers = header::HeaderMap::new();
+        for (name, val) in res.headers() {
+            if let (Ok(name), Ok(val)) = (
+                header::HeaderName::from_bytes(name.as_ref()),
+                header::HeaderValue::from_bytes(val.as_ref()),
+            ) {
+                headers.append(name, val);
+            }
+        }
+
+        Ok(UpstreamResponse {
+            status,
+            headers,
+            body: res
+                .bytes_stream()
+                .map(move |chunk| {
+                    let _upstream = &upstream;
+                    chunk.map_err(|err| PayloadError::Io(io::Error::other(err)))
+                })
+                .boxed_local(),
+        })
+    }
+}
+
+/// HTTP client used to send requests upstream.
+#[derive(Debug, Clone, Copy)]
+enum ClientKind {
+    Awc,
+    Reqwest,
+}
+
+/// Response received from an upstream by either client.
+struct UpstreamResponse {
+    status: StatusCode,
+    headers: header::HeaderMap,
+
+    /// Body, which keeps the upstream counted as busy until it has been streamed.
+    body: LocalBoxStream<'static, Result<Bytes, PayloadError>>,
+}
+
+/// Refreshes a stale cache entry without holding up the client, which has been served the stale
+/// response.
+fn revalidate_in_background(
+    upstream_req: UpstreamRequest,
+    proxy: web::Data<Proxy>,
+    client: ClientKind,
+    cache: web::Data<Cache>,
+    entry: Arc<CachedResponse>,
+) {
+    if !cache.begin_revalidation(&entry) {
+        return;
+    }
 
     actix_web::rt::spawn(async move {
-        while let Some(chunk) = payload.next().await {
-            tx.send(chunk).unwrap();
+        let body = RequestBody::Buffered(Bytes::new());
+
+        match upstream_req
+            .send(&proxy, client, body, &entry.validators())
+            .await
+        {
+            Ok(res) if res.status == StatusCode::NOT_MODIFIED => {
+                cache.revalidated(&entry, &res.headers);
+            }
+
+            Ok(res) => {
+                // read the whole body so that it is stored
+                if let Ok(body) = cache::fill(
+                    &cache,
+                    &upstream_req.req,
+                    res.status,
+                    &res.headers,
+                    res.body,
+                ) {
+                    body.for_each(|_| future::ready(())).await;
+                }
+            }
+
+            Err(err) => log::warn!("failed to revalidate cached response: {err}"),
         }
+
+        cache.end_revalidation(&entry);
     });
+}
+
+/// Forwards `req` to `path` on its upstreams using `client`, answering from the cache when
+/// possible.
+async fn forward_with(
+    req: HttpRequest,
+    payload: web::Payload,
+    proxy: web::Data<Proxy>,
+    path: &str,
+    client: ClientKind,
+) -> Result<HttpResponse, Error> {
+    let upstream_req = proxy.resolve(&req, path);
+
+    if tunnel::is_upgrade(&req) {
+        return upstream_req.tunnel(payload, &proxy.tunnel_config).await;
+    }
 
-    let forwarded_req = client
-        .request(
-            reqwest::Method::from_bytes(method.as_str().as_bytes()).unwrap(),
-            new_url,
-        )
-        .body(reqwest::Body::wrap_stream(UnboundedReceiverStream::new(rx)));
-
-    // TODO: This forwarded implementation is incomplete as it only handles the unofficial
-    // X-Forwarded-For header but not the official Forwarded one.
-    let forwarded_req = match peer_addr {
-        Some(PeerAddr(addr)) => forwarded_req.header("x-forwarded-for", addr.ip().to_string()),
-        None => forwarded_req,
+    let cache = proxy.cache.clone();
+
+    let lookup = match &cache {
+        Some(cache) => cache.lookup(&req).await,
+        None => Lookup::Bypass,
     };
 
-    let res = forwarded_req
-        .send()
-        .await
-        .map_err(error::ErrorInternalServerError)?;
+    let bypass = matches!(lookup, Lookup::Bypass);
+    let mut validators = header::HeaderMap::new();
+
+    let stale = match lookup {
+        Lookup::Fresh(entry) => return Ok(entry.response(&req, CacheStatus::Hit)),
 
-    let mut client_resp =
-        HttpResponse::build(actix_web::http::StatusCode::from_u16(res.status().as_u16()).unwrap());
+        Lookup::StaleWhileRevalidate(entry) => {
+            let res = entry.response(&req, CacheStatus::Stale);
+
+            if let Some(cache) = cache {
+                revalidate_in_background(upstream_req, proxy, client, cache, entry);
+            }
+
+            return Ok(res);
+        }
+
+        Lookup::Stale(entry) => {
+            validators = entry.validators();
+            Some(entry)
+        }
 
-    // Remove `Connection` as per
-    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Connection#Directives
-    for (header_name, header_value) in res.headers().iter().filter(|(h, _)| *h != "connection") {
-        client_resp.insert_header((
-            actix_web::http::header::HeaderName::from_bytes(header_name.as_ref()).unwrap(),
-            actix_web::http::header::HeaderValue::from_bytes(header_value.as_ref()).unwrap(),
-        ));
+        Lookup::Bypass | Lookup::Miss => None,
+    };
+
+    let body = proxy.retry_policy.body(&req, payload).await?;
+    let res = upstream_req.send(&proxy, client, body, &validators).await?;
+
+    if let Some(cache) = &cache {
+        if let Some(entry) = stale.filter(|_| res.status == StatusCode::NOT_MODIFIED) {
+            let entry = cache.revalidated(&entry, &res.headers);
+            return Ok(entry.response(&req, CacheStatus::Revalidated));
+        }
+
+        // unsafe requests that succeed may have changed the resource
+        if !req.method().is_safe() && !res.status.is_server_error() {
+            cache.invalidate(&req);
+        }
+    }
+
+    let mut client_resp = HttpResponse::build(res.status);
+    // Remove hop-by-hop headers as per
+    // https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1
+    for (header_name, header_value) in headers::end_to_end(&res.headers) {
+        client_resp.append_header((header_name.clone(), header_value.clone()));
+    }
+
+    if cache.is_some() {
+        client_resp.insert_header(CacheStatus::Miss.header());
     }
 
-    Ok(client_resp.streaming(res.bytes_stream()))
+    let Some(cache) = cache.filter(|_| !bypass) else {
+        return Ok(client_resp.streaming(res.body));
+    };
+
+    Ok(
+        match cache::fill(&cache, &req, res.status, &res.headers, res.body) {
+            Ok(body) => client_resp.streaming(body),
+            Err(body) => client_resp.streaming(body),
+        },
+    )
+}
+
+/// Forwards the incoming HTTP request using `awc`.
+async fn forward(
+    req: HttpRequest,
+    payload: web::Payload,
+    proxy: web::Data<Proxy>,
+) -> Result<HttpResponse, Error> {
+    let path = req.uri().path().to_owned();
+    forward_with(req, payload, proxy, &path, ClientKind::Awc).await
+}
+
+/// Same as `forward` but uses `reqwest` as the client used to forward the request.
+async fn forward_reqwest(
+    req: HttpRequest,
+    payload: web::Payload,
+    proxy: web::Data<Proxy>,
+) -> Result<HttpResponse, Error> {
+    let path = req.uri().path();
+    let path = path.strip_prefix(REQWEST_PREFIX).unwrap_or(path).to_owned();
+
+    forward_with(req, payload, proxy, &path, ClientKind::Reqwest).await
 }
 
 #[derive(clap::Parser, Debug)]
@@ -119,6 +549,75 @@ struct CliArguments {
     listen_port: u16,
     forward_addr: String,
     forward_port: u16,
+
+    /// Additional upstream server to balance requests across, as `addr:port`. May be given
+    /// multiple times.
+    #[arg(long = "upstream")]
+    upstreams: Vec<String>,
+
+    /// Strategy used to pick an upstream for each request.
+    #[arg(long, value_enum, default_value_t = Balance::RoundRobin)]
+    balance: Balance,
+
+    /// TOML file of host- and path-based routes. Requests matching no route are sent to the
+    /// upstreams given on the command line. Reloaded on SIGHUP.
+    #[arg(long)]
+    routes: Option<PathBuf>,
+
+    /// Path polled on each upstream to check its health. Active health checks are disabled when
+    /// not set.
+    #[arg(long)]
+    health_check_path: Option<String>,
+
+    /// Seconds between active health checks.
+    #[arg(long, default_value_t = 10)]
+    health_check_interval: u64,
+
+    /// Seconds to wait for a connection to an upstream to be established, for routes that do not
+    /// set their own.
+    #[arg(long, default_value_t = 5)]
+    connect_timeout: u64,
+
+    /// Seconds to wait for an upstream to respond, for routes that do not set their own. Requests
+    /// that time out fail with `504 Gateway Timeout`.
+    #[arg(long, default_value_t = 30)]
+    read_timeout: u64,
+
+    /// Number of times an idempotent request with a small or no body is retried against another
+    /// upstream after a connection error or timeout.
+    #[arg(long, default_value_t = 2)]
+    retries: u32,
+
+    /// Number of consecutive connection errors, timeouts or 5xx responses after which an
+    /// upstream is ejected from the pool.
+    #[arg(long, default_value_t = 3)]
+    max_failures: u32,
+
+    /// Seconds for which an ejected upstream is left out of the pool, before a single request is
+    /// let through to check whether it has recovered.
+    #[arg(long, default_value_t = 30)]
+    ejection_duration: u64,
+
+    /// Seconds without traffic after which a tunneled connection is closed.
+    #[arg(long, default_value_t = 300)]
+    tunnel_idle_timeout: u64,
+
+    /// Size of the in-memory response cache, in MiB. Responses are not cached when not set.
+    #[arg(long)]
+    cache_size: Option<u64>,
+
+    /// Directory that responses evicted from the in-memory cache are moved to.
+    #[arg(long, requires = "cache_size")]
+    cache_dir: Option<PathBuf>,
+
+    /// Size of the on-disk response cache, in MiB.
+    #[arg(long, default_value_t = 1024)]
+    cache_disk_size: u64,
+
+    /// IP address of a proxy in front of this one, whose forwarding headers are trusted. May be
+    /// given multiple times.
+    #[arg(long = "trusted-proxy")]
+    trusted_proxies: Vec<IpAddr>,
 }
 
 #[actix_web::main]
@@ -127,13 +626,18 @@ async fn main() -> io::Result<()> {
 
     let args = CliArguments::parse();
 
-    let forward_socket_addr = (args.forward_addr, args.forward_port)
-        .to_socket_addrs()?
-        .next()
-        .expect("given forwarding address was not valid");
+    let forward_urls =
+        iter::once((args.forward_addr.as_str(), args.forward_port).to_socket_addrs())
+            .chain(args.upstreams.iter().map(|addr| addr.to_socket_addrs()))
+            .map(|addrs| {
+                let forward_socket_addr = addrs?
+                    .next()
+                    .expect("given forwarding address was not valid");
 
-    let forward_url = format!("http://{forward_socket_addr}");
-    let forward_url = Url::parse(&forward_url).unwrap();
+                let forward_url = format!("http://{forward_socket_addr}");
+                Ok(Url::parse(&forward_url).unwrap())
+            })
+            .collect::<io::Result<Vec<_>>>()?;
 
     log::info!(
         "starting HTTP server at http://{}:{}",
@@ -141,16 +645,81 @@ async fn main() -> io::Result<()> {
         args.listen_port
     );
 
-    log::info!("forwarding to {forward_url}");
+    for forward_url in &forward_urls {
+        log::info!("forwarding to {forward_url}");
+    }
+
+    let ejection = EjectionConfig {
+        max_failures: args.max_failures,
+        duration: Duration::from_secs(args.ejection_duration),
+    };
+
+    let timeouts = Timeouts {
+        connect: Duration::from_secs(args.connect_timeout),
+        read: Duration::from_secs(args.read_timeout),
+    };
 
-    let reqwest_client = reqwest::Client::default();
+    let router = web::Data::new(Router::new(
+        UpstreamPool::new(forward_urls, args.balance, ejection, timeouts),
+        args.routes,
+        args.balance,
+        ejection,
+        timeouts,
+    )?);
+
+    #[cfg(unix)]
+    {
+        let router = router.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(err) = router.reload_on_sighup().await {
+                log::error!("failed to listen for SIGHUP: {err}");
+            }
+        });
+    }
+
+    if let Some(path) = args.health_check_path {
+        let router = router.clone();
+        let interval = Duration::from_secs(args.health_check_interval);
+        actix_web::rt::spawn(async move { router.run_health_checks(path, interval).await });
+    }
+
+    let tunnel_config = TunnelConfig {
+        idle_timeout: Duration::from_secs(args.tunnel_idle_timeout),
+    };
+
+    let retry_policy = RetryPolicy {
+        retries: args.retries,
+    };
+
+    let cache = args
+        .cache_size
+        .map(|size| {
+            Cache::new(CacheConfig {
+                max_size: size * 1024 * 1024,
+                disk_dir: args.cache_dir,
+                disk_max_size: args.cache_disk_size * 1024 * 1024,
+            })
+        })
+        .transpose()?
+        .map(web::Data::new);
+
+    let trusted_proxies = TrustedProxies::new(args.trusted_proxies);
 
     HttpServer::new(move || {
+        let proxy = Proxy {
+            router: router.clone(),
+            trusted_proxies: trusted_proxies.clone(),
+            retry_policy,
+            tunnel_config,
+            cache: cache.clone(),
+            awc_clients: RefCell::default(),
+            reqwest_clients: RefCell::default(),
+        };
+
         App::new()
-            .app_data(web::Data::new(Client::default()))
-            .app_data(web::Data::new(reqwest_client.clone()))
-            .app_data(web::Data::new(forward_url.clone()))
+            .app_data(web::Data::new(proxy))
             .wrap(middleware::Logger::default())
+            .service(web::resource(STATUS_PATH).route(web::get().to(status)))
             .service(web::scope(REQWEST_PREFIX).default_service(web::to(forward_reqwest)))
             .default_service(web::to(forward))
     })
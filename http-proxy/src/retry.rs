@@ -0,0 +1,178 @@
+//! Retrying of requests whose upstream could not be reached, and mapping of upstream errors to
+//! responses.
+
+use std::fmt;
+
+use actix_web::{
+    Error, HttpRequest, error,
+    error::PayloadError,
+    http::{Method, header},
+    web::{self, Bytes, BytesMut},
+};
+use futures_util::{StreamExt as _, stream, stream::LocalBoxStream};
+
+/// Largest request body that is held in memory so that the request can be retried.
+const MAX_BUFFERED_BODY: usize = 64 * 1024;
+
+/// Body of a request to be forwarded.
+pub enum RequestBody {
+    /// The whole body, which can be sent again if an attempt fails.
+    Buffered(Bytes),
+
+    /// A body streamed to the upstream as it arrives, which can only be sent once.
+    Streaming(LocalBoxStream<'static, Result<Bytes, PayloadError>>),
+}
+
+/// Settings for retrying requests against another upstream.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Maximum number of additional attempts after the first one fails.
+    pub retries: u32,
+}
+
+impl RequestBody {
+    /// Splits the body into the buffered part, which is sent on every attempt, and the stream
+    /// that is sent on the first attempt only.
+    pub fn into_parts(
+        self,
+    ) -> (
+        Bytes,
+        Option<LocalBoxStream<'static, Result<Bytes, PayloadError>>>,
+    ) {
+        match self {
+            Self::Buffered(body) => (body, None),
+            Self::Streaming(stream) => (Bytes::new(), Some(stream)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Prepares the body of `req` for forwarding.
+    ///
+    /// The body of an idempotent request is read into memory if it is small enough, so that the
+    /// request can be retried. Other bodies are streamed to the upstream as they arrive.
+    pub async fn body(
+        &self,
+        req: &HttpRequest,
+        mut payload: web::Payload,
+    ) -> Result<RequestBody, Error> {
+        let too_large = content_length(req).is_some_and(|len| len > MAX_BUFFERED_BODY as u64);
+
+        if self.retries == 0 || !is_idempotent(req.method()) || too_large {
+            return Ok(RequestBody::Streaming(payload.boxed_local()));
+        }
+
+        let mut buf = BytesMut::new();
+
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk?;
+
+            if buf.len() + chunk.len() > MAX_BUFFERED_BODY {
+                // send what has been read so far followed by the rest of the body
+                let read = stream::iter([Ok(buf.freeze()), Ok(chunk)]);
+                return Ok(RequestBody::Streaming(read.chain(payload).boxed_local()));
+            }
+
+            buf.extend_from_slice(&chunk);
+        }
+
+        Ok(RequestBody::Buffered(buf.freeze()))
+    }
+
+    /// Returns the number of attempts that may be made to send a request with `body`.
+    ///
+    /// Only requests whose body has been buffered are retried, since a streamed body cannot be
+    /// sent again.
+    pub fn attempts(&self, body: &RequestBody) -> u32 {
+        match body {
+            RequestBody::Buffered(_) => self.retries + 1,
+            RequestBody::Streaming(_) => 1,
+        }
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+fn content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Converts an error sending a request upstream into a `504 Gateway Timeout` response if it timed
+/// out, or a `502 Bad Gateway` response otherwise.
+pub fn gateway_error<E>(err: E, timed_out: bool) -> Error
+where
+    E: fmt::Debug + fmt::Display + 'static,
+{
+    if timed_out {
+        error::ErrorGatewayTimeout(err)
+    } else {
+        error::ErrorBadGateway(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{FromRequest as _, test::TestRequest};
+
+    use super::*;
+
+    async fn body(policy: RetryPolicy, req: TestRequest, body: Vec<u8>) -> RequestBody {
+        let (req, mut payload) = req.set_payload(body).to_http_parts();
+        let payload = web::Payload::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+
+        policy.body(&req, payload).await.unwrap()
+    }
+
+    async fn collect(body: RequestBody) -> Bytes {
+        match body {
+            RequestBody::Buffered(body) => body,
+            RequestBody::Streaming(stream) => stream
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+                .await
+                .concat()
+                .into(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn retries_idempotent_requests_with_small_bodies() {
+        let policy = RetryPolicy { retries: 2 };
+
+        let empty = body(policy, TestRequest::get(), Vec::new()).await;
+        assert_eq!(policy.attempts(&empty), 3);
+
+        let post = body(policy, TestRequest::post(), b"hello".to_vec()).await;
+        assert_eq!(policy.attempts(&post), 1);
+        assert_eq!(collect(post).await, "hello");
+
+        let put = body(policy, TestRequest::put(), b"hello".to_vec()).await;
+        assert_eq!(policy.attempts(&put), 3);
+        assert_eq!(collect(put).await, "hello");
+
+        let never = body(RetryPolicy { retries: 0 }, TestRequest::put(), Vec::new()).await;
+        assert_eq!(RetryPolicy { retries: 0 }.attempts(&never), 1);
+    }
+
+    #[actix_web::test]
+    async fn streams_large_bodies_without_losing_them() {
+        let policy = RetryPolicy { retries: 2 };
+        let large = vec![b'a'; MAX_BUFFERED_BODY + 1];
+
+        let put = body(policy, TestRequest::put(), large.clone()).await;
+        assert_eq!(policy.attempts(&put), 1);
+        assert_eq!(collect(put).await, large);
+    }
+}
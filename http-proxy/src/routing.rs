@@ -0,0 +1,541 @@
+//! Host- and path-based routing of requests to upstream pools.
+//!
+//! Routes are loaded from a TOML file of the form:
+//!
+//! ```toml
+//! [[route]]
+//! host = "api.example.com"
+//! path_prefix = "/v1"
+//! rewrite = "/api/v1"
+//! upstreams = ["http://127.0.0.1:8081", "http://127.0.0.1:8082"]
+//! balance = "least-connections"
+//! connect_timeout = 2
+//! read_timeout = 60
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use awc::Client;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::upstream::{Balance, EjectionConfig, PoolStatus, Timeouts, Upstream, UpstreamPool};
+
+#[derive(Debug, Deserialize)]
+struct RoutesConfig {
+    #[serde(default, rename = "route")]
+    routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    /// Host that the route applies to, or all hosts if not set. A leading `*.` matches any
+    /// subdomain.
+    host: Option<String>,
+
+    /// Path prefix that the route applies to, matched on segment boundaries.
+    #[serde(default = "default_path_prefix")]
+    path_prefix: String,
+
+    /// Whether to remove the matched prefix before forwarding.
+    #[serde(default)]
+    strip_prefix: bool,
+
+    /// Replacement for the matched prefix. Takes precedence over `strip_prefix`.
+    rewrite: Option<String>,
+
+    upstreams: Vec<Url>,
+
+    /// Balancing strategy for the route's upstreams. Defaults to the one given on the command line.
+    balance: Option<Balance>,
+
+    /// Seconds to wait for a connection to one of the route's upstreams. Defaults to the timeout
+    /// given on the command line.
+    connect_timeout: Option<u64>,
+
+    /// Seconds to wait for one of the route's upstreams to respond. Defaults to the timeout given
+    /// on the command line.
+    read_timeout: Option<u64>,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_owned()
+}
+
+/// A route to a pool of upstreams.
+#[derive(Debug)]
+struct Route {
+    host: Option<String>,
+    path_prefix: String,
+    strip_prefix: bool,
+    rewrite: Option<String>,
+    pool: Arc<UpstreamPool>,
+}
+
+impl Route {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        let host_matches = match &self.host {
+            None => true,
+            Some(pattern) => match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => host == pattern,
+            },
+        };
+
+        host_matches
+            && path.strip_prefix(&self.path_prefix).is_some_and(|rest| {
+                rest.is_empty() || rest.starts_with('/') || self.path_prefix.ends_with('/')
+            })
+    }
+
+    /// Returns the path to forward to the upstream, given a request path matched by this route.
+    fn forward_path(&self, path: &str) -> String {
+        let rest = &path[self.path_prefix.len()..];
+
+        match (&self.rewrite, self.strip_prefix) {
+            (Some(rewrite), _) => join_path(rewrite, rest),
+            (None, true) => join_path("/", rest),
+            (None, false) => path.to_owned(),
+        }
+    }
+}
+
+/// Joins `rest` onto the path `base`, with a single `/` between them.
+pub fn join_path(base: &str, rest: &str) -> String {
+    let base = base.trim_end_matches('/');
+    let rest = rest.trim_start_matches('/');
+
+    if rest.is_empty() && !base.is_empty() {
+        base.to_owned()
+    } else {
+        format!("{base}/{rest}")
+    }
+}
+
+/// Immutable set of routes, replaced as a whole when the configuration is reloaded.
+#[derive(Debug)]
+pub struct RoutingTable {
+    /// Routes, ordered so that the first match is the most specific.
+    routes: Vec<Route>,
+
+    /// Pool used for requests that match no route.
+    fallback: Arc<UpstreamPool>,
+}
+
+impl RoutingTable {
+    /// Builds a table from `config`.
+    ///
+    /// Upstreams that are also in `previous` are carried over rather than created afresh, so that
+    /// reloading does not reset their health, circuit breakers or ejections.
+    fn new(
+        config: RoutesConfig,
+        fallback: Arc<UpstreamPool>,
+        balance: Balance,
+        ejection: EjectionConfig,
+        timeouts: Timeouts,
+        previous: Option<&RoutingTable>,
+    ) -> io::Result<Self> {
+        let mut upstreams = previous
+            .into_iter()
+            .flat_map(|table| table.pools())
+            .chain([&fallback])
+            .flat_map(|pool| pool.upstreams())
+            .map(|upstream| (upstream.url().clone(), Arc::clone(upstream)))
+            .collect::<HashMap<_, _>>();
+
+        let mut routes = config
+            .routes
+            .into_iter()
+            .map(|route| {
+                if route.upstreams.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("route for {} has no upstreams", route.path_prefix),
+                    ));
+                }
+
+                if !route.path_prefix.starts_with('/') {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("path prefix {} must start with /", route.path_prefix),
+                    ));
+                }
+
+                let timeouts = Timeouts {
+                    connect: route
+                        .connect_timeout
+                        .map_or(timeouts.connect, Duration::from_secs),
+                    read: route
+                        .read_timeout
+                        .map_or(timeouts.read, Duration::from_secs),
+                };
+
+                Ok(Route {
+                    host: route.host.map(|host| host.to_ascii_lowercase()),
+                    path_prefix: route.path_prefix,
+                    strip_prefix: route.strip_prefix,
+                    rewrite: route.rewrite,
+                    pool: Arc::new(UpstreamPool::with_upstreams(
+                        route
+                            .upstreams
+                            .into_iter()
+                            .map(|url| {
+                                let upstream = upstreams
+                                    .entry(url.clone())
+                                    .or_insert_with(|| Arc::new(Upstream::new(url)));
+                                Arc::clone(upstream)
+                            })
+                            .collect(),
+                        route.balance.unwrap_or(balance),
+                        ejection,
+                        timeouts,
+                    )),
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // host-specific routes first, then longest prefix first
+        routes.sort_by_key(|route| (route.host.is_none(), Reverse(route.path_prefix.len())));
+
+        Ok(Self { routes, fallback })
+    }
+
+    /// Returns the pool that should serve a request and the path to forward it to.
+    ///
+    /// `host` may include a port, which is ignored.
+    pub fn resolve(&self, host: &str, path: &str) -> (Arc<UpstreamPool>, String) {
+        let host = host
+            .rsplit_once(':')
+            .filter(|(_, port)| port.bytes().all(|b| b.is_ascii_digit()))
+            .map_or(host, |(host, _)| host)
+            .to_ascii_lowercase();
+
+        match self.routes.iter().find(|route| route.matches(&host, path)) {
+            Some(route) => (Arc::clone(&route.pool), route.forward_path(path)),
+            None => (Arc::clone(&self.fallback), path.to_owned()),
+        }
+    }
+
+    fn pools(&self) -> impl Iterator<Item = &Arc<UpstreamPool>> {
+        self.routes
+            .iter()
+            .map(|route| &route.pool)
+            .chain([&self.fallback])
+    }
+
+    /// Describes the current state of each route's pool.
+    pub fn status(&self) -> RoutingStatus {
+        RoutingStatus {
+            routes: self
+                .routes
+                .iter()
+                .map(|route| RouteStatus {
+                    host: route.host.clone(),
+                    path_prefix: route.path_prefix.clone(),
+                    pool: route.pool.status(),
+                })
+                .collect(),
+            fallback: self.fallback.status(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoutingStatus {
+    routes: Vec<RouteStatus>,
+    fallback: PoolStatus,
+}
+
+#[derive(Debug, Serialize)]
+struct RouteStatus {
+    host: Option<String>,
+    path_prefix: String,
+    pool: PoolStatus,
+}
+
+/// Holds the current routing table and reloads it from the configuration file on request.
+///
+/// Requests take a reference to the table when they start, so reloading does not affect those
+/// already in flight.
+#[derive(Debug)]
+pub struct Router {
+    table: RwLock<Arc<RoutingTable>>,
+    config_path: Option<PathBuf>,
+    fallback: Arc<UpstreamPool>,
+    balance: Balance,
+    ejection: EjectionConfig,
+    timeouts: Timeouts,
+}
+
+impl Router {
+    /// Constructs a router, loading routes from `config_path` if given.
+    ///
+    /// `balance`, `ejection` and `timeouts` apply to routes that do not set their own.
+    pub fn new(
+        fallback: UpstreamPool,
+        config_path: Option<PathBuf>,
+        balance: Balance,
+        ejection: EjectionConfig,
+        timeouts: Timeouts,
+    ) -> io::Result<Self> {
+        let fallback = Arc::new(fallback);
+        let table = load(
+            config_path.as_deref(),
+            &fallback,
+            balance,
+            ejection,
+            timeouts,
+            None,
+        )?;
+
+        Ok(Self {
+            table: RwLock::new(Arc::new(table)),
+            config_path,
+            fallback,
+            balance,
+            ejection,
+            timeouts,
+        })
+    }
+
+    /// Returns the current routing table.
+    pub fn table(&self) -> Arc<RoutingTable> {
+        Arc::clone(&self.table.read().unwrap())
+    }
+
+    /// Reloads routes from the configuration file, keeping the current table if it is invalid.
+    ///
+    /// Upstreams that were already configured keep their health and circuit state.
+    pub fn reload(&self) -> io::Result<()> {
+        let table = load(
+            self.config_path.as_deref(),
+            &self.fallback,
+            self.balance,
+            self.ejection,
+            self.timeouts,
+            Some(&self.table()),
+        )?;
+
+        *self.table.write().unwrap() = Arc::new(table);
+        Ok(())
+    }
+
+    /// Reloads routes each time the process receives `SIGHUP`.
+    #[cfg(unix)]
+    pub async fn reload_on_sighup(&self) -> io::Result<()> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        while hangup.recv().await.is_some() {
+            match self.reload() {
+                Ok(()) => log::info!("reloaded routes"),
+                Err(err) => log::error!("failed to reload routes, keeping previous ones: {err}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Periodically checks the health of the upstreams of every route.
+    ///
+    /// All upstreams are checked at once, and each check times out after half the interval, so
+    /// that an unresponsive upstream cannot hold up the checks of others.
+    pub async fn run_health_checks(&self, path: String, interval: Duration) {
+        let client = Client::builder().timeout(interval / 2).finish();
+        let mut ticker = actix_web::rt::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            let table = self.table();
+            join_all(table.pools().map(|pool| pool.check_health(&client, &path))).await;
+        }
+    }
+}
+
+fn load(
+    config_path: Option<&Path>,
+    fallback: &Arc<UpstreamPool>,
+    balance: Balance,
+    ejection: EjectionConfig,
+    timeouts: Timeouts,
+    previous: Option<&RoutingTable>,
+) -> io::Result<RoutingTable> {
+    let config = match config_path {
+        Some(path) => toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        None => RoutesConfig { routes: Vec::new() },
+    };
+
+    RoutingTable::new(
+        config,
+        Arc::clone(fallback),
+        balance,
+        ejection,
+        timeouts,
+        previous,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EJECTION: EjectionConfig = EjectionConfig {
+        max_failures: 3,
+        duration: Duration::from_secs(30),
+    };
+
+    fn table(config: &str) -> RoutingTable {
+        let fallback = UpstreamPool::new(
+            vec![Url::parse("http://127.0.0.1:9000").unwrap()],
+            Balance::RoundRobin,
+            EJECTION,
+            Timeouts::default(),
+        );
+
+        reload(config, Arc::new(fallback), None)
+    }
+
+    fn reload(
+        config: &str,
+        fallback: Arc<UpstreamPool>,
+        previous: Option<&RoutingTable>,
+    ) -> RoutingTable {
+        RoutingTable::new(
+            toml::from_str(config).unwrap(),
+            fallback,
+            Balance::RoundRobin,
+            EJECTION,
+            Timeouts::default(),
+            previous,
+        )
+        .unwrap()
+    }
+
+    fn resolve(table: &RoutingTable, host: &str, path: &str) -> (u16, String) {
+        let (pool, path) = table.resolve(host, path);
+        let port = pool.select("").unwrap().url().port().unwrap();
+        (port, path)
+    }
+
+    #[test]
+    fn picks_most_specific_route() {
+        let table = table(
+            r#"
+            [[route]]
+            path_prefix = "/api"
+            upstreams = ["http://127.0.0.1:9001"]
+
+            [[route]]
+            path_prefix = "/api/admin"
+            upstreams = ["http://127.0.0.1:9002"]
+
+            [[route]]
+            host = "*.example.com"
+            upstreams = ["http://127.0.0.1:9003"]
+            "#,
+        );
+
+        assert_eq!(resolve(&table, "localhost", "/api/users").0, 9001);
+        assert_eq!(resolve(&table, "localhost", "/api/admin/x").0, 9002);
+        assert_eq!(resolve(&table, "localhost", "/apikeys").0, 9000);
+        assert_eq!(resolve(&table, "www.Example.com:8080", "/api").0, 9003);
+        assert_eq!(resolve(&table, "example.com", "/").0, 9000);
+    }
+
+    #[test]
+    fn strips_and_rewrites_prefix() {
+        let table = table(
+            r#"
+            [[route]]
+            path_prefix = "/old"
+            rewrite = "/new"
+            upstreams = ["http://127.0.0.1:9001"]
+
+            [[route]]
+            path_prefix = "/svc/"
+            strip_prefix = true
+            upstreams = ["http://127.0.0.1:9002"]
+            "#,
+        );
+
+        assert_eq!(resolve(&table, "", "/old/a/b").1, "/new/a/b");
+        assert_eq!(resolve(&table, "", "/old").1, "/new");
+        assert_eq!(resolve(&table, "", "/svc/items").1, "/items");
+        assert_eq!(resolve(&table, "", "/svc/").1, "/");
+        assert_eq!(resolve(&table, "", "/other").1, "/other");
+    }
+
+    #[test]
+    fn routes_override_default_timeouts() {
+        let table = table(
+            r#"
+            [[route]]
+            path_prefix = "/slow"
+            upstreams = ["http://127.0.0.1:9001"]
+            read_timeout = 120
+
+            [[route]]
+            path_prefix = "/"
+            upstreams = ["http://127.0.0.1:9002"]
+            "#,
+        );
+
+        let (pool, _) = table.resolve("", "/slow/report");
+        assert_eq!(
+            pool.timeouts(),
+            Timeouts {
+                read: Duration::from_secs(120),
+                ..Timeouts::default()
+            }
+        );
+
+        let (pool, _) = table.resolve("", "/other");
+        assert_eq!(pool.timeouts(), Timeouts::default());
+    }
+
+    #[test]
+    fn reload_keeps_upstream_state() {
+        let table = table(
+            r#"
+            [[route]]
+            path_prefix = "/api"
+            upstreams = ["http://127.0.0.1:9001"]
+            "#,
+        );
+
+        let (pool, _) = table.resolve("", "/api");
+        for _ in 0..EJECTION.max_failures {
+            pool.select("").unwrap().report_failure();
+        }
+        assert!(pool.select("").is_none());
+
+        let table = reload(
+            r#"
+            [[route]]
+            path_prefix = "/api"
+            upstreams = ["http://127.0.0.1:9001", "http://127.0.0.1:9002"]
+            "#,
+            Arc::clone(&table.fallback),
+            Some(&table),
+        );
+
+        // the ejected upstream stays out of rotation
+        for _ in 0..4 {
+            assert_eq!(resolve(&table, "", "/api").0, 9002);
+        }
+    }
+}
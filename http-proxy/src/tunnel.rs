@@ -0,0 +1,682 @@
+//! Tunneling of upgraded connections, such as WebSockets, to upstreams.
+//!
+//! The handshake is forwarded over a new connection to the upstream. If it accepts the upgrade,
+//! bytes are then copied in both directions until either side closes the connection or no data
+//! has been sent in either direction for the idle timeout. If it refuses, its response is relayed
+//! to the client as usual.
+
+use std::{cell::Cell, io, io::Write as _, rc::Rc, time::Duration};
+
+use actix_web::{
+    Error, HttpRequest, HttpResponse, error,
+    http::{
+        Method, StatusCode,
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+    },
+    web::{self, Bytes},
+};
+use futures_util::{Stream, StreamExt as _, stream};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::TcpStream,
+    time::{Instant, timeout, timeout_at},
+};
+use url::Url;
+
+use crate::{
+    headers::{self, Forwarded},
+    upstream::UpstreamGuard,
+};
+
+/// Largest upstream response head that is accepted.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Maximum number of headers in an upstream response.
+const MAX_HEADERS: usize = 64;
+
+/// Size of reads from the upstream connection.
+const READ_SIZE: usize = 8 * 1024;
+
+/// Settings for tunneled connections.
+#[derive(Debug, Clone, Copy)]
+pub struct TunnelConfig {
+    /// Time without traffic in either direction after which a tunnel is closed. Also bounds the
+    /// time taken to connect to the upstream and complete the handshake.
+    pub idle_timeout: Duration,
+}
+
+/// Returns true if the request asks to upgrade the connection (`Connection: upgrade` along with
+/// an `Upgrade` header), in which case it must be tunneled rather than forwarded.
+pub fn is_upgrade(req: &HttpRequest) -> bool {
+    req.head().upgrade() && req.headers().contains_key(header::UPGRADE)
+}
+
+/// Returns true if `protocol`, from an `Upgrade` header, is WebSocket.
+///
+/// Once the connection has switched protocols, the server only passes on the client's bytes for
+/// WebSocket connections, so other protocols cannot be relayed.
+fn is_websocket(protocol: &HeaderValue) -> bool {
+    protocol
+        .to_str()
+        .is_ok_and(|val| val.trim().eq_ignore_ascii_case("websocket"))
+}
+
+/// Time of the last traffic through a tunnel, shared by both directions.
+#[derive(Debug)]
+struct Activity {
+    last: Cell<Instant>,
+    idle_timeout: Duration,
+}
+
+impl Activity {
+    fn new(idle_timeout: Duration) -> Rc<Self> {
+        Rc::new(Self {
+            last: Cell::new(Instant::now()),
+            idle_timeout,
+        })
+    }
+
+    fn deadline(&self) -> Instant {
+        self.last.get() + self.idle_timeout
+    }
+
+    /// Waits for `fut`, giving up once the tunnel has been idle for the timeout.
+    async fn until_idle<F: Future>(&self, fut: F) -> Option<F::Output> {
+        let mut fut = std::pin::pin!(fut);
+
+        loop {
+            match timeout_at(self.deadline(), &mut fut).await {
+                Ok(output) => {
+                    self.last.set(Instant::now());
+                    return Some(output);
+                }
+
+                // the other direction saw traffic in the meantime
+                Err(_) if Instant::now() < self.deadline() => continue,
+
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+/// Forwards an upgrade handshake to the upstream at `url` and, if it is accepted, tunnels the
+/// connection to it.
+pub async fn open(
+    req: &HttpRequest,
+    mut payload: web::Payload,
+    upstream: UpstreamGuard,
+    url: &Url,
+    forwarded: &Forwarded,
+    config: &TunnelConfig,
+) -> Result<HttpResponse, Error> {
+    if url.scheme() != "http" {
+        return Err(error::ErrorBadGateway(format!(
+            "cannot tunnel to {} upstream",
+            url.scheme()
+        )));
+    }
+
+    let (host, port) = url
+        .host_str()
+        .zip(url.port_or_known_default())
+        .ok_or_else(|| error::ErrorBadGateway("invalid upstream address"))?;
+
+    let handshake = async {
+        let mut conn = TcpStream::connect((host, port)).await?;
+        conn.write_all(&request_head(req, url, forwarded)).await?;
+
+        let mut buf = Vec::new();
+        let head_len = loop {
+            if buf.len() > MAX_HEAD_SIZE {
+                return Err(std::io::Error::other("response head too large"));
+            }
+
+            let len = buf.len();
+            buf.resize(len + READ_SIZE, 0);
+            let n = conn.read(&mut buf[len..]).await?;
+            buf.truncate(len + n);
+
+            if n == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut res = httparse::Response::new(&mut headers);
+
+            if let httparse::Status::Complete(head_len) =
+                res.parse(&buf).map_err(std::io::Error::other)?
+            {
+                break head_len;
+            }
+        };
+
+        Ok((conn, buf, head_len))
+    };
+
+    let (conn, mut buf, head_len) = match timeout(config.idle_timeout, handshake).await {
+        Ok(Ok(handshake)) => handshake,
+        Ok(Err(err)) => {
+            upstream.report_failure();
+            return Err(error::ErrorBadGateway(err));
+        }
+        Err(_) => {
+            upstream.report_failure();
+            return Err(error::ErrorGatewayTimeout("upstream handshake timed out"));
+        }
+    };
+
+    // parse again to borrow headers from the now-complete buffer
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    res.parse(&buf).map_err(error::ErrorBadGateway)?;
+
+    let status = res
+        .code
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| error::ErrorBadGateway("invalid upstream status"))?;
+
+    if status.is_server_error() {
+        upstream.report_failure();
+    } else {
+        upstream.report_success();
+    }
+
+    let mut upstream_headers = header::HeaderMap::new();
+    for hdr in res.headers.iter() {
+        if let (Ok(name), Ok(val)) = (
+            HeaderName::from_bytes(hdr.name.as_bytes()),
+            HeaderValue::from_bytes(hdr.value),
+        ) {
+            upstream_headers.append(name, val);
+        }
+    }
+
+    let mut client_resp = HttpResponse::build(status);
+    for (name, val) in headers::end_to_end(&upstream_headers) {
+        client_resp.append_header((name.clone(), val.clone()));
+    }
+
+    let leftover = buf.split_off(head_len);
+
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        // upgrade refused; relay the response as usual
+        let framing =
+            Framing::of(req.method(), status, &upstream_headers).map_err(error::ErrorBadGateway)?;
+
+        let body = RefusedBody {
+            conn,
+            buf: leftover,
+            framing,
+            idle_timeout: config.idle_timeout,
+            _upstream: upstream,
+        };
+
+        return Ok(client_resp.streaming(body.into_stream()));
+    }
+
+    let Some(protocol) = upstream_headers
+        .get(header::UPGRADE)
+        .or_else(|| req.headers().get(header::UPGRADE))
+        .cloned()
+    else {
+        return Err(error::ErrorBadGateway(
+            "upstream switched to an unknown protocol",
+        ));
+    };
+
+    if !is_websocket(&protocol) {
+        log::warn!(
+            "cannot relay {} connection to upstream {}",
+            protocol.to_str().unwrap_or("unknown"),
+            upstream.url()
+        );
+        return Err(error::ErrorBadGateway(
+            "only WebSocket connections can be tunneled",
+        ));
+    }
+
+    let activity = Activity::new(config.idle_timeout);
+    let (upstream_read, mut upstream_write) = conn.into_split();
+
+    // client to upstream
+    actix_web::rt::spawn({
+        let activity = Rc::clone(&activity);
+
+        async move {
+            while let Some(Some(Ok(chunk))) = activity.until_idle(payload.next()).await {
+                if upstream_write.write_all(&chunk).await.is_err() {
+                    break;
+                }
+            }
+
+            let _ = upstream_write.shutdown().await;
+        }
+    });
+
+    // upstream to client; holds the upstream guard so the tunnel counts as an active request
+    let upstream_to_client = stream::unfold(
+        (upstream_read, vec![0; READ_SIZE], activity, upstream),
+        |(mut upstream_read, mut buf, activity, upstream)| async move {
+            let n = match activity.until_idle(upstream_read.read(&mut buf)).await {
+                Some(Ok(n)) if n > 0 => n,
+                _ => return None,
+            };
+
+            let chunk = Bytes::copy_from_slice(&buf[..n]);
+            Some((
+                Ok::<_, Error>(chunk),
+                (upstream_read, buf, activity, upstream),
+            ))
+        },
+    );
+
+    let upstream_to_client =
+        stream::iter((!leftover.is_empty()).then(|| Ok(Bytes::from(leftover))))
+            .chain(upstream_to_client);
+
+    Ok(client_resp.upgrade(protocol).streaming(upstream_to_client))
+}
+
+/// How the body of a response that refused the upgrade is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// A fixed number of bytes remain.
+    Length(u64),
+
+    /// Chunked transfer coding, in the given position.
+    Chunked(Chunk),
+
+    /// The body ends when the upstream closes the connection.
+    Close,
+}
+
+/// Position within a chunked body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    /// Expecting a chunk size line.
+    Size,
+
+    /// The given number of bytes of chunk data remain.
+    Data(u64),
+
+    /// Expecting the line break that ends chunk data.
+    DataEnd,
+}
+
+impl Framing {
+    /// Determines the framing of a response to a `method` request, per RFC 9112 §6.3.
+    fn of(method: &Method, status: StatusCode, headers: &HeaderMap) -> io::Result<Self> {
+        if method == Method::HEAD
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return Ok(Self::Length(0));
+        }
+
+        let chunked = headers
+            .get_all(header::TRANSFER_ENCODING)
+            .filter_map(|val| val.to_str().ok())
+            .flat_map(|val| val.split(','))
+            .next_back()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+
+        if chunked {
+            return Ok(Self::Chunked(Chunk::Size));
+        }
+
+        match headers.get(header::CONTENT_LENGTH) {
+            Some(len) => len
+                .to_str()
+                .ok()
+                .and_then(|len| len.trim().parse().ok())
+                .map(Self::Length)
+                .ok_or_else(|| io::Error::other("invalid upstream Content-Length")),
+            None => Ok(Self::Close),
+        }
+    }
+}
+
+/// Body of a response that refused the upgrade, read from the upstream connection.
+struct RefusedBody {
+    conn: TcpStream,
+
+    /// Bytes read from the upstream but not yet processed.
+    buf: Vec<u8>,
+
+    framing: Framing,
+    idle_timeout: Duration,
+
+    /// Keeps the request counted as active until the body has been relayed.
+    _upstream: UpstreamGuard,
+}
+
+impl RefusedBody {
+    /// Reads more from the upstream, returning false at the end of the connection.
+    async fn fill(&mut self) -> io::Result<bool> {
+        let len = self.buf.len();
+        self.buf.resize(len + READ_SIZE, 0);
+
+        let n = timeout(self.idle_timeout, self.conn.read(&mut self.buf[len..]))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        self.buf.truncate(len + n);
+
+        Ok(n > 0)
+    }
+
+    /// Like [`fill`](Self::fill), but the end of the connection is an error.
+    async fn fill_more(&mut self) -> io::Result<()> {
+        if self.fill().await? {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    /// Takes up to `max` bytes of body data, reading from the upstream if none are buffered.
+    async fn take(&mut self, max: u64) -> io::Result<Bytes> {
+        if self.buf.is_empty() {
+            self.fill_more().await?;
+        }
+
+        let len = self
+            .buf
+            .len()
+            .min(usize::try_from(max).unwrap_or(usize::MAX));
+        Ok(Bytes::from(self.buf.drain(..len).collect::<Vec<_>>()))
+    }
+
+    /// Returns the next piece of the body, or `None` once it is complete.
+    async fn next(&mut self) -> io::Result<Option<Bytes>> {
+        loop {
+            match self.framing {
+                Framing::Length(0) => return Ok(None),
+
+                Framing::Length(remaining) => {
+                    let data = self.take(remaining).await?;
+                    self.framing = Framing::Length(remaining - data.len() as u64);
+                    return Ok(Some(data));
+                }
+
+                Framing::Close => {
+                    if self.buf.is_empty() && !self.fill().await? {
+                        return Ok(None);
+                    }
+
+                    return Ok(Some(Bytes::from(std::mem::take(&mut self.buf))));
+                }
+
+                Framing::Chunked(Chunk::Size) => match httparse::parse_chunk_size(&self.buf) {
+                    Ok(httparse::Status::Complete((len, 0))) => {
+                        // trailers are not relayed
+                        self.buf.drain(..len);
+                        self.framing = Framing::Length(0);
+                    }
+                    Ok(httparse::Status::Complete((len, size))) => {
+                        self.buf.drain(..len);
+                        self.framing = Framing::Chunked(Chunk::Data(size));
+                    }
+                    Ok(httparse::Status::Partial) => self.fill_more().await?,
+                    Err(_) => return Err(io::Error::other("invalid chunk size")),
+                },
+
+                Framing::Chunked(Chunk::Data(0)) => self.framing = Framing::Chunked(Chunk::DataEnd),
+
+                Framing::Chunked(Chunk::Data(remaining)) => {
+                    let data = self.take(remaining).await?;
+                    self.framing = Framing::Chunked(Chunk::Data(remaining - data.len() as u64));
+                    return Ok(Some(data));
+                }
+
+                Framing::Chunked(Chunk::DataEnd) => {
+                    if self.buf.len() < 2 {
+                        self.fill_more().await?;
+                        continue;
+                    }
+
+                    if !self.buf.starts_with(b"\r\n") {
+                        return Err(io::Error::other("invalid chunk data"));
+                    }
+
+                    self.buf.drain(..2);
+                    self.framing = Framing::Chunked(Chunk::Size);
+                }
+            }
+        }
+    }
+
+    fn into_stream(self) -> impl Stream<Item = Result<Bytes, Error>> {
+        stream::try_unfold(self, |mut body| async move {
+            match body.next().await {
+                Ok(Some(data)) => Ok(Some((data, body))),
+                Ok(None) => Ok(None),
+                Err(err) => {
+                    log::warn!("failed to relay upstream response: {err}");
+                    Err(error::ErrorBadGateway(err))
+                }
+            }
+        })
+    }
+}
+
+/// Builds the head of the handshake request sent to the upstream.
+fn request_head(req: &HttpRequest, url: &Url, forwarded: &Forwarded) -> Vec<u8> {
+    let mut target = url.path().to_owned();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+
+    let mut head = Vec::new();
+    let _ = write!(head, "{} {target} HTTP/1.1\r\n", req.method());
+
+    let mut append = |name: &[u8], val: &[u8]| {
+        head.extend_from_slice(name);
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(val);
+        head.extend_from_slice(b"\r\n");
+    };
+
+    for (name, val) in headers::end_to_end(req.headers()) {
+        if !headers::is_forwarding(name.as_str()) {
+            append(name.as_str().as_bytes(), val.as_bytes());
+        }
+    }
+
+    append(b"connection", b"upgrade");
+    if let Some(upgrade) = req.headers().get(header::UPGRADE) {
+        append(b"upgrade", upgrade.as_bytes());
+    }
+
+    for (name, val) in forwarded.iter() {
+        append(name.as_bytes(), val.as_bytes());
+    }
+
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{FromRequest as _, test::TestRequest};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        headers::TrustedProxies,
+        upstream::{Balance, EjectionConfig, Timeouts, UpstreamPool},
+    };
+
+    /// Opens a tunnel to an upstream that answers the handshake with `response`.
+    async fn open_with_response(response: Vec<u8>) -> Result<HttpResponse, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = conn.read(&mut buf).await.unwrap();
+            conn.write_all(&response).await.unwrap();
+        });
+
+        let pool = UpstreamPool::new(
+            vec![url.clone()],
+            Balance::RoundRobin,
+            EjectionConfig {
+                max_failures: 1,
+                duration: Duration::from_secs(1),
+            },
+            Timeouts::default(),
+        );
+
+        let (req, mut payload) = TestRequest::get()
+            .insert_header((header::CONNECTION, "Upgrade"))
+            .insert_header((header::UPGRADE, "websocket"))
+            .to_http_parts();
+        let payload = web::Payload::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+        let forwarded = Forwarded::new(&req, &TrustedProxies::default());
+        let config = TunnelConfig {
+            idle_timeout: Duration::from_secs(5),
+        };
+
+        open(
+            &req,
+            payload,
+            pool.select("").unwrap(),
+            &url,
+            &forwarded,
+            &config,
+        )
+        .await
+    }
+
+    #[test]
+    fn any_upgrade_is_tunneled() {
+        let req = TestRequest::get()
+            .insert_header((header::CONNECTION, "Upgrade, HTTP2-Settings"))
+            .insert_header((header::UPGRADE, "h2c"))
+            .to_http_request();
+        assert!(is_upgrade(&req));
+
+        let req = TestRequest::get()
+            .insert_header((header::UPGRADE, "websocket"))
+            .to_http_request();
+        assert!(!is_upgrade(&req));
+    }
+
+    #[actix_web::test]
+    async fn refused_upgrade_body_is_streamed() {
+        let body = "a".repeat(MAX_HEAD_SIZE * 2);
+
+        let mut response = format!(
+            "HTTP/1.1 400 Bad Request\r\ncontent-length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(body.as_bytes());
+
+        let res = open_with_response(response).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let bytes = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(bytes, body.as_bytes());
+    }
+
+    #[actix_web::test]
+    async fn refused_upgrade_chunked_body_is_decoded() {
+        let mut response =
+            b"HTTP/1.1 426 Upgrade Required\r\ntransfer-encoding: chunked\r\n\r\n".to_vec();
+        let chunk = "b".repeat(MAX_HEAD_SIZE);
+        response.extend_from_slice(format!("{:x}\r\n{chunk}\r\n", chunk.len()).as_bytes());
+        response.extend_from_slice(b"5;ext=1\r\nhello\r\n0\r\nx-trailer: 1\r\n\r\n");
+
+        let res = open_with_response(response).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UPGRADE_REQUIRED);
+
+        let bytes = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(bytes, format!("{chunk}hello").as_bytes());
+    }
+
+    #[actix_web::test]
+    async fn refused_upgrade_body_cut_short_is_an_error() {
+        let response = b"HTTP/1.1 400 Bad Request\r\ncontent-length: 10\r\n\r\nshort".to_vec();
+
+        let res = open_with_response(response).await.unwrap();
+        assert!(actix_web::body::to_bytes(res.into_body()).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn other_protocols_are_not_relayed() {
+        let response =
+            b"HTTP/1.1 101 Switching Protocols\r\nconnection: upgrade\r\nupgrade: h2c\r\n\r\n"
+                .to_vec();
+
+        let err = open_with_response(response).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[test]
+    fn handshake_keeps_upgrade_headers() {
+        let req = TestRequest::get()
+            .uri("/ws")
+            .peer_addr("192.0.2.1:4000".parse().unwrap())
+            .insert_header((header::HOST, "example.com"))
+            .insert_header((header::CONNECTION, "keep-alive, Upgrade"))
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .insert_header(("x-forwarded-for", "1.2.3.4"))
+            .to_http_request();
+
+        assert!(is_upgrade(&req));
+
+        let url = Url::parse("http://127.0.0.1:8080/chat?room=1").unwrap();
+        let forwarded = Forwarded::new(&req, &TrustedProxies::default());
+        let head = String::from_utf8(request_head(&req, &url, &forwarded)).unwrap();
+        let mut lines = head.lines();
+
+        assert_eq!(lines.next(), Some("GET /chat?room=1 HTTP/1.1"));
+
+        let mut headers = lines
+            .take_while(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+        headers.sort();
+
+        assert_eq!(
+            headers,
+            [
+                "connection: upgrade",
+                "forwarded: for=192.0.2.1;proto=http;host=example.com",
+                "host: example.com",
+                "sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==",
+                "upgrade: websocket",
+                "x-forwarded-for: 192.0.2.1",
+                "x-forwarded-host: example.com",
+                "x-forwarded-proto: http",
+            ]
+        );
+    }
+
+    #[test]
+    fn handshake_drops_untrusted_forwarding_headers() {
+        let req = TestRequest::get()
+            .uri("/ws")
+            .peer_addr("192.0.2.1:4000".parse().unwrap())
+            .insert_header((header::CONNECTION, "Upgrade"))
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header(("x-forwarded-host", "evil.example"))
+            .to_http_request();
+
+        let url = Url::parse("http://127.0.0.1:8080/ws").unwrap();
+        let forwarded = Forwarded::new(&req, &TrustedProxies::default());
+        let head = String::from_utf8(request_head(&req, &url, &forwarded)).unwrap();
+
+        assert!(!head.contains("x-forwarded-host"));
+    }
+}
@@ -0,0 +1,602 @@
+//! Pool of upstream servers, with load balancing and health checking.
+
+use std::{
+    collections::{BTreeMap, hash_map::DefaultHasher},
+    hash::{Hash as _, Hasher as _},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use awc::Client;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::routing::join_path;
+
+/// Number of points each upstream occupies on the consistent-hash ring.
+const VIRTUAL_NODES: usize = 100;
+
+/// Strategy used to pick an upstream for each request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    /// Cycle through upstreams in order.
+    RoundRobin,
+
+    /// Pick the upstream with the fewest in-flight requests.
+    LeastConnections,
+
+    /// Pick an upstream based on a hash of the client address, so that each client sticks to
+    /// the same upstream while it is available.
+    ConsistentHash,
+}
+
+/// Settings for passive ejection of failing upstreams.
+#[derive(Debug, Clone, Copy)]
+pub struct EjectionConfig {
+    /// Number of consecutive failures after which an upstream is ejected.
+    pub max_failures: u32,
+
+    /// How long an ejected upstream is left out of rotation.
+    pub duration: Duration,
+}
+
+/// Time limits for requests sent to the upstreams of a pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timeouts {
+    /// How long to wait for a connection to be established.
+    pub connect: Duration,
+
+    /// How long to wait for a response once the request has been sent.
+    pub read: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(5),
+            read: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State of an upstream's circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Circuit {
+    /// Requests are sent to the upstream.
+    Closed,
+
+    /// The upstream has failed repeatedly and is left out of rotation until the given time.
+    Open { until: Instant },
+
+    /// The ejection has expired; a single probe request is let through to decide whether to close
+    /// the circuit or open it again.
+    HalfOpen { probing: bool },
+}
+
+/// A single upstream server and its health state.
+#[derive(Debug)]
+pub struct Upstream {
+    url: Url,
+
+    /// Result of the most recent active health check.
+    healthy: AtomicBool,
+
+    /// Number of failures since the last successful response.
+    consecutive_failures: AtomicU32,
+
+    /// Circuit breaker, which ejects the upstream after repeated failures.
+    circuit: Mutex<Circuit>,
+
+    /// Number of in-flight requests.
+    active_requests: AtomicUsize,
+
+    /// Number of requests sent since startup.
+    total_requests: AtomicU64,
+}
+
+impl Upstream {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            healthy: AtomicBool::new(true),
+            consecutive_failures: AtomicU32::new(0),
+            circuit: Mutex::new(Circuit::Closed),
+            active_requests: AtomicUsize::new(0),
+            total_requests: AtomicU64::new(0),
+        }
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Returns the URL of `path` on the upstream, below the path of the upstream's own URL.
+    fn url_for(&self, path: &str) -> Url {
+        let mut url = self.url.clone();
+        url.set_path(&join_path(self.url.path(), path));
+        url
+    }
+
+    /// Returns the circuit state, moving an open circuit whose ejection has expired to half-open.
+    fn circuit(&self) -> Circuit {
+        let mut circuit = self.circuit.lock().unwrap();
+
+        if let Circuit::Open { until } = *circuit {
+            if until <= Instant::now() {
+                *circuit = Circuit::HalfOpen { probing: false };
+            }
+        }
+
+        *circuit
+    }
+
+    fn is_available(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+            && matches!(
+                self.circuit(),
+                Circuit::Closed | Circuit::HalfOpen { probing: false }
+            )
+    }
+
+    /// Claims the upstream for a request if it is available.
+    ///
+    /// Returns `Some(true)` if the request is the probe of a half-open circuit. The circuit is
+    /// checked and the probe claimed under one lock, so that concurrent requests cannot both
+    /// become the probe.
+    fn try_acquire(&self) -> Option<bool> {
+        if !self.healthy.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let mut circuit = self.circuit.lock().unwrap();
+
+        if let Circuit::Open { until } = *circuit {
+            if until <= Instant::now() {
+                *circuit = Circuit::HalfOpen { probing: false };
+            }
+        }
+
+        match *circuit {
+            Circuit::Closed => Some(false),
+            Circuit::HalfOpen { probing: false } => {
+                *circuit = Circuit::HalfOpen { probing: true };
+                Some(true)
+            }
+            Circuit::Open { .. } | Circuit::HalfOpen { probing: true } => None,
+        }
+    }
+}
+
+/// Handle to the upstream selected for a request.
+///
+/// Counts as an in-flight request until dropped, so it should be kept alive while the response
+/// body is streamed.
+#[derive(Debug)]
+pub struct UpstreamGuard {
+    upstream: Arc<Upstream>,
+    ejection: EjectionConfig,
+
+    /// Whether this request is probing a half-open circuit.
+    probe: bool,
+}
+
+impl UpstreamGuard {
+    /// Claims `upstream` for a request, returning `None` if it is not available.
+    fn acquire(upstream: &Arc<Upstream>, ejection: EjectionConfig) -> Option<Self> {
+        let probe = upstream.try_acquire()?;
+
+        upstream.active_requests.fetch_add(1, Ordering::Relaxed);
+        upstream.total_requests.fetch_add(1, Ordering::Relaxed);
+
+        Some(Self {
+            upstream: Arc::clone(upstream),
+            ejection,
+            probe,
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        self.upstream.url()
+    }
+
+    /// Returns the URL of `path` on the upstream; see [`Upstream::url_for`].
+    pub fn url_for(&self, path: &str) -> Url {
+        self.upstream.url_for(path)
+    }
+
+    /// Records a successful response from the upstream, closing its circuit.
+    pub fn report_success(&self) {
+        self.upstream
+            .consecutive_failures
+            .store(0, Ordering::Relaxed);
+
+        let mut circuit = self.upstream.circuit.lock().unwrap();
+
+        if *circuit != Circuit::Closed {
+            log::info!("upstream {} recovered", self.upstream.url);
+            *circuit = Circuit::Closed;
+        }
+    }
+
+    /// Records a connection error, timeout or 5xx response from the upstream, ejecting it if it
+    /// has failed too many times in a row or if its probe request failed.
+    pub fn report_failure(&self) {
+        let failures = self
+            .upstream
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+
+        let mut circuit = self.upstream.circuit.lock().unwrap();
+
+        let eject = match *circuit {
+            Circuit::Closed => failures >= self.ejection.max_failures,
+            Circuit::HalfOpen { .. } => true,
+            Circuit::Open { .. } => false,
+        };
+
+        if eject {
+            log::warn!(
+                "ejecting upstream {} after {failures} consecutive failures",
+                self.upstream.url
+            );
+            *circuit = Circuit::Open {
+                until: Instant::now() + self.ejection.duration,
+            };
+        }
+    }
+}
+
+impl Drop for UpstreamGuard {
+    fn drop(&mut self) {
+        self.upstream
+            .active_requests
+            .fetch_sub(1, Ordering::Relaxed);
+
+        if self.probe {
+            // the probe ended without an outcome being reported; let another request probe
+            let mut circuit = self.upstream.circuit.lock().unwrap();
+
+            if *circuit == (Circuit::HalfOpen { probing: true }) {
+                *circuit = Circuit::HalfOpen { probing: false };
+            }
+        }
+    }
+}
+
+/// Set of upstreams that requests are balanced across.
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<Arc<Upstream>>,
+    balance: Balance,
+    ejection: EjectionConfig,
+    timeouts: Timeouts,
+
+    /// Round-robin position.
+    next: AtomicUsize,
+
+    /// Consistent-hash ring, mapping points to upstream indices.
+    ring: BTreeMap<u64, usize>,
+}
+
+impl UpstreamPool {
+    pub fn new(
+        urls: Vec<Url>,
+        balance: Balance,
+        ejection: EjectionConfig,
+        timeouts: Timeouts,
+    ) -> Self {
+        let upstreams = urls.into_iter().map(Upstream::new).map(Arc::new).collect();
+        Self::with_upstreams(upstreams, balance, ejection, timeouts)
+    }
+
+    /// Constructs a pool of existing upstreams, keeping their health and circuit state.
+    pub fn with_upstreams(
+        upstreams: Vec<Arc<Upstream>>,
+        balance: Balance,
+        ejection: EjectionConfig,
+        timeouts: Timeouts,
+    ) -> Self {
+        let ring = upstreams
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, upstream)| {
+                let url = upstream.url();
+                (0..VIRTUAL_NODES).map(move |node| (hash(&format!("{url}#{node}")), idx))
+            })
+            .collect();
+
+        Self {
+            upstreams,
+            balance,
+            ejection,
+            timeouts,
+            next: AtomicUsize::new(0),
+            ring,
+        }
+    }
+
+    /// Returns the time limits for requests sent to the pool's upstreams.
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Selects an available upstream for a request, using `hash_key` for consistent hashing.
+    ///
+    /// Returns `None` if all upstreams are unhealthy or ejected.
+    pub fn select(&self, hash_key: &str) -> Option<UpstreamGuard> {
+        let acquire = |upstream| UpstreamGuard::acquire(upstream, self.ejection);
+
+        match self.balance {
+            Balance::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                let len = self.upstreams.len();
+
+                (0..len)
+                    .map(|offset| &self.upstreams[(start + offset) % len])
+                    .find_map(acquire)
+            }
+
+            Balance::LeastConnections => {
+                let mut candidates = self
+                    .upstreams
+                    .iter()
+                    .filter(|upstream| upstream.is_available())
+                    .collect::<Vec<_>>();
+                candidates.sort_by_key(|upstream| upstream.active_requests.load(Ordering::Relaxed));
+
+                candidates.into_iter().find_map(acquire)
+            }
+
+            Balance::ConsistentHash => {
+                let point = hash(hash_key);
+
+                // walk the ring clockwise from the key's point until an available upstream is found
+                self.ring
+                    .range(point..)
+                    .chain(self.ring.range(..point))
+                    .map(|(_, &idx)| &self.upstreams[idx])
+                    .find_map(acquire)
+            }
+        }
+    }
+
+    /// Sends a GET request to `path` on every upstream at once, marking those that do not respond
+    /// with a success status as unhealthy.
+    pub async fn check_health(&self, client: &Client, path: &str) {
+        join_all(self.upstreams.iter().map(|upstream| async move {
+            let url = upstream.url_for(path);
+
+            let healthy = match client.get(url.as_str()).send().await {
+                Ok(res) => res.status().is_success(),
+                Err(err) => {
+                    log::debug!("health check of {} failed: {err}", upstream.url);
+                    false
+                }
+            };
+
+            if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                log::warn!(
+                    "upstream {} is now {}",
+                    upstream.url,
+                    if healthy { "healthy" } else { "unhealthy" }
+                );
+            }
+        }))
+        .await;
+    }
+
+    /// Iterates over the upstreams in the pool.
+    pub fn upstreams(&self) -> impl Iterator<Item = &Arc<Upstream>> {
+        self.upstreams.iter()
+    }
+
+    /// Describes the current state of the pool.
+    pub fn status(&self) -> PoolStatus {
+        PoolStatus {
+            balance: self.balance,
+            upstreams: self
+                .upstreams
+                .iter()
+                .map(|upstream| UpstreamStatus {
+                    url: upstream.url.to_string(),
+                    healthy: upstream.healthy.load(Ordering::Relaxed),
+                    circuit: match upstream.circuit() {
+                        Circuit::Closed => "closed",
+                        Circuit::Open { .. } => "open",
+                        Circuit::HalfOpen { .. } => "half-open",
+                    },
+                    consecutive_failures: upstream.consecutive_failures.load(Ordering::Relaxed),
+                    active_requests: upstream.active_requests.load(Ordering::Relaxed),
+                    total_requests: upstream.total_requests.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolStatus {
+    balance: Balance,
+    upstreams: Vec<UpstreamStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct UpstreamStatus {
+    url: String,
+    healthy: bool,
+    circuit: &'static str,
+    consecutive_failures: u32,
+    active_requests: usize,
+    total_requests: u64,
+}
+
+fn hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(balance: Balance) -> UpstreamPool {
+        let urls = ["http://127.0.0.1:8081", "http://127.0.0.1:8082"]
+            .into_iter()
+            .map(|url| Url::parse(url).unwrap())
+            .collect();
+
+        UpstreamPool::new(
+            urls,
+            balance,
+            EjectionConfig {
+                max_failures: 2,
+                duration: Duration::from_secs(60),
+            },
+            Timeouts::default(),
+        )
+    }
+
+    fn port(guard: &UpstreamGuard) -> u16 {
+        guard.url().port().unwrap()
+    }
+
+    #[test]
+    fn round_robin_skips_ejected_upstreams() {
+        let pool = pool(Balance::RoundRobin);
+
+        let ports = (0..4)
+            .map(|_| port(&pool.select("").unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(ports, [8081, 8082, 8081, 8082]);
+
+        let guard = pool.select("").unwrap();
+        assert_eq!(port(&guard), 8081);
+        guard.report_failure();
+        guard.report_failure();
+
+        for _ in 0..4 {
+            assert_eq!(port(&pool.select("").unwrap()), 8082);
+        }
+
+        pool.upstreams[1].healthy.store(false, Ordering::Relaxed);
+        assert!(pool.select("").is_none());
+    }
+
+    #[test]
+    fn half_open_circuit_lets_one_probe_through() {
+        let pool = pool(Balance::RoundRobin);
+        pool.upstreams[1].healthy.store(false, Ordering::Relaxed);
+
+        let guard = pool.select("").unwrap();
+        guard.report_failure();
+        guard.report_failure();
+        drop(guard);
+        assert!(pool.select("").is_none());
+
+        // let the ejection expire
+        *pool.upstreams[0].circuit.lock().unwrap() = Circuit::Open {
+            until: Instant::now(),
+        };
+
+        let probe = pool.select("").unwrap();
+        assert!(pool.select("").is_none());
+
+        // a failed probe opens the circuit again straight away
+        probe.report_failure();
+        drop(probe);
+        assert!(pool.select("").is_none());
+
+        *pool.upstreams[0].circuit.lock().unwrap() = Circuit::HalfOpen { probing: false };
+        let probe = pool.select("").unwrap();
+        probe.report_success();
+        drop(probe);
+
+        assert!(pool.select("").is_some());
+        assert!(pool.select("").is_some());
+    }
+
+    #[actix_web::test]
+    async fn hung_upstreams_are_checked_concurrently() {
+        // accept connections but never respond
+        let listeners = (0..3)
+            .map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect::<Vec<_>>();
+
+        let urls = listeners
+            .iter()
+            .map(|listener| Url::parse(&format!("http://{}", listener.local_addr().unwrap())))
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let pool = UpstreamPool::new(
+            urls,
+            Balance::RoundRobin,
+            EjectionConfig {
+                max_failures: 2,
+                duration: Duration::from_secs(60),
+            },
+            Timeouts::default(),
+        );
+
+        let client = Client::builder()
+            .timeout(Duration::from_millis(300))
+            .finish();
+
+        let start = Instant::now();
+        pool.check_health(&client, "/health").await;
+
+        assert!(start.elapsed() < Duration::from_millis(600));
+        assert!(pool.select("").is_none());
+    }
+
+    #[test]
+    fn joins_upstream_base_path() {
+        let upstream = Upstream::new(Url::parse("http://127.0.0.1:8081/base/").unwrap());
+        assert_eq!(
+            upstream.url_for("/items/1").as_str(),
+            "http://127.0.0.1:8081/base/items/1"
+        );
+
+        let upstream = Upstream::new(Url::parse("http://127.0.0.1:8081").unwrap());
+        assert_eq!(upstream.url_for("/").as_str(), "http://127.0.0.1:8081/");
+    }
+
+    #[test]
+    fn least_connections_prefers_idle_upstream() {
+        let pool = pool(Balance::LeastConnections);
+
+        let first = pool.select("").unwrap();
+        let second = pool.select("").unwrap();
+        assert_ne!(port(&first), port(&second));
+
+        drop(first);
+        let third = pool.select("").unwrap();
+        assert_ne!(port(&third), port(&second));
+    }
+
+    #[test]
+    fn consistent_hash_is_sticky() {
+        let pool = pool(Balance::ConsistentHash);
+
+        let chosen = port(&pool.select("192.0.2.1").unwrap());
+        for _ in 0..4 {
+            assert_eq!(port(&pool.select("192.0.2.1").unwrap()), chosen);
+        }
+
+        // fails over to the other upstream when the chosen one is unhealthy
+        let idx = pool
+            .upstreams
+            .iter()
+            .position(|upstream| upstream.url.port() == Some(chosen))
+            .unwrap();
+        pool.upstreams[idx].healthy.store(false, Ordering::Relaxed);
+        assert_ne!(port(&pool.select("192.0.2.1").unwrap()), chosen);
+    }
+}
@@ -1,17 +1,35 @@
-use std::io;
+use std::{collections::HashMap, env, io, time::Duration};
 
 use actix_governor::{Governor, GovernorConfigBuilder};
 use actix_web::{
-    App, HttpResponse, HttpServer, middleware,
+    App, Error, HttpResponse, HttpServer, error, guard,
+    http::header::HeaderName,
+    middleware,
     web::{self},
 };
 
 mod rate_limit;
+mod redis_store;
 
 async fn index() -> HttpResponse {
     HttpResponse::Ok().body("succeed")
 }
 
+fn fib_recursive(n: u64) -> u64 {
+    if n <= 1 {
+        return n;
+    }
+    fib_recursive(n - 1) + fib_recursive(n - 2)
+}
+
+async fn fibonacci(n: web::Path<u64>) -> Result<HttpResponse, Error> {
+    let n = n.into_inner().min(40);
+    let result = web::block(move || fib_recursive(n))
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().body(result.to_string()))
+}
+
 #[actix_web::main]
 async fn main() -> io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
@@ -22,6 +40,40 @@ async fn main() -> io::Result<()> {
         .finish()
         .unwrap();
 
+    // created outside the server factory so that all workers share the same buckets;
+    // the first is equivalent to the governor config above
+    let simple_limiter = rate_limit::RateLimit::new(1)
+        .period(Duration::from_secs(10))
+        .burst(2)
+        .idle_timeout(Duration::from_secs(30));
+    let api_key_limiter =
+        rate_limit::RateLimit::new(5).key_by_header(HeaderName::from_static("x-api-key"));
+    let user_limiter = rate_limit::RateLimit::new(3).key_by(|req| {
+        // key by the `user` query parameter
+        web::Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.get("user").cloned())
+            .unwrap_or_default()
+    });
+
+    // shared between all instances connected to the same Redis server
+    let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_owned());
+    let redis_client = redis::Client::open(redis_url).expect("invalid Redis URL");
+    let distributed_limiter = rate_limit::RateLimit::new(2)
+        .store(redis_store::RedisStore::new(redis_client).timeout(Duration::from_millis(100)));
+
+    // route classes, declared once and applied to the whole app
+    let policy_limiter = rate_limit::RateLimit::from_policies([
+        rate_limit::RateLimitPolicy::new("fibonacci", 20)
+            .period(Duration::from_secs(60))
+            .burst(10)
+            .idle_timeout(Duration::from_secs(120))
+            .path("/fibonacci/{n}")
+            .cost(5),
+        rate_limit::RateLimitPolicy::new("writes", 5).guard(guard::Post()),
+    ])
+    .map_err(io::Error::other)?;
+
     log::info!("starting HTTP server at http://localhost:8080");
 
     HttpServer::new(move || {
@@ -33,9 +85,27 @@ async fn main() -> io::Result<()> {
             )
             .service(
                 web::resource("/test/simple")
-                    .wrap(rate_limit::RateLimit::new(2))
+                    .wrap(simple_limiter.clone())
+                    .route(web::get().to(index)),
+            )
+            .service(
+                web::resource("/test/api-key")
+                    .wrap(api_key_limiter.clone())
+                    .route(web::get().to(index)),
+            )
+            .service(
+                web::resource("/test/distributed")
+                    .wrap(distributed_limiter.clone())
+                    .route(web::get().to(index)),
+            )
+            .service(
+                web::resource("/test/user")
+                    .wrap(user_limiter.clone())
                     .route(web::get().to(index)),
             )
+            .service(web::resource("/fibonacci/{n}").route(web::get().to(fibonacci)))
+            .service(web::resource("/test/submit").route(web::post().to(index)))
+            .wrap(policy_limiter.clone())
             .wrap(middleware::NormalizePath::trim())
             .wrap(middleware::Logger::default())
     })
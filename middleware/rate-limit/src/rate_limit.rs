@@ -1,30 +1,112 @@
 //! Simple leaky-bucket rate-limiter.
+//!
+//! Each client gets its own bucket, identified by a key extracted from the request (peer IP by
+//! default). Buckets are kept in a [`RateLimitStore`] that is shared by all workers; the default
+//! [`MemoryStore`] keeps them in process memory and periodically evicts idle buckets.
+//!
+//! Different limits and per-request costs can be applied to classes of routes using
+//! [`RateLimitPolicy`]s, which are matched by path pattern or guard.
 
 use std::{
-    cell::RefCell,
-    cmp::min,
+    collections::HashMap,
+    fmt,
     future::{Ready, ready},
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use actix_web::{
-    Error, HttpResponse,
+    Error, HttpResponse, HttpResponseBuilder,
     body::EitherBody,
-    dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+    dev::{ResourceDef, Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+    guard::Guard,
+    http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER},
 };
-use chrono::{DateTime, Utc};
-use futures_util::{FutureExt as _, TryFutureExt as _, future::LocalBoxFuture};
+use futures_util::future::LocalBoxFuture;
+use serde_json::json;
+
+// rate limit header names from the IETF httpapi-ratelimit-headers draft
+const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+const RATELIMIT_RESET: &str = "ratelimit-reset";
+
+/// Default period over which the request limit applies.
+const DEFAULT_PERIOD: Duration = Duration::from_secs(10);
+
+/// Default time after which an unused bucket is removed from the store.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Strategy used to determine which bucket a request is counted against.
+#[derive(Clone)]
+pub enum RateLimitKey {
+    /// Key by the IP address of the connected peer.
+    PeerIp,
+
+    /// Key by the value of a request header, falling back to peer IP when it is missing.
+    Header(HeaderName),
+
+    /// Key by the result of a custom function.
+    Custom(Arc<dyn Fn(&ServiceRequest) -> String + Send + Sync>),
+}
+
+impl RateLimitKey {
+    fn extract(&self, req: &ServiceRequest) -> String {
+        match self {
+            RateLimitKey::PeerIp => peer_ip(req),
+
+            RateLimitKey::Header(name) => req
+                .headers()
+                .get(name)
+                .and_then(|val| val.to_str().ok())
+                .map(|val| format!("{name}:{val}"))
+                .unwrap_or_else(|| peer_ip(req)),
+
+            RateLimitKey::Custom(key_fn) => key_fn(req),
+        }
+    }
+}
+
+/// Rounds up to whole seconds, so clients never retry too early.
+fn ceil_secs(dur: Duration) -> u64 {
+    dur.as_millis().div_ceil(1000) as u64
+}
+
+fn peer_ip(req: &ServiceRequest) -> String {
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
 
 #[doc(hidden)]
-pub struct RateLimitService<S> {
-    service: S,
-    token_bucket: RefCell<TokenBucket>,
+pub struct RateLimitService<S, L> {
+    service: Rc<S>,
+    key: RateLimitKey,
+    limits: L,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl<S, L: Limits> RateLimitService<S, L> {
+    /// Determines which bucket the request is counted against and the config for that bucket.
+    ///
+    /// Returns `None` if no limit applies to the request.
+    fn bucket_for(&self, req: &ServiceRequest) -> Option<(String, RateLimitConfig)> {
+        let (namespace, config) = self.limits.limit_for(req)?;
+        let client = self.key.extract(req);
+
+        match namespace {
+            Some(namespace) => Some((format!("{namespace}:{client}"), config)),
+            None => Some((client, config)),
+        }
+    }
 }
 
-impl<S, B> Service<ServiceRequest> for RateLimitService<S>
+impl<S, B, L> Service<ServiceRequest> for RateLimitService<S, L>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
+    L: Limits,
 {
     type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
@@ -35,101 +117,730 @@ where
     fn call(&self, req: ServiceRequest) -> Self::Future {
         log::info!("request is passing through the AddMsg middleware");
 
-        if !self.token_bucket.borrow_mut().allow_query() {
-            // request has been rate limited
+        let service = Rc::clone(&self.service);
+        let store = Arc::clone(&self.store);
 
-            return Box::pin(async {
-                Ok(req.into_response(
-                    HttpResponse::TooManyRequests()
-                        .finish()
-                        .map_into_right_body(),
-                ))
-            });
+        let Some((key, config)) = self.bucket_for(&req) else {
+            return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) });
+        };
+
+        Box::pin(async move {
+            let decision = store.check(&key, &config).await;
+
+            if !decision.allowed {
+                // request has been rate limited
+                log::debug!("rate limited request from {key}");
+
+                let retry_after = decision.retry_after.map(ceil_secs);
+
+                let mut res = HttpResponse::TooManyRequests();
+                decision.insert_headers(&mut res);
+
+                let detail = match retry_after {
+                    Some(retry_after) => {
+                        res.insert_header((RETRY_AFTER, retry_after));
+                        format!("rate limit exceeded, retry in {retry_after} seconds")
+                    }
+                    None => "rate limit exceeded, and the request will never be allowed".to_owned(),
+                };
+
+                let res = res.content_type("application/problem+json").json(json!({
+                    "type": "about:blank",
+                    "title": "Too Many Requests",
+                    "status": 429,
+                    "detail": detail,
+                    "retry_after": retry_after,
+                }));
+
+                return Ok(req.into_response(res.map_into_right_body()));
+            }
+
+            let mut res = service.call(req).await?;
+            decision.insert_headers_map(res.headers_mut());
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+/// Outcome of a rate limit check, along with the bucket state used to produce response headers.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitDecision {
+    /// Whether the request may proceed.
+    pub(crate) allowed: bool,
+
+    /// Maximum number of requests allowed in a window.
+    pub(crate) limit: u64,
+
+    /// Number of requests remaining after this one.
+    pub(crate) remaining: u64,
+
+    /// Time until the bucket is full again, or `None` if it is never refilled.
+    pub(crate) reset: Option<Duration>,
+
+    /// Time until the next request will be allowed, or `None` if it never will be.
+    pub(crate) retry_after: Option<Duration>,
+}
+
+impl RateLimitDecision {
+    fn header_pairs(&self) -> impl Iterator<Item = (HeaderName, u64)> {
+        [
+            Some((HeaderName::from_static(RATELIMIT_LIMIT), self.limit)),
+            Some((HeaderName::from_static(RATELIMIT_REMAINING), self.remaining)),
+            self.reset
+                .map(|reset| (HeaderName::from_static(RATELIMIT_RESET), ceil_secs(reset))),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    fn insert_headers(&self, res: &mut HttpResponseBuilder) {
+        for header in self.header_pairs() {
+            res.insert_header(header);
         }
+    }
 
-        self.service
-            .call(req)
-            .map_ok(ServiceResponse::map_into_left_body)
-            .boxed_local()
+    fn insert_headers_map(&self, headers: &mut HeaderMap) {
+        for (name, val) in self.header_pairs() {
+            headers.insert(name, HeaderValue::from(val));
+        }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct RateLimit {
-    /// Request limit for 10 second period.
-    limit: u64,
+/// Rate limiting middleware that keeps a separate token bucket for each client.
+///
+/// The bucket store is created along with the `RateLimit` and shared by its clones, so construct
+/// it outside the `HttpServer::new` closure in order for the limit to apply across all workers.
+///
+/// Requests are either checked against a single limit or, when constructed using
+/// [`RateLimit::from_policies`], against the first matching policy. The type parameter tells the
+/// two apart, so that settings of the single limit cannot be applied to a policy limiter.
+#[derive(Clone)]
+pub struct RateLimit<L = SingleLimit> {
+    /// How requests are mapped to buckets.
+    key: RateLimitKey,
+
+    /// Limits applied to requests.
+    limits: L,
+
+    /// Buckets shared between all workers.
+    store: Arc<dyn RateLimitStore>,
 }
 
 impl RateLimit {
-    /// Constructs new rate limiter.
+    /// Constructs new rate limiter that allows `limit` requests per 10 second period and keys
+    /// clients by peer IP.
     pub fn new(limit: u64) -> Self {
-        Self { limit }
+        Self {
+            key: RateLimitKey::PeerIp,
+            limits: SingleLimit(RateLimitConfig::new(limit)),
+            store: Arc::new(MemoryStore::new()),
+        }
+    }
+
+    /// Sets the period over which `limit` requests are allowed.
+    ///
+    /// Tokens are refilled smoothly over the period, rather than all at once when it ends.
+    pub fn period(mut self, period: Duration) -> Self {
+        self.limits.0.period = period;
+        self
+    }
+
+    /// Sets the bucket capacity; i.e., how many requests can be made in quick succession.
+    ///
+    /// Defaults to `limit`. New clients start with a full bucket.
+    pub fn burst(mut self, burst: u64) -> Self {
+        self.limits.0.burst = burst;
+        self
+    }
+
+    /// Sets how long a client's bucket can go unused before it is evicted.
+    ///
+    /// Buckets are never evicted before they have refilled completely.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.limits.0.idle_timeout = idle_timeout;
+        self
+    }
+}
+
+impl RateLimit<Policies> {
+    /// Constructs new rate limiter that only limits requests matching one of the given policies.
+    ///
+    /// Each policy sets its own period, burst capacity and idle timeout.
+    ///
+    /// # Errors
+    /// Returns an error if a policy could never allow a request; i.e., if it has a limit of zero
+    /// or its cost exceeds its burst capacity.
+    pub fn from_policies(
+        policies: impl IntoIterator<Item = RateLimitPolicy>,
+    ) -> Result<Self, InvalidPolicy> {
+        let policies = policies
+            .into_iter()
+            .map(|policy| policy.validate().map(|()| Arc::new(policy)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            key: RateLimitKey::PeerIp,
+            limits: Policies(policies),
+            store: Arc::new(MemoryStore::new()),
+        })
     }
 }
 
-impl<S, B> Transform<S, ServiceRequest> for RateLimit
+impl<L> RateLimit<L> {
+    /// Keys clients by the value of the given header (e.g., `X-Api-Key`).
+    ///
+    /// Requests without the header are keyed by peer IP.
+    pub fn key_by_header(mut self, name: HeaderName) -> Self {
+        self.key = RateLimitKey::Header(name);
+        self
+    }
+
+    /// Keys clients using a custom function.
+    pub fn key_by<F>(mut self, key_fn: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> String + Send + Sync + 'static,
+    {
+        self.key = RateLimitKey::Custom(Arc::new(key_fn));
+        self
+    }
+
+    /// Sets the store in which buckets are kept.
+    ///
+    /// Defaults to a [`MemoryStore`], which is only shared between the workers of one process.
+    pub fn store(mut self, store: impl RateLimitStore + 'static) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+}
+
+impl<S, B, L> Transform<S, ServiceRequest> for RateLimit<L>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
     S::Future: 'static,
     B: 'static,
+    L: Limits,
 {
     type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
-    type Transform = RateLimitService<S>;
+    type Transform = RateLimitService<S, L>;
     type InitError = ();
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(RateLimitService {
-            service,
-            token_bucket: RefCell::new(TokenBucket::new(self.limit)),
+            service: Rc::new(service),
+            key: self.key.clone(),
+            limits: self.limits.clone(),
+            store: Arc::clone(&self.store),
         }))
     }
 }
 
-struct TokenBucket {
-    /// Request limit for 10 second period.
+/// Determines which limit, if any, applies to a request.
+pub trait Limits: Clone + 'static {
+    /// Returns the namespace of the buckets for the limit that applies to `req`, if it has one,
+    /// and the settings for those buckets.
+    fn limit_for(&self, req: &ServiceRequest) -> Option<(Option<&str>, RateLimitConfig)>;
+}
+
+/// Single limit applied to every request; see [`RateLimit::new`].
+#[derive(Debug, Clone, Copy)]
+pub struct SingleLimit(RateLimitConfig);
+
+impl Limits for SingleLimit {
+    fn limit_for(&self, _req: &ServiceRequest) -> Option<(Option<&str>, RateLimitConfig)> {
+        Some((None, self.0))
+    }
+}
+
+/// Limits applied to classes of routes; see [`RateLimit::from_policies`].
+#[derive(Clone)]
+pub struct Policies(Vec<Arc<RateLimitPolicy>>);
+
+impl Limits for Policies {
+    fn limit_for(&self, req: &ServiceRequest) -> Option<(Option<&str>, RateLimitConfig)> {
+        self.0
+            .iter()
+            .find(|policy| policy.matches(req))
+            .map(|policy| (Some(policy.name.as_str()), policy.config))
+    }
+}
+
+/// Error returned when a [`RateLimitPolicy`] could never allow a request.
+#[derive(Debug)]
+pub struct InvalidPolicy {
+    /// Name of the offending policy.
+    name: String,
+
+    /// Why the policy is invalid.
+    reason: &'static str,
+}
+
+impl fmt::Display for InvalidPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid rate limit policy {:?}: {}",
+            self.name, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidPolicy {}
+
+/// Limit applied to a class of routes, matched by path pattern and/or guard.
+///
+/// Each policy keeps its own buckets, separate from those of other policies.
+pub struct RateLimitPolicy {
+    /// Name used to namespace this policy's buckets.
+    name: String,
+
+    /// Path pattern that requests must match, if any.
+    path: Option<ResourceDef>,
+
+    /// Guard that requests must pass, if any.
+    guard: Option<Box<dyn Guard + Send + Sync>>,
+
+    /// Settings for each client's bucket.
+    config: RateLimitConfig,
+}
+
+impl RateLimitPolicy {
+    /// Constructs new policy that allows `limit` requests per 10 second period.
+    ///
+    /// Matches all requests until a path pattern or guard is set.
+    pub fn new(name: impl Into<String>, limit: u64) -> Self {
+        Self {
+            name: name.into(),
+            path: None,
+            guard: None,
+            config: RateLimitConfig::new(limit),
+        }
+    }
+
+    /// Only applies the policy to requests whose path matches `pattern` (e.g., `/fibonacci/{n}`).
+    ///
+    /// Patterns use the same syntax as resource definitions.
+    pub fn path(mut self, pattern: &str) -> Self {
+        self.path = Some(ResourceDef::new(pattern));
+        self
+    }
+
+    /// Only applies the policy to requests that pass the given guard.
+    pub fn guard(mut self, guard: impl Guard + Send + Sync + 'static) -> Self {
+        self.guard = Some(Box::new(guard));
+        self
+    }
+
+    /// Sets the period over which `limit` tokens are refilled.
+    pub fn period(mut self, period: Duration) -> Self {
+        self.config.period = period;
+        self
+    }
+
+    /// Sets the bucket capacity. Defaults to `limit`.
+    pub fn burst(mut self, burst: u64) -> Self {
+        self.config.burst = burst;
+        self
+    }
+
+    /// Sets how long a client's bucket can go unused before it is evicted.
+    ///
+    /// Buckets are never evicted before they have refilled completely.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets the number of tokens taken by each matching request. Defaults to 1.
+    ///
+    /// Must not exceed the bucket capacity, or requests could never be allowed.
+    pub fn cost(mut self, cost: u64) -> Self {
+        self.config.cost = cost;
+        self
+    }
+
+    /// Checks that the policy allows at least some requests.
+    fn validate(&self) -> Result<(), InvalidPolicy> {
+        let reason = if self.config.limit == 0 {
+            "limit must be at least 1"
+        } else if self.config.cost > self.config.burst {
+            "cost exceeds burst capacity"
+        } else {
+            return Ok(());
+        };
+
+        Err(InvalidPolicy {
+            name: self.name.clone(),
+            reason,
+        })
+    }
+
+    fn matches(&self, req: &ServiceRequest) -> bool {
+        self.path
+            .as_ref()
+            .is_none_or(|path| path.is_match(req.path()))
+            && self
+                .guard
+                .as_ref()
+                .is_none_or(|guard| guard.check(&req.guard_ctx()))
+    }
+}
+
+/// Settings applied to a client's bucket.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// Number of requests allowed per period.
+    pub(crate) limit: u64,
+
+    /// Period over which `limit` tokens are refilled.
+    pub(crate) period: Duration,
+
+    /// Max number of tokens a bucket can hold.
+    pub(crate) burst: u64,
+
+    /// Number of tokens taken by each request.
+    pub(crate) cost: u64,
+
+    /// Time after which an unused bucket is evicted.
+    pub(crate) idle_timeout: Duration,
+}
+
+impl RateLimitConfig {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            period: DEFAULT_PERIOD,
+            burst: limit,
+            cost: 1,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+
+    /// Calculates time taken for an empty bucket to refill completely.
+    pub(crate) fn time_to_refill(&self) -> Duration {
+        if self.limit == 0 {
+            return self.period;
+        }
+
+        self.period.mul_f64(self.burst as f64 / self.limit as f64)
+    }
+}
+
+/// Storage backend for client buckets.
+pub trait RateLimitStore: Send + Sync {
+    /// Takes `config.cost` tokens from the bucket identified by `key`, creating it if needed.
+    fn check<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a RateLimitConfig,
+    ) -> LocalBoxFuture<'a, RateLimitDecision>;
+}
+
+/// Store that keeps buckets in process memory, shared across workers.
+#[derive(Default)]
+pub struct MemoryStore {
+    inner: Mutex<MemoryStoreInner>,
+}
+
+struct MemoryStoreInner {
+    buckets: HashMap<String, TokenBucket>,
+
+    /// Time that idle buckets were last evicted.
+    last_sweep: Instant,
+}
+
+impl Default for MemoryStoreInner {
+    fn default() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+}
+
+impl MemoryStore {
+    /// Constructs new, empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mutates the bucket for `key`, creating it if needed, and evicts idle buckets.
+    pub(crate) fn allow_query(&self, key: &str, config: &RateLimitConfig) -> RateLimitDecision {
+        let mut inner = self.inner.lock().unwrap();
+
+        let now = Instant::now();
+        let idle_timeout = config.idle_timeout;
+
+        if now.saturating_duration_since(inner.last_sweep) >= idle_timeout {
+            inner.buckets.retain(|_, bucket| {
+                now.saturating_duration_since(bucket.last_update) < idle_timeout
+                    || bucket.tokens_at(now) < bucket.capacity
+            });
+            inner.last_sweep = now;
+        }
+
+        inner
+            .buckets
+            .entry(key.to_owned())
+            .or_insert_with(|| TokenBucket::new(config, now))
+            .allow_query(now)
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn check<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a RateLimitConfig,
+    ) -> LocalBoxFuture<'a, RateLimitDecision> {
+        Box::pin(ready(self.allow_query(key, config)))
+    }
+}
+
+pub(crate) struct TokenBucket {
+    /// Number of requests allowed per period.
     limit: u64,
 
-    /// Max number of requests for 10 second period, in this case equal to limit.
-    capacity: u64,
+    /// Max number of tokens the bucket can hold.
+    capacity: f64,
 
-    /// Time that last request was accepted.
-    last_req_time: DateTime<Utc>,
+    /// Number of tokens added per millisecond.
+    refill_rate: f64,
 
-    /// Numbers of tokens remaining.
+    /// Number of tokens taken by each request.
+    cost: f64,
+
+    /// Time that the token count was last updated.
+    last_update: Instant,
+
+    /// Numbers of tokens remaining, as of `last_update`.
     ///
     /// Initialized equal to capacity.
-    tokens: u64,
+    pub(crate) tokens: f64,
 }
 
 impl TokenBucket {
-    /// Constructs new leaky bucket.
-    fn new(limit: u64) -> Self {
+    /// Constructs new, full leaky bucket.
+    pub(crate) fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        let period_ms = config.period.as_millis().max(1) as f64;
+
         TokenBucket {
-            limit,
-            last_req_time: DateTime::<Utc>::UNIX_EPOCH,
-            capacity: limit,
-            tokens: 0,
+            limit: config.limit,
+            capacity: config.burst as f64,
+            refill_rate: config.limit as f64 / period_ms,
+            cost: config.cost as f64,
+            last_update: now,
+            tokens: config.burst as f64,
         }
     }
 
-    /// Mutates leaky bucket for accepted request.
-    fn allow_query(&mut self) -> bool {
-        let current_time = Utc::now();
+    /// Number of tokens added per millisecond.
+    pub(crate) fn refill_rate(&self) -> f64 {
+        self.refill_rate
+    }
+
+    /// Calculates number of tokens in the bucket at the given time.
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed_ms = now.saturating_duration_since(self.last_update).as_millis() as f64;
+        (self.tokens + elapsed_ms * self.refill_rate).min(self.capacity)
+    }
+
+    /// Calculates time until the bucket holds `tokens` tokens, assuming none are taken.
+    ///
+    /// Returns `None` if the bucket will never hold that many tokens.
+    fn time_until(&self, tokens: f64) -> Option<Duration> {
+        let missing = tokens - self.tokens;
 
-        let time_elapsed = (current_time.timestamp() - self.last_req_time.timestamp()) as u64;
+        if missing <= 0.0 {
+            return Some(Duration::ZERO);
+        }
 
-        let tokens_to_add = time_elapsed * self.limit / 10;
+        if tokens > self.capacity || self.refill_rate <= 0.0 {
+            return None;
+        }
 
-        self.tokens = min(self.tokens + tokens_to_add, self.capacity);
+        Some(Duration::from_millis(
+            (missing / self.refill_rate).ceil() as u64
+        ))
+    }
 
-        if self.tokens > 0 {
-            self.last_req_time = current_time;
-            self.tokens -= 1;
+    /// Mutates leaky bucket for accepted request.
+    fn allow_query(&mut self, now: Instant) -> RateLimitDecision {
+        self.tokens = self.tokens_at(now);
+        self.last_update = now;
+
+        let allowed = if self.tokens >= self.cost {
+            self.tokens -= self.cost;
             true
         } else {
             false
+        };
+
+        self.decision(allowed)
+    }
+
+    /// Describes current bucket state, after a request was either allowed or rejected.
+    pub(crate) fn decision(&self, allowed: bool) -> RateLimitDecision {
+        RateLimitDecision {
+            allowed,
+            limit: self.limit,
+            remaining: self.tokens.floor() as u64,
+            reset: self.time_until(self.capacity),
+            retry_after: self.time_until(self.cost),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpResponse, guard, http::StatusCode, test::TestRequest, web};
+
+    use super::*;
+
+    fn config(limit: u64, period: Duration, burst: u64) -> RateLimitConfig {
+        RateLimitConfig {
+            period,
+            burst,
+            ..RateLimitConfig::new(limit)
+        }
+    }
+
+    #[test]
+    fn bucket_starts_full_and_refills_smoothly() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&config(10, Duration::from_secs(1), 2), start);
+
+        assert!(bucket.allow_query(start).allowed);
+        assert!(bucket.allow_query(start).allowed);
+
+        let denied = bucket.allow_query(start);
+        assert!(!denied.allowed);
+        assert_eq!(denied.limit, 10);
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(100)));
+        assert_eq!(denied.reset, Some(Duration::from_millis(200)));
+
+        // one token is refilled every 100ms
+        let later = start + Duration::from_millis(150);
+        let allowed = bucket.allow_query(later);
+        assert!(allowed.allowed);
+        assert_eq!(allowed.remaining, 0);
+        assert!(!bucket.allow_query(later).allowed);
+    }
+
+    #[test]
+    fn idle_timeout_may_be_unbounded() {
+        let store = MemoryStore::new();
+        let config = RateLimitConfig {
+            idle_timeout: Duration::MAX,
+            ..RateLimitConfig::new(1)
+        };
+
+        assert!(store.allow_query("client", &config).allowed);
+        assert!(!store.allow_query("client", &config).allowed);
+    }
+
+    #[actix_web::test]
+    async fn buckets_are_keyed_per_client() {
+        let limiter = RateLimit::new(1)
+            .burst(1)
+            .key_by_header(HeaderName::from_static("x-api-key"));
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(limiter)
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = |key: &'static str| {
+            TestRequest::default()
+                .insert_header(("x-api-key", key))
+                .to_request()
+        };
+
+        let res = actix_web::test::call_service(&app, req("alice")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(RATELIMIT_LIMIT).unwrap(), "1");
+        assert_eq!(res.headers().get(RATELIMIT_REMAINING).unwrap(), "0");
+
+        let res = actix_web::test::call_service(&app, req("alice")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "10");
+
+        let res = actix_web::test::call_service(&app, req("bob")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn empty_bucket_without_refill_is_never_retried() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&config(0, Duration::from_secs(1), 1), start);
+
+        assert!(bucket.allow_query(start).allowed);
+
+        let denied = bucket.allow_query(start + Duration::from_secs(60));
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, None);
+        assert_eq!(denied.reset, None);
+    }
+
+    #[test]
+    fn policies_that_never_allow_requests_are_rejected() {
+        let Err(err) = RateLimit::from_policies([RateLimitPolicy::new("none", 0)]) else {
+            panic!("policy with no limit was accepted");
+        };
+        assert_eq!(err.reason, "limit must be at least 1");
+
+        let policy = RateLimitPolicy::new("costly", 5).burst(2).cost(3);
+        let Err(err) = RateLimit::from_policies([policy]) else {
+            panic!("policy costing more than its burst capacity was accepted");
+        };
+        assert_eq!(err.name, "costly");
+        assert_eq!(err.reason, "cost exceeds burst capacity");
+    }
+
+    #[actix_web::test]
+    async fn policies_apply_per_route_with_cost() {
+        let limiter = RateLimit::from_policies([
+            RateLimitPolicy::new("expensive", 10)
+                .path("/expensive/{n}")
+                .cost(4),
+            RateLimitPolicy::new("writes", 1).guard(guard::Post()),
+        ])
+        .unwrap();
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(limiter)
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let get = |path: &str| TestRequest::get().uri(path).to_request();
+
+        // 10 tokens allow two requests costing 4 each
+        for _ in 0..2 {
+            let res = actix_web::test::call_service(&app, get("/expensive/30")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        let res = actix_web::test::call_service(&app, get("/expensive/30")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(RATELIMIT_REMAINING).unwrap(), "2");
+
+        // requests matching no policy are not limited
+        for _ in 0..20 {
+            let res = actix_web::test::call_service(&app, get("/cheap")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers().get(RATELIMIT_LIMIT).is_none());
         }
+
+        let post = || TestRequest::post().uri("/cheap").to_request();
+        let res = actix_web::test::call_service(&app, post()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = actix_web::test::call_service(&app, post()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
     }
 }
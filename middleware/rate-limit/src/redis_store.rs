@@ -0,0 +1,232 @@
+//! Redis-backed bucket store, for sharing rate limits between multiple server instances.
+
+use std::{
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::rt::time::timeout;
+use futures_util::future::LocalBoxFuture;
+use redis::{
+    RedisResult, Script,
+    aio::{ConnectionManager, ConnectionManagerConfig},
+};
+use tokio::sync::OnceCell;
+
+use crate::rate_limit::{
+    MemoryStore, RateLimitConfig, RateLimitDecision, RateLimitStore, TokenBucket,
+};
+
+/// Prefix applied to all keys written by the store.
+const KEY_PREFIX: &str = "rate-limit:";
+
+/// Default time to wait for Redis before falling back to local buckets.
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Minimum time between attempts to (re)establish the initial connection.
+const CONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Refills and takes a token from a bucket stored as a hash, atomically.
+///
+/// Uses the Redis server clock so that instances with skewed clocks agree on refill timing.
+///
+/// KEYS[1]: bucket key
+/// ARGV[1]: bucket capacity
+/// ARGV[2]: tokens added per millisecond
+/// ARGV[3]: key expiry in milliseconds
+/// ARGV[4]: tokens taken by the request
+///
+/// Returns whether the request was allowed and the number of tokens remaining.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local refill_rate = tonumber(ARGV[2])
+local ttl = tonumber(ARGV[3])
+local cost = tonumber(ARGV[4])
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or capacity
+local updated = tonumber(state[2]) or now
+
+tokens = math.min(capacity, tokens + math.max(0, now - updated) * refill_rate)
+
+local allowed = 0
+if tokens >= cost then
+    tokens = tokens - cost
+    allowed = 1
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], ttl)
+
+return { allowed, tostring(tokens) }
+"#;
+
+/// Store that keeps buckets in Redis, so that all instances connected to it share the same limits.
+///
+/// When Redis is unreachable or slow to respond, requests are limited using local, in-memory
+/// buckets instead.
+pub struct RedisStore {
+    client: redis::Client,
+
+    /// Connection established on first use, which reconnects automatically thereafter.
+    conn: OnceCell<ConnectionManager>,
+
+    /// Earliest time that another attempt to establish the connection may be made.
+    next_connect: Mutex<Instant>,
+
+    script: Script,
+
+    /// Time to wait for Redis before falling back to local buckets.
+    timeout: Duration,
+
+    /// Buckets used while Redis is unavailable.
+    fallback: MemoryStore,
+}
+
+impl RedisStore {
+    /// Constructs new store using the given client.
+    ///
+    /// No connection is made until the first request is checked.
+    pub fn new(client: redis::Client) -> Self {
+        Self {
+            client,
+            conn: OnceCell::new(),
+            next_connect: Mutex::new(Instant::now()),
+            script: Script::new(TOKEN_BUCKET_SCRIPT),
+            timeout: DEFAULT_TIMEOUT,
+            fallback: MemoryStore::new(),
+        }
+    }
+
+    /// Sets time to wait for Redis before falling back to local buckets.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn connection(&self) -> RedisResult<ConnectionManager> {
+        if let Some(conn) = self.conn.get() {
+            return Ok(conn.clone());
+        }
+
+        {
+            let mut next_connect = self.next_connect.lock().unwrap();
+
+            if Instant::now() < *next_connect {
+                return Err(io::Error::from(io::ErrorKind::NotConnected).into());
+            }
+
+            *next_connect = Instant::now() + CONNECT_INTERVAL;
+        }
+
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(self.timeout)
+            .set_response_timeout(self.timeout)
+            .set_number_of_retries(1);
+
+        let conn = self
+            .conn
+            .get_or_try_init(|| self.client.get_connection_manager_with_config(config))
+            .await?;
+
+        Ok(conn.clone())
+    }
+
+    async fn check_redis(
+        &self,
+        key: &str,
+        config: &RateLimitConfig,
+    ) -> RedisResult<RateLimitDecision> {
+        let mut conn = self.connection().await?;
+
+        let mut bucket = TokenBucket::new(config, Instant::now());
+        let ttl = config.time_to_refill().max(config.idle_timeout);
+
+        let mut invocation = self.script.key(format!("{KEY_PREFIX}{key}"));
+        invocation
+            .arg(config.burst)
+            .arg(bucket.refill_rate().to_string())
+            .arg(ttl.as_millis() as u64)
+            .arg(config.cost);
+
+        let (allowed, tokens) = timeout(
+            self.timeout,
+            invocation.invoke_async::<(i64, String)>(&mut conn),
+        )
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        bucket.tokens = tokens.parse().unwrap_or_default();
+
+        Ok(bucket.decision(allowed == 1))
+    }
+}
+
+impl RateLimitStore for RedisStore {
+    fn check<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a RateLimitConfig,
+    ) -> LocalBoxFuture<'a, RateLimitDecision> {
+        Box::pin(async move {
+            match self.check_redis(key, config).await {
+                Ok(decision) => decision,
+                Err(err) => {
+                    log::warn!("Redis rate limit check failed, using local buckets: {err}");
+                    self.fallback.allow_query(key, config)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            limit: 1,
+            period: Duration::from_secs(60),
+            burst: 1,
+            cost: 1,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+
+    #[actix_web::test]
+    async fn falls_back_to_local_buckets() {
+        // nothing listens on this port
+        let store = RedisStore::new(redis::Client::open("redis://127.0.0.1:1").unwrap());
+
+        assert!(store.check("fallback", &config()).await.allowed);
+        assert!(!store.check("fallback", &config()).await.allowed);
+    }
+
+    #[actix_web::test]
+    #[ignore = "requires Redis instance running"]
+    async fn instances_share_buckets() {
+        let client = redis::Client::open("redis://127.0.0.1:6379").unwrap();
+
+        // simulate two server instances connected to the same Redis
+        let store1 = RedisStore::new(client.clone()).timeout(Duration::from_secs(2));
+        let store2 = RedisStore::new(client).timeout(Duration::from_secs(2));
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let key = format!("test-{}", now.as_nanos());
+
+        let decision = store1.check(&key, &config()).await;
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+
+        let decision = store2.check(&key, &config()).await;
+        assert!(!decision.allowed);
+        assert!(decision.retry_after.unwrap() > Duration::from_secs(50));
+    }
+}
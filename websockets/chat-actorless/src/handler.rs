@@ -1,10 +1,14 @@
 use std::time::{Duration, Instant};
 
-use actix_ws::AggregatedMessage;
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, ProtocolError};
 use futures_util::StreamExt as _;
-use tokio::{sync::mpsc, time::interval};
+use tokio::time::interval;
 
-use crate::{ChatServerHandle, ConnId};
+use crate::{
+    ChatServerHandle, ConnId,
+    limits::{LimitConfig, SessionLimits},
+    protocol::{ChatRequest, ChatResponse},
+};
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -21,25 +25,45 @@ pub async fn chat_ws(
 ) {
     log::info!("connected");
 
-    let mut name: Option<String> = None;
     let mut last_heartbeat = Instant::now();
+    let limit_config = LimitConfig::default();
+    let mut limits = SessionLimits::new(limit_config);
     let mut interval = interval(HEARTBEAT_INTERVAL);
 
-    let (conn_tx, mut conn_rx) = mpsc::unbounded_channel();
-
     // unwrap: chat server is not dropped before the HTTP server
-    let conn_id = chat_server.connect(conn_tx).await;
+    let (conn_id, mut conn_rx) = chat_server.connect().await;
 
+    // frames, and messages split across frames, longer than the limit are refused by the codec
     let mut msg_stream = msg_stream
-        .max_frame_size(128 * 1024)
+        .max_frame_size(limit_config.max_frame_len)
         .aggregate_continuations()
-        .max_continuation_size(2 * 1024 * 1024);
+        .max_continuation_size(limit_config.max_frame_len);
 
     let close_reason = loop {
         tokio::select! {
-            Some(Ok(msg)) = msg_stream.next() => {
+            Some(msg) = msg_stream.next() => {
                 log::debug!("msg: {msg:?}");
 
+                let msg = match msg {
+                    Ok(msg) => msg,
+
+                    // the frame was skipped, so the connection can carry on
+                    Err(ProtocolError::Overflow) => {
+                        let err = limits.refuse_too_long(Instant::now());
+                        send(&mut session, &ChatResponse::error(err)).await;
+                        continue;
+                    }
+
+                    Err(err) => {
+                        log::warn!("conn {conn_id}: {err}");
+
+                        break Some(CloseReason {
+                            code: CloseCode::Protocol,
+                            description: Some(err.to_string()),
+                        });
+                    }
+                };
+
                 match msg {
                     AggregatedMessage::Ping(bytes) => {
                         last_heartbeat = Instant::now();
@@ -51,21 +75,36 @@ pub async fn chat_ws(
                     }
 
                     AggregatedMessage::Text(text) => {
-                        process_text_msg(&chat_server, &mut session, &text, conn_id, &mut name)
+                        process_text_msg(&chat_server, &mut session, &mut limits, &text, conn_id)
                             .await;
                     }
 
-                    AggregatedMessage::Binary(_bin) => {
-                        log::warn!("unexpected binary message");
+                    AggregatedMessage::Binary(bin) => {
+                        let err = match limits.check_frame(bin.len(), Instant::now()) {
+                            Err(err) => err.to_string(),
+                            Ok(()) => "requests must be sent as text frames".to_owned(),
+                        };
+                        send(&mut session, &ChatResponse::error(err)).await;
                     }
 
                     AggregatedMessage::Close(reason) => break reason,
                 }
             }
 
-            Some(chat_msg) = conn_rx.recv() => {
-                 session.text(chat_msg).await.unwrap();
-            }
+            // messages are only taken from the queue as fast as the client reads them
+            chat_msg = conn_rx.recv() => match chat_msg {
+                Some(chat_msg) => send(&mut session, &chat_msg).await,
+
+                // the server closed the queue because the client fell too far behind
+                None => {
+                    log::warn!("conn {conn_id}: disconnecting slow client");
+
+                    break Some(CloseReason {
+                        code: CloseCode::Again,
+                        description: Some("not keeping up with messages".to_owned()),
+                    });
+                }
+            },
 
             _ = interval.tick() => {
                 if Instant::now().duration_since(last_heartbeat) > CLIENT_TIMEOUT {
@@ -80,7 +119,7 @@ pub async fn chat_ws(
         }
     };
 
-    chat_server.disconnect(conn_id);
+    chat_server.disconnect(conn_id).await;
 
     // attempt to close connection gracefully
     let _ = session.close(close_reason).await;
@@ -89,67 +128,100 @@ pub async fn chat_ws(
 async fn process_text_msg(
     chat_server: &ChatServerHandle,
     session: &mut actix_ws::Session,
+    limits: &mut SessionLimits,
     text: &str,
     conn: ConnId,
-    name: &mut Option<String>,
 ) {
-    // strip leading and trailing whitespace (spaces, newlines, etc.)
-    let msg = text.trim();
+    if let Err(err) = limits.check_frame(text.len(), Instant::now()) {
+        send(session, &ChatResponse::error(err)).await;
+        return;
+    }
 
-    // we check for /<cmd> type of messages
-    if msg.starts_with('/') {
-        let mut cmd_args = msg.splitn(2, ' ');
+    let req = match serde_json::from_str(text) {
+        Ok(req) => req,
+        Err(err) => {
+            let res = ChatResponse::error(format!("invalid request: {err}"));
+            send(session, &res).await;
+            return;
+        }
+    };
 
-        // unwrap: we have guaranteed non-zero string length already
-        match cmd_args.next().unwrap() {
-            "/list" => {
-                log::info!("conn {conn}: listing rooms");
+    // muted clients may do anything but talk
+    if matches!(req, ChatRequest::Message(_) | ChatRequest::Direct { .. }) {
+        if let Err(err) = limits.check_send(Instant::now()) {
+            send(session, &ChatResponse::error(err)).await;
+            return;
+        }
+    }
 
-                let rooms = chat_server.list_rooms().await;
+    let res = match req {
+        ChatRequest::List => {
+            log::info!("conn {conn}: listing rooms");
 
-                for room in rooms {
-                    session.text(room).await.unwrap();
-                }
-            }
+            Ok(Some(ChatResponse::Rooms(chat_server.list_rooms().await)))
+        }
 
-            "/join" => match cmd_args.next() {
-                Some(room) => {
-                    log::info!("conn {conn}: joining room {room}");
+        ChatRequest::Join { room, password } => {
+            log::info!("conn {conn}: joining room {room}");
 
-                    chat_server.join_room(conn, room).await;
+            chat_server
+                .join_room(conn, room.clone(), password)
+                .await
+                .map(|()| Some(ChatResponse::Joined(room)))
+        }
 
-                    session.text(format!("joined {room}")).await.unwrap();
-                }
+        ChatRequest::Name(name) => {
+            log::info!("conn {conn}: setting name to: {name}");
 
-                None => {
-                    session.text("!!! room name is required").await.unwrap();
-                }
-            },
+            chat_server.set_name(conn, name).await.map(|()| None)
+        }
 
-            "/name" => match cmd_args.next() {
-                Some(new_name) => {
-                    log::info!("conn {conn}: setting name to: {new_name}");
-                    name.replace(new_name.to_owned());
-                }
-                None => {
-                    session.text("!!! name is required").await.unwrap();
-                }
-            },
+        ChatRequest::Message(msg) => chat_server.send_message(conn, msg).await.map(|()| None),
 
-            _ => {
-                session
-                    .text(format!("!!! unknown command: {msg}"))
-                    .await
-                    .unwrap();
-            }
+        ChatRequest::Direct { to, text } => {
+            chat_server.send_direct(conn, to, text).await.map(|()| None)
+        }
+
+        ChatRequest::Moderate(action) => chat_server.moderate(conn, action).await.map(|()| None),
+
+        ChatRequest::History { .. } => Ok(Some(ChatResponse::error("message history is not kept"))),
+
+        ChatRequest::Typing(typing) => {
+            chat_server.typing(conn, typing).await;
+            Ok(None)
         }
-    } else {
-        // prefix message with our name, if assigned
-        let msg = match name {
-            Some(name) => format!("{name}: {msg}"),
-            None => msg.to_owned(),
-        };
-
-        chat_server.send_message(conn, msg).await
+
+        ChatRequest::Read { up_to } => {
+            chat_server.read(conn, up_to).await;
+            Ok(None)
+        }
+
+        ChatRequest::Away(away) => {
+            chat_server.set_away(conn, away).await;
+            Ok(None)
+        }
+
+        ChatRequest::Members => Ok(chat_server
+            .members(conn)
+            .await
+            .map(|(room, members)| ChatResponse::Members { room, members })),
+
+        // heartbeats use WebSocket pings
+        ChatRequest::Ping => Ok(None),
+    };
+
+    match res {
+        Ok(Some(res)) => send(session, &res).await,
+        Ok(None) => {}
+        Err(err) => send(session, &ChatResponse::error(err)).await,
     }
 }
+
+/// Sends a response to the client as a JSON text frame.
+async fn send(session: &mut actix_ws::Session, res: &ChatResponse) {
+    // unwrap: responses are plain data that always serializes
+    let text = serde_json::to_string(res).unwrap();
+
+    // errors if the client disconnected, which the main loop notices
+    let _ = session.text(text).await;
+}
@@ -14,8 +14,13 @@ use tokio::{
 mod handler;
 mod server;
 
+use websocket_chat_common::{fanout, filter, limits, permissions, protocol, queue};
+
 pub use self::server::{ChatServer, ChatServerHandle};
 
+/// File with the words that are masked in messages, one per line.
+const BLOCKED_WORDS_FILE: &str = "blocked-words.txt";
+
 /// Connection ID.
 pub type ConnId = u64;
 
@@ -23,12 +28,17 @@ pub type ConnId = u64;
 pub type RoomId = String;
 
 /// Message sent to a room/client.
-pub type Msg = String;
+pub type Msg = protocol::ChatResponse;
 
 async fn index() -> impl Responder {
     NamedFile::open_async("./static/index.html").await.unwrap()
 }
 
+/// Depths of connection message queues, in the Prometheus text format.
+async fn metrics(queue_metrics: web::Data<queue::QueueMetrics>) -> impl Responder {
+    queue_metrics.to_string()
+}
+
 /// Handshake and start WebSocket handler with heartbeats.
 async fn chat_ws(
     req: HttpRequest,
@@ -51,19 +61,27 @@ async fn chat_ws(
 async fn main() -> io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
-    log::info!("starting HTTP server at http://localhost:8080");
+    let queue_metrics = web::Data::new(queue::QueueMetrics::default());
+    let (chat_server, server_tx) = ChatServer::new(
+        fanout::from_env().await?,
+        queue::QueueConfig::from_env()?,
+        queue_metrics.clone().into_inner(),
+    );
+    let chat_server = chat_server.with_filter(filter::Filters::standard(BLOCKED_WORDS_FILE)?);
 
-    let (chat_server, server_tx) = ChatServer::new();
+    log::info!("starting HTTP server at http://localhost:8080");
 
     let chat_server = spawn(chat_server.run());
 
     let http_server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(server_tx.clone()))
+            .app_data(queue_metrics.clone())
             // WebSocket UI HTML file
             .service(web::resource("/").to(index))
             // websocket routes
             .service(web::resource("/ws").route(web::get().to(chat_ws)))
+            .service(web::resource("/metrics").route(web::get().to(metrics)))
             // standard middleware
             .wrap(middleware::NormalizePath::trim())
             .wrap(middleware::Logger::default())
@@ -1,4 +1,22 @@
 //! A multi-room chat server.
+//!
+//! Who may join and talk in a room is decided by [`Permissions`], which the owner of each room
+//! controls. Users are identified by their name, which is unique among connected users, on this
+//! server and the others it shares rooms with.
+//!
+//! Connections are sent [`ChatResponse`]s. Typing indicators, read receipts and changes in
+//! [`Presence`] are passed on to the other users in a room as they happen and are not kept.
+//!
+//! What is sent to a room is also published through a [`Fanout`] backend, so that several servers
+//! can share rooms. Changes to room permissions are published too, names are reserved through
+//! the backend, and messages are numbered by the backend if it is shared. Commands that wait for
+//! the backend carry on once it answers, without holding up other commands in the meantime.
+//!
+//! Commands and responses go through bounded queues. Responses to a connection whose client reads
+//! too slowly are dropped, or the client is disconnected, as set by its [`QueueConfig`].
+//!
+//! Messages pass through a [`MessageFilter`] before they are sent, which may rewrite or reject
+//! them.
 
 use std::{
     collections::{HashMap, HashSet},
@@ -9,17 +27,27 @@ use std::{
     },
 };
 
+use futures_util::{FutureExt as _, StreamExt as _};
 use rand::Rng as _;
 use tokio::sync::{mpsc, oneshot};
 
-use crate::{ConnId, Msg, RoomId};
+use crate::{
+    ConnId, Msg, RoomId,
+    fanout::{Event, Fanout},
+    filter::{Filters, MessageFilter},
+    permissions::{MAIN_ROOM, Moderation, Permissions, RoomError},
+    protocol::{ChatMessage, ChatResponse, Member, Presence},
+    queue::{self, QueueConfig, QueueMetrics, QueueReceiver, QueueSender},
+};
+
+/// Number of commands that can wait for the [`ChatServer`] before senders have to wait too.
+const COMMAND_QUEUE_CAPACITY: usize = 256;
 
 /// A command received by the [`ChatServer`].
 #[derive(Debug)]
 enum Command {
     Connect {
-        conn_tx: mpsc::UnboundedSender<Msg>,
-        res_tx: oneshot::Sender<ConnId>,
+        res_tx: oneshot::Sender<(ConnId, QueueReceiver<Msg>)>,
     },
 
     Disconnect {
@@ -33,14 +61,102 @@ enum Command {
     Join {
         conn: ConnId,
         room: RoomId,
-        res_tx: oneshot::Sender<()>,
+        password: Option<String>,
+        res_tx: oneshot::Sender<Result<(), RoomError>>,
+    },
+
+    Name {
+        conn: ConnId,
+        name: String,
+        res_tx: oneshot::Sender<Result<(), RoomError>>,
     },
 
     Message {
-        msg: Msg,
+        msg: String,
+        conn: ConnId,
+        res_tx: oneshot::Sender<Result<(), RoomError>>,
+    },
+
+    Direct {
+        conn: ConnId,
+        to: String,
+        msg: String,
+        res_tx: oneshot::Sender<Result<(), RoomError>>,
+    },
+
+    Moderate {
+        conn: ConnId,
+        action: Moderation,
+        res_tx: oneshot::Sender<Result<(), RoomError>>,
+    },
+
+    Typing {
         conn: ConnId,
-        res_tx: oneshot::Sender<()>,
+        typing: bool,
     },
+
+    Read {
+        conn: ConnId,
+        up_to: u64,
+    },
+
+    Away {
+        conn: ConnId,
+        away: bool,
+    },
+
+    Members {
+        conn: ConnId,
+        res_tx: oneshot::Sender<Option<(RoomId, Vec<Member>)>>,
+    },
+
+    /// Sent by the server to itself once the name of a new connection is reserved, or not.
+    Connected {
+        conn: ConnId,
+        name: String,
+        claimed: io::Result<bool>,
+        res_tx: oneshot::Sender<(ConnId, QueueReceiver<Msg>)>,
+    },
+
+    /// Sent by the server to itself once a message is numbered, or could not be.
+    Numbered {
+        id: io::Result<Option<u64>>,
+        name: String,
+        room: RoomId,
+        msg: String,
+        res_tx: oneshot::Sender<Result<(), RoomError>>,
+    },
+
+    /// Sent by the server to itself once a name that a user asked for is reserved, or not.
+    Renamed {
+        conn: ConnId,
+        name: String,
+        claimed: io::Result<bool>,
+        res_tx: oneshot::Sender<Result<(), RoomError>>,
+    },
+}
+
+/// A connected session.
+#[derive(Debug)]
+struct Session {
+    /// Message queue.
+    tx: QueueSender<Msg>,
+
+    /// Unique name of the session's user.
+    name: String,
+
+    /// Whether the user said they are away.
+    away: bool,
+}
+
+impl Session {
+    fn presence(&self) -> Presence {
+        if self.away {
+            Presence::Away
+        } else {
+            Presence::Online
+        }
+    }
 }
 
 /// A multi-room chat server.
@@ -48,175 +164,730 @@ enum Command {
 /// Contains the logic of how connections chat with each other plus room management.
 ///
 /// Call and spawn [`run`](Self::run) to start processing commands.
-#[derive(Debug)]
 pub struct ChatServer {
-    /// Map of connection IDs to their message receivers.
-    sessions: HashMap<ConnId, mpsc::UnboundedSender<Msg>>,
+    /// Map of connection IDs to their message receivers and names.
+    sessions: HashMap<ConnId, Session>,
 
     /// Map of room name to participant IDs in that room.
     rooms: HashMap<RoomId, HashSet<ConnId>>,
 
+    /// Who may join and talk in each room.
+    permissions: Permissions,
+
     /// Tracks total number of historical connections established.
     visitor_count: Arc<AtomicUsize>,
 
+    /// ID of the last message sent to a room.
+    last_message_id: u64,
+
+    /// Carries room traffic to and from other servers.
+    fanout: Box<dyn Fanout>,
+
+    /// Size and overflow policy of connection message queues.
+    queue_config: QueueConfig,
+
+    /// Depths of connection message queues.
+    queue_metrics: Arc<QueueMetrics>,
+
+    /// Decides whether and how messages are sent.
+    filter: Box<dyn MessageFilter>,
+
     /// Command receiver.
-    cmd_rx: mpsc::UnboundedReceiver<Command>,
+    cmd_rx: mpsc::Receiver<Command>,
+
+    /// Sender of commands that the server sends itself once the fanout backend has answered.
+    then_tx: mpsc::UnboundedSender<Command>,
+
+    /// Receiver of commands that the server sends itself.
+    then_rx: mpsc::UnboundedReceiver<Command>,
 }
 
 impl ChatServer {
-    pub fn new() -> (Self, ChatServerHandle) {
+    pub fn new(
+        fanout: Box<dyn Fanout>,
+        queue_config: QueueConfig,
+        queue_metrics: Arc<QueueMetrics>,
+    ) -> (Self, ChatServerHandle) {
         // create empty server
         let mut rooms = HashMap::with_capacity(4);
 
         // create default room
-        rooms.insert("main".to_owned(), HashSet::new());
+        rooms.insert(MAIN_ROOM.to_owned(), HashSet::new());
+        fanout.add_room(MAIN_ROOM);
 
-        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let (cmd_tx, cmd_rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+        let (then_tx, then_rx) = mpsc::unbounded_channel();
 
         (
             Self {
                 sessions: HashMap::new(),
                 rooms,
+                permissions: Permissions::default(),
                 visitor_count: Arc::new(AtomicUsize::new(0)),
+                last_message_id: 0,
+                fanout,
+                queue_config,
+                queue_metrics,
+                filter: Box::new(Filters::default()),
                 cmd_rx,
+                then_tx,
+                then_rx,
             },
             ChatServerHandle { cmd_tx },
         )
     }
 
-    /// Send message to users in a room.
+    /// Passes messages through `filter` before they are sent.
+    pub fn with_filter(mut self, filter: impl MessageFilter + 'static) -> Self {
+        self.filter = Box::new(filter);
+        self
+    }
+
+    /// Send message to users in a room, on this server and others.
     ///
     /// `skip` is used to prevent messages triggered by a connection also being received by it.
-    async fn send_system_message(&self, room: &str, skip: ConnId, msg: impl Into<Msg>) {
-        if let Some(sessions) = self.rooms.get(room) {
-            let msg = msg.into();
+    async fn send_to_room(&self, room: &str, skip: ConnId, msg: Msg) {
+        self.fanout.publish(room, &msg);
+        self.deliver(room, skip, msg);
+    }
 
+    /// Send message to the users in a room that are connected to this server.
+    fn deliver(&self, room: &str, skip: ConnId, msg: Msg) {
+        if let Some(sessions) = self.rooms.get(room) {
             for conn_id in sessions {
                 if *conn_id != skip {
-                    if let Some(tx) = self.sessions.get(conn_id) {
-                        // errors if client disconnected abruptly and hasn't been timed-out yet
-                        let _ = tx.send(msg.clone());
+                    if let Some(session) = self.sessions.get(conn_id) {
+                        // errors if client disconnected abruptly and hasn't been timed-out yet,
+                        // or was too slow and is being disconnected, which its handler notices
+                        let _ = session.tx.send(msg.clone());
                     }
                 }
             }
         }
     }
 
-    /// Send message to all other users in current room.
+    /// Send a notice about a room to users in it.
     ///
-    /// `conn` is used to find current room and prevent messages sent by a connection also being
-    /// received by it.
-    async fn send_message(&self, conn: ConnId, msg: impl Into<Msg>) {
-        if let Some(room) = self
-            .rooms
+    /// `skip` is used to prevent notices triggered by a connection also being received by it.
+    async fn send_system_message(&self, room: &str, skip: ConnId, text: impl Into<String>) {
+        self.send_to_room(room, skip, ChatResponse::notice(Some(room), text))
+            .await;
+    }
+
+    /// Send message to a single connection.
+    fn send_to(&self, conn: ConnId, msg: Msg) {
+        if let Some(session) = self.sessions.get(&conn) {
+            let _ = session.tx.send(msg);
+        }
+    }
+
+    /// Returns the room that a connection is in.
+    fn room_of(&self, conn: ConnId) -> Option<RoomId> {
+        self.rooms
+            .iter()
+            .find_map(|(room, participants)| participants.contains(&conn).then(|| room.clone()))
+    }
+
+    /// Returns the connection of the user with the given name.
+    fn conn_of(&self, name: &str) -> Option<ConnId> {
+        self.sessions
             .iter()
-            .find_map(|(room, participants)| participants.contains(&conn).then_some(room))
-        {
-            self.send_system_message(room, conn, msg).await;
+            .find_map(|(id, session)| (session.name == name).then_some(*id))
+    }
+
+    /// Returns the name of a connection's user and the room it is in.
+    fn name_and_room(&self, conn: ConnId) -> Option<(String, RoomId)> {
+        let name = self.sessions.get(&conn)?.name.clone();
+        Some((name, self.room_of(conn)?))
+    }
+
+    /// Tell the users in a room about a change in the presence of a user.
+    ///
+    /// `skip` is used to prevent the user's own connection being told.
+    async fn send_presence(&self, room: &str, skip: ConnId, user: &str, presence: Presence) {
+        let msg = ChatResponse::Presence {
+            room: room.to_owned(),
+            user: user.to_owned(),
+            presence,
+        };
+        self.send_to_room(room, skip, msg).await;
+    }
+
+    /// Send message to all users in current room, if the connection may talk there, once the
+    /// fanout backend has numbered it.
+    ///
+    /// `conn` is used to find current room. The sender gets the message back too, so that it
+    /// learns the message's ID.
+    fn send_message(
+        &mut self,
+        conn: ConnId,
+        msg: String,
+        res_tx: oneshot::Sender<Result<(), RoomError>>,
+    ) {
+        let (Some(name), Some(room)) = (
+            self.sessions.get(&conn).map(|s| s.name.clone()),
+            self.room_of(conn),
+        ) else {
+            let _ = res_tx.send(Ok(()));
+            return;
+        };
+
+        if let Err(err) = self.permissions.check_send(&room, &name) {
+            let _ = res_tx.send(Err(err));
+            return;
+        }
+
+        let msg = match self.filter.apply(&name, Some(&room), msg) {
+            Ok(msg) => msg,
+            Err(reason) => {
+                let _ = res_tx.send(Err(RoomError::Rejected(reason)));
+                return;
+            }
         };
+
+        let id = self.fanout.message_id();
+        self.then(async move {
+            Command::Numbered {
+                id: id.await,
+                name,
+                room,
+                msg,
+                res_tx,
+            }
+        });
+    }
+
+    /// Send a message to the users in `room`, under the ID that the fanout backend gave it.
+    async fn send_numbered(
+        &mut self,
+        id: io::Result<Option<u64>>,
+        name: String,
+        room: RoomId,
+        msg: String,
+    ) -> Result<(), RoomError> {
+        let id = match id {
+            Ok(Some(id)) => id,
+            Ok(None) => {
+                self.last_message_id += 1;
+                self.last_message_id
+            }
+            Err(err) => {
+                log::error!("failed to number message: {err}");
+                return Err(RoomError::NotSent);
+            }
+        };
+        let msg = ChatMessage::new(id, name, Some(&room), msg);
+        self.send_to_room(&room, 0, ChatResponse::Message(msg))
+            .await;
+
+        Ok(())
     }
 
-    /// Register new session and assign unique ID to this session
-    async fn connect(&mut self, tx: mpsc::UnboundedSender<Msg>) -> ConnId {
-        log::info!("Someone joined");
+    /// Send a private message to the user with the given name.
+    fn send_direct(&mut self, conn: ConnId, to: &str, msg: String) -> Result<(), RoomError> {
+        let from = self.sessions.get(&conn).ok_or(RoomError::UnknownUser)?;
+        let to = self.conn_of(to).ok_or(RoomError::UnknownUser)?;
+        let msg = self
+            .filter
+            .apply(&from.name, None, msg)
+            .map_err(RoomError::Rejected)?;
+
+        // private messages are not numbered
+        let msg = ChatMessage::new(0, &from.name, None, msg);
+        self.send_to(to, ChatResponse::Message(msg));
+        Ok(())
+    }
+
+    /// Carry on with the command that `cmd` resolves to once the fanout backend has answered,
+    /// handling other commands in the meantime.
+    fn then(&self, cmd: impl Future<Output = Command> + Send + 'static) {
+        let mut cmd = cmd.boxed();
+
+        // backends that answer straight away keep the order of commands
+        if let Some(cmd) = (&mut cmd).now_or_never() {
+            let _ = self.then_tx.send(cmd);
+            return;
+        }
+
+        let then_tx = self.then_tx.clone();
+        tokio::spawn(async move {
+            let _ = then_tx.send(cmd.await);
+        });
+    }
+
+    /// Pick a unique ID and name for a new session, and reserve the name on other servers.
+    ///
+    /// The session is registered once the name is reserved.
+    fn connect(&self, res_tx: oneshot::Sender<(ConnId, QueueReceiver<Msg>)>) {
+        // random connection ID, named after it until the user picks a name
+        let (id, name) = loop {
+            let id = rand::rng().random::<ConnId>();
+            let name = format!("anon-{:04x}", id >> 48);
+
+            if !self.sessions.contains_key(&id) && self.conn_of(&name).is_none() {
+                break (id, name);
+            }
+        };
+
+        let claim = self.fanout.claim_name(&name);
+        self.then(async move {
+            Command::Connected {
+                conn: id,
+                claimed: claim.await,
+                name,
+                res_tx,
+            }
+        });
+    }
+
+    /// Register new session under a name reserved for it, or pick another one if it was taken.
+    ///
+    /// Sends the ID and the queue of messages for the session.
+    async fn connected(
+        &mut self,
+        id: ConnId,
+        name: String,
+        claimed: io::Result<bool>,
+        res_tx: oneshot::Sender<(ConnId, QueueReceiver<Msg>)>,
+    ) {
+        match claimed {
+            Ok(true) => {}
+            Ok(false) => return self.connect(res_tx),
+            // the user can still chat, and pick a name of their own later
+            Err(err) => log::error!("failed to reserve name {name}: {err}"),
+        }
+
+        // taken by a session that connected in the meantime
+        if self.sessions.contains_key(&id) || self.conn_of(&name).is_some() {
+            return self.connect(res_tx);
+        }
+        log::info!("{name} joined");
 
         // notify all users in same room
-        self.send_system_message("main", 0, "Someone joined").await;
+        self.send_system_message(MAIN_ROOM, 0, format!("{name} joined"))
+            .await;
 
-        // register session with random connection ID
-        let id = rand::rng().random::<ConnId>();
-        self.sessions.insert(id, tx);
+        let (tx, rx) = queue::queue(self.queue_config, self.queue_metrics.clone());
+        self.sessions.insert(
+            id,
+            Session {
+                tx,
+                name: name.clone(),
+                away: false,
+            },
+        );
+        self.send_to(id, ChatResponse::Name(name.clone()));
 
         // auto join session to main room
-        self.rooms.entry("main".to_owned()).or_default().insert(id);
+        self.rooms
+            .entry(MAIN_ROOM.to_owned())
+            .or_default()
+            .insert(id);
+        self.send_presence(MAIN_ROOM, id, &name, Presence::Online)
+            .await;
 
         let count = self.visitor_count.fetch_add(1, Ordering::SeqCst);
-        self.send_system_message("main", 0, format!("Total visitors {count}"))
+        self.send_system_message(MAIN_ROOM, 0, format!("Total visitors {count}"))
             .await;
 
         // send id back
-        id
+        let _ = res_tx.send((id, rx));
     }
 
     /// Unregister connection from room map and broadcast disconnection message.
     async fn disconnect(&mut self, conn_id: ConnId) {
-        println!("Someone disconnected");
+        // remove sender
+        let Some(Session { name, .. }) = self.sessions.remove(&conn_id) else {
+            return;
+        };
+        println!("{name} disconnected");
+
+        // the name is free to be taken by someone else now
+        self.release_name(&name);
+        self.forget_user(&name);
+
+        self.leave_rooms(conn_id, &name).await;
+    }
 
+    /// Remove connection from all rooms, telling the other users in them.
+    async fn leave_rooms(&mut self, conn_id: ConnId, name: &str) {
         let mut rooms: Vec<RoomId> = Vec::new();
 
-        // remove sender
-        if self.sessions.remove(&conn_id).is_some() {
-            // remove session from all rooms
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&conn_id) {
-                    rooms.push(name.to_owned());
-                }
+        for (room, sessions) in &mut self.rooms {
+            if sessions.remove(&conn_id) {
+                rooms.push(room.to_owned());
             }
         }
 
         // send message to other users
         for room in rooms {
-            self.send_system_message(&room, 0, "Someone disconnected")
+            self.send_system_message(&room, 0, format!("{name} left"))
                 .await;
+            self.send_presence(&room, 0, name, Presence::Offline).await;
         }
     }
 
-    /// Returns list of created room names.
-    fn list_rooms(&mut self) -> Vec<RoomId> {
-        self.rooms.keys().cloned().collect()
-    }
+    /// Reserve a name that a connection's user asked for on other servers, before changing it.
+    fn rename(&self, conn: ConnId, name: String, res_tx: oneshot::Sender<Result<(), RoomError>>) {
+        if self.conn_of(&name).is_some() {
+            let _ = res_tx.send(Err(RoomError::NameTaken));
+            return;
+        }
 
-    /// Join room, send disconnect message to old room send join message to new room.
-    async fn join_room(&mut self, conn_id: ConnId, room: RoomId) {
-        let mut rooms = Vec::new();
+        let claim = self.fanout.claim_name(&name);
+        self.then(async move {
+            Command::Renamed {
+                conn,
+                claimed: claim.await,
+                name,
+                res_tx,
+            }
+        });
+    }
 
-        // remove session from all rooms
-        for (n, sessions) in &mut self.rooms {
-            if sessions.remove(&conn_id) {
-                rooms.push(n.to_owned());
+    /// Change the name of a connection's user, once the name is reserved on other servers.
+    async fn renamed(
+        &mut self,
+        conn: ConnId,
+        name: String,
+        claimed: io::Result<bool>,
+    ) -> Result<(), RoomError> {
+        match claimed {
+            Ok(true) => {}
+            Ok(false) => return Err(RoomError::NameTaken),
+            Err(err) => {
+                log::error!("failed to reserve name {name}: {err}");
+                return Err(RoomError::NameNotReserved);
             }
         }
-        // send message to other users
-        for room in rooms {
-            self.send_system_message(&room, 0, "Someone disconnected")
+
+        // taken by a session of this server in the meantime, which keeps it reserved
+        if self.conn_of(&name).is_some() {
+            return Err(RoomError::NameTaken);
+        }
+
+        let Some(session) = self.sessions.get_mut(&conn) else {
+            self.release_name(&name);
+            return Ok(());
+        };
+        let old_name = std::mem::replace(&mut session.name, name.clone());
+        let presence = session.presence();
+
+        self.release_name(&old_name);
+        self.forget_user(&old_name);
+
+        if let Some(room) = self.room_of(conn) {
+            self.send_system_message(&room, conn, format!("{old_name} is now known as {name}"))
+                .await;
+            self.send_presence(&room, conn, &old_name, Presence::Offline)
                 .await;
+            self.send_presence(&room, conn, &name, presence).await;
+        }
+
+        Ok(())
+    }
+
+    /// Let users of other servers take a name that no one here has any more.
+    fn release_name(&self, name: &str) {
+        if self.conn_of(name).is_none() {
+            self.fanout.release_name(name);
+        }
+    }
+
+    /// Forget the rooms owned by and invitations of a user of this server, on this server and
+    /// others.
+    ///
+    /// Names are reserved across servers, so no user of another server has the same name.
+    fn forget_user(&mut self, name: &str) {
+        for room in self.permissions.forget_user(name) {
+            self.fanout.publish_update(&self.permissions.update(&room));
+        }
+    }
+
+    /// Move the connection of user `name` in `room` back to the main room, e.g. once they are
+    /// kicked.
+    async fn remove_from_room(&mut self, room: &str, name: &str) {
+        let Some(target) = self.conn_of(name) else {
+            return;
+        };
+
+        let in_room = self
+            .rooms
+            .get_mut(room)
+            .is_some_and(|participants| participants.remove(&target));
+
+        if in_room {
+            let removal = format!("you were removed from {room}");
+            self.send_to(target, ChatResponse::notice(Some(room), removal));
+            self.send_to(target, ChatResponse::Joined(MAIN_ROOM.to_owned()));
+            self.rooms
+                .entry(MAIN_ROOM.to_owned())
+                .or_default()
+                .insert(target);
+
+            if let Some(session) = self.sessions.get(&target) {
+                self.send_presence(room, target, &session.name, Presence::Offline)
+                    .await;
+                self.send_presence(MAIN_ROOM, target, &session.name, session.presence())
+                    .await;
+            }
+        }
+    }
+
+    /// Apply a moderation action to the connection's current room.
+    ///
+    /// Users who are kicked or banned are moved back to the main room, on other servers too.
+    async fn moderate(&mut self, conn: ConnId, action: Moderation) -> Result<(), RoomError> {
+        let (Some(name), Some(room)) = (
+            self.sessions.get(&conn).map(|s| s.name.clone()),
+            self.room_of(conn),
+        ) else {
+            return Ok(());
+        };
+
+        self.permissions.moderate(&room, &name, &action)?;
+        let update = self.permissions.update(&room).removing(action.removes());
+        self.fanout.publish_update(&update);
+
+        if let Moderation::Invite(invited) = &action {
+            if let Some(invited) = self.conn_of(invited) {
+                let invite = format!("{name} invited you to {room}");
+                self.send_to(invited, ChatResponse::notice(Some(&room), invite));
+            }
+        }
+
+        if let Some(target) = action.removes() {
+            self.remove_from_room(&room, target).await;
+        }
+
+        self.send_system_message(&room, 0, action.announcement())
+            .await;
+
+        Ok(())
+    }
+
+    /// Returns list of created room names, on this server and others.
+    fn list_rooms(&mut self, res_tx: oneshot::Sender<Vec<RoomId>>) {
+        let rooms = self.fanout.rooms(self.rooms.keys().cloned().collect());
+
+        // other servers are asked without holding up commands
+        tokio::spawn(async move {
+            let _ = res_tx.send(rooms.await);
+        });
+    }
+
+    /// Join room, send disconnect message to old room send join message to new room.
+    async fn join_room(
+        &mut self,
+        conn_id: ConnId,
+        room: RoomId,
+        password: Option<&str>,
+    ) -> Result<(), RoomError> {
+        let Some(name) = self.sessions.get(&conn_id).map(|s| s.name.clone()) else {
+            return Ok(());
+        };
+
+        if self.permissions.join(&room, &name, password)? {
+            self.fanout.publish_update(&self.permissions.update(&room));
         }
 
+        self.leave_rooms(conn_id, &name).await;
+
+        if !self.rooms.contains_key(&room) {
+            self.fanout.add_room(&room);
+        }
         self.rooms.entry(room.clone()).or_default().insert(conn_id);
 
-        self.send_system_message(&room, conn_id, "Someone connected")
+        self.send_system_message(&room, conn_id, format!("{name} joined"))
             .await;
+
+        if let Some(session) = self.sessions.get(&conn_id) {
+            self.send_presence(&room, conn_id, &name, session.presence())
+                .await;
+        }
+
+        Ok(())
+    }
+
+    /// Tell the other users in a connection's room that its user started or stopped typing.
+    async fn typing(&self, conn: ConnId, typing: bool) {
+        let Some((name, room)) = self.name_and_room(conn) else {
+            return;
+        };
+
+        // muted users cannot send what they type
+        if self.permissions.check_send(&room, &name).is_err() {
+            return;
+        }
+
+        let msg = ChatResponse::Typing {
+            room: room.clone(),
+            user: name,
+            typing,
+        };
+        self.send_to_room(&room, conn, msg).await;
+    }
+
+    /// Tell the other users in a connection's room that its user has read the room's messages up
+    /// to `up_to`.
+    async fn read(&self, conn: ConnId, up_to: u64) {
+        let Some((name, room)) = self.name_and_room(conn) else {
+            return;
+        };
+
+        let msg = ChatResponse::Read {
+            room: room.clone(),
+            user: name,
+            up_to,
+        };
+        self.send_to_room(&room, conn, msg).await;
+    }
+
+    /// Set whether a connection's user is away, telling the other users in its room.
+    async fn set_away(&mut self, conn: ConnId, away: bool) {
+        let Some(session) = self.sessions.get_mut(&conn) else {
+            return;
+        };
+
+        if session.away == away {
+            return;
+        }
+        session.away = away;
+        let presence = session.presence();
+
+        if let Some((name, room)) = self.name_and_room(conn) {
+            self.send_presence(&room, conn, &name, presence).await;
+        }
+    }
+
+    /// Returns the connection's room and the users in it on this server, sorted by name.
+    fn members(&self, conn: ConnId) -> Option<(RoomId, Vec<Member>)> {
+        let room = self.room_of(conn)?;
+
+        let mut members = self.rooms[&room]
+            .iter()
+            .filter_map(|id| self.sessions.get(id))
+            .map(|session| Member {
+                name: session.name.clone(),
+                presence: session.presence(),
+            })
+            .collect::<Vec<_>>();
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Some((room, members))
     }
 
     pub async fn run(mut self) -> io::Result<()> {
-        while let Some(cmd) = self.cmd_rx.recv().await {
-            match cmd {
-                Command::Connect { conn_tx, res_tx } => {
-                    let conn_id = self.connect(conn_tx).await;
-                    let _ = res_tx.send(conn_id);
-                }
+        let mut events = self.fanout.events();
 
-                Command::Disconnect { conn } => {
-                    self.disconnect(conn).await;
-                }
+        loop {
+            tokio::select! {
+                cmd = self.cmd_rx.recv() => match cmd {
+                    Some(cmd) => self.handle_command(cmd).await,
+                    None => break,
+                },
 
-                Command::List { res_tx } => {
-                    let _ = res_tx.send(self.list_rooms());
-                }
+                Some(event) = events.next() => self.handle_event(event).await,
 
-                Command::Join { conn, room, res_tx } => {
-                    self.join_room(conn, room).await;
-                    let _ = res_tx.send(());
-                }
+                // never ends, as the server holds on to a sender
+                Some(cmd) = self.then_rx.recv() => self.handle_command(cmd).await,
+            }
+        }
 
-                Command::Message { conn, msg, res_tx } => {
-                    self.send_message(conn, msg).await;
-                    let _ = res_tx.send(());
+        Ok(())
+    }
+
+    /// Act on what another server published.
+    async fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Room { room, response } => self.deliver(&room, 0, response),
+
+            Event::Permissions(update) => {
+                self.permissions.apply(&update);
+                if let Some(name) = &update.removed {
+                    self.remove_from_room(&update.room, name).await;
                 }
             }
         }
+    }
 
-        Ok(())
+    async fn handle_command(&mut self, cmd: Command) {
+        match cmd {
+            Command::Connect { res_tx } => self.connect(res_tx),
+
+            Command::Disconnect { conn } => {
+                self.disconnect(conn).await;
+            }
+
+            Command::List { res_tx } => self.list_rooms(res_tx),
+
+            Command::Join {
+                conn,
+                room,
+                password,
+                res_tx,
+            } => {
+                let res = self.join_room(conn, room, password.as_deref()).await;
+                let _ = res_tx.send(res);
+            }
+
+            Command::Name { conn, name, res_tx } => self.rename(conn, name, res_tx),
+
+            Command::Message { conn, msg, res_tx } => self.send_message(conn, msg, res_tx),
+
+            Command::Direct {
+                conn,
+                to,
+                msg,
+                res_tx,
+            } => {
+                let _ = res_tx.send(self.send_direct(conn, &to, msg));
+            }
+
+            Command::Moderate {
+                conn,
+                action,
+                res_tx,
+            } => {
+                let _ = res_tx.send(self.moderate(conn, action).await);
+            }
+
+            Command::Typing { conn, typing } => self.typing(conn, typing).await,
+
+            Command::Read { conn, up_to } => self.read(conn, up_to).await,
+
+            Command::Away { conn, away } => self.set_away(conn, away).await,
+
+            Command::Members { conn, res_tx } => {
+                let _ = res_tx.send(self.members(conn));
+            }
+
+            Command::Connected {
+                conn,
+                name,
+                claimed,
+                res_tx,
+            } => self.connected(conn, name, claimed, res_tx).await,
+
+            Command::Numbered {
+                id,
+                name,
+                room,
+                msg,
+                res_tx,
+            } => {
+                let _ = res_tx.send(self.send_numbered(id, name, room, msg).await);
+            }
+
+            Command::Renamed {
+                conn,
+                name,
+                claimed,
+                res_tx,
+            } => {
+                let _ = res_tx.send(self.renamed(conn, name, claimed).await);
+            }
+        }
     }
 }
 
@@ -225,18 +896,16 @@ impl ChatServer {
 /// Reduces boilerplate of setting up response channels in WebSocket handlers.
 #[derive(Debug, Clone)]
 pub struct ChatServerHandle {
-    cmd_tx: mpsc::UnboundedSender<Command>,
+    cmd_tx: mpsc::Sender<Command>,
 }
 
 impl ChatServerHandle {
-    /// Register client message sender and obtain connection ID.
-    pub async fn connect(&self, conn_tx: mpsc::UnboundedSender<Msg>) -> ConnId {
+    /// Register client and obtain connection ID and the queue of messages for the client.
+    pub async fn connect(&self) -> (ConnId, QueueReceiver<Msg>) {
         let (res_tx, res_rx) = oneshot::channel();
 
         // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::Connect { conn_tx, res_tx })
-            .unwrap();
+        self.cmd_tx.send(Command::Connect { res_tx }).await.unwrap();
 
         // unwrap: chat server does not drop out response channel
         res_rx.await.unwrap()
@@ -247,14 +916,19 @@ impl ChatServerHandle {
         let (res_tx, res_rx) = oneshot::channel();
 
         // unwrap: chat server should not have been dropped
-        self.cmd_tx.send(Command::List { res_tx }).unwrap();
+        self.cmd_tx.send(Command::List { res_tx }).await.unwrap();
 
         // unwrap: chat server does not drop our response channel
         res_rx.await.unwrap()
     }
 
     /// Join `room`, creating it if it does not exist.
-    pub async fn join_room(&self, conn: ConnId, room: impl Into<RoomId>) {
+    pub async fn join_room(
+        &self,
+        conn: ConnId,
+        room: impl Into<RoomId>,
+        password: Option<String>,
+    ) -> Result<(), RoomError> {
         let (res_tx, res_rx) = oneshot::channel();
 
         // unwrap: chat server should not have been dropped
@@ -262,16 +936,40 @@ impl ChatServerHandle {
             .send(Command::Join {
                 conn,
                 room: room.into(),
+                password,
+                res_tx,
+            })
+            .await
+            .unwrap();
+
+        // unwrap: chat server does not drop our response channel
+        res_rx.await.unwrap()
+    }
+
+    /// Change the name of the connection's user, if no one else is using it.
+    pub async fn set_name(&self, conn: ConnId, name: impl Into<String>) -> Result<(), RoomError> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::Name {
+                conn,
+                name: name.into(),
                 res_tx,
             })
+            .await
             .unwrap();
 
         // unwrap: chat server does not drop our response channel
-        res_rx.await.unwrap();
+        res_rx.await.unwrap()
     }
 
     /// Broadcast message to current room.
-    pub async fn send_message(&self, conn: ConnId, msg: impl Into<Msg>) {
+    pub async fn send_message(
+        &self,
+        conn: ConnId,
+        msg: impl Into<String>,
+    ) -> Result<(), RoomError> {
         let (res_tx, res_rx) = oneshot::channel();
 
         // unwrap: chat server should not have been dropped
@@ -281,15 +979,438 @@ impl ChatServerHandle {
                 conn,
                 res_tx,
             })
+            .await
+            .unwrap();
+
+        // unwrap: chat server does not drop our response channel
+        res_rx.await.unwrap()
+    }
+
+    /// Send a private message to the user with name `to`.
+    pub async fn send_direct(
+        &self,
+        conn: ConnId,
+        to: impl Into<String>,
+        msg: impl Into<String>,
+    ) -> Result<(), RoomError> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::Direct {
+                conn,
+                to: to.into(),
+                msg: msg.into(),
+                res_tx,
+            })
+            .await
+            .unwrap();
+
+        // unwrap: chat server does not drop our response channel
+        res_rx.await.unwrap()
+    }
+
+    /// Apply a moderation action to the current room, which only its owner may do.
+    pub async fn moderate(&self, conn: ConnId, action: Moderation) -> Result<(), RoomError> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::Moderate {
+                conn,
+                action,
+                res_tx,
+            })
+            .await
             .unwrap();
 
         // unwrap: chat server does not drop our response channel
-        res_rx.await.unwrap();
+        res_rx.await.unwrap()
+    }
+
+    /// Tell the current room that the user started or stopped typing.
+    pub async fn typing(&self, conn: ConnId, typing: bool) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::Typing { conn, typing })
+            .await
+            .unwrap();
+    }
+
+    /// Tell the current room that the user has read its messages up to `up_to`.
+    pub async fn read(&self, conn: ConnId, up_to: u64) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::Read { conn, up_to })
+            .await
+            .unwrap();
+    }
+
+    /// Set whether the user is away.
+    pub async fn set_away(&self, conn: ConnId, away: bool) {
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::Away { conn, away })
+            .await
+            .unwrap();
+    }
+
+    /// List the users in the current room, returning the room too.
+    ///
+    /// Only users connected to this server are listed.
+    pub async fn members(&self, conn: ConnId) -> Option<(RoomId, Vec<Member>)> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        // unwrap: chat server should not have been dropped
+        self.cmd_tx
+            .send(Command::Members { conn, res_tx })
+            .await
+            .unwrap();
+
+        // unwrap: chat server does not drop our response channel
+        res_rx.await.unwrap()
     }
 
     /// Unregister message sender and broadcast disconnection message to current room.
-    pub fn disconnect(&self, conn: ConnId) {
+    pub async fn disconnect(&self, conn: ConnId) {
         // unwrap: chat server should not have been dropped
-        self.cmd_tx.send(Command::Disconnect { conn }).unwrap();
+        self.cmd_tx
+            .send(Command::Disconnect { conn })
+            .await
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{
+        future::{self, BoxFuture},
+        stream::BoxStream,
+    };
+
+    use super::*;
+    use crate::{
+        fanout::{Hub, Local},
+        permissions::RoomUpdate,
+    };
+
+    /// Backend that never numbers messages, like Redis while it cannot be reached.
+    struct Unreachable;
+
+    impl Fanout for Unreachable {
+        fn publish(&self, _room: &str, _response: &ChatResponse) {}
+
+        fn publish_update(&self, _update: &RoomUpdate) {}
+
+        fn message_id(&self) -> BoxFuture<'static, io::Result<Option<u64>>> {
+            future::pending().boxed()
+        }
+
+        fn claim_name(&self, name: &str) -> BoxFuture<'static, io::Result<bool>> {
+            Local.claim_name(name)
+        }
+
+        fn release_name(&self, _name: &str) {}
+
+        fn add_room(&self, _room: &str) {}
+
+        fn remove_room(&self, _room: &str) {}
+
+        fn rooms(&self, local: Vec<String>) -> BoxFuture<'static, Vec<String>> {
+            Local.rooms(local)
+        }
+
+        fn events(&self) -> BoxStream<'static, Event> {
+            Local.events()
+        }
+    }
+
+    /// Waits for the next chat message sent to a connection, skipping notices.
+    async fn next_message(rx: &mut QueueReceiver<Msg>) -> ChatMessage {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+
+            if let ChatResponse::Message(msg) = msg {
+                return msg;
+            }
+        }
+    }
+
+    /// Waits for a notice with the given text to be sent to a connection.
+    async fn wait_for_notice(rx: &mut QueueReceiver<Msg>, text: &str) {
+        loop {
+            let res = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+
+            if matches!(res, ChatResponse::Notice(notice) if notice.text == text) {
+                return;
+            }
+        }
+    }
+
+    /// Waits for the next response sent to a connection that is not a message or notice.
+    async fn next_event(rx: &mut QueueReceiver<Msg>) -> ChatResponse {
+        loop {
+            let res = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+
+            if !matches!(
+                res,
+                ChatResponse::Message(_) | ChatResponse::Notice(_) | ChatResponse::Name(_)
+            ) {
+                return res;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn typing_read_receipts_and_presence() {
+        let metrics = Arc::new(QueueMetrics::default());
+        let (server, chat) = ChatServer::new(Box::new(Local), QueueConfig::default(), metrics);
+        tokio::spawn(server.run());
+
+        let (alice, mut alice_rx) = chat.connect().await;
+        chat.set_name(alice, "alice").await.unwrap();
+        chat.join_room(alice, "rust", None).await.unwrap();
+
+        let (bob, mut bob_rx) = chat.connect().await;
+        chat.set_name(bob, "bob").await.unwrap();
+        chat.join_room(bob, "rust", None).await.unwrap();
+
+        let presence = |user: &str, presence| ChatResponse::Presence {
+            room: "rust".to_owned(),
+            user: user.to_owned(),
+            presence,
+        };
+        assert_eq!(
+            next_event(&mut alice_rx).await,
+            presence("bob", Presence::Online)
+        );
+
+        chat.typing(bob, true).await;
+        assert_eq!(
+            next_event(&mut alice_rx).await,
+            ChatResponse::Typing {
+                room: "rust".to_owned(),
+                user: "bob".to_owned(),
+                typing: true,
+            }
+        );
+
+        chat.read(alice, 3).await;
+        assert_eq!(
+            next_event(&mut bob_rx).await,
+            ChatResponse::Read {
+                room: "rust".to_owned(),
+                user: "alice".to_owned(),
+                up_to: 3,
+            }
+        );
+
+        chat.set_away(bob, true).await;
+        assert_eq!(
+            next_event(&mut alice_rx).await,
+            presence("bob", Presence::Away)
+        );
+
+        let member = |name: &str, presence| Member {
+            name: name.to_owned(),
+            presence,
+        };
+        assert_eq!(
+            chat.members(alice).await,
+            Some((
+                "rust".to_owned(),
+                vec![
+                    member("alice", Presence::Online),
+                    member("bob", Presence::Away)
+                ],
+            ))
+        );
+
+        chat.disconnect(bob).await;
+        assert_eq!(
+            next_event(&mut alice_rx).await,
+            presence("bob", Presence::Offline)
+        );
+    }
+
+    #[tokio::test]
+    async fn servers_share_rooms_through_fanout() {
+        let hub = Hub::default();
+        let metrics = Arc::new(QueueMetrics::default());
+
+        let (server_a, chat_a) = ChatServer::new(
+            Box::new(hub.node("a")),
+            QueueConfig::default(),
+            metrics.clone(),
+        );
+        let (server_b, chat_b) =
+            ChatServer::new(Box::new(hub.node("b")), QueueConfig::default(), metrics);
+        tokio::spawn(server_a.run());
+        tokio::spawn(server_b.run());
+
+        let (alice, mut alice_rx) = chat_a.connect().await;
+        let (bob, mut bob_rx) = chat_b.connect().await;
+
+        chat_a.join_room(alice, "rust", None).await.unwrap();
+        chat_b.join_room(bob, "rust", None).await.unwrap();
+
+        chat_a.send_message(alice, "hello from a").await.unwrap();
+        let msg = next_message(&mut bob_rx).await;
+        assert_eq!(msg.room.as_deref(), Some("rust"));
+        assert_eq!(msg.text, "hello from a");
+        assert_eq!(next_message(&mut alice_rx).await, msg);
+
+        chat_b.send_message(bob, "hello from b").await.unwrap();
+        assert_eq!(next_message(&mut alice_rx).await.text, "hello from b");
+
+        // rooms of either server are listed by both
+        chat_a.join_room(alice, "only-a", None).await.unwrap();
+        assert_eq!(chat_b.list_rooms().await, ["main", "only-a", "rust"]);
+    }
+
+    #[tokio::test]
+    async fn servers_share_permissions_through_fanout() {
+        let hub = Hub::default();
+        let metrics = Arc::new(QueueMetrics::default());
+
+        let (server_a, chat_a) = ChatServer::new(
+            Box::new(hub.node("a")),
+            QueueConfig::default(),
+            metrics.clone(),
+        );
+        let (server_b, chat_b) =
+            ChatServer::new(Box::new(hub.node("b")), QueueConfig::default(), metrics);
+        tokio::spawn(server_a.run());
+        tokio::spawn(server_b.run());
+
+        let (alice, mut alice_rx) = chat_a.connect().await;
+        chat_a.set_name(alice, "alice").await.unwrap();
+        let (bob, mut bob_rx) = chat_b.connect().await;
+        chat_b.set_name(bob, "bob").await.unwrap();
+
+        // server b has applied alice's ownership of the room once she has left the main room
+        chat_a.join_room(alice, "rust", None).await.unwrap();
+        wait_for_notice(&mut bob_rx, "alice left").await;
+        chat_b.join_room(bob, "rust", None).await.unwrap();
+        wait_for_notice(&mut alice_rx, "bob joined").await;
+
+        // messages are numbered by the backend
+        chat_a.send_message(alice, "first").await.unwrap();
+        chat_b.send_message(bob, "second").await.unwrap();
+        assert_eq!(next_message(&mut alice_rx).await.id, 1);
+        assert_eq!(next_message(&mut alice_rx).await.id, 2);
+
+        assert_eq!(
+            chat_b
+                .moderate(bob, Moderation::Mute("alice".to_owned()))
+                .await,
+            Err(RoomError::NotOwner)
+        );
+        chat_a
+            .moderate(alice, Moderation::Ban("bob".to_owned()))
+            .await
+            .unwrap();
+        wait_for_notice(&mut bob_rx, "you were removed from rust").await;
+        assert_eq!(
+            next_event(&mut bob_rx).await,
+            ChatResponse::Joined(MAIN_ROOM.to_owned())
+        );
+        assert_eq!(
+            chat_b.join_room(bob, "rust", None).await,
+            Err(RoomError::Banned)
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_waiting_for_an_id_do_not_hold_up_commands() {
+        let metrics = Arc::new(QueueMetrics::default());
+        let (server, chat) =
+            ChatServer::new(Box::new(Unreachable), QueueConfig::default(), metrics);
+        tokio::spawn(server.run());
+
+        let (alice, _alice_rx) = chat.connect().await;
+        let sending = tokio::spawn({
+            let chat = chat.clone();
+            async move { chat.send_message(alice, "hello").await }
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            chat.set_name(alice, "alice").await.unwrap();
+            chat.join_room(alice, "rust", None).await.unwrap();
+            chat.connect().await;
+        })
+        .await
+        .unwrap();
+        assert!(!sending.is_finished());
+    }
+
+    #[tokio::test]
+    async fn names_are_reserved_across_servers() {
+        let hub = Hub::default();
+        let metrics = Arc::new(QueueMetrics::default());
+
+        let (server_a, chat_a) = ChatServer::new(
+            Box::new(hub.node("a")),
+            QueueConfig::default(),
+            metrics.clone(),
+        );
+        let (server_b, chat_b) =
+            ChatServer::new(Box::new(hub.node("b")), QueueConfig::default(), metrics);
+        tokio::spawn(server_a.run());
+        tokio::spawn(server_b.run());
+
+        let (alice, _alice_rx) = chat_a.connect().await;
+        chat_a.set_name(alice, "alice").await.unwrap();
+        let (mallory, _mallory_rx) = chat_b.connect().await;
+
+        assert_eq!(
+            chat_b.set_name(mallory, "alice").await,
+            Err(RoomError::NameTaken)
+        );
+
+        // commands are handled in order, so alice is gone once the rooms are listed
+        chat_a.disconnect(alice).await;
+        chat_a.list_rooms().await;
+        chat_b.set_name(mallory, "alice").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn slow_client_is_disconnected() {
+        let config = QueueConfig {
+            capacity: 4,
+            overflow: queue::Overflow::Disconnect,
+        };
+        let metrics = Arc::new(QueueMetrics::default());
+        let (server, chat) = ChatServer::new(Box::new(Local), config, metrics.clone());
+        tokio::spawn(server.run());
+
+        // alice never reads her messages
+        let (_alice, mut alice_rx) = chat.connect().await;
+        let (bob, mut bob_rx) = chat.connect().await;
+
+        for i in 0..5 {
+            chat.send_message(bob, format!("message {i}"))
+                .await
+                .unwrap();
+            next_message(&mut bob_rx).await;
+        }
+
+        assert_eq!(alice_rx.recv().await, None);
+        assert!(
+            metrics
+                .to_string()
+                .contains("chat_session_queue_disconnects_total 1\n")
+        );
     }
 }
@@ -1,3 +1,4 @@
+use actix::{Actor as _, SystemRegistry};
 use actix_files::{Files, NamedFile};
 use actix_web::{App, Error, HttpRequest, HttpServer, Responder, middleware::Logger, web};
 use actix_web_actors::ws;
@@ -6,6 +7,9 @@ mod message;
 mod server;
 mod session;
 
+use websocket_chat_common::{fanout, permissions, protocol};
+
+use server::WsChatServer;
 use session::WsChatSession;
 
 async fn index() -> impl Responder {
@@ -21,6 +25,10 @@ async fn chat_ws(req: HttpRequest, stream: web::Payload) -> Result<impl Responde
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    // shares rooms with other nodes if `REDIS_URL` is set
+    let fanout = fanout::from_env().await?;
+    SystemRegistry::set(WsChatServer::new(fanout).start());
+
     log::info!("starting HTTP server at http://localhost:8080");
 
     HttpServer::new(move || {
@@ -1,16 +1,31 @@
 use actix::prelude::*;
 
+use crate::{
+    permissions::{Moderation, RoomError},
+    protocol::ChatResponse,
+};
+
+/// Sent to sessions by the server.
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
-pub struct ChatMessage(pub String);
+pub struct ChatMessage(pub ChatResponse);
 
+/// Registers a session, which is given an ID and a name and put in the main room.
 #[derive(Clone, Message)]
-#[rtype(result = "u64")]
-pub struct JoinRoom(pub String, pub Option<String>, pub Recipient<ChatMessage>);
+#[rtype(result = "(u64, String)")]
+pub struct Connect(pub Recipient<ChatMessage>);
 
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
-pub struct LeaveRoom(pub String, pub u64);
+pub struct Disconnect(pub u64);
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct JoinRoom(pub u64, pub String, pub Option<String>);
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct SetName(pub u64, pub String);
 
 #[derive(Clone, Message)]
 #[rtype(result = "Vec<String>")]
@@ -18,4 +33,12 @@ pub struct ListRooms;
 
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
-pub struct SendMessage(pub String, pub u64, pub String);
+pub struct SendMessage(pub u64, pub String);
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct DirectMessage(pub u64, pub String, pub String);
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct Moderate(pub u64, pub Moderation);
@@ -1,60 +1,213 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use actix::prelude::*;
 use actix_broker::BrokerSubscribe;
 
-use crate::message::{ChatMessage, JoinRoom, LeaveRoom, ListRooms, SendMessage};
+use crate::{
+    fanout::{Event, Fanout, Local},
+    message::{
+        ChatMessage, Connect, DirectMessage, Disconnect, JoinRoom, ListRooms, Moderate,
+        SendMessage, SetName,
+    },
+    permissions::{MAIN_ROOM, Moderation, Permissions, RoomError},
+    protocol::{self, ChatResponse},
+};
 
 type Client = Recipient<ChatMessage>;
-type Room = HashMap<u64, Client>;
 
-#[derive(Default)]
+struct Session {
+    client: Client,
+    /// Unique name of the session's user
+    name: String,
+}
+
 pub struct WsChatServer {
-    rooms: HashMap<String, Room>,
+    sessions: HashMap<u64, Session>,
+    rooms: HashMap<String, HashSet<u64>>,
+    permissions: Permissions,
+    /// ID of the last message sent to a room
+    last_message_id: u64,
+    /// Carries room traffic to and from other nodes
+    fanout: Box<dyn Fanout>,
+}
+
+impl Default for WsChatServer {
+    fn default() -> Self {
+        Self::new(Box::new(Local))
+    }
 }
 
 impl WsChatServer {
-    fn take_room(&mut self, room_name: &str) -> Option<Room> {
-        let room = self.rooms.get_mut(room_name)?;
-        let room = std::mem::take(room);
-        Some(room)
+    /// Creates a server that shares rooms with other nodes through `fanout`.
+    ///
+    /// Register it with [`SystemRegistry::set`] before sessions look it up.
+    pub fn new(fanout: Box<dyn Fanout>) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            rooms: HashMap::new(),
+            permissions: Permissions::default(),
+            last_message_id: 0,
+            fanout,
+        }
+    }
+
+    fn send_to(&self, id: u64, msg: ChatResponse) {
+        if let Some(session) = self.sessions.get(&id) {
+            let _ = session.client.try_send(ChatMessage(msg));
+        }
     }
 
-    fn add_client_to_room(&mut self, room_name: &str, id: Option<u64>, client: Client) -> u64 {
-        let mut id = id.unwrap_or_else(rand::random);
+    fn send_notice(&mut self, room_name: &str, text: String, src: u64) -> Option<()> {
+        let notice = ChatResponse::notice(Some(room_name), text);
+        self.send_chat_message(room_name, &notice, src)
+    }
 
-        if let Some(room) = self.rooms.get_mut(room_name) {
-            loop {
-                if room.contains_key(&id) {
-                    id = rand::random();
-                } else {
-                    break;
-                }
-            }
+    fn send_chat_message(&mut self, room_name: &str, msg: &ChatResponse, src: u64) -> Option<()> {
+        self.fanout.publish(room_name, msg);
+        self.deliver(room_name, msg, src)
+    }
+
+    /// Sends to the sessions in the room that are connected to this node.
+    fn deliver(&mut self, room_name: &str, msg: &ChatResponse, _src: u64) -> Option<()> {
+        let room = self.rooms.get(room_name)?;
 
-            room.insert(id, client);
-            return id;
+        let dead = room
+            .iter()
+            .copied()
+            .filter(|id| {
+                self.sessions.get(id).is_none_or(|session| {
+                    matches!(
+                        session.client.try_send(ChatMessage(msg.clone())),
+                        Err(SendError::Closed(_))
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // clear out sessions that have gone away
+        for id in dead {
+            self.remove_session(id);
         }
 
-        // Create a new room for the first client
-        let mut room: Room = HashMap::new();
+        Some(())
+    }
 
-        room.insert(id, client);
-        self.rooms.insert(room_name.to_owned(), room);
+    fn remove_session(&mut self, id: u64) {
+        if let Some(session) = self.sessions.remove(&id) {
+            // the name is free to be taken by someone else now
+            self.release_name(&session.name);
+            self.forget_user(&session.name);
+        }
 
-        id
+        for room in self.rooms.values_mut() {
+            room.remove(&id);
+        }
     }
 
-    fn send_chat_message(&mut self, room_name: &str, msg: &str, _src: u64) -> Option<()> {
-        let mut room = self.take_room(room_name)?;
+    /// Lets users of other nodes take a name that no one here has any more.
+    fn release_name(&self, name: &str) {
+        if self.id_of(name).is_none() {
+            self.fanout.release_name(name);
+        }
+    }
 
-        for (id, client) in room.drain() {
-            if client.try_send(ChatMessage(msg.to_owned())).is_ok() {
-                self.add_client_to_room(room_name, Some(id), client);
+    /// Forgets the rooms owned by and invitations of a user of this node, on this node and others.
+    ///
+    /// Names are reserved across nodes, so no user of another node has the same name.
+    fn forget_user(&mut self, name: &str) {
+        for room_name in self.permissions.forget_user(name) {
+            self.fanout
+                .publish_update(&self.permissions.update(&room_name));
+        }
+    }
+
+    /// Moves the session of user `name` in the room back to the main room, e.g. once they are
+    /// kicked.
+    fn remove_from_room(&mut self, room_name: &str, name: &str) {
+        let Some(target) = self.id_of(name) else {
+            return;
+        };
+
+        if self.room_of(target).as_deref() == Some(room_name) {
+            let removal = format!("you were removed from {room_name}");
+            self.send_to(target, ChatResponse::notice(Some(room_name), removal));
+            self.send_to(target, ChatResponse::Joined(MAIN_ROOM.to_owned()));
+            self.add_client_to_room(MAIN_ROOM, target);
+        }
+    }
+
+    fn room_of(&self, id: u64) -> Option<String> {
+        self.rooms
+            .iter()
+            .find_map(|(name, room)| room.contains(&id).then(|| name.clone()))
+    }
+
+    fn id_of(&self, name: &str) -> Option<u64> {
+        self.sessions
+            .iter()
+            .find_map(|(id, session)| (session.name == name).then_some(*id))
+    }
+
+    /// Registers a session and puts it in the main room, once a name is reserved for it on other
+    /// nodes.
+    fn register(&mut self, client: Client) -> ResponseActFuture<Self, (u64, String)> {
+        // named after the session ID until the user picks a name
+        let (id, name) = loop {
+            let id = rand::random::<u64>();
+            let name = format!("anon-{:04x}", id >> 48);
+
+            if !self.sessions.contains_key(&id) && self.id_of(&name).is_none() {
+                break (id, name);
             }
+        };
+
+        let claim = self.fanout.claim_name(&name);
+
+        Box::pin(claim.into_actor(self).then(move |claimed, act, _ctx| {
+            let taken = match claimed {
+                Ok(claimed) => !claimed,
+                // the user can still chat, and pick a name of their own later
+                Err(err) => {
+                    log::error!("failed to reserve name {name}: {err}");
+                    false
+                }
+            };
+
+            // also taken if a session connected in the meantime
+            if taken || act.sessions.contains_key(&id) || act.id_of(&name).is_some() {
+                return act.register(client);
+            }
+
+            act.sessions.insert(
+                id,
+                Session {
+                    client,
+                    name: name.clone(),
+                },
+            );
+            act.add_client_to_room(MAIN_ROOM, id);
+
+            Box::pin(fut::ready((id, name)))
+        }))
+    }
+
+    fn add_client_to_room(&mut self, room_name: &str, id: u64) {
+        let name = self.sessions[&id].name.clone();
+
+        for room in self.rooms.values_mut() {
+            room.remove(&id);
         }
 
-        Some(())
+        if !self.rooms.contains_key(room_name) {
+            self.fanout.add_room(room_name);
+        }
+        self.rooms
+            .entry(room_name.to_owned())
+            .or_default()
+            .insert(id);
+
+        let join_msg = format!("{name} joined {room_name}");
+        self.send_notice(room_name, join_msg, id);
     }
 }
 
@@ -62,52 +215,223 @@ impl Actor for WsChatServer {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.subscribe_system_async::<LeaveRoom>(ctx);
+        self.subscribe_system_async::<Disconnect>(ctx);
         self.subscribe_system_async::<SendMessage>(ctx);
+        self.subscribe_system_async::<DirectMessage>(ctx);
+        self.subscribe_system_async::<Moderate>(ctx);
+        ctx.add_stream(self.fanout.events());
+    }
+}
+
+/// Acts on room traffic and permission changes published by other nodes.
+impl StreamHandler<Event> for WsChatServer {
+    fn handle(&mut self, event: Event, _ctx: &mut Self::Context) {
+        match event {
+            Event::Room { room, response } => {
+                self.deliver(&room, &response, 0);
+            }
+
+            Event::Permissions(update) => {
+                self.permissions.apply(&update);
+                if let Some(name) = &update.removed {
+                    self.remove_from_room(&update.room, name);
+                }
+            }
+        }
+    }
+}
+
+impl Handler<Connect> for WsChatServer {
+    type Result = ResponseActFuture<Self, (u64, String)>;
+
+    fn handle(&mut self, msg: Connect, _ctx: &mut Self::Context) -> Self::Result {
+        let Connect(client) = msg;
+        self.register(client)
+    }
+}
+
+impl Handler<Disconnect> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _ctx: &mut Self::Context) {
+        self.remove_session(msg.0);
     }
 }
 
 impl Handler<JoinRoom> for WsChatServer {
-    type Result = MessageResult<JoinRoom>;
+    type Result = Result<(), RoomError>;
 
     fn handle(&mut self, msg: JoinRoom, _ctx: &mut Self::Context) -> Self::Result {
-        let JoinRoom(room_name, client_name, client) = msg;
+        let JoinRoom(id, room_name, password) = msg;
 
-        let id = self.add_client_to_room(&room_name, None, client);
-        let join_msg = format!(
-            "{} joined {room_name}",
-            client_name.unwrap_or_else(|| "anon".to_owned()),
-        );
+        let Some(session) = self.sessions.get(&id) else {
+            return Ok(());
+        };
 
-        self.send_chat_message(&room_name, &join_msg, id);
-        MessageResult(id)
+        if self
+            .permissions
+            .join(&room_name, &session.name, password.as_deref())?
+        {
+            self.fanout
+                .publish_update(&self.permissions.update(&room_name));
+        }
+
+        self.add_client_to_room(&room_name, id);
+        Ok(())
     }
 }
 
-impl Handler<LeaveRoom> for WsChatServer {
-    type Result = ();
+impl Handler<SetName> for WsChatServer {
+    type Result = ResponseActFuture<Self, Result<(), RoomError>>;
 
-    fn handle(&mut self, msg: LeaveRoom, _ctx: &mut Self::Context) {
-        if let Some(room) = self.rooms.get_mut(&msg.0) {
-            room.remove(&msg.1);
+    /// Changes the name once it is reserved on other nodes.
+    fn handle(&mut self, msg: SetName, _ctx: &mut Self::Context) -> Self::Result {
+        let SetName(id, name) = msg;
+
+        if self.id_of(&name).is_some() {
+            return Box::pin(fut::ready(Err(RoomError::NameTaken)));
         }
+
+        let claim = self.fanout.claim_name(&name);
+
+        Box::pin(claim.into_actor(self).map(move |claimed, act, _ctx| {
+            match claimed {
+                Ok(true) => {}
+                Ok(false) => return Err(RoomError::NameTaken),
+                Err(err) => {
+                    log::error!("failed to reserve name {name}: {err}");
+                    return Err(RoomError::NameNotReserved);
+                }
+            }
+
+            // taken by a session of this node in the meantime, which keeps it reserved
+            if act.id_of(&name).is_some() {
+                return Err(RoomError::NameTaken);
+            }
+
+            match act.sessions.get_mut(&id) {
+                Some(session) => {
+                    let old_name = std::mem::replace(&mut session.name, name);
+                    act.release_name(&old_name);
+                    act.forget_user(&old_name);
+                }
+                None => act.release_name(&name),
+            }
+
+            Ok(())
+        }))
     }
 }
 
 impl Handler<ListRooms> for WsChatServer {
-    type Result = MessageResult<ListRooms>;
+    type Result = ResponseFuture<Vec<String>>;
 
+    /// Lists the rooms of other nodes too.
     fn handle(&mut self, _: ListRooms, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(self.rooms.keys().cloned().collect())
+        self.fanout.rooms(self.rooms.keys().cloned().collect())
     }
 }
 
 impl Handler<SendMessage> for WsChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: SendMessage, _ctx: &mut Self::Context) {
-        let SendMessage(room_name, id, msg) = msg;
-        self.send_chat_message(&room_name, &msg, id);
+    fn handle(&mut self, msg: SendMessage, ctx: &mut Self::Context) {
+        let SendMessage(id, msg) = msg;
+
+        let (Some(name), Some(room_name)) = (
+            self.sessions.get(&id).map(|s| s.name.clone()),
+            self.room_of(id),
+        ) else {
+            return;
+        };
+
+        if let Err(err) = self.permissions.check_send(&room_name, &name) {
+            self.send_to(id, ChatResponse::error(err));
+            return;
+        }
+
+        // messages of shared rooms are numbered by the fanout backend
+        self.fanout
+            .message_id()
+            .into_actor(self)
+            .map(move |res, act, _| {
+                let message_id = match res {
+                    Ok(Some(message_id)) => message_id,
+                    Ok(None) => {
+                        act.last_message_id += 1;
+                        act.last_message_id
+                    }
+                    Err(err) => {
+                        log::error!("failed to number message: {err}");
+                        act.send_to(id, ChatResponse::error(RoomError::NotSent));
+                        return;
+                    }
+                };
+
+                let content = protocol::ChatMessage::new(message_id, name, Some(&room_name), msg);
+                act.send_chat_message(&room_name, &ChatResponse::Message(content), id);
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Handler<DirectMessage> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: DirectMessage, _ctx: &mut Self::Context) {
+        let DirectMessage(id, to, msg) = msg;
+
+        let Some(from) = self.sessions.get(&id).map(|s| s.name.clone()) else {
+            return;
+        };
+
+        match self.id_of(&to) {
+            Some(to) => {
+                // private messages are not numbered
+                let msg = protocol::ChatMessage::new(0, from, None, msg);
+                self.send_to(to, ChatResponse::Message(msg));
+            }
+            None => self.send_to(id, ChatResponse::error(RoomError::UnknownUser)),
+        }
+    }
+}
+
+impl Handler<Moderate> for WsChatServer {
+    type Result = ();
+
+    /// Users who are kicked or banned are moved back to the main room, on other nodes too.
+    fn handle(&mut self, msg: Moderate, _ctx: &mut Self::Context) {
+        let Moderate(id, action) = msg;
+
+        let (Some(name), Some(room_name)) = (
+            self.sessions.get(&id).map(|s| s.name.clone()),
+            self.room_of(id),
+        ) else {
+            return;
+        };
+
+        if let Err(err) = self.permissions.moderate(&room_name, &name, &action) {
+            self.send_to(id, ChatResponse::error(err));
+            return;
+        }
+        let update = self
+            .permissions
+            .update(&room_name)
+            .removing(action.removes());
+        self.fanout.publish_update(&update);
+
+        if let Moderation::Invite(invited) = &action {
+            if let Some(invited) = self.id_of(invited) {
+                let invite = format!("{name} invited you to {room_name}");
+                self.send_to(invited, ChatResponse::notice(Some(&room_name), invite));
+            }
+        }
+
+        if let Some(target) = action.removes() {
+            self.remove_from_room(&room_name, target);
+        }
+
+        self.send_notice(&room_name, action.announcement(), id);
     }
 }
 
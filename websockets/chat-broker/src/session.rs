@@ -3,41 +3,75 @@ use actix_broker::BrokerIssue;
 use actix_web_actors::ws;
 
 use crate::{
-    message::{ChatMessage, JoinRoom, LeaveRoom, ListRooms, SendMessage},
+    message::{
+        ChatMessage, Connect, DirectMessage, Disconnect, JoinRoom, ListRooms, Moderate,
+        SendMessage, SetName,
+    },
+    protocol::{ChatRequest, ChatResponse},
     server::WsChatServer,
 };
 
 #[derive(Default)]
 pub struct WsChatSession {
     id: u64,
-    room: String,
-    name: Option<String>,
+    name: String,
 }
 
 impl WsChatSession {
-    pub fn join_room(&mut self, room_name: &str, ctx: &mut ws::WebsocketContext<Self>) {
-        let room_name = room_name.to_owned();
-
-        // First send a leave message for the current room
-        let leave_msg = LeaveRoom(self.room.clone(), self.id);
+    pub fn connect(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        WsChatServer::from_registry()
+            .send(Connect(ctx.address().recipient()))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok((id, name)) => {
+                        act.id = id;
+                        send(ctx, &ChatResponse::Name(name.clone()));
+                        act.name = name;
+                    }
+                    Err(_) => ctx.stop(),
+                }
 
-        // issue_sync comes from having the `BrokerIssue` trait in scope.
-        self.issue_system_sync(leave_msg, ctx);
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
 
-        // Then send a join message for the new room
-        let join_msg = JoinRoom(
-            room_name.to_owned(),
-            self.name.clone(),
-            ctx.address().recipient(),
-        );
+    pub fn join_room(
+        &mut self,
+        room_name: String,
+        password: Option<String>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let join_msg = JoinRoom(self.id, room_name.clone(), password);
 
         WsChatServer::from_registry()
             .send(join_msg)
             .into_actor(self)
-            .then(|id, act, _ctx| {
-                if let Ok(id) = id {
-                    act.id = id;
-                    act.room = room_name;
+            .then(|res, _act, ctx| {
+                match res {
+                    Ok(Ok(())) => send(ctx, &ChatResponse::Joined(room_name)),
+                    Ok(Err(err)) => send(ctx, &ChatResponse::error(err)),
+                    Err(_) => {}
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn set_name(&mut self, name: String, ctx: &mut ws::WebsocketContext<Self>) {
+        WsChatServer::from_registry()
+            .send(SetName(self.id, name.clone()))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {
+                        send(ctx, &ChatResponse::Name(name.clone()));
+                        act.name = name;
+                    }
+                    Ok(Err(err)) => send(ctx, &ChatResponse::error(err)),
+                    Err(_) => {}
                 }
 
                 fut::ready(())
@@ -51,9 +85,7 @@ impl WsChatSession {
             .into_actor(self)
             .then(|res, _, ctx| {
                 if let Ok(rooms) = res {
-                    for room in rooms {
-                        ctx.text(room);
-                    }
+                    send(ctx, &ChatResponse::Rooms(rooms));
                 }
 
                 fut::ready(())
@@ -61,13 +93,9 @@ impl WsChatSession {
             .wait(ctx);
     }
 
-    pub fn send_msg(&self, msg: &str) {
-        let content = format!(
-            "{}: {msg}",
-            self.name.clone().unwrap_or_else(|| "anon".to_owned()),
-        );
-
-        let msg = SendMessage(self.room.clone(), self.id, content);
+    pub fn send_msg(&self, msg: String) {
+        // the server adds our name to the message
+        let msg = SendMessage(self.id, msg);
 
         // issue_async comes from having the `BrokerIssue` trait in scope.
         self.issue_system_async(msg);
@@ -78,16 +106,13 @@ impl Actor for WsChatSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.join_room("main", ctx);
+        self.connect(ctx);
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
-        log::info!(
-            "WsChatSession closed for {}({}) in room {}",
-            self.name.clone().unwrap_or_else(|| "anon".to_owned()),
-            self.id,
-            self.room
-        );
+        log::info!("WsChatSession closed for {}({})", self.name, self.id);
+
+        self.issue_system_async(Disconnect(self.id));
     }
 }
 
@@ -95,7 +120,7 @@ impl Handler<ChatMessage> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: ChatMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        send(ctx, &msg.0);
     }
 }
 
@@ -112,39 +137,43 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
         log::debug!("WEBSOCKET MESSAGE: {msg:?}");
 
         match msg {
-            ws::Message::Text(text) => {
-                let msg = text.trim();
-
-                if msg.starts_with('/') {
-                    let mut command = msg.splitn(2, ' ');
-
-                    match command.next() {
-                        Some("/list") => self.list_rooms(ctx),
-
-                        Some("/join") => {
-                            if let Some(room_name) = command.next() {
-                                self.join_room(room_name, ctx);
-                            } else {
-                                ctx.text("!!! room name is required");
-                            }
-                        }
-
-                        Some("/name") => {
-                            if let Some(name) = command.next() {
-                                self.name = Some(name.to_owned());
-                                ctx.text(format!("name changed to: {name}"));
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-
-                        _ => ctx.text(format!("!!! unknown command: {msg:?}")),
-                    }
+            ws::Message::Text(text) => match serde_json::from_str(&text) {
+                Ok(ChatRequest::List) => self.list_rooms(ctx),
+
+                Ok(ChatRequest::Join { room, password }) => self.join_room(room, password, ctx),
+
+                Ok(ChatRequest::Name(name)) => self.set_name(name, ctx),
+
+                Ok(ChatRequest::Message(msg)) => self.send_msg(msg),
 
-                    return;
+                Ok(ChatRequest::Direct { to, text }) => {
+                    self.issue_system_async(DirectMessage(self.id, to, text))
                 }
-                self.send_msg(msg);
-            }
+
+                Ok(ChatRequest::Moderate(action)) => {
+                    self.issue_system_async(Moderate(self.id, action))
+                }
+
+                Ok(ChatRequest::History { .. }) => {
+                    send(ctx, &ChatResponse::error("message history is not kept"))
+                }
+
+                Ok(
+                    ChatRequest::Typing(_)
+                    | ChatRequest::Read { .. }
+                    | ChatRequest::Away(_)
+                    | ChatRequest::Members,
+                ) => send(
+                    ctx,
+                    &ChatResponse::error(
+                        "typing, read receipts and presence are not supported by this server",
+                    ),
+                ),
+
+                Ok(ChatRequest::Ping) => {}
+
+                Err(err) => send(ctx, &ChatResponse::error(format!("invalid request: {err}"))),
+            },
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -153,3 +182,9 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
         }
     }
 }
+
+/// Sends a response to the peer as a JSON text frame.
+fn send(ctx: &mut ws::WebsocketContext<WsChatSession>, res: &ChatResponse) {
+    // unwrap: responses are plain data that always serializes
+    ctx.text(serde_json::to_string(res).unwrap());
+}
@@ -0,0 +1,683 @@
+//! Fan-out of room traffic between chat server nodes, shared by the chat examples that can be
+//! scaled out.
+//!
+//! Each chat server keeps its rooms in memory, so on their own, two instances of a server behind
+//! a load balancer are two separate chats. A [`Fanout`] backend links them: a node delivers what
+//! is sent to a room to its own sessions and publishes it, and delivers what the other nodes
+//! publish to its own sessions in the same room. Changes to the permissions of a room are
+//! published the same way, and messages are numbered by the backend so that their IDs are unique
+//! across nodes. As permissions are tied to user names, names are reserved through the backend so
+//! that no two nodes give out the same one.
+//!
+//! [`Local`] is for a single node. [`Redis`] uses Redis pub/sub, and also keeps track of which
+//! rooms each node has so that room lists cover all of them.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    env, io,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use futures_util::{
+    FutureExt as _, StreamExt as _,
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+};
+use redis::{
+    AsyncCommands as _, RedisResult,
+    aio::{ConnectionManager, ConnectionManagerConfig},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{permissions::RoomUpdate, protocol::ChatResponse};
+
+/// Environment variable that, if set, is the URL of the Redis server used to fan out room traffic.
+pub const REDIS_URL_VAR: &str = "REDIS_URL";
+
+/// Redis pub/sub channel that room traffic is published to.
+const CHANNEL: &str = "chat:rooms";
+
+/// Prefix of the Redis keys holding the set of rooms of each node.
+const ROOMS_KEY_PREFIX: &str = "chat:node-rooms:";
+
+/// Redis key holding the ID of the last message sent to any room.
+const MESSAGE_ID_KEY: &str = "chat:message-id";
+
+/// Prefix of the Redis keys holding the node that reserved each user name.
+const NAME_KEY_PREFIX: &str = "chat:name:";
+
+/// Reserves the names of `KEYS` for node `ARGV[1]` for `ARGV[2]` seconds, unless another node has
+/// them, and returns whether each one was reserved.
+const CLAIM_NAMES_SCRIPT: &str = r"
+local claimed = {}
+for i, key in ipairs(KEYS) do
+    local owner = redis.call('GET', key)
+    if not owner or owner == ARGV[1] then
+        redis.call('SET', key, ARGV[1], 'EX', ARGV[2])
+        claimed[i] = 1
+    else
+        claimed[i] = 0
+    end
+end
+return claimed
+";
+
+/// Gives up the name of `KEYS[1]` if node `ARGV[1]` has it.
+const RELEASE_NAME_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// Number of writes that can wait for Redis. Events published while it is full are dropped.
+const OUTGOING_CAPACITY: usize = 1024;
+
+/// How long the rooms of a node are listed, and its names reserved, after it was last heard from.
+const NODE_TTL: Duration = Duration::from_secs(30);
+
+/// How long to wait before subscribing again after the subscription was lost.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between attempts to reconnect to Redis.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Something that happened on one node, for the others to act on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A response sent to a room, to be delivered to the sessions in it.
+    Room {
+        room: String,
+        response: ChatResponse,
+    },
+
+    /// A change to the permissions of a room, to be applied to each node's copy.
+    Permissions(RoomUpdate),
+}
+
+/// An [`Event`], as published by a node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Published {
+    /// Node that published the event, which has already acted on it.
+    node: String,
+
+    event: Event,
+}
+
+/// Carries room traffic between the nodes of a chat server.
+pub trait Fanout: Send + Sync {
+    /// Publishes a response that this node has sent to its own sessions in `room`.
+    fn publish(&self, room: &str, response: &ChatResponse);
+
+    /// Publishes a change to the permissions of a room that this node has made.
+    fn publish_update(&self, update: &RoomUpdate);
+
+    /// Returns a new message ID, unique across nodes, or `None` if this node is on its own and
+    /// numbers messages itself.
+    fn message_id(&self) -> BoxFuture<'static, io::Result<Option<u64>>>;
+
+    /// Reserves `name` for a user of this node, so that users of other nodes cannot take it.
+    ///
+    /// Returns `false` if a user of another node has the name. Whether a user of this node has it
+    /// is for the node to check.
+    fn claim_name(&self, name: &str) -> BoxFuture<'static, io::Result<bool>>;
+
+    /// Gives up a name that no user of this node has any more.
+    fn release_name(&self, name: &str);
+
+    /// Records that this node has a room, so that it is listed by the other nodes.
+    fn add_room(&self, room: &str);
+
+    /// Records that this node no longer has a room.
+    fn remove_room(&self, room: &str);
+
+    /// Returns `local`, the rooms of this node, together with the rooms of the other nodes.
+    fn rooms(&self, local: Vec<String>) -> BoxFuture<'static, Vec<String>>;
+
+    /// Returns a stream of the events published by the other nodes.
+    ///
+    /// The stream never ends, even if there is nothing to receive.
+    fn events(&self) -> BoxStream<'static, Event>;
+}
+
+/// Picks the Redis backend if [`REDIS_URL_VAR`] is set, and [`Local`] otherwise.
+pub async fn from_env() -> io::Result<Box<dyn Fanout>> {
+    match env::var(REDIS_URL_VAR) {
+        Ok(url) => {
+            let redis = Redis::connect(&url).await.map_err(io::Error::other)?;
+            log::info!(
+                "fanning out room traffic through Redis as node {}",
+                redis.node
+            );
+            Ok(Box::new(redis))
+        }
+        Err(_) => Ok(Box::new(Local)),
+    }
+}
+
+/// Backend of a single node, which has nothing to fan out to.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Local;
+
+impl Fanout for Local {
+    fn publish(&self, _room: &str, _response: &ChatResponse) {}
+
+    fn publish_update(&self, _update: &RoomUpdate) {}
+
+    fn message_id(&self) -> BoxFuture<'static, io::Result<Option<u64>>> {
+        future::ready(Ok(None)).boxed()
+    }
+
+    fn claim_name(&self, _name: &str) -> BoxFuture<'static, io::Result<bool>> {
+        future::ready(Ok(true)).boxed()
+    }
+
+    fn release_name(&self, _name: &str) {}
+
+    fn add_room(&self, _room: &str) {}
+
+    fn remove_room(&self, _room: &str) {}
+
+    fn rooms(&self, local: Vec<String>) -> BoxFuture<'static, Vec<String>> {
+        future::ready(local).boxed()
+    }
+
+    fn events(&self) -> BoxStream<'static, Event> {
+        stream::pending().boxed()
+    }
+}
+
+/// Work for the task that writes to Redis, which does it in order.
+#[derive(Debug)]
+enum Outgoing {
+    Publish(String),
+
+    /// Store the current set of rooms of this node.
+    StoreRooms,
+
+    /// Give up a name reserved by this node.
+    ReleaseName(String),
+}
+
+/// Backend that fans out room traffic through Redis pub/sub.
+///
+/// Every node publishes to and subscribes to the same channel. The rooms of each node are kept in
+/// a Redis set that expires unless the node keeps refreshing it, so the rooms of nodes that
+/// stopped are forgotten. Messages are numbered by incrementing a counter kept in Redis. User names
+/// are reserved with a key per name holding the node that has it, which expires unless the node
+/// keeps refreshing it too.
+///
+/// Writes wait in a bounded queue. If Redis falls behind and the queue fills up, events published
+/// meanwhile are dropped rather than held in memory; the set of rooms is stored again when the
+/// next refresh is due, and names that could not be given up expire.
+pub struct Redis {
+    /// Random ID of this node.
+    node: String,
+
+    conn: ConnectionManager,
+
+    /// Rooms of this node.
+    rooms: Arc<Mutex<BTreeSet<String>>>,
+
+    /// Names reserved by this node.
+    names: Arc<Mutex<BTreeSet<String>>>,
+
+    out_tx: mpsc::Sender<Outgoing>,
+
+    events: broadcast::Sender<Event>,
+}
+
+impl Redis {
+    /// Connects to the Redis server at `url` as a new node.
+    pub async fn connect(url: &str) -> RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        // the default backoff waits minutes between attempts
+        let config = ConnectionManagerConfig::new()
+            .set_factor(1)
+            .set_max_delay(MAX_RECONNECT_DELAY.as_millis() as u64);
+        let conn = ConnectionManager::new_with_config(client.clone(), config).await?;
+
+        let node = format!("{:016x}", rand::random::<u64>());
+        let rooms = Arc::new(Mutex::new(BTreeSet::new()));
+        let names = Arc::new(Mutex::new(BTreeSet::new()));
+        let (out_tx, out_rx) = mpsc::channel(OUTGOING_CAPACITY);
+        let (events, _) = broadcast::channel(256);
+
+        tokio::spawn(write_outgoing(
+            conn.clone(),
+            node.clone(),
+            rooms.clone(),
+            names.clone(),
+            out_rx,
+        ));
+        tokio::spawn(subscribe(client, node.clone(), events.clone()));
+
+        Ok(Self {
+            node,
+            conn,
+            rooms,
+            names,
+            out_tx,
+            events,
+        })
+    }
+
+    fn publish_event(&self, event: Event) {
+        let published = Published {
+            node: self.node.clone(),
+            event,
+        };
+
+        // unwrap: events contain nothing that cannot be serialized
+        let payload = serde_json::to_string(&published).unwrap();
+        if let Err(mpsc::error::TrySendError::Full(_)) =
+            self.out_tx.try_send(Outgoing::Publish(payload))
+        {
+            log::warn!("Redis is not keeping up, dropped an event for other nodes");
+        }
+    }
+
+    fn store_rooms(&self) {
+        // a full queue is already behind, and the refresh stores the rooms anyway
+        let _ = self.out_tx.try_send(Outgoing::StoreRooms);
+    }
+}
+
+impl Fanout for Redis {
+    fn publish(&self, room: &str, response: &ChatResponse) {
+        self.publish_event(Event::Room {
+            room: room.to_owned(),
+            response: response.clone(),
+        });
+    }
+
+    fn publish_update(&self, update: &RoomUpdate) {
+        self.publish_event(Event::Permissions(update.clone()));
+    }
+
+    fn message_id(&self) -> BoxFuture<'static, io::Result<Option<u64>>> {
+        let mut conn = self.conn.clone();
+
+        async move {
+            conn.incr(MESSAGE_ID_KEY, 1)
+                .await
+                .map(Some)
+                .map_err(io::Error::other)
+        }
+        .boxed()
+    }
+
+    fn claim_name(&self, name: &str) -> BoxFuture<'static, io::Result<bool>> {
+        let mut conn = self.conn.clone();
+        let node = self.node.clone();
+        let names = self.names.clone();
+        let name = name.to_owned();
+
+        async move {
+            let claimed = claim_names(&mut conn, &node, [&name])
+                .await
+                .map_err(io::Error::other)?
+                .contains(&true);
+
+            // kept up to date by the refresh from now on
+            if claimed {
+                names.lock().unwrap().insert(name);
+            }
+
+            Ok(claimed)
+        }
+        .boxed()
+    }
+
+    fn release_name(&self, name: &str) {
+        self.names.lock().unwrap().remove(name);
+
+        // a name that is no longer refreshed expires anyway
+        let _ = self.out_tx.try_send(Outgoing::ReleaseName(name.to_owned()));
+    }
+
+    fn add_room(&self, room: &str) {
+        self.rooms.lock().unwrap().insert(room.to_owned());
+        self.store_rooms();
+    }
+
+    fn remove_room(&self, room: &str) {
+        self.rooms.lock().unwrap().remove(room);
+        self.store_rooms();
+    }
+
+    fn rooms(&self, local: Vec<String>) -> BoxFuture<'static, Vec<String>> {
+        let mut conn = self.conn.clone();
+        let own_key = rooms_key(&self.node);
+
+        async move {
+            let mut rooms = local.into_iter().collect::<BTreeSet<_>>();
+
+            match remote_rooms(&mut conn, &own_key).await {
+                Ok(remote) => rooms.extend(remote),
+                Err(err) => log::error!("failed to list rooms of other nodes: {err}"),
+            }
+
+            rooms.into_iter().collect()
+        }
+        .boxed()
+    }
+
+    fn events(&self) -> BoxStream<'static, Event> {
+        stream::unfold(self.events.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::warn!("dropped {skipped} events from other nodes");
+                    }
+                    // the sender lives as long as the backend does
+                    Err(broadcast::error::RecvError::Closed) => future::pending::<()>().await,
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+fn rooms_key(node: &str) -> String {
+    format!("{ROOMS_KEY_PREFIX}{node}")
+}
+
+fn name_key(name: &str) -> String {
+    format!("{NAME_KEY_PREFIX}{name}")
+}
+
+/// Publishes events and keeps this node's set of rooms and reserved names up to date, until the
+/// backend is dropped.
+async fn write_outgoing(
+    mut conn: ConnectionManager,
+    node: String,
+    rooms: Arc<Mutex<BTreeSet<String>>>,
+    names: Arc<Mutex<BTreeSet<String>>>,
+    mut out_rx: mpsc::Receiver<Outgoing>,
+) {
+    let rooms_key = rooms_key(&node);
+    let mut refresh = tokio::time::interval(NODE_TTL / 3);
+
+    loop {
+        let res = tokio::select! {
+            out = out_rx.recv() => match out {
+                Some(Outgoing::Publish(payload)) => conn.publish(CHANNEL, payload).await,
+                Some(Outgoing::StoreRooms) => {
+                    let rooms = rooms.lock().unwrap().clone();
+                    store_rooms(&mut conn, &rooms_key, &rooms).await
+                }
+                Some(Outgoing::ReleaseName(name)) => release_name(&mut conn, &node, &name).await,
+                None => break,
+            },
+
+            // also restores the set if Redis lost it, or an update was dropped
+            _ = refresh.tick() => {
+                let rooms = rooms.lock().unwrap().clone();
+                let names = names.lock().unwrap().clone();
+
+                match store_rooms(&mut conn, &rooms_key, &rooms).await {
+                    Ok(()) => refresh_names(&mut conn, &node, &names).await,
+                    Err(err) => Err(err),
+                }
+            }
+        };
+
+        if let Err(err) = res {
+            log::error!("failed to write to Redis: {err}");
+        }
+    }
+
+    let _: RedisResult<()> = conn.del(&rooms_key).await;
+
+    let names = names.lock().unwrap().clone();
+    for name in names {
+        let _ = release_name(&mut conn, &node, &name).await;
+    }
+}
+
+async fn store_rooms(
+    conn: &mut ConnectionManager,
+    key: &str,
+    rooms: &BTreeSet<String>,
+) -> RedisResult<()> {
+    // SADD needs at least one member
+    if rooms.is_empty() {
+        return conn.del(key).await;
+    }
+
+    redis::pipe()
+        .atomic()
+        .del(key)
+        .sadd(key, rooms)
+        .expire(key, NODE_TTL.as_secs() as i64)
+        .query_async(conn)
+        .await
+}
+
+/// Reserves `names` for `node`, unless another node has them, and returns whether each one was
+/// reserved.
+async fn claim_names(
+    conn: &mut ConnectionManager,
+    node: &str,
+    names: impl IntoIterator<Item = &String>,
+) -> RedisResult<Vec<bool>> {
+    let script = redis::Script::new(CLAIM_NAMES_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+
+    for name in names {
+        invocation.key(name_key(name));
+    }
+
+    invocation
+        .arg(node)
+        .arg(NODE_TTL.as_secs())
+        .invoke_async(conn)
+        .await
+}
+
+/// Keeps the names reserved by `node` from expiring, reserving them again if Redis lost them.
+async fn refresh_names(
+    conn: &mut ConnectionManager,
+    node: &str,
+    names: &BTreeSet<String>,
+) -> RedisResult<()> {
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let claimed = claim_names(conn, node, names).await?;
+
+    for (name, claimed) in names.iter().zip(claimed) {
+        if !claimed {
+            log::warn!("name {name} expired and was taken by a user of another node");
+        }
+    }
+
+    Ok(())
+}
+
+async fn release_name(conn: &mut ConnectionManager, node: &str, name: &str) -> RedisResult<()> {
+    redis::Script::new(RELEASE_NAME_SCRIPT)
+        .key(name_key(name))
+        .arg(node)
+        .invoke_async(conn)
+        .await
+}
+
+async fn remote_rooms(conn: &mut ConnectionManager, own_key: &str) -> RedisResult<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut iter = conn
+        .scan_match::<_, String>(format!("{ROOMS_KEY_PREFIX}*"))
+        .await?;
+
+    while let Some(key) = iter.next_item().await {
+        if key != own_key {
+            keys.push(key);
+        }
+    }
+    drop(iter);
+
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    conn.sunion(keys).await
+}
+
+/// Forwards the events of other nodes to `events`, subscribing again whenever the subscription is
+/// lost.
+async fn subscribe(client: redis::Client, node: String, events: broadcast::Sender<Event>) {
+    loop {
+        match receive_events(&client, &node, &events).await {
+            Ok(()) => log::warn!("Redis subscription ended, subscribing again"),
+            Err(err) => log::error!("Redis subscription failed: {err}"),
+        }
+
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+async fn receive_events(
+    client: &redis::Client,
+    node: &str,
+    events: &broadcast::Sender<Event>,
+) -> RedisResult<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(CHANNEL).await?;
+
+    let mut messages = pubsub.into_on_message();
+
+    while let Some(msg) = messages.next().await {
+        let payload = msg.get_payload::<String>()?;
+
+        match serde_json::from_str::<Published>(&payload) {
+            // this node has acted on its own events already
+            Ok(published) if published.node == node => {}
+            Ok(published) => {
+                // errors if no server is listening
+                let _ = events.send(published.event);
+            }
+            Err(err) => log::warn!("ignoring malformed event from Redis: {err}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Backend linking nodes that run in the same process in memory, as Redis would, e.g. in tests.
+pub struct Hub {
+    node: String,
+    events: broadcast::Sender<Published>,
+    rooms: Arc<Mutex<HashMap<String, BTreeSet<String>>>>,
+    /// Node that reserved each name.
+    names: Arc<Mutex<HashMap<String, String>>>,
+    last_message_id: Arc<AtomicU64>,
+}
+
+impl Hub {
+    /// Creates the backend of a new node, linked to `self`.
+    pub fn node(&self, node: &str) -> Self {
+        Self {
+            node: node.to_owned(),
+            events: self.events.clone(),
+            rooms: self.rooms.clone(),
+            names: self.names.clone(),
+            last_message_id: self.last_message_id.clone(),
+        }
+    }
+
+    fn publish_event(&self, event: Event) {
+        let _ = self.events.send(Published {
+            node: self.node.clone(),
+            event,
+        });
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self {
+            node: String::new(),
+            events: broadcast::channel(256).0,
+            rooms: Default::default(),
+            names: Default::default(),
+            last_message_id: Default::default(),
+        }
+    }
+}
+
+impl Fanout for Hub {
+    fn publish(&self, room: &str, response: &ChatResponse) {
+        self.publish_event(Event::Room {
+            room: room.to_owned(),
+            response: response.clone(),
+        });
+    }
+
+    fn publish_update(&self, update: &RoomUpdate) {
+        self.publish_event(Event::Permissions(update.clone()));
+    }
+
+    fn message_id(&self) -> BoxFuture<'static, io::Result<Option<u64>>> {
+        let id = self.last_message_id.fetch_add(1, Ordering::Relaxed) + 1;
+        future::ready(Ok(Some(id))).boxed()
+    }
+
+    fn claim_name(&self, name: &str) -> BoxFuture<'static, io::Result<bool>> {
+        let mut names = self.names.lock().unwrap();
+        let owner = names
+            .entry(name.to_owned())
+            .or_insert_with(|| self.node.clone());
+
+        future::ready(Ok(*owner == self.node)).boxed()
+    }
+
+    fn release_name(&self, name: &str) {
+        let mut names = self.names.lock().unwrap();
+
+        if names.get(name) == Some(&self.node) {
+            names.remove(name);
+        }
+    }
+
+    fn add_room(&self, room: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms
+            .entry(self.node.clone())
+            .or_default()
+            .insert(room.to_owned());
+    }
+
+    fn remove_room(&self, room: &str) {
+        if let Some(rooms) = self.rooms.lock().unwrap().get_mut(&self.node) {
+            rooms.remove(room);
+        }
+    }
+
+    fn rooms(&self, local: Vec<String>) -> BoxFuture<'static, Vec<String>> {
+        let mut rooms = local.into_iter().collect::<BTreeSet<_>>();
+        rooms.extend(self.rooms.lock().unwrap().values().flatten().cloned());
+
+        future::ready(rooms.into_iter().collect()).boxed()
+    }
+
+    fn events(&self) -> BoxStream<'static, Event> {
+        let node = self.node.clone();
+
+        stream::unfold(self.events.subscribe(), |mut rx| async move {
+            let published = rx.recv().await.ok()?;
+            Some((published, rx))
+        })
+        .filter_map(move |published| {
+            future::ready((published.node != node).then_some(published.event))
+        })
+        .boxed()
+    }
+}
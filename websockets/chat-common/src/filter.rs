@@ -0,0 +1,217 @@
+//! Filters that chat messages pass through before they are sent, shared by the chat examples.
+//!
+//! A [`MessageFilter`] sees every message before the server sends it, and may let it through,
+//! rewrite it or reject it. Servers hold a single filter, which is usually a chain of them built
+//! with [`Filters`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// How long a user may not send the same message again.
+const REPEAT_WINDOW: Duration = Duration::from_secs(10);
+
+/// What a filter decided about a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Send the message as it is.
+    Allow,
+
+    /// Send this text instead.
+    Rewrite(String),
+
+    /// Do not send the message, telling the sender why.
+    Reject(String),
+}
+
+/// Hook that decides whether, and in what form, a message is sent.
+pub trait MessageFilter: Send + Sync {
+    /// Checks `text`, which `user` sends to `room`, or to another user if `room` is `None`.
+    fn check(&mut self, user: &str, room: Option<&str>, text: &str) -> Verdict;
+
+    /// Checks `text` and returns what to send, or why it was rejected.
+    fn apply(&mut self, user: &str, room: Option<&str>, text: String) -> Result<String, String> {
+        match self.check(user, room, &text) {
+            Verdict::Allow => Ok(text),
+            Verdict::Rewrite(text) => Ok(text),
+            Verdict::Reject(reason) => Err(reason),
+        }
+    }
+}
+
+/// Chain of filters, each seeing the text as rewritten by the ones before it.
+///
+/// An empty chain allows everything.
+#[derive(Default)]
+pub struct Filters(Vec<Box<dyn MessageFilter>>);
+
+impl Filters {
+    /// Adds a filter to the end of the chain.
+    pub fn with(mut self, filter: impl MessageFilter + 'static) -> Self {
+        self.0.push(Box::new(filter));
+        self
+    }
+
+    /// The filters used by the chat servers: blocked words from `path`, if it exists, and
+    /// [`Repeats`].
+    pub fn standard(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut filters = Self::default();
+
+        if path.as_ref().exists() {
+            filters = filters.with(BlockedWords::load(path)?);
+        }
+
+        Ok(filters.with(Repeats::default()))
+    }
+}
+
+impl MessageFilter for Filters {
+    fn check(&mut self, user: &str, room: Option<&str>, text: &str) -> Verdict {
+        let mut rewritten = None::<String>;
+
+        for filter in &mut self.0 {
+            let current = rewritten.as_deref().unwrap_or(text);
+
+            match filter.check(user, room, current) {
+                Verdict::Allow => {}
+                Verdict::Rewrite(text) => rewritten = Some(text),
+                Verdict::Reject(reason) => return Verdict::Reject(reason),
+            }
+        }
+
+        rewritten.map_or(Verdict::Allow, Verdict::Rewrite)
+    }
+}
+
+/// Masks blocked words with asterisks.
+#[derive(Debug, Default)]
+pub struct BlockedWords {
+    /// Lowercase blocked words.
+    words: HashSet<String>,
+}
+
+impl BlockedWords {
+    pub fn new<I, S>(words: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self {
+            words: words
+                .into_iter()
+                .map(|word| word.as_ref().to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Loads blocked words from a file with one word per line.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let words = fs::read_to_string(path)?;
+
+        Ok(Self::new(
+            words
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#')),
+        ))
+    }
+}
+
+impl MessageFilter for BlockedWords {
+    fn check(&mut self, _user: &str, _room: Option<&str>, text: &str) -> Verdict {
+        let mut masked = String::with_capacity(text.len());
+        let mut found = false;
+
+        for (is_word, part) in split_words(text) {
+            if is_word && self.words.contains(&part.to_lowercase()) {
+                masked.extend(part.chars().map(|_| '*'));
+                found = true;
+            } else {
+                masked.push_str(part);
+            }
+        }
+
+        if found {
+            Verdict::Rewrite(masked)
+        } else {
+            Verdict::Allow
+        }
+    }
+}
+
+/// Splits text into runs of word and non-word characters, telling which is which.
+fn split_words(text: &str) -> impl Iterator<Item = (bool, &str)> {
+    let mut rest = text;
+
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let is_word = first.is_alphanumeric();
+        let end = rest
+            .find(|c: char| c.is_alphanumeric() != is_word)
+            .unwrap_or(rest.len());
+
+        let (part, tail) = rest.split_at(end);
+        rest = tail;
+        Some((is_word, part))
+    })
+}
+
+/// Rejects a message that its sender sent just before, a common form of spam.
+#[derive(Debug, Default)]
+pub struct Repeats {
+    /// Last message of each user and when it was sent.
+    last: HashMap<String, (String, Instant)>,
+}
+
+impl MessageFilter for Repeats {
+    fn check(&mut self, user: &str, _room: Option<&str>, text: &str) -> Verdict {
+        let now = Instant::now();
+
+        // forget users that have been quiet, so that the map does not keep growing
+        if self.last.len() > 1024 {
+            self.last
+                .retain(|_, (_, at)| now.duration_since(*at) < REPEAT_WINDOW);
+        }
+
+        if let Some((last, at)) = self.last.get(user) {
+            if last == text && now.duration_since(*at) < REPEAT_WINDOW {
+                return Verdict::Reject("you just sent that message".to_owned());
+            }
+        }
+
+        self.last.insert(user.to_owned(), (text.to_owned(), now));
+        Verdict::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_masks_words_and_rejects_repeats() {
+        let mut filters = Filters::default()
+            .with(BlockedWords::new(["darn"]))
+            .with(Repeats::default());
+
+        assert_eq!(
+            filters.apply("alice", Some("main"), "Darn it, darned printer!".to_owned()),
+            Ok("**** it, darned printer!".to_owned())
+        );
+        assert_eq!(
+            filters.apply("alice", Some("main"), "darn it, darned printer!".to_owned()),
+            Err("you just sent that message".to_owned())
+        );
+        assert_eq!(
+            filters.apply("bob", None, "darn it, darned printer!".to_owned()),
+            Ok("**** it, darned printer!".to_owned())
+        );
+        assert_eq!(
+            filters.check("alice", Some("main"), "hello"),
+            Verdict::Allow
+        );
+    }
+}
@@ -0,0 +1,9 @@
+//! Modules shared by the chat examples, so that they speak the same protocol and enforce the same
+//! rules.
+
+pub mod fanout;
+pub mod filter;
+pub mod limits;
+pub mod permissions;
+pub mod protocol;
+pub mod queue;
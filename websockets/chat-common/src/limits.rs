@@ -0,0 +1,248 @@
+//! Per-session limits on how long and how frequent requests may be, shared by the chat examples.
+//!
+//! Sessions check every text and binary frame of their client. Frames that are too long or too
+//! frequent are refused, and a client that keeps breaking the limits is muted for a while.
+//!
+//! The WebSocket codec should be limited to [`LimitConfig::max_frame_len`] too, so that it refuses
+//! longer frames before they are handed to the session, which reports them with
+//! [`SessionLimits::refuse_too_long`].
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Limits applied to each session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitConfig {
+    /// Longest frame accepted, in bytes.
+    pub max_frame_len: usize,
+
+    /// Frames accepted per second, on average.
+    pub frames_per_sec: u32,
+
+    /// Frames accepted at once after a quiet period.
+    pub burst: u32,
+
+    /// Number of violations within `violation_window` that gets the client muted.
+    pub violations_before_mute: usize,
+
+    pub violation_window: Duration,
+
+    /// How long a client stays muted.
+    pub mute_duration: Duration,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_len: 4 * 1024,
+            frames_per_sec: 5,
+            burst: 10,
+            violations_before_mute: 3,
+            violation_window: Duration::from_secs(30),
+            mute_duration: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Reason that a frame was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitError {
+    /// The frame is longer than the limit.
+    TooLong { max: usize },
+
+    /// The client is sending frames faster than the limit.
+    TooFast,
+
+    /// The client broke the limits too often and may not send messages until the mute ends.
+    Muted { remaining: Duration },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLong { max } => write!(f, "message is too long, the limit is {max} bytes"),
+            Self::TooFast => f.write_str("you are sending messages too quickly"),
+            Self::Muted { remaining } => write!(
+                f,
+                "you are muted for {}s for breaking the message limits",
+                remaining.as_secs().max(1)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+/// Limit state of a single session.
+///
+/// Frame frequency is limited by a token bucket, which holds `burst` tokens and gains
+/// `frames_per_sec` tokens a second.
+#[derive(Debug, Clone)]
+pub struct SessionLimits {
+    config: LimitConfig,
+    tokens: f64,
+    refilled_at: Instant,
+
+    /// Times of recent violations, oldest first.
+    violations: VecDeque<Instant>,
+
+    muted_until: Option<Instant>,
+}
+
+impl SessionLimits {
+    pub fn new(config: LimitConfig) -> Self {
+        Self {
+            config,
+            tokens: f64::from(config.burst),
+            refilled_at: Instant::now(),
+            violations: VecDeque::new(),
+            muted_until: None,
+        }
+    }
+
+    /// Checks a frame of `len` bytes received at `now`.
+    ///
+    /// Refused frames count as violations, and the violation that reaches the limit mutes the
+    /// client, which is reported instead of the violation itself.
+    pub fn check_frame(&mut self, len: usize, now: Instant) -> Result<(), LimitError> {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(self.config.frames_per_sec))
+            .min(f64::from(self.config.burst));
+        self.refilled_at = now;
+
+        let err = if len > self.config.max_frame_len {
+            LimitError::TooLong {
+                max: self.config.max_frame_len,
+            }
+        } else if self.tokens < 1.0 {
+            LimitError::TooFast
+        } else {
+            self.tokens -= 1.0;
+            return Ok(());
+        };
+
+        Err(self.violation(err, now))
+    }
+
+    /// Records a frame received at `now` that the codec refused for being longer than the limit,
+    /// returning the error to report.
+    pub fn refuse_too_long(&mut self, now: Instant) -> LimitError {
+        let err = LimitError::TooLong {
+            max: self.config.max_frame_len,
+        };
+
+        self.violation(err, now)
+    }
+
+    /// Checks whether the client may send chat messages at `now`.
+    pub fn check_send(&self, now: Instant) -> Result<(), LimitError> {
+        match self.muted_until {
+            Some(until) if until > now => Err(LimitError::Muted {
+                remaining: until - now,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn violation(&mut self, err: LimitError, now: Instant) -> LimitError {
+        while self
+            .violations
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) > self.config.violation_window)
+        {
+            self.violations.pop_front();
+        }
+        self.violations.push_back(now);
+
+        if self.violations.len() < self.config.violations_before_mute {
+            return err;
+        }
+
+        self.violations.clear();
+        self.muted_until = Some(now + self.config.mute_duration);
+
+        LimitError::Muted {
+            remaining: self.config.mute_duration,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_and_mute() {
+        let config = LimitConfig {
+            max_frame_len: 10,
+            frames_per_sec: 1,
+            burst: 2,
+            violations_before_mute: 3,
+            violation_window: Duration::from_secs(10),
+            mute_duration: Duration::from_secs(60),
+        };
+        let start = Instant::now();
+        let mut limits = SessionLimits::new(config);
+
+        assert_eq!(limits.check_frame(5, start), Ok(()));
+        assert_eq!(limits.check_frame(5, start), Ok(()));
+        assert_eq!(limits.check_frame(5, start), Err(LimitError::TooFast));
+
+        // a token is regained each second
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limits.check_frame(5, later), Ok(()));
+        assert_eq!(
+            limits.check_frame(11, later),
+            Err(LimitError::TooLong { max: 10 })
+        );
+        assert_eq!(limits.check_send(later), Ok(()));
+
+        // third violation within the window mutes
+        assert_eq!(
+            limits.check_frame(5, later),
+            Err(LimitError::Muted {
+                remaining: Duration::from_secs(60)
+            })
+        );
+        assert_eq!(
+            limits.check_send(later + Duration::from_secs(59)),
+            Err(LimitError::Muted {
+                remaining: Duration::from_secs(1)
+            })
+        );
+        assert_eq!(limits.check_send(later + Duration::from_secs(60)), Ok(()));
+    }
+
+    #[test]
+    fn old_violations_are_forgotten() {
+        let config = LimitConfig {
+            max_frame_len: 10,
+            violations_before_mute: 2,
+            violation_window: Duration::from_secs(10),
+            ..LimitConfig::default()
+        };
+        let start = Instant::now();
+        let mut limits = SessionLimits::new(config);
+
+        assert!(matches!(
+            limits.check_frame(11, start),
+            Err(LimitError::TooLong { .. })
+        ));
+        let later = start + Duration::from_secs(11);
+        assert_eq!(
+            limits.refuse_too_long(later),
+            LimitError::TooLong { max: 10 }
+        );
+        assert_eq!(limits.check_send(later), Ok(()));
+
+        assert!(matches!(
+            limits.refuse_too_long(later),
+            LimitError::Muted { .. }
+        ));
+    }
+}
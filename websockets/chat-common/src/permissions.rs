@@ -0,0 +1,504 @@
+//! Room permissions, shared by all of the chat examples so that they enforce the same rules.
+//!
+//! A room is created by the first user to join it, who becomes its owner. Owners can make their
+//! room invite-only or password-protected, and kick, ban or mute other users. The `main` room is
+//! open to everyone and has no owner.
+//!
+//! Servers that share rooms with other nodes publish a [`RoomUpdate`] after each change to a
+//! room's permissions, which the other nodes [apply](Permissions::apply) to their own copy.
+
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    fmt,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Room that every session starts in.
+pub const MAIN_ROOM: &str = "main";
+
+/// Who may join a room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Access {
+    /// Anyone who is not banned.
+    Open,
+
+    /// Only the owner and invited users.
+    InviteOnly,
+
+    /// Anyone who knows the password.
+    Password(String),
+}
+
+/// An action that the owner of a room can take.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Moderation {
+    /// Allow a user to join an invite-only room.
+    Invite(String),
+
+    /// Remove a user from the room. They may join again.
+    Kick(String),
+
+    /// Remove a user from the room and stop them joining again.
+    Ban(String),
+
+    /// Let a banned user join again.
+    Unban(String),
+
+    /// Stop a user sending messages to the room.
+    Mute(String),
+
+    /// Let a muted user send messages again.
+    Unmute(String),
+
+    /// Change who may join the room.
+    SetAccess(Access),
+}
+
+impl Moderation {
+    /// Parses a moderation command of the text protocol, e.g. `/kick bob`.
+    ///
+    /// Returns `None` if `cmd` is not a moderation command.
+    pub fn parse(cmd: &str, arg: Option<&str>) -> Option<Result<Self, RoomError>> {
+        let arg = arg.map(str::trim).filter(|arg| !arg.is_empty());
+        let user = || arg.map(str::to_owned).ok_or(RoomError::MissingArgument);
+
+        let action = match cmd {
+            "/invite" => user().map(Self::Invite),
+            "/kick" => user().map(Self::Kick),
+            "/ban" => user().map(Self::Ban),
+            "/unban" => user().map(Self::Unban),
+            "/mute" => user().map(Self::Mute),
+            "/unmute" => user().map(Self::Unmute),
+            "/public" => Ok(Self::SetAccess(Access::Open)),
+            "/private" => Ok(Self::SetAccess(Access::InviteOnly)),
+            "/password" => user().map(|password| Self::SetAccess(Access::Password(password))),
+            _ => return None,
+        };
+
+        Some(action)
+    }
+
+    /// Returns the user that has to be removed from the room once this action is taken, if any.
+    pub fn removes(&self) -> Option<&str> {
+        match self {
+            Self::Kick(user) | Self::Ban(user) => Some(user),
+            _ => None,
+        }
+    }
+
+    fn target(&self) -> Option<&str> {
+        match self {
+            Self::Invite(user)
+            | Self::Kick(user)
+            | Self::Ban(user)
+            | Self::Unban(user)
+            | Self::Mute(user)
+            | Self::Unmute(user) => Some(user),
+            Self::SetAccess(_) => None,
+        }
+    }
+
+    /// Returns the text that the members of the room are sent once this action is taken.
+    pub fn announcement(&self) -> String {
+        match self {
+            Self::Invite(user) => format!("{user} was invited"),
+            Self::Kick(user) => format!("{user} was kicked"),
+            Self::Ban(user) => format!("{user} was banned"),
+            Self::Unban(user) => format!("{user} was unbanned"),
+            Self::Mute(user) => format!("{user} was muted"),
+            Self::Unmute(user) => format!("{user} was unmuted"),
+            Self::SetAccess(Access::Open) => "room is now open to everyone".to_owned(),
+            Self::SetAccess(Access::InviteOnly) => "room is now invite-only".to_owned(),
+            Self::SetAccess(Access::Password(_)) => "room now requires a password".to_owned(),
+        }
+    }
+}
+
+/// Reason that a request was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomError {
+    /// The user is banned from the room.
+    Banned,
+
+    /// The room is invite-only and the user was not invited.
+    NotInvited,
+
+    /// The room requires a password and the wrong one was given.
+    WrongPassword,
+
+    /// The user is muted in the room.
+    Muted,
+
+    /// Only the owner of the room may do this.
+    NotOwner,
+
+    /// The owner of a room cannot be the target of moderation.
+    OwnerTarget,
+
+    /// No user by the given name is connected.
+    UnknownUser,
+
+    /// Another connected user already has the requested name.
+    NameTaken,
+
+    /// The name could not be reserved on other servers, and was not changed.
+    NameNotReserved,
+
+    /// A command was missing its argument.
+    MissingArgument,
+
+    /// A message filter rejected the message, for the given reason.
+    Rejected(String),
+
+    /// The message could not be numbered or stored, and was not sent.
+    NotSent,
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Banned => "you are banned from this room",
+            Self::NotInvited => "this room is invite-only",
+            Self::WrongPassword => "wrong room password",
+            Self::Muted => "you are muted in this room",
+            Self::NotOwner => "only the room owner can do that",
+            Self::OwnerTarget => "the room owner cannot be moderated",
+            Self::UnknownUser => "no such user is connected",
+            Self::NameTaken => "name is already taken",
+            Self::NameNotReserved => "name could not be changed, try again later",
+            Self::MissingArgument => "argument is required",
+            Self::Rejected(reason) => reason,
+            Self::NotSent => "message could not be sent",
+        })
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Room {
+    /// User that created the room. Rooms without an owner cannot be moderated.
+    owner: Option<String>,
+    access: Access,
+    invited: HashSet<String>,
+    banned: HashSet<String>,
+    muted: HashSet<String>,
+}
+
+impl Room {
+    fn new(owner: Option<String>) -> Self {
+        Self {
+            owner,
+            access: Access::Open,
+            invited: HashSet::new(),
+            banned: HashSet::new(),
+            muted: HashSet::new(),
+        }
+    }
+
+    fn is_owner(&self, user: &str) -> bool {
+        self.owner.as_deref() == Some(user)
+    }
+}
+
+/// The permissions of a room after a change, published to the other nodes sharing the room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomUpdate {
+    pub room: String,
+
+    /// Permissions of the room, or `None` if it was removed.
+    permissions: Option<Room>,
+
+    /// User who was kicked or banned, whose sessions have to leave the room.
+    pub removed: Option<String>,
+}
+
+impl RoomUpdate {
+    /// Records that `user`, if any, was removed from the room.
+    pub fn removing(mut self, user: Option<&str>) -> Self {
+        self.removed = user.map(str::to_owned);
+        self
+    }
+}
+
+/// Permissions of every room, by room name.
+///
+/// Only decides whether requests are allowed. Servers keep track of which sessions are in which
+/// room themselves.
+#[derive(Debug)]
+pub struct Permissions {
+    rooms: HashMap<String, Room>,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        let mut rooms = HashMap::new();
+        rooms.insert(MAIN_ROOM.to_owned(), Room::new(None));
+
+        Self { rooms }
+    }
+}
+
+impl Permissions {
+    /// Checks whether `user` may join room `name`, creating it with them as owner if it does not
+    /// exist.
+    ///
+    /// A room whose owner has been forgotten is claimed by the next user allowed to join it.
+    /// Returns whether either happened, which is a change to publish.
+    pub fn join(
+        &mut self,
+        name: &str,
+        user: &str,
+        password: Option<&str>,
+    ) -> Result<bool, RoomError> {
+        let room = match self.rooms.entry(name.to_owned()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(Room::new(Some(user.to_owned())));
+                return Ok(true);
+            }
+        };
+
+        if room.is_owner(user) {
+            return Ok(false);
+        }
+
+        if room.banned.contains(user) {
+            return Err(RoomError::Banned);
+        }
+
+        match &room.access {
+            Access::Open => {}
+            Access::InviteOnly if room.invited.contains(user) => {}
+            Access::InviteOnly => return Err(RoomError::NotInvited),
+            Access::Password(expected) if password == Some(expected) => {}
+            Access::Password(_) => return Err(RoomError::WrongPassword),
+        }
+
+        if room.owner.is_none() && name != MAIN_ROOM {
+            room.owner = Some(user.to_owned());
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Checks whether `user` may send messages to `room`.
+    pub fn check_send(&self, room: &str, user: &str) -> Result<(), RoomError> {
+        match self.rooms.get(room) {
+            Some(room) if room.banned.contains(user) => Err(RoomError::Banned),
+            Some(room) if room.muted.contains(user) => Err(RoomError::Muted),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies a moderation action taken by `user` in `room`.
+    ///
+    /// Removing the target from the room, if the action requires it, is left to the caller.
+    pub fn moderate(
+        &mut self,
+        room: &str,
+        user: &str,
+        action: &Moderation,
+    ) -> Result<(), RoomError> {
+        let room = match self.rooms.get_mut(room) {
+            Some(room) if room.is_owner(user) => room,
+            _ => return Err(RoomError::NotOwner),
+        };
+
+        if action.target().is_some_and(|target| room.is_owner(target)) {
+            return Err(RoomError::OwnerTarget);
+        }
+
+        match action.clone() {
+            Moderation::Invite(target) => {
+                room.invited.insert(target);
+            }
+            Moderation::Kick(_) => {}
+            Moderation::Ban(target) => {
+                room.invited.remove(&target);
+                room.banned.insert(target);
+            }
+            Moderation::Unban(target) => {
+                room.banned.remove(&target);
+            }
+            Moderation::Mute(target) => {
+                room.muted.insert(target);
+            }
+            Moderation::Unmute(target) => {
+                room.muted.remove(&target);
+            }
+            Moderation::SetAccess(access) => room.access = access,
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the permissions of room `name`, which is created afresh by the next user to join
+    /// it. The main room is kept.
+    pub fn remove_room(&mut self, name: &str) {
+        if name != MAIN_ROOM {
+            self.rooms.remove(name);
+        }
+    }
+
+    /// Moves the permissions of room `from` to `to`, replacing those of `to`, if any.
+    pub fn rename_room(&mut self, from: &str, to: &str) {
+        if let Some(room) = self.rooms.remove(from) {
+            self.rooms.insert(to.to_owned(), room);
+        }
+    }
+
+    /// Forgets the rooms owned by and invitations of `user`, e.g. once an unauthenticated user
+    /// disconnects and their name can be taken by someone else. Bans and mutes are kept.
+    ///
+    /// Returns the names of the rooms that changed.
+    pub fn forget_user(&mut self, user: &str) -> Vec<String> {
+        let mut changed = Vec::new();
+
+        for (name, room) in &mut self.rooms {
+            let owned = room.is_owner(user);
+            if owned {
+                room.owner = None;
+            }
+
+            if room.invited.remove(user) || owned {
+                changed.push(name.clone());
+            }
+        }
+
+        changed
+    }
+
+    /// Returns the current permissions of room `name`, to be published after changing them.
+    pub fn update(&self, name: &str) -> RoomUpdate {
+        RoomUpdate {
+            room: name.to_owned(),
+            permissions: self.rooms.get(name).cloned(),
+            removed: None,
+        }
+    }
+
+    /// Applies an update published by another node, which has already checked that the change
+    /// was allowed. The main room is kept.
+    pub fn apply(&mut self, update: &RoomUpdate) {
+        match &update.permissions {
+            Some(room) => {
+                self.rooms.insert(update.room.clone(), room.clone());
+            }
+            None => self.remove_room(&update.room),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owner_controls_access() {
+        let mut perms = Permissions::default();
+
+        perms.join("secret", "alice", None).unwrap();
+        perms
+            .moderate(
+                "secret",
+                "alice",
+                &Moderation::SetAccess(Access::InviteOnly),
+            )
+            .unwrap();
+
+        assert_eq!(
+            perms.join("secret", "bob", None),
+            Err(RoomError::NotInvited)
+        );
+        perms
+            .moderate("secret", "alice", &Moderation::Invite("bob".to_owned()))
+            .unwrap();
+        assert_eq!(perms.join("secret", "bob", None), Ok(false));
+
+        let password = Moderation::SetAccess(Access::Password("hunter2".to_owned()));
+        assert_eq!(
+            perms.moderate("secret", "bob", &password),
+            Err(RoomError::NotOwner)
+        );
+        perms.moderate("secret", "alice", &password).unwrap();
+        assert_eq!(
+            perms.join("secret", "carol", Some("guess")),
+            Err(RoomError::WrongPassword)
+        );
+        assert_eq!(perms.join("secret", "carol", Some("hunter2")), Ok(false));
+
+        // main room has no owner
+        assert_eq!(perms.join(MAIN_ROOM, "bob", None), Ok(false));
+        assert_eq!(
+            perms.moderate(MAIN_ROOM, "alice", &Moderation::Kick("bob".to_owned())),
+            Err(RoomError::NotOwner)
+        );
+    }
+
+    #[test]
+    fn bans_and_mutes() {
+        let mut perms = Permissions::default();
+        perms.join("room", "alice", None).unwrap();
+        perms.join("room", "bob", None).unwrap();
+
+        let mute = Moderation::parse("/mute", Some("bob")).unwrap().unwrap();
+        perms.moderate("room", "alice", &mute).unwrap();
+        assert_eq!(perms.check_send("room", "bob"), Err(RoomError::Muted));
+        assert_eq!(perms.check_send("room", "alice"), Ok(()));
+
+        let ban = Moderation::parse("/ban", Some("bob")).unwrap().unwrap();
+        assert_eq!(ban.removes(), Some("bob"));
+        perms.moderate("room", "alice", &ban).unwrap();
+        assert_eq!(perms.join("room", "bob", None), Err(RoomError::Banned));
+
+        let unban = Moderation::parse("/unban", Some("bob")).unwrap().unwrap();
+        perms.moderate("room", "alice", &unban).unwrap();
+        assert_eq!(perms.join("room", "bob", None), Ok(false));
+
+        // bob claims the room once alice is forgotten
+        perms.forget_user("alice");
+        assert_eq!(perms.join("room", "bob", None), Ok(true));
+        assert_eq!(
+            perms.moderate("room", "bob", &mute),
+            Err(RoomError::OwnerTarget)
+        );
+        perms.join("room", "alice", None).unwrap();
+
+        assert_eq!(
+            perms.moderate("room", "bob", &Moderation::Kick("bob".to_owned())),
+            Err(RoomError::OwnerTarget)
+        );
+        assert_eq!(
+            Moderation::parse("/kick", None),
+            Some(Err(RoomError::MissingArgument))
+        );
+        assert_eq!(Moderation::parse("/list", None), None);
+    }
+
+    #[test]
+    fn updates_are_applied_by_other_nodes() {
+        let mut here = Permissions::default();
+        let mut there = Permissions::default();
+
+        here.join("room", "alice", None).unwrap();
+        let ban = Moderation::Ban("bob".to_owned());
+        here.moderate("room", "alice", &ban).unwrap();
+
+        let update = here.update("room").removing(ban.removes());
+        assert_eq!(update.removed.as_deref(), Some("bob"));
+        there.apply(&update);
+        assert_eq!(there.join("room", "bob", None), Err(RoomError::Banned));
+        assert_eq!(
+            there.moderate("room", "carol", &Moderation::Kick("bob".to_owned())),
+            Err(RoomError::NotOwner)
+        );
+
+        assert_eq!(here.forget_user("alice"), ["room"]);
+        here.remove_room("room");
+        there.apply(&here.update("room"));
+        assert_eq!(there.join("room", "bob", None), Ok(true));
+    }
+}
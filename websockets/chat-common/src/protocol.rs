@@ -0,0 +1,254 @@
+//! Wire protocol shared by all of the chat examples, so that any client can talk to any server.
+//!
+//! Requests and responses are JSON objects tagged with `cmd`, with their arguments under `data`,
+//! e.g. `{"cmd":"Join","data":{"room":"rust"}}`. WebSocket sessions send one per text frame, while
+//! the TCP sessions of the chat-tcp example put a length prefix in front of each.
+
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::permissions::Moderation;
+
+/// Client request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "cmd", content = "data")]
+pub enum ChatRequest {
+    /// List rooms
+    List,
+    /// Join rooms
+    Join {
+        room: String,
+        /// Password, for password-protected rooms
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// Change name
+    Name(String),
+    /// Send message
+    Message(String),
+    /// Send private message to another user
+    Direct { to: String, text: String },
+    /// Moderate current room, room owner only
+    Moderate(Moderation),
+    /// Replay messages of the current room sent after the message with the given ID
+    History { after: u64 },
+    /// Tell the current room that the user started (`true`) or stopped (`false`) typing
+    Typing(bool),
+    /// Tell the current room that the user has read its messages up to the given ID
+    Read { up_to: u64 },
+    /// Set whether the user is away
+    Away(bool),
+    /// List the users in the current room
+    Members,
+    /// Ping
+    Ping,
+}
+
+/// Server response
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "cmd", content = "data")]
+pub enum ChatResponse {
+    Ping,
+
+    /// Name that the session's user is known by
+    Name(String),
+
+    /// List of rooms
+    Rooms(Vec<String>),
+
+    /// Joined
+    Joined(String),
+
+    /// Message
+    Message(ChatMessage),
+
+    /// Something happened, e.g. a user joined the room
+    Notice(Notice),
+
+    /// Request was refused or could not be understood
+    Error(String),
+
+    /// A user in a room started or stopped typing
+    Typing {
+        room: String,
+        user: String,
+        typing: bool,
+    },
+
+    /// A user has read the messages of a room up to the given ID
+    Read {
+        room: String,
+        user: String,
+        up_to: u64,
+    },
+
+    /// The presence of a user in a room changed
+    Presence {
+        room: String,
+        user: String,
+        presence: Presence,
+    },
+
+    /// Users in the current room
+    Members {
+        room: String,
+        members: Vec<Member>,
+    },
+}
+
+impl ChatResponse {
+    /// Creates a notice about `room`, or about the whole server if `room` is `None`.
+    pub fn notice(room: Option<&str>, text: impl Into<String>) -> Self {
+        Self::Notice(Notice {
+            timestamp: now(),
+            room: room.map(str::to_owned),
+            text: text.into(),
+        })
+    }
+
+    /// Creates an error frame.
+    pub fn error(err: impl fmt::Display) -> Self {
+        Self::Error(err.to_string())
+    }
+}
+
+/// A message sent by a user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    /// Message ID. IDs increase with each message sent to a room, so clients can tell which
+    /// messages they have seen. Private messages are not numbered and have ID 0.
+    pub id: u64,
+
+    /// When the message was sent, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// User that sent the message.
+    pub sender: String,
+
+    /// Room the message was sent to, or `None` for private messages.
+    pub room: Option<String>,
+
+    pub text: String,
+}
+
+impl ChatMessage {
+    /// Creates a message sent now.
+    pub fn new(
+        id: u64,
+        sender: impl Into<String>,
+        room: Option<&str>,
+        text: impl Into<String>,
+    ) -> Self {
+        Self {
+            id,
+            timestamp: now(),
+            sender: sender.into(),
+            room: room.map(str::to_owned),
+            text: text.into(),
+        }
+    }
+}
+
+/// A notice from the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Notice {
+    /// When the notice was sent, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// Room the notice is about, or `None` for notices about the whole server.
+    pub room: Option<String>,
+
+    pub text: String,
+}
+
+/// Whether a user is in a room and paying attention to it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    /// In the room.
+    Online,
+
+    /// In the room, but away.
+    Away,
+
+    /// Left the room or disconnected.
+    Offline,
+}
+
+/// A user in a room.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+
+    pub presence: Presence,
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_frames() {
+        let join = r#"{"cmd":"Join","data":{"room":"rust"}}"#;
+        assert_eq!(
+            serde_json::from_str::<ChatRequest>(join).unwrap(),
+            ChatRequest::Join {
+                room: "rust".to_owned(),
+                password: None,
+            }
+        );
+
+        let list = serde_json::to_string(&ChatRequest::List).unwrap();
+        assert_eq!(list, r#"{"cmd":"List"}"#);
+
+        let msg = ChatResponse::Message(ChatMessage {
+            id: 7,
+            timestamp: 1_700_000_000_000,
+            sender: "alice".to_owned(),
+            room: Some("rust".to_owned()),
+            text: "hi".to_owned(),
+        });
+        assert_eq!(
+            serde_json::to_value(&msg).unwrap(),
+            serde_json::json!({
+                "cmd": "Message",
+                "data": {
+                    "id": 7,
+                    "timestamp": 1_700_000_000_000u64,
+                    "sender": "alice",
+                    "room": "rust",
+                    "text": "hi",
+                },
+            })
+        );
+
+        let typing = r#"{"cmd":"Typing","data":true}"#;
+        assert_eq!(
+            serde_json::from_str::<ChatRequest>(typing).unwrap(),
+            ChatRequest::Typing(true)
+        );
+
+        let presence = ChatResponse::Presence {
+            room: "rust".to_owned(),
+            user: "bob".to_owned(),
+            presence: Presence::Away,
+        };
+        assert_eq!(
+            serde_json::to_string(&presence).unwrap(),
+            r#"{"cmd":"Presence","data":{"room":"rust","user":"bob","presence":"Away"}}"#
+        );
+
+        let err = serde_json::to_string(&ChatResponse::error("nope")).unwrap();
+        assert_eq!(err, r#"{"cmd":"Error","data":"nope"}"#);
+    }
+}
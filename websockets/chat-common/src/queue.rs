@@ -0,0 +1,411 @@
+//! Bounded queues of responses waiting to be written to a session, shared by the chat examples.
+//!
+//! Sessions only take responses off their queue as fast as their client reads them, so a client
+//! that stops reading fills its queue instead of the server's memory. What happens to a full queue
+//! is decided by its [`Overflow`] policy.
+
+use std::{
+    collections::VecDeque,
+    env, fmt, io,
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+use futures_util::stream::{self, Stream};
+use tokio::sync::Notify;
+
+/// Environment variable holding the number of responses each session queue holds.
+pub const CAPACITY_VAR: &str = "CHAT_QUEUE_CAPACITY";
+
+/// Environment variable holding the [`Overflow`] policy of session queues.
+pub const OVERFLOW_VAR: &str = "CHAT_QUEUE_OVERFLOW";
+
+/// What to do with a response sent to a full queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the oldest queued response to make room.
+    DropOldest,
+
+    /// Drop the response being sent.
+    DropNewest,
+
+    /// Drop everything queued and disconnect the client.
+    Disconnect,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(format!(
+                "unknown overflow policy {s:?}, expected drop-oldest, drop-newest or disconnect"
+            )),
+        }
+    }
+}
+
+/// Size and [`Overflow`] policy of session queues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            overflow: Overflow::DropOldest,
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Reads the config from [`CAPACITY_VAR`] and [`OVERFLOW_VAR`], using the defaults for those
+    /// that are not set.
+    pub fn from_env() -> io::Result<Self> {
+        let mut config = Self::default();
+
+        if let Ok(capacity) = env::var(CAPACITY_VAR) {
+            config.capacity = capacity
+                .parse()
+                .ok()
+                .filter(|capacity| *capacity > 0)
+                .ok_or_else(|| {
+                    invalid_input(format!("{CAPACITY_VAR} must be a positive number"))
+                })?;
+        }
+
+        if let Ok(overflow) = env::var(OVERFLOW_VAR) {
+            config.overflow = overflow.parse().map_err(invalid_input)?;
+        }
+
+        Ok(config)
+    }
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Depths of and drops from all session queues, in the Prometheus text format when displayed.
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    /// Number of open queues.
+    queues: AtomicUsize,
+
+    /// Number of responses waiting in all queues.
+    depth: AtomicUsize,
+
+    /// Most responses that have waited in a single queue.
+    max_depth: AtomicUsize,
+
+    /// Number of responses dropped from full queues.
+    dropped: AtomicU64,
+
+    /// Number of clients disconnected because their queue was full.
+    disconnects: AtomicU64,
+}
+
+impl QueueMetrics {
+    /// Number of responses waiting in all queues.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Number of responses dropped from full queues.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for QueueMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let metrics = [
+            (
+                "chat_session_queues",
+                "gauge",
+                "Number of open session queues",
+                self.queues.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "chat_session_queue_depth",
+                "gauge",
+                "Number of responses waiting in all session queues",
+                self.depth.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "chat_session_queue_max_depth",
+                "gauge",
+                "Most responses that have waited in a single session queue",
+                self.max_depth.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "chat_session_queue_dropped_total",
+                "counter",
+                "Number of responses dropped from full session queues",
+                self.dropped.load(Ordering::Relaxed),
+            ),
+            (
+                "chat_session_queue_disconnects_total",
+                "counter",
+                "Number of clients disconnected because their session queue was full",
+                self.disconnects.load(Ordering::Relaxed),
+            ),
+        ];
+
+        for (name, kind, help, value) in metrics {
+            writeln!(f, "# HELP {name} {help}")?;
+            writeln!(f, "# TYPE {name} {kind}")?;
+            writeln!(f, "{name} {value}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Error returned when a response could not be queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The receiving session is gone.
+    Closed,
+
+    /// The queue was full and its client is being disconnected.
+    Disconnected,
+}
+
+#[derive(Debug)]
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    notify: Notify,
+    metrics: Arc<QueueMetrics>,
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let left = self.state.get_mut().map_or(0, |state| state.items.len());
+        self.metrics.depth.fetch_sub(left, Ordering::Relaxed);
+        self.metrics.queues.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Creates a session queue, counted in `metrics`.
+pub fn queue<T>(
+    config: QueueConfig,
+    metrics: Arc<QueueMetrics>,
+) -> (QueueSender<T>, QueueReceiver<T>) {
+    metrics.queues.fetch_add(1, Ordering::Relaxed);
+
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(config.capacity),
+            closed: false,
+        }),
+        notify: Notify::new(),
+        metrics,
+    });
+
+    (
+        QueueSender {
+            shared: shared.clone(),
+            config,
+        },
+        QueueReceiver { shared },
+    )
+}
+
+/// Sending half of a session queue, kept by the server.
+///
+/// Dropping it lets the session take what is left, then closes the queue.
+#[derive(Debug)]
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+    config: QueueConfig,
+}
+
+impl<T> QueueSender<T> {
+    /// Queues a response, applying the overflow policy if the queue is full.
+    pub fn send(&self, item: T) -> Result<(), SendError> {
+        let metrics = &self.shared.metrics;
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.closed {
+            return Err(SendError::Closed);
+        }
+
+        if state.items.len() >= self.config.capacity {
+            match self.config.overflow {
+                Overflow::DropOldest => {
+                    state.items.pop_front();
+                    metrics.depth.fetch_sub(1, Ordering::Relaxed);
+                    metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Overflow::DropNewest => {
+                    metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Overflow::Disconnect => {
+                    let dropped = state.items.len();
+                    state.items.clear();
+                    state.closed = true;
+                    drop(state);
+
+                    metrics.depth.fetch_sub(dropped, Ordering::Relaxed);
+                    metrics
+                        .dropped
+                        .fetch_add(dropped as u64 + 1, Ordering::Relaxed);
+                    metrics.disconnects.fetch_add(1, Ordering::Relaxed);
+                    self.shared.notify.notify_one();
+
+                    return Err(SendError::Disconnected);
+                }
+            }
+        }
+
+        state.items.push_back(item);
+        metrics.depth.fetch_add(1, Ordering::Relaxed);
+        metrics
+            .max_depth
+            .fetch_max(state.items.len(), Ordering::Relaxed);
+        drop(state);
+
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    /// Number of responses waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().items.len()
+    }
+
+    /// Whether no responses are waiting in the queue.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+/// Receiving half of a session queue, kept by the session.
+#[derive(Debug)]
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueReceiver<T> {
+    /// Waits for the next response, or returns `None` once the queue is closed.
+    ///
+    /// A queue closed because its client was too slow to keep up returns `None` straight away.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+
+                if let Some(item) = state.items.pop_front() {
+                    self.shared.metrics.depth.fetch_sub(1, Ordering::Relaxed);
+                    return Some(item);
+                }
+
+                if state.closed {
+                    return None;
+                }
+            }
+
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// Turns the receiver into a stream of responses.
+    pub fn into_stream(self) -> impl Stream<Item = T> {
+        stream::unfold(self, |mut rx| async move {
+            let item = rx.recv().await?;
+            Some((item, rx))
+        })
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(overflow: Overflow) -> QueueConfig {
+        QueueConfig {
+            capacity: 2,
+            overflow,
+        }
+    }
+
+    #[tokio::test]
+    async fn overflow_policies() {
+        let metrics = Arc::new(QueueMetrics::default());
+
+        let (tx, mut rx) = queue(config(Overflow::DropOldest), metrics.clone());
+        for i in 0..4 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(tx.len(), 2);
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, Some(3));
+
+        let (tx, mut rx) = queue(config(Overflow::DropNewest), metrics.clone());
+        for i in 0..4 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(rx.recv().await, Some(0));
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(metrics.dropped(), 4);
+
+        let (tx, mut rx) = queue(config(Overflow::Disconnect), metrics.clone());
+        tx.send(0).unwrap();
+        tx.send(1).unwrap();
+        assert_eq!(tx.send(2), Err(SendError::Disconnected));
+        assert_eq!(rx.recv().await, None);
+        assert_eq!(tx.send(3), Err(SendError::Closed));
+        assert_eq!(metrics.dropped(), 7);
+        assert_eq!(metrics.depth(), 0);
+
+        let rendered = metrics.to_string();
+        assert!(rendered.contains("chat_session_queues 3\n"));
+        assert!(rendered.contains("chat_session_queue_max_depth 2\n"));
+        assert!(rendered.contains("chat_session_queue_disconnects_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn receiver_drains_after_sender_is_dropped() {
+        let metrics = Arc::new(QueueMetrics::default());
+
+        let (tx, mut rx) = queue(QueueConfig::default(), metrics.clone());
+        tx.send("a").unwrap();
+        tx.send("b").unwrap();
+        drop(tx);
+
+        assert_eq!(rx.recv().await, Some("a"));
+        drop(rx);
+        assert_eq!(metrics.depth(), 0);
+        assert!(metrics.to_string().contains("chat_session_queues 0\n"));
+    }
+}
@@ -1,80 +1,157 @@
 #!/usr/bin/env python3
 """websocket cmd client for actix/websocket-tcp-chat example."""
+
 import argparse
 import asyncio
-import signal
+import json
 import sys
+from contextlib import suppress
+from datetime import datetime
 
 import aiohttp
 
-queue = asyncio.Queue()
-
-
-async def start_client(url, loop):
-    name = input('Please enter your name: ')
-
-    ws = await aiohttp.ClientSession().ws_connect(url, autoclose=False, autoping=False)
-
-    def stdin_callback():
-        line = sys.stdin.buffer.readline().decode('utf-8')
-        if not line:
-            loop.stop()
-        else:
-            # Queue.put is a coroutine, so you can't call it directly.
-            asyncio.ensure_future(queue.put(ws.send_str(name + ': ' + line)))
 
-    loop.add_reader(sys.stdin, stdin_callback)
-
-    async def dispatch():
+MODERATION = {
+    "/invite": "Invite",
+    "/kick": "Kick",
+    "/ban": "Ban",
+    "/unban": "Unban",
+    "/mute": "Mute",
+    "/unmute": "Unmute",
+}
+
+
+def parse_command(line: str) -> dict:
+    """Turns a line typed by the user into a request of the chat protocol."""
+    text = line.strip()
+
+    if not text.startswith("/"):
+        return {"cmd": "Message", "data": text}
+
+    cmd, _, arg = text.partition(" ")
+    arg = arg.strip()
+    first, _, second = arg.partition(" ")
+    second = second.strip()
+
+    if cmd == "/list":
+        return {"cmd": "List"}
+    if cmd == "/public":
+        return {"cmd": "Moderate", "data": {"SetAccess": "Open"}}
+    if cmd == "/private":
+        return {"cmd": "Moderate", "data": {"SetAccess": "InviteOnly"}}
+
+    if not arg:
+        raise ValueError("argument is required")
+
+    if cmd == "/join":
+        return {"cmd": "Join", "data": {"room": first, "password": second or None}}
+    if cmd == "/name":
+        return {"cmd": "Name", "data": arg}
+    if cmd == "/msg" and second:
+        return {"cmd": "Direct", "data": {"to": first, "text": second}}
+    if cmd == "/password":
+        return {"cmd": "Moderate", "data": {"SetAccess": {"Password": arg}}}
+    if cmd in MODERATION:
+        return {"cmd": "Moderate", "data": {MODERATION[cmd]: arg}}
+
+    raise ValueError(f"invalid command: {text}")
+
+
+def show_response(res: dict) -> None:
+    """Prints a response of the chat server."""
+    cmd, data = res["cmd"], res.get("data")
+
+    def time(timestamp: int) -> str:
+        return datetime.fromtimestamp(timestamp / 1000).strftime("%H:%M:%S")
+
+    if cmd == "Message":
+        where = f"#{data['room']} [{data['id']}]" if data["room"] else "private"
+        print(f"{time(data['timestamp'])} {where} {data['sender']}: {data['text']}")
+    elif cmd == "Notice":
+        print(f"{time(data['timestamp'])} {data['text']}")
+    elif cmd == "Rooms":
+        print("Rooms:", ", ".join(data))
+    elif cmd == "Joined":
+        print("Joined", data)
+    elif cmd == "Name":
+        print("Your name is", data)
+    elif cmd == "Error":
+        print("!!!", data)
+
+
+async def start_client(url: str) -> None:
+    name = input("Please enter your name: ")
+
+    async def dispatch(ws: aiohttp.ClientWebSocketResponse) -> None:
         while True:
             msg = await ws.receive()
+
             if msg.type == aiohttp.WSMsgType.TEXT:
-                print('Text: ', msg.data.strip())
+                show_response(json.loads(msg.data))
             elif msg.type == aiohttp.WSMsgType.BINARY:
-                print('Binary: ', msg.data)
+                print("Binary: ", msg.data)
             elif msg.type == aiohttp.WSMsgType.PING:
                 await ws.pong()
             elif msg.type == aiohttp.WSMsgType.PONG:
-                print('Pong received')
+                print("Pong received")
             else:
                 if msg.type == aiohttp.WSMsgType.CLOSE:
                     await ws.close()
                 elif msg.type == aiohttp.WSMsgType.ERROR:
-                    print('Error during receive %s' % ws.exception())
+                    print("Error during receive %s" % ws.exception())
                 elif msg.type == aiohttp.WSMsgType.CLOSED:
                     pass
+
                 break
 
-    await dispatch()
+    async with aiohttp.ClientSession() as session:
+        async with session.ws_connect(url, autoclose=False, autoping=False) as ws:
+            dispatch_task = asyncio.create_task(dispatch(ws))
 
+            if name:
+                await ws.send_json({"cmd": "Name", "data": name})
 
-async def tick():
-    while True:
-        await (await queue.get())
+            # Exit with Ctrl+D
+            while line := await asyncio.to_thread(sys.stdin.readline):
+                if not line.strip():
+                    continue
 
+                try:
+                    await ws.send_json(parse_command(line))
+                except ValueError as err:
+                    print("!!!", err)
 
-async def main(url, loop):
-    await asyncio.wait([start_client(url, loop), tick()])
+            dispatch_task.cancel()
+            with suppress(asyncio.CancelledError):
+                await dispatch_task
 
 
 ARGS = argparse.ArgumentParser(
-    description="websocket console client for wssrv.py example.")
+    description="websocket console client for the websocket-tcp-chat example."
+)
 ARGS.add_argument(
-    '--host', action="store", dest='host',
-    default='127.0.0.1', help='Host name')
+    "--host", action="store", dest="host", default="127.0.0.1", help="Host name"
+)
 ARGS.add_argument(
-    '--port', action="store", dest='port',
-    default=8080, type=int, help='Port number')
+    "--port", action="store", dest="port", default=8080, type=int, help="Port number"
+)
 
-if __name__ == '__main__':
+if __name__ == "__main__":
     args = ARGS.parse_args()
-    if ':' in args.host:
-        args.host, port = args.host.split(':', 1)
+    if ":" in args.host:
+        args.host, port = args.host.split(":", 1)
         args.port = int(port)
 
-    url = 'http://{}:{}/ws/'.format(args.host, args.port)
-
-    loop = asyncio.get_event_loop()
-    loop.add_signal_handler(signal.SIGINT, loop.stop)
-    asyncio.Task(main(url, loop))
-    loop.run_forever()
+    url = f"http://{args.host}:{args.port}/ws"
+
+    print("""
+    /list 	list all available rooms
+    /join name [password] 	join room, if room does not exist, create new one
+    /name name 	change your name
+    /msg user message 	send a direct message to a user
+    /invite, /kick, /ban, /unban, /mute, /unmute user 	moderate the current room
+    /public, /private, /password secret 	set who may join the current room
+    some message 	just string, send message to all peers in same room
+    ctrl-D to exit
+    """)
+    asyncio.run(start_client(url))
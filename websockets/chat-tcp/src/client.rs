@@ -1,15 +1,38 @@
-use std::{io, thread};
+use std::{io, path::PathBuf, thread};
 
 use futures_util::{SinkExt as _, StreamExt as _};
 use tokio::{net::TcpStream, select, sync::mpsc};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 mod codec;
+mod tls;
+
+use websocket_chat_common::{permissions::Moderation, protocol};
+
+/// Command line options.
+struct Args {
+    prefix: codec::PrefixWidth,
+    encoding: codec::Encoding,
+
+    /// CA that the server's certificate is checked against, if TLS is used.
+    tls_ca: Option<PathBuf>,
+
+    /// Certificate chain and key presented to servers that require one.
+    identity: Option<(PathBuf, PathBuf)>,
+}
 
 #[actix_web::main]
 async fn main() {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            return;
+        }
+    };
+
     println!("Running chat client");
 
     let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
@@ -34,19 +57,41 @@ async fn main() {
         }
     });
 
-    let io = TcpStream::connect(("127.0.0.1", 12345)).await.unwrap();
-    let mut framed = actix_codec::Framed::new(io, codec::ClientChatCodec);
+    let (io, options) = match connect(&args).await {
+        Ok(connected) => connected,
+        Err(err) => {
+            eprintln!("!!! {err}");
+            return;
+        }
+    };
+    println!(
+        "!!! connected, messages may be up to {} bytes",
+        options.max_frame_size
+    );
+    let mut framed = actix_codec::Framed::new(io, codec::ClientChatCodec::new(options));
 
     loop {
         select! {
             Some(msg) = framed.next() => {
                 match msg {
-                    Ok(codec::ChatResponse::Message(ref msg)) => {
-                        println!("message: {msg}");
+                    Ok(codec::ChatResponse::Message(msg)) => match msg.room {
+                        Some(room) => {
+                            println!("message: #{room} [{}] {}: {}", msg.id, msg.sender, msg.text);
+                        }
+                        None => println!("private message: {}: {}", msg.sender, msg.text),
+                    },
+                    Ok(codec::ChatResponse::Notice(notice)) => {
+                        println!("notice: {}", notice.text);
+                    }
+                    Ok(codec::ChatResponse::Name(ref name)) => {
+                        println!("!!! your name is {name}");
                     }
                     Ok(codec::ChatResponse::Joined(ref msg)) => {
                         println!("!!! joined: {msg}");
                     }
+                    Ok(codec::ChatResponse::Error(ref msg)) => {
+                        println!("!!! {msg}");
+                    }
 
                     Ok(codec::ChatResponse::Rooms(rooms)) => {
                         println!("!!! Available rooms:");
@@ -85,6 +130,76 @@ async fn main() {
     input_thread.join().unwrap();
 }
 
+/// Connects to the server, starting TLS if asked to, and settles the framing.
+async fn connect(args: &Args) -> io::Result<(tls::Connection, codec::Options)> {
+    let stream = TcpStream::connect(("127.0.0.1", 12345)).await?;
+
+    let mut io: tls::Connection = match &args.tls_ca {
+        Some(ca) => {
+            let identity = args
+                .identity
+                .as_ref()
+                .map(|(cert, key)| (cert.as_path(), key.as_path()));
+            let config = tls::client_config(ca, identity)?;
+            tls::connect(config, "localhost", stream).await?
+        }
+        None => Box::new(stream),
+    };
+
+    let options = codec::connect(&mut io, args.prefix, args.encoding).await?;
+    Ok((io, options))
+}
+
+/// Reads the framing to propose to the server from `--prefix 16|32` and
+/// `--encoding json|msgpack`, which default to 32 and json.
+///
+/// `--tls` connects over TLS, trusting the CA in `--ca FILE`, which defaults
+/// to `certs/rootCA.pem`. `--cert FILE` and `--key FILE` give the client
+/// certificate for servers that require one.
+fn parse_args() -> Result<Args, String> {
+    let mut prefix = codec::PrefixWidth::U32;
+    let mut encoding = codec::Encoding::Json;
+    let mut tls = false;
+    let mut ca = PathBuf::from("certs/rootCA.pem");
+    let mut cert = None;
+    let mut key = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--tls" {
+            tls = true;
+            continue;
+        }
+
+        let value = args.next().ok_or_else(|| format!("{arg} needs a value"))?;
+
+        match arg.as_str() {
+            "--prefix" => prefix = value.parse()?,
+            "--encoding" => encoding = value.parse()?,
+            "--ca" => ca = value.into(),
+            "--cert" => cert = Some(PathBuf::from(value)),
+            "--key" => key = Some(PathBuf::from(value)),
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+    }
+
+    let identity = match (cert, key) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => return Err("--cert and --key must be given together".to_owned()),
+    };
+    if identity.is_some() && !tls {
+        return Err("--cert and --key need --tls".to_owned());
+    }
+
+    Ok(Args {
+        prefix,
+        encoding,
+        tls_ca: tls.then_some(ca),
+        identity,
+    })
+}
+
 fn parse_client_command(msg: &str) -> Option<codec::ChatRequest> {
     let m = msg.trim();
 
@@ -99,16 +214,50 @@ fn parse_client_command(msg: &str) -> Option<codec::ChatRequest> {
             "/list" => Some(codec::ChatRequest::List),
             "/join" => {
                 if v.len() == 2 {
-                    Some(codec::ChatRequest::Join(v[1].to_owned()))
+                    // `/join room password` for password-protected rooms
+                    let (room, password) = match v[1].split_once(' ') {
+                        Some((room, password)) => (room, Some(password.to_owned())),
+                        None => (v[1], None),
+                    };
+
+                    Some(codec::ChatRequest::Join {
+                        room: room.to_owned(),
+                        password,
+                    })
                 } else {
                     println!("!!! room name is required");
                     None
                 }
             }
-            _ => {
-                println!("!!! unknown command");
-                None
+            "/name" => {
+                if v.len() == 2 {
+                    Some(codec::ChatRequest::Name(v[1].to_owned()))
+                } else {
+                    println!("!!! name is required");
+                    None
+                }
             }
+            "/msg" => match v.get(1).and_then(|rest| rest.split_once(' ')) {
+                Some((to, text)) => Some(codec::ChatRequest::Direct {
+                    to: to.to_owned(),
+                    text: text.trim().to_owned(),
+                }),
+                None => {
+                    println!("!!! user name and message are required");
+                    None
+                }
+            },
+            cmd => match Moderation::parse(cmd, v.get(1).copied()) {
+                Some(Ok(action)) => Some(codec::ChatRequest::Moderate(action)),
+                Some(Err(err)) => {
+                    println!("!!! {err}");
+                    None
+                }
+                None => {
+                    println!("!!! unknown command");
+                    None
+                }
+            },
         }
     } else {
         Some(codec::ChatRequest::Message(m.to_owned()))
@@ -1,106 +1,468 @@
+//! Framing of the chat protocol on TCP connections.
+//!
+//! A connection starts with a handshake, in which the client proposes a protocol version, the
+//! width of the length prefix in front of each frame and how frame payloads are encoded. The
+//! server answers with whether it accepts, and with the largest request frame it takes.
+//!
+//! ```text
+//! hello: b"CHAT" | version: u8 | prefix width: u8 | encoding: u8
+//! reply: b"CHAT" | status: u8 | version: u8 | prefix width: u8 | encoding: u8 | max frame: u32
+//! ```
+//!
+//! After the handshake, each frame is a big-endian length prefix of the agreed width followed by
+//! a [`ChatRequest`] or [`ChatResponse`] in the agreed encoding.
+
 #![allow(dead_code)]
-use std::io;
+use std::{env, fmt, io, str::FromStr, time::Duration};
 
-use actix::prelude::*;
 use actix_codec::{Decoder, Encoder};
 use actix_web::web::{BufMut, BytesMut};
 use byteorder::{BigEndian, ByteOrder};
-use serde::{Deserialize, Serialize};
+use serde::{Serialize, de::DeserializeOwned};
 use serde_json as json;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+pub use crate::protocol::{ChatRequest, ChatResponse};
+
+/// Bytes that both handshake messages start with.
+pub const MAGIC: &[u8; 4] = b"CHAT";
+
+/// Latest version of the framing, and the only one this implementation speaks.
+pub const VERSION: u8 = 1;
+
+/// Environment variable holding the largest request frame the server accepts, in bytes.
+pub const MAX_FRAME_SIZE_VAR: &str = "CHAT_MAX_FRAME_SIZE";
+
+/// Largest request frame the server accepts if [`MAX_FRAME_SIZE_VAR`] is not set.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// How long the server waits for a client's hello.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const HELLO_LEN: usize = 7;
+const REPLY_LEN: usize = 12;
+
+const STATUS_ACCEPTED: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 1;
+
+/// Width of the length prefix in front of each frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixWidth {
+    /// `u16` prefix, for frames of up to 64 KiB.
+    U16,
 
-/// Client request
-#[derive(Serialize, Deserialize, Debug, Message)]
-#[rtype(result = "()")]
-#[serde(tag = "cmd", content = "data")]
-pub enum ChatRequest {
-    /// List rooms
-    List,
-    /// Join rooms
-    Join(String),
-    /// Send message
-    Message(String),
-    /// Ping
-    Ping,
+    /// `u32` prefix, for frames of up to 4 GiB.
+    U32,
 }
 
-/// Server response
-#[derive(Serialize, Deserialize, Debug, Message)]
-#[rtype(result = "()")]
-#[serde(tag = "cmd", content = "data")]
-pub enum ChatResponse {
-    Ping,
+impl PrefixWidth {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            2 => Some(Self::U16),
+            4 => Some(Self::U32),
+            _ => None,
+        }
+    }
 
-    /// List of rooms
-    Rooms(Vec<String>),
+    /// Number of bytes in the prefix.
+    fn len(self) -> usize {
+        match self {
+            Self::U16 => 2,
+            Self::U32 => 4,
+        }
+    }
 
-    /// Joined
-    Joined(String),
+    /// Largest payload that the prefix can describe.
+    fn max_payload(self) -> usize {
+        match self {
+            Self::U16 => u16::MAX as usize,
+            Self::U32 => u32::MAX as usize,
+        }
+    }
+}
 
-    /// Message
-    Message(String),
+impl FromStr for PrefixWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "16" => Ok(Self::U16),
+            "32" => Ok(Self::U32),
+            _ => Err(format!("unknown prefix width {s:?}, expected 16 or 32")),
+        }
+    }
 }
 
-/// Codec for Client -> Server transport
-pub struct ChatCodec;
+/// Encoding of frame payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
 
-impl Decoder for ChatCodec {
-    type Item = ChatRequest;
-    type Error = io::Error;
+impl Encoding {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Json),
+            1 => Some(Self::MessagePack),
+            _ => None,
+        }
+    }
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Json => 0,
+            Self::MessagePack => 1,
+        }
+    }
+
+    fn encode<T: Serialize>(self, item: &T) -> Vec<u8> {
+        // unwrap: requests and responses are plain data that always serializes
+        match self {
+            Self::Json => json::to_vec(item).unwrap(),
+            // the protocol's tagged enums need field names, which MessagePack leaves out by default
+            Self::MessagePack => rmp_serde::to_vec_named(item).unwrap(),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T, String> {
+        match self {
+            Self::Json => json::from_slice(payload).map_err(|err| err.to_string()),
+            Self::MessagePack => rmp_serde::from_slice(payload).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "msgpack" => Ok(Self::MessagePack),
+            _ => Err(format!("unknown encoding {s:?}, expected json or msgpack")),
+        }
+    }
+}
+
+/// Framing agreed on in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub prefix: PrefixWidth,
+    pub encoding: Encoding,
+
+    /// Largest request frame the server accepts, in bytes.
+    pub max_frame_size: usize,
+}
+
+/// Reads the largest request frame to accept from [`MAX_FRAME_SIZE_VAR`], or returns
+/// [`DEFAULT_MAX_FRAME_SIZE`] if it is not set.
+pub fn max_frame_size_from_env() -> io::Result<usize> {
+    match env::var(MAX_FRAME_SIZE_VAR) {
+        Ok(size) => {
+            size.parse().ok().filter(|size| *size > 0).ok_or_else(|| {
+                invalid_data(format!("{MAX_FRAME_SIZE_VAR} must be a positive number"))
+            })
+        }
+        Err(_) => Ok(DEFAULT_MAX_FRAME_SIZE),
+    }
+}
+
+/// Returns whether `res` can be sent to every client, whatever the prefix width and encoding it
+/// agreed on.
+pub fn fits_every_client(res: &ChatResponse) -> bool {
+    [Encoding::Json, Encoding::MessagePack]
+        .into_iter()
+        .all(|encoding| encoding.encode(res).len() <= PrefixWidth::U16.max_payload())
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Server side of the handshake.
+///
+/// Clients asking for something this server does not speak are told what it does speak before
+/// the error is returned.
+pub async fn accept<S>(stream: &mut S, max_frame_size: usize) -> io::Result<Options>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut hello = [0; HELLO_LEN];
+    stream.read_exact(&mut hello).await?;
+
+    let prefix = PrefixWidth::from_byte(hello[5]);
+    let encoding = Encoding::from_byte(hello[6]);
+
+    let (status, options) = match (
+        &hello[..4] == MAGIC && hello[4] == VERSION,
+        prefix,
+        encoding,
+    ) {
+        (true, Some(prefix), Some(encoding)) => (
+            STATUS_ACCEPTED,
+            Options {
+                prefix,
+                encoding,
+                max_frame_size: max_frame_size.min(prefix.max_payload()),
+            },
+        ),
+        _ => (
+            STATUS_UNSUPPORTED,
+            Options {
+                prefix: PrefixWidth::U32,
+                encoding: Encoding::Json,
+                max_frame_size,
+            },
+        ),
+    };
+
+    let mut reply = [0; REPLY_LEN];
+    reply[..4].copy_from_slice(MAGIC);
+    reply[4] = status;
+    reply[5] = VERSION;
+    reply[6] = options.prefix.len() as u8;
+    reply[7] = options.encoding.to_byte();
+    BigEndian::write_u32(
+        &mut reply[8..],
+        options.max_frame_size.min(u32::MAX as usize) as u32,
+    );
+    stream.write_all(&reply).await?;
+
+    if status == STATUS_ACCEPTED {
+        Ok(options)
+    } else {
+        Err(invalid_data(format!("unsupported handshake {hello:?}")))
+    }
+}
+
+/// Client side of the handshake, proposing `prefix` and `encoding`.
+pub async fn connect<S>(
+    stream: &mut S,
+    prefix: PrefixWidth,
+    encoding: Encoding,
+) -> io::Result<Options>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut hello = [0; HELLO_LEN];
+    hello[..4].copy_from_slice(MAGIC);
+    hello[4] = VERSION;
+    hello[5] = prefix.len() as u8;
+    hello[6] = encoding.to_byte();
+    stream.write_all(&hello).await?;
+
+    let mut reply = [0; REPLY_LEN];
+    stream.read_exact(&mut reply).await?;
+
+    if &reply[..4] != MAGIC {
+        return Err(invalid_data("server does not speak the chat protocol"));
+    }
+
+    if reply[4] != STATUS_ACCEPTED {
+        return Err(invalid_data(format!(
+            "server refused the handshake, it speaks version {} with a {}-byte prefix and encoding {}",
+            reply[5], reply[6], reply[7]
+        )));
+    }
+
+    Ok(Options {
+        prefix,
+        encoding,
+        max_frame_size: BigEndian::read_u32(&reply[8..]) as usize,
+    })
+}
+
+/// Frame that could not be decoded, which the connection survives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame is longer than the limit, and was skipped.
+    TooLarge { size: usize, max: usize },
+
+    /// The payload is not a valid message.
+    Invalid(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { size, max } => write!(
+                f,
+                "frame of {size} bytes is larger than the limit of {max} bytes"
+            ),
+            Self::Invalid(err) => write!(f, "invalid request: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Length-prefixed framing shared by both codecs.
+#[derive(Debug)]
+struct Framing {
+    prefix: PrefixWidth,
+
+    /// Largest frame decoded, longer ones are skipped.
+    max_frame_size: usize,
+
+    /// Bytes of a skipped frame that have yet to arrive.
+    discarding: usize,
+}
+
+impl Framing {
+    fn new(prefix: PrefixWidth, max_frame_size: usize) -> Self {
+        Self {
+            prefix,
+            max_frame_size: max_frame_size.min(prefix.max_payload()),
+            discarding: 0,
+        }
+    }
+
+    fn decode(&mut self, src: &mut BytesMut) -> Option<Result<BytesMut, FrameError>> {
+        if self.discarding > 0 {
+            let skip = self.discarding.min(src.len());
+            let _ = src.split_to(skip);
+            self.discarding -= skip;
+
+            if self.discarding > 0 {
+                return None;
             }
-            BigEndian::read_u16(src.as_ref()) as usize
+        }
+
+        let prefix_len = self.prefix.len();
+        if src.len() < prefix_len {
+            return None;
+        }
+
+        let size = match self.prefix {
+            PrefixWidth::U16 => BigEndian::read_u16(src) as usize,
+            PrefixWidth::U32 => BigEndian::read_u32(src) as usize,
         };
 
-        if src.len() >= size + 2 {
-            let _ = src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<ChatRequest>(&buf)?))
-        } else {
-            Ok(None)
+        if size > self.max_frame_size {
+            let _ = src.split_to(prefix_len);
+            self.discarding = size;
+
+            return Some(Err(FrameError::TooLarge {
+                size,
+                max: self.max_frame_size,
+            }));
+        }
+
+        if src.len() < prefix_len + size {
+            src.reserve(prefix_len + size - src.len());
+            return None;
+        }
+
+        let _ = src.split_to(prefix_len);
+        Some(Ok(src.split_to(size)))
+    }
+
+    fn encode(&self, payload: &[u8], dst: &mut BytesMut) -> io::Result<()> {
+        // frames that do not fit in the prefix would corrupt the stream
+        if payload.len() > self.prefix.max_payload() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "frame of {} bytes does not fit a {}-byte length prefix",
+                    payload.len(),
+                    self.prefix.len()
+                ),
+            ));
+        }
+
+        dst.reserve(self.prefix.len() + payload.len());
+        match self.prefix {
+            PrefixWidth::U16 => dst.put_u16(payload.len() as u16),
+            PrefixWidth::U32 => dst.put_u32(payload.len() as u32),
+        }
+        dst.put(payload);
+
+        Ok(())
+    }
+}
+
+/// Codec for Client -> Server transport
+///
+/// Frames that are too large or malformed are decoded as errors, to be answered with an error
+/// response, rather than ending the stream. Likewise, responses too large for the prefix are
+/// replaced with an error response.
+#[derive(Debug)]
+pub struct ChatCodec {
+    framing: Framing,
+    encoding: Encoding,
+}
+
+impl ChatCodec {
+    pub fn new(options: Options) -> Self {
+        Self {
+            framing: Framing::new(options.prefix, options.max_frame_size),
+            encoding: options.encoding,
         }
     }
 }
 
+impl Decoder for ChatCodec {
+    type Item = Result<ChatRequest, FrameError>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        Ok(self.framing.decode(src).map(|frame| {
+            frame.and_then(|payload| self.encoding.decode(&payload).map_err(FrameError::Invalid))
+        }))
+    }
+}
+
 impl Encoder<ChatResponse> for ChatCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: ChatResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
+        let payload = self.encoding.encode(&msg);
+        let max = self.framing.prefix.max_payload();
 
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16(msg_ref.len() as u16);
-        dst.put(msg_ref);
+        // failing to encode would end the connection
+        if payload.len() > max {
+            let err = ChatResponse::error(format!(
+                "dropped a response of {} bytes, larger than the limit of {max} bytes",
+                payload.len()
+            ));
+            return self.framing.encode(&self.encoding.encode(&err), dst);
+        }
 
-        Ok(())
+        self.framing.encode(&payload, dst)
     }
 }
 
 /// Codec for Server -> Client transport
-pub struct ClientChatCodec;
+#[derive(Debug)]
+pub struct ClientChatCodec {
+    framing: Framing,
+    encoding: Encoding,
+}
+
+impl ClientChatCodec {
+    pub fn new(options: Options) -> Self {
+        Self {
+            // the server is trusted to send sensible frames
+            framing: Framing::new(options.prefix, usize::MAX),
+            encoding: options.encoding,
+        }
+    }
+}
 
 impl Decoder for ClientChatCodec {
     type Item = ChatResponse;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            }
-            BigEndian::read_u16(src.as_ref()) as usize
-        };
-
-        if src.len() >= size + 2 {
-            let _ = src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<ChatResponse>(&buf)?))
-        } else {
-            Ok(None)
+        match self.framing.decode(src) {
+            Some(Ok(payload)) => self
+                .encoding
+                .decode(&payload)
+                .map(Some)
+                .map_err(invalid_data),
+            Some(Err(err)) => Err(invalid_data(err.to_string())),
+            None => Ok(None),
         }
     }
 }
@@ -109,13 +471,109 @@ impl Encoder<ChatRequest> for ClientChatCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: ChatRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
+        self.framing.encode(&self.encoding.encode(&msg), dst)
+    }
+}
 
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16(msg_ref.len() as u16);
-        dst.put(msg_ref);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(())
+    fn options(prefix: PrefixWidth, encoding: Encoding, max_frame_size: usize) -> Options {
+        Options {
+            prefix,
+            encoding,
+            max_frame_size,
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        let (client_options, server_options) = tokio::join!(
+            connect(&mut client, PrefixWidth::U16, Encoding::MessagePack),
+            accept(&mut server, 1024 * 1024),
+        );
+
+        // a u16 prefix caps the frame size
+        let expected = options(PrefixWidth::U16, Encoding::MessagePack, u16::MAX as usize);
+        assert_eq!(client_options.unwrap(), expected);
+        assert_eq!(server_options.unwrap(), expected);
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(b"CHAT\x02\x04\x00").await.unwrap();
+        assert!(accept(&mut server, 1024).await.is_err());
+
+        let mut reply = [0; REPLY_LEN];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..6], b"CHAT\x01\x01");
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            let options = options(PrefixWidth::U32, encoding, 1024 * 1024);
+            let mut client = ClientChatCodec::new(options);
+            let mut server = ChatCodec::new(options);
+
+            // larger than a u16 prefix could describe
+            let req = ChatRequest::Message("a".repeat(100_000));
+            let mut buf = BytesMut::new();
+            client.encode(req.clone(), &mut buf).unwrap();
+            assert_eq!(server.decode(&mut buf).unwrap(), Some(Ok(req)));
+
+            let res = ChatResponse::Presence {
+                room: "main".to_owned(),
+                user: "alice".to_owned(),
+                presence: crate::protocol::Presence::Away,
+            };
+            server.encode(res.clone(), &mut buf).unwrap();
+            assert_eq!(client.decode(&mut buf).unwrap(), Some(res));
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_skipped() {
+        let options = options(PrefixWidth::U16, Encoding::Json, 16);
+        let mut client = ClientChatCodec::new(options);
+        let mut server = ChatCodec::new(options);
+
+        let mut buf = BytesMut::new();
+        client
+            .encode(
+                ChatRequest::Message("too long for the limit".to_owned()),
+                &mut buf,
+            )
+            .unwrap();
+        client.encode(ChatRequest::List, &mut buf).unwrap();
+
+        // the oversized frame arrives in pieces
+        let mut received = buf.split_to(5);
+        assert_eq!(
+            server.decode(&mut received).unwrap(),
+            Some(Err(FrameError::TooLarge { size: 49, max: 16 }))
+        );
+        assert_eq!(server.decode(&mut received).unwrap(), None);
+
+        received.unsplit(buf);
+        assert_eq!(
+            server.decode(&mut received).unwrap(),
+            Some(Ok(ChatRequest::List))
+        );
+
+        // u16 prefixes cannot describe larger frames, which are refused instead of truncated
+        let long = ChatRequest::Message("a".repeat(70_000));
+        assert!(client.encode(long, &mut BytesMut::new()).is_err());
+
+        // responses are replaced with an error
+        let long = ChatResponse::notice(None, "a".repeat(70_000));
+        assert!(!fits_every_client(&long));
+        let mut buf = BytesMut::new();
+        server.encode(long, &mut buf).unwrap();
+        let Some(ChatResponse::Error(err)) = client.decode(&mut buf).unwrap() else {
+            panic!("oversized response was not replaced");
+        };
+        assert!(err.contains("limit of 65535 bytes"), "{err}");
     }
 }
@@ -8,6 +8,11 @@ use actix_web_actors::ws;
 mod codec;
 mod server;
 mod session;
+mod tls;
+
+use websocket_chat_common::{permissions, protocol};
+
+use self::protocol::{ChatRequest, ChatResponse};
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -29,8 +34,6 @@ async fn chat_route(
         WsChatSession {
             id: 0,
             hb: Instant::now(),
-            room: "main".to_owned(),
-            name: None,
             addr: srv.get_ref().clone(),
         },
         &req,
@@ -44,10 +47,6 @@ struct WsChatSession {
     /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
     /// otherwise we drop connection.
     hb: Instant,
-    /// joined room
-    room: String,
-    /// peer name
-    name: Option<String>,
     /// Chat server
     addr: Addr<server::ChatServer>,
 }
@@ -74,7 +73,10 @@ impl Actor for WsChatSession {
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok((id, name)) => {
+                        act.id = id;
+                        send(ctx, &ChatResponse::Name(name));
+                    }
                     // something is wrong with chat server
                     _ => ctx.stop(),
                 }
@@ -95,7 +97,7 @@ impl Handler<session::Message> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: session::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        send(ctx, &msg.0);
     }
 }
 
@@ -119,71 +121,10 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Text(text) => {
-                let m = text.trim();
-                // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            println!("List rooms");
-                            self.addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
-                                        }
-                                        _ => println!("Something is wrong"),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) pauses all events in context,
-                            // so actor wont receive any new messages until it get list
-                            // of rooms back
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                v[1].clone_into(&mut self.room);
-                                self.addr.do_send(server::Join {
-                                    id: self.id,
-                                    name: self.room.clone(),
-                                });
-
-                                ctx.text("joined");
-                            } else {
-                                ctx.text("!!! room name is required");
-                            }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {m:?}")),
-                    }
-                } else {
-                    let msg = if let Some(ref name) = self.name {
-                        format!("{name}: {m}")
-                    } else {
-                        m.to_owned()
-                    };
-                    // send message to chat server
-                    self.addr.do_send(server::Message {
-                        id: self.id,
-                        msg,
-                        room: self.room.clone(),
-                    })
-                }
-            }
+            ws::Message::Text(text) => match serde_json::from_str(&text) {
+                Ok(req) => self.handle_request(req, ctx),
+                Err(err) => send(ctx, &ChatResponse::error(format!("invalid request: {err}"))),
+            },
             ws::Message::Binary(_) => println!("Unexpected binary"),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
@@ -195,6 +136,30 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
 }
 
 impl WsChatSession {
+    /// Forwards a request of the peer to the chat server, which treats it the
+    /// same as one from a TCP session
+    fn handle_request(&mut self, req: ChatRequest, ctx: &mut ws::WebsocketContext<Self>) {
+        if req == ChatRequest::Ping {
+            self.hb = Instant::now();
+            return;
+        }
+
+        // .wait(ctx) pauses all events in context, so responses are sent in the
+        // same order as requests
+        self.addr
+            .send(server::Request { id: self.id, req })
+            .into_actor(self)
+            .then(|res, _, ctx| {
+                match res {
+                    Ok(Some(res)) => send(ctx, &res),
+                    Ok(None) => {}
+                    _ => println!("Something is wrong"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
     /// helper method that sends ping to client every 5 seconds (HEARTBEAT_INTERVAL).
     ///
     /// also this method checks heartbeats from client
@@ -220,6 +185,12 @@ impl WsChatSession {
     }
 }
 
+/// Sends a response to the peer as a JSON text frame
+fn send(ctx: &mut ws::WebsocketContext<WsChatSession>, res: &ChatResponse) {
+    // unwrap: responses are plain data that always serializes
+    ctx.text(serde_json::to_string(res).unwrap());
+}
+
 // the actor-based WebSocket examples REQUIRE `actix_web::main` for actor support
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -228,9 +199,16 @@ async fn main() -> std::io::Result<()> {
     // start chat server actor
     let server = server::ChatServer::default().start();
 
-    // start TCP server in separate thread
-    let srv = server.clone();
-    session::tcp_server("127.0.0.1:12345", srv);
+    // start TCP server, which shares the chat server with WebSocket sessions
+    // request frames are limited to `CHAT_MAX_FRAME_SIZE` bytes, 1 MiB by default
+    let max_frame_size = codec::max_frame_size_from_env()?;
+    // TLS is used if `CHAT_TLS_CERT` and `CHAT_TLS_KEY` are set, and client
+    // certificates are required if `CHAT_TLS_CLIENT_CA` is set too
+    let tls = tls::acceptor_from_env()?;
+    let scheme = if tls.is_some() { "TLS" } else { "TCP" };
+    let tcp_addr =
+        session::tcp_server(("127.0.0.1", 12345), server.clone(), max_frame_size, tls).await?;
+    log::info!("starting {scheme} server at {tcp_addr}");
 
     log::info!("starting HTTP+WebSocket server at http://localhost:8080");
 
@@ -248,3 +226,295 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    use actix_codec::Framed;
+    use awc::ws::{Frame, Message};
+    use futures_util::{Sink, SinkExt as _, Stream, StreamExt as _};
+    use tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::{TcpStream, ToSocketAddrs},
+        time::timeout,
+    };
+
+    use super::*;
+    use crate::{
+        codec::{ClientChatCodec, Encoding, PrefixWidth},
+        protocol::ChatMessage,
+    };
+
+    /// Waits for the next response to a TCP client, skipping heartbeats
+    async fn next_tcp<T>(tcp: &mut Framed<T, ClientChatCodec>) -> ChatResponse
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            let res = timeout(Duration::from_secs(5), tcp.next()).await.unwrap();
+
+            match res.unwrap().unwrap() {
+                ChatResponse::Ping => {}
+                res => return res,
+            }
+        }
+    }
+
+    /// Waits for the next response to a TCP client that is not a notice
+    async fn next_tcp_reply<T>(tcp: &mut Framed<T, ClientChatCodec>) -> ChatResponse
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            match next_tcp(tcp).await {
+                ChatResponse::Notice(_) => {}
+                res => return res,
+            }
+        }
+    }
+
+    /// Connects a TCP client using a `prefix`-wide length prefix and JSON,
+    /// once it is given a name
+    async fn connect_tcp(
+        addr: impl ToSocketAddrs,
+        prefix: PrefixWidth,
+    ) -> Framed<TcpStream, ClientChatCodec> {
+        let mut io = TcpStream::connect(addr).await.unwrap();
+        let options = codec::connect(&mut io, prefix, Encoding::Json)
+            .await
+            .unwrap();
+
+        let mut tcp = Framed::new(io, ClientChatCodec::new(options));
+        let ChatResponse::Name(_) = next_tcp(&mut tcp).await else {
+            panic!("TCP client was not given a name");
+        };
+        tcp
+    }
+
+    /// Waits for the next response to a WebSocket client, skipping heartbeats
+    async fn next_ws<S, E>(ws: &mut S) -> ChatResponse
+    where
+        S: Stream<Item = Result<Frame, E>> + Unpin,
+        E: std::fmt::Debug,
+    {
+        loop {
+            let frame = timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+
+            if let Frame::Text(text) = frame.unwrap().unwrap() {
+                return serde_json::from_slice(&text).unwrap();
+            }
+        }
+    }
+
+    async fn send_ws<S>(ws: &mut S, req: &ChatRequest)
+    where
+        S: Sink<Message> + Unpin,
+        S::Error: std::fmt::Debug,
+    {
+        let text = serde_json::to_string(req).unwrap();
+        ws.send(Message::Text(text.into())).await.unwrap();
+    }
+
+    fn text_of(res: ChatResponse) -> ChatMessage {
+        match res {
+            ChatResponse::Message(msg) => msg,
+            res => panic!("expected message, got {res:?}"),
+        }
+    }
+
+    /// Connects to a TLS server using the bundled certificates, presenting the
+    /// client certificate if `identity` is set
+    async fn connect_tls(
+        addr: impl ToSocketAddrs,
+        identity: bool,
+    ) -> std::io::Result<Framed<tls::Connection, ClientChatCodec>> {
+        let certs = Path::new("certs");
+        let (cert, key) = (certs.join("client-cert.pem"), certs.join("client-key.pem"));
+        let identity = identity.then_some((cert.as_path(), key.as_path()));
+        let config = tls::client_config(&certs.join("rootCA.pem"), identity)?;
+
+        let io = TcpStream::connect(addr).await?;
+        let mut io = tls::connect(config, "localhost", io).await?;
+        let options = codec::connect(&mut io, PrefixWidth::U32, Encoding::Json).await?;
+
+        Ok(Framed::new(io, ClientChatCodec::new(options)))
+    }
+
+    #[actix_web::test]
+    async fn tls_server_requires_client_certificates() {
+        let certs = Path::new("certs");
+        let config = tls::server_config(
+            &certs.join("server-cert.pem"),
+            &certs.join("server-key.pem"),
+            Some(&certs.join("rootCA.pem")),
+        )
+        .unwrap();
+
+        let server = server::ChatServer::default().start();
+        let tcp_addr = session::tcp_server(
+            "127.0.0.1:0",
+            server,
+            codec::DEFAULT_MAX_FRAME_SIZE,
+            Some(tls::TlsAcceptor::from(Arc::new(config))),
+        )
+        .await
+        .unwrap();
+
+        let mut tcp = connect_tls(tcp_addr, true).await.unwrap();
+        let ChatResponse::Name(_) = next_tcp(&mut tcp).await else {
+            panic!("TLS client was not given a name");
+        };
+        tcp.send(ChatRequest::List).await.unwrap();
+        assert_eq!(
+            next_tcp(&mut tcp).await,
+            ChatResponse::Rooms(vec!["main".to_owned()])
+        );
+
+        // without a certificate the server ends the connection during the
+        // handshake
+        assert!(connect_tls(tcp_addr, false).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn oversized_tcp_frames_get_an_error() {
+        let server = server::ChatServer::default().start();
+        let tcp_addr = session::tcp_server("127.0.0.1:0", server, 64, None)
+            .await
+            .unwrap();
+
+        let mut io = TcpStream::connect(tcp_addr).await.unwrap();
+        let options = codec::connect(&mut io, PrefixWidth::U16, Encoding::Json)
+            .await
+            .unwrap();
+        assert_eq!(options.max_frame_size, 64);
+
+        let mut tcp = Framed::new(io, ClientChatCodec::new(options));
+        let ChatResponse::Name(_) = next_tcp(&mut tcp).await else {
+            panic!("TCP client was not given a name");
+        };
+
+        tcp.send(ChatRequest::Message("a".repeat(1000)))
+            .await
+            .unwrap();
+        let ChatResponse::Error(err) = next_tcp(&mut tcp).await else {
+            panic!("oversized frame was not refused");
+        };
+        assert!(err.contains("limit of 64 bytes"), "{err}");
+
+        // the connection carries on after the skipped frame
+        tcp.send(ChatRequest::List).await.unwrap();
+        assert_eq!(
+            next_tcp(&mut tcp).await,
+            ChatResponse::Rooms(vec!["main".to_owned()])
+        );
+    }
+
+    #[actix_web::test]
+    async fn long_messages_do_not_drop_clients_with_narrow_prefixes() {
+        let server = server::ChatServer::default().start();
+        let tcp_addr =
+            session::tcp_server("127.0.0.1:0", server, codec::DEFAULT_MAX_FRAME_SIZE, None)
+                .await
+                .unwrap();
+
+        let mut wide = connect_tcp(tcp_addr, PrefixWidth::U32).await;
+        let mut narrow = connect_tcp(tcp_addr, PrefixWidth::U16).await;
+
+        // fits the sender's frame limit, but not a u16 prefix
+        wide.send(ChatRequest::Message("a".repeat(70_000)))
+            .await
+            .unwrap();
+        let ChatResponse::Error(err) = next_tcp_reply(&mut wide).await else {
+            panic!("long message was not refused");
+        };
+        assert!(err.contains("too long"), "{err}");
+
+        // the client with the narrow prefix is still connected
+        wide.send(ChatRequest::Message("hello".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(text_of(next_tcp_reply(&mut narrow).await).text, "hello");
+    }
+
+    #[actix_web::test]
+    async fn tcp_and_websocket_clients_share_rooms() {
+        let server = server::ChatServer::default().start();
+        let tcp_addr = session::tcp_server(
+            "127.0.0.1:0",
+            server.clone(),
+            codec::DEFAULT_MAX_FRAME_SIZE,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let mut srv = actix_test::start(move || {
+            App::new()
+                .app_data(web::Data::new(server.clone()))
+                .service(web::resource("/ws").to(chat_route))
+        });
+
+        let mut io = TcpStream::connect(tcp_addr).await.unwrap();
+        let options = codec::connect(&mut io, PrefixWidth::U32, Encoding::MessagePack)
+            .await
+            .unwrap();
+        let mut tcp = Framed::new(io, ClientChatCodec::new(options));
+        let ChatResponse::Name(tcp_name) = next_tcp(&mut tcp).await else {
+            panic!("TCP client was not given a name");
+        };
+
+        let mut ws = srv.ws_at("/ws").await.unwrap();
+        let ChatResponse::Name(ws_name) = next_ws(&mut ws).await else {
+            panic!("WebSocket client was not given a name");
+        };
+
+        // the TCP client sees the WebSocket client arrive in the main room
+        let ChatResponse::Notice(notice) = next_tcp(&mut tcp).await else {
+            panic!("TCP client was not told about the WebSocket client");
+        };
+        assert_eq!(notice.text, format!("{ws_name} joined"));
+
+        let join = ChatRequest::Join {
+            room: "bridge".to_owned(),
+            password: None,
+        };
+
+        tcp.send(join.clone()).await.unwrap();
+        assert_eq!(
+            next_tcp(&mut tcp).await,
+            ChatResponse::Joined("bridge".to_owned())
+        );
+
+        send_ws(&mut ws, &join).await;
+        // the WebSocket client was told the TCP client left the main room first
+        let ChatResponse::Notice(notice) = next_ws(&mut ws).await else {
+            panic!("WebSocket client was not told about the TCP client leaving");
+        };
+        assert_eq!(notice.text, format!("{tcp_name} left"));
+        assert_eq!(
+            next_ws(&mut ws).await,
+            ChatResponse::Joined("bridge".to_owned())
+        );
+        let ChatResponse::Notice(notice) = next_tcp(&mut tcp).await else {
+            panic!("TCP client was not told about the WebSocket client joining");
+        };
+        assert_eq!(notice.room.as_deref(), Some("bridge"));
+
+        tcp.send(ChatRequest::Message("hello from tcp".to_owned()))
+            .await
+            .unwrap();
+        let from_tcp = text_of(next_ws(&mut ws).await);
+        assert_eq!(from_tcp.sender, tcp_name);
+        assert_eq!(from_tcp.room.as_deref(), Some("bridge"));
+        assert_eq!(from_tcp.text, "hello from tcp");
+        assert_eq!(text_of(next_tcp(&mut tcp).await), from_tcp);
+
+        send_ws(&mut ws, &ChatRequest::Message("hello from ws".to_owned())).await;
+        let from_ws = text_of(next_tcp(&mut tcp).await);
+        assert_eq!(from_ws.sender, ws_name);
+        assert_eq!(from_ws.text, "hello from ws");
+        assert!(from_ws.id > from_tcp.id);
+        assert_eq!(text_of(next_ws(&mut ws).await), from_ws);
+    }
+}
@@ -1,19 +1,34 @@
 //! `ChatServer` is an actor. It maintains list of connection client session.
 //! And manages available rooms. Peers send messages to other peers in same
 //! room through `ChatServer`.
+//!
+//! Who may join and talk in a room is decided by [`Permissions`], which the
+//! owner of each room controls. Users are identified by their name, which is
+//! unique among connected users.
+//!
+//! TCP and WebSocket sessions register with the same `ChatServer` and send it
+//! the same [`Request`]s, so they share rooms. Sessions are sent
+//! [`ChatResponse`]s, whichever transport they use. Messages are refused if
+//! they would not fit the narrowest length prefix, so that one client cannot
+//! send what others in its room cannot receive.
 
 use std::collections::{HashMap, HashSet};
 
 use actix::prelude::*;
 use rand::Rng as _;
 
-use crate::session;
+use crate::{
+    codec,
+    permissions::{MAIN_ROOM, Moderation, Permissions, RoomError},
+    protocol::{ChatMessage, ChatRequest, ChatResponse},
+    session,
+};
 
 /// Message for chat server communications
 ///
-/// New chat session is created
+/// New chat session is created, replies with its id and name
 #[derive(Message)]
-#[rtype(u64)]
+#[rtype(result = "(u64, String)")]
 pub struct Connect {
     pub addr: Recipient<session::Message>,
 }
@@ -25,68 +40,108 @@ pub struct Disconnect {
     pub id: u64,
 }
 
-/// Send message to specific room
+/// Request of a session, whichever transport it uses
+///
+/// Replies with the response for the requesting session, if there is one.
+/// Heartbeat pings are answered by the sessions themselves.
 #[derive(Message)]
-#[rtype(result = "()")]
-pub struct Message {
+#[rtype(result = "Option<ChatResponse>")]
+pub struct Request {
     /// Id of the client session
     pub id: u64,
-    /// Peer message
-    pub msg: String,
-    /// Room name
-    pub room: String,
+    pub req: ChatRequest,
 }
 
-/// List of available rooms
-pub struct ListRooms;
-
-impl actix::Message for ListRooms {
-    type Result = Vec<String>;
-}
-
-/// Join room, if room does not exists create new one.
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct Join {
-    /// Client id
-    pub id: u64,
-    /// Room name
-    pub name: String,
+/// A connected session
+struct Session {
+    addr: Recipient<session::Message>,
+    /// Unique name of the session's user
+    name: String,
 }
 
 /// `ChatServer` manages chat rooms and responsible for coordinating chat
 /// session. implementation is super primitive
 pub struct ChatServer {
-    sessions: HashMap<u64, Recipient<session::Message>>,
+    sessions: HashMap<u64, Session>,
     rooms: HashMap<String, HashSet<u64>>,
+    permissions: Permissions,
+    /// ID of the last message sent to a room
+    last_message_id: u64,
 }
 
 impl Default for ChatServer {
     fn default() -> ChatServer {
         // default room
         let mut rooms = HashMap::new();
-        rooms.insert("main".to_owned(), HashSet::new());
+        rooms.insert(MAIN_ROOM.to_owned(), HashSet::new());
 
         ChatServer {
             sessions: HashMap::new(),
             rooms,
+            permissions: Permissions::default(),
+            last_message_id: 0,
         }
     }
 }
 
 impl ChatServer {
     /// Send message to all users in the room
-    fn send_message(&self, room: &str, message: &str, skip_id: u64) {
+    fn send_message(&self, room: &str, message: &ChatResponse, skip_id: u64) {
         if let Some(sessions) = self.rooms.get(room) {
             for id in sessions {
                 if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        addr.do_send(session::Message(message.to_owned()));
+                    if let Some(session) = self.sessions.get(id) {
+                        session.addr.do_send(session::Message(message.clone()));
                     }
                 }
             }
         }
     }
+
+    /// Send a notice about a room to all users in it
+    fn send_notice(&self, room: &str, text: String, skip_id: u64) {
+        self.send_message(room, &ChatResponse::notice(Some(room), text), skip_id);
+    }
+
+    /// Send message to a single session
+    fn send_to(&self, id: u64, message: ChatResponse) {
+        if let Some(session) = self.sessions.get(&id) {
+            session.addr.do_send(session::Message(message));
+        }
+    }
+
+    /// Returns the name of a session's user and the room it is in
+    fn name_and_room(&self, id: u64) -> Option<(String, String)> {
+        let name = self.sessions.get(&id)?.name.clone();
+        let room = self
+            .rooms
+            .iter()
+            .find_map(|(room, sessions)| sessions.contains(&id).then(|| room.clone()))?;
+
+        Some((name, room))
+    }
+
+    /// Returns the session of the user with the given name
+    fn id_of(&self, name: &str) -> Option<u64> {
+        self.sessions
+            .iter()
+            .find_map(|(id, session)| (session.name == name).then_some(*id))
+    }
+
+    /// Remove session from all rooms, telling the other users in them
+    fn leave_rooms(&mut self, id: u64, name: &str) {
+        let mut rooms = Vec::new();
+
+        for (room, sessions) in &mut self.rooms {
+            if sessions.remove(&id) {
+                rooms.push(room.to_owned());
+            }
+        }
+
+        for room in rooms {
+            self.send_notice(&room, format!("{name} left"), 0);
+        }
+    }
 }
 
 /// Make actor from `ChatServer`
@@ -100,23 +155,36 @@ impl Actor for ChatServer {
 ///
 /// Register new session and assign unique id to this session
 impl Handler<Connect> for ChatServer {
-    type Result = u64;
+    type Result = MessageResult<Connect>;
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        println!("Someone joined");
+        // register session with random id, named after it until the user picks a name
+        let (id, name) = loop {
+            let id = rand::rng().random::<u64>();
+            let name = format!("anon-{:04x}", id >> 48);
+
+            if !self.sessions.contains_key(&id) && self.id_of(&name).is_none() {
+                break (id, name);
+            }
+        };
+        println!("{name} joined");
 
         // notify all users in same room
-        self.send_message("main", "Someone joined", 0);
+        self.send_notice(MAIN_ROOM, format!("{name} joined"), 0);
 
-        // register session with random id
-        let id = rand::rng().random::<u64>();
-        self.sessions.insert(id, msg.addr);
+        self.sessions.insert(
+            id,
+            Session {
+                addr: msg.addr,
+                name: name.clone(),
+            },
+        );
 
         // auto join session to main room
-        self.rooms.get_mut("main").unwrap().insert(id);
+        self.rooms.get_mut(MAIN_ROOM).unwrap().insert(id);
 
         // send id back
-        id
+        MessageResult((id, name))
     }
 }
 
@@ -125,74 +193,171 @@ impl Handler<Disconnect> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        println!("Someone disconnected");
+        // remove address
+        let Some(Session { name, .. }) = self.sessions.remove(&msg.id) else {
+            return;
+        };
+        println!("{name} disconnected");
 
-        let mut rooms: Vec<String> = Vec::new();
+        // the name is free to be taken by someone else now
+        self.permissions.forget_user(&name);
 
-        // remove address
-        if self.sessions.remove(&msg.id).is_some() {
-            // remove session from all rooms
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
-                }
-            }
-        }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
-        }
+        self.leave_rooms(msg.id, &name);
     }
 }
 
-/// Handler for Message message.
-impl Handler<Message> for ChatServer {
-    type Result = ();
+/// Handler for Request message.
+impl Handler<Request> for ChatServer {
+    type Result = Option<ChatResponse>;
 
-    fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
-        self.send_message(&msg.room, msg.msg.as_str(), msg.id);
+    fn handle(&mut self, msg: Request, _: &mut Context<Self>) -> Self::Result {
+        let Request { id, req } = msg;
+
+        let res = match req {
+            ChatRequest::List => return Some(ChatResponse::Rooms(self.list_rooms())),
+            ChatRequest::History { .. } => {
+                return Some(ChatResponse::error("message history is not kept"));
+            }
+            ChatRequest::Typing(_)
+            | ChatRequest::Read { .. }
+            | ChatRequest::Away(_)
+            | ChatRequest::Members => {
+                return Some(ChatResponse::error(
+                    "typing, read receipts and presence are not supported by this server",
+                ));
+            }
+            ChatRequest::Ping => return None,
+            ChatRequest::Join { room, password } => self
+                .join(id, &room, password.as_deref())
+                .map(|()| Some(ChatResponse::Joined(room))),
+            ChatRequest::Name(name) => self.set_name(id, name).map(|()| None),
+            ChatRequest::Message(msg) => self.send_chat_message(id, msg).map(|()| None),
+            ChatRequest::Direct { to, text } => self.send_direct(id, &to, text).map(|()| None),
+            ChatRequest::Moderate(action) => self.moderate(id, action).map(|()| None),
+        };
+
+        res.unwrap_or_else(|err| Some(ChatResponse::error(err)))
     }
 }
 
-/// Handler for `ListRooms` message.
-impl Handler<ListRooms> for ChatServer {
-    type Result = MessageResult<ListRooms>;
+impl ChatServer {
+    /// Send message to the room the session is in
+    fn send_chat_message(&mut self, id: u64, msg: String) -> Result<(), RoomError> {
+        let Some((name, room)) = self.name_and_room(id) else {
+            return Ok(());
+        };
+
+        self.permissions.check_send(&room, &name)?;
+
+        // the sender gets the message back too, so that it learns its ID
+        let message = ChatMessage::new(self.last_message_id + 1, name, Some(&room), msg);
+        let message = ChatResponse::Message(message);
+        check_size(&message)?;
+
+        self.last_message_id += 1;
+        self.send_message(&room, &message, 0);
+        Ok(())
+    }
 
-    fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
-        let mut rooms = Vec::new();
+    /// Send a private message to another user
+    fn send_direct(&self, id: u64, to: &str, msg: String) -> Result<(), RoomError> {
+        let from = self.sessions.get(&id).ok_or(RoomError::UnknownUser)?;
+        let to = self.id_of(to).ok_or(RoomError::UnknownUser)?;
 
-        for key in self.rooms.keys() {
-            rooms.push(key.to_owned())
-        }
+        let message = ChatResponse::Message(ChatMessage::new(0, &from.name, None, msg));
+        check_size(&message)?;
 
-        MessageResult(rooms)
+        self.send_to(to, message);
+        Ok(())
     }
-}
 
-/// Join room, send disconnect message to old room
-/// send join message to new room
-impl Handler<Join> for ChatServer {
-    type Result = ();
+    /// Moderate the room the session is in, which only its owner may do
+    ///
+    /// Users who are kicked or banned are moved back to the main room.
+    fn moderate(&mut self, id: u64, action: Moderation) -> Result<(), RoomError> {
+        let Some((name, room)) = self.name_and_room(id) else {
+            return Ok(());
+        };
 
-    fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
-        let Join { id, name } = msg;
-        let mut rooms = Vec::new();
+        self.permissions.moderate(&room, &name, &action)?;
 
-        // remove session from all rooms
-        for (n, sessions) in &mut self.rooms {
-            if sessions.remove(&id) {
-                rooms.push(n.to_owned());
+        if let Moderation::Invite(invited) = &action {
+            if let Some(invited) = self.id_of(invited) {
+                let invite = format!("{name} invited you to {room}");
+                self.send_to(invited, ChatResponse::notice(Some(&room), invite));
             }
         }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
+
+        if let Some(target) = action.removes().and_then(|target| self.id_of(target)) {
+            let in_room = self
+                .rooms
+                .get_mut(&room)
+                .is_some_and(|sessions| sessions.remove(&target));
+
+            if in_room {
+                let removal = format!("you were removed from {room}");
+                self.send_to(target, ChatResponse::notice(Some(&room), removal));
+                self.send_to(target, ChatResponse::Joined(MAIN_ROOM.to_owned()));
+                self.rooms.get_mut(MAIN_ROOM).unwrap().insert(target);
+            }
         }
 
-        if self.rooms.get_mut(&name).is_none() {
-            self.rooms.insert(name.clone(), HashSet::new());
+        self.send_notice(&room, action.announcement(), 0);
+        Ok(())
+    }
+
+    /// Change the name of the session's user, which must not be used by anyone
+    /// else
+    fn set_name(&mut self, id: u64, name: String) -> Result<(), RoomError> {
+        if self.id_of(&name).is_some() {
+            return Err(RoomError::NameTaken);
+        }
+
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return Ok(());
+        };
+        let old_name = std::mem::replace(&mut session.name, name.clone());
+
+        self.permissions.forget_user(&old_name);
+
+        if let Some((_, room)) = self.name_and_room(id) {
+            self.send_notice(&room, format!("{old_name} is now known as {name}"), id);
         }
-        self.send_message(&name, "Someone connected", id);
-        self.rooms.get_mut(&name).unwrap().insert(id);
+
+        Ok(())
+    }
+
+    /// List of available rooms
+    fn list_rooms(&self) -> Vec<String> {
+        self.rooms.keys().cloned().collect()
+    }
+
+    /// Join room, if room does not exists create new one.
+    ///
+    /// Send disconnect message to old room, send join message to new room.
+    fn join(&mut self, id: u64, name: &str, password: Option<&str>) -> Result<(), RoomError> {
+        let Some(user) = self.sessions.get(&id).map(|s| s.name.clone()) else {
+            return Ok(());
+        };
+
+        self.permissions.join(name, &user, password)?;
+
+        self.leave_rooms(id, &user);
+
+        self.send_notice(name, format!("{user} joined"), id);
+        self.rooms.entry(name.to_owned()).or_default().insert(id);
+
+        Ok(())
+    }
+}
+
+/// Refuses a message that some clients could not be sent
+fn check_size(message: &ChatResponse) -> Result<(), RoomError> {
+    if codec::fits_every_client(message) {
+        Ok(())
+    } else {
+        Err(RoomError::Rejected(
+            "message is too long for some clients to receive".to_owned(),
+        ))
     }
 }
@@ -3,26 +3,26 @@
 
 use std::{
     io, net,
-    str::FromStr,
     time::{Duration, Instant},
 };
 
 use actix::{prelude::*, spawn};
 use tokio::{
     io::{WriteHalf, split},
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, ToSocketAddrs},
 };
 use tokio_util::codec::FramedRead;
 
 use crate::{
-    codec::{ChatCodec, ChatRequest, ChatResponse},
+    codec::{self, ChatCodec, ChatRequest, ChatResponse, FrameError},
     server::{self, ChatServer},
+    tls::{Connection, TlsAcceptor},
 };
 
 /// Chat server sends this messages to session
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
+pub struct Message(pub ChatResponse);
 
 /// `ChatSession` actor is responsible for tcp peer communications.
 pub struct ChatSession {
@@ -33,10 +33,8 @@ pub struct ChatSession {
     /// Client must send ping at least once per 10 seconds, otherwise we drop
     /// connection.
     hb: Instant,
-    /// joined room
-    room: String,
     /// Framed wrapper
-    framed: actix::io::FramedWrite<ChatResponse, WriteHalf<TcpStream>, ChatCodec>,
+    framed: actix::io::FramedWrite<ChatResponse, WriteHalf<Connection>, ChatCodec>,
 }
 
 impl Actor for ChatSession {
@@ -59,7 +57,10 @@ impl Actor for ChatSession {
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok((id, name)) => {
+                        act.id = id;
+                        act.framed.write(ChatResponse::Name(name));
+                    }
                     // something is wrong with chat server
                     _ => ctx.stop(),
                 }
@@ -78,62 +79,48 @@ impl Actor for ChatSession {
 impl actix::io::WriteHandler<io::Error> for ChatSession {}
 
 /// To use `Framed` we have to define Io type and Codec
-impl StreamHandler<Result<ChatRequest, io::Error>> for ChatSession {
+impl StreamHandler<Result<Result<ChatRequest, FrameError>, io::Error>> for ChatSession {
     /// This is main event loop for client requests
-    fn handle(&mut self, msg: Result<ChatRequest, io::Error>, ctx: &mut Context<Self>) {
+    fn handle(
+        &mut self,
+        msg: Result<Result<ChatRequest, FrameError>, io::Error>,
+        ctx: &mut Context<Self>,
+    ) {
         match msg {
-            Ok(ChatRequest::List) => {
-                // Send ListRooms message to chat server and wait for response
-                println!("List rooms");
+            // we update heartbeat time on ping from peer
+            Ok(Ok(ChatRequest::Ping)) => self.hb = Instant::now(),
+            Ok(Ok(req)) => {
+                // forward request to chat server and wait for its response,
+                // .wait(ctx) pauses all events in context so responses are sent
+                // in the same order as requests
                 self.addr
-                    .send(server::ListRooms)
+                    .send(server::Request { id: self.id, req })
                     .into_actor(self)
                     .then(|res, act, _| {
                         match res {
-                            Ok(rooms) => {
-                                act.framed.write(ChatResponse::Rooms(rooms));
-                            }
+                            Ok(Some(res)) => act.framed.write(res),
+                            Ok(None) => {}
                             _ => println!("Something is wrong"),
                         }
                         actix::fut::ready(())
                     })
                     .wait(ctx)
-                // .wait(ctx) pauses all events in context,
-                // so actor wont receive any new messages until it get list of rooms back
-            }
-            Ok(ChatRequest::Join(name)) => {
-                println!("Join to room: {name}");
-                name.clone_into(&mut self.room);
-                self.addr.do_send(server::Join {
-                    id: self.id,
-                    name: name.clone(),
-                });
-                self.framed.write(ChatResponse::Joined(name));
             }
-            Ok(ChatRequest::Message(message)) => {
-                // send message to chat server
-                println!("Peer message: {message}");
-                self.addr.do_send(server::Message {
-                    id: self.id,
-                    msg: message,
-                    room: self.room.clone(),
-                })
-            }
-            // we update heartbeat time on ping from peer
-            Ok(ChatRequest::Ping) => self.hb = Instant::now(),
-            _ => ctx.stop(),
+            // the codec skipped the frame, so the connection can carry on
+            Ok(Err(err)) => self.framed.write(ChatResponse::error(err)),
+            Err(_) => ctx.stop(),
         }
     }
 }
 
-/// Handler for Message, chat server sends this message, we just send string to
+/// Handler for Message, chat server sends this message, we just send it to
 /// peer
 impl Handler<Message> for ChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
         // send message to peer
-        self.framed.write(ChatResponse::Message(msg.0));
+        self.framed.write(msg.0);
     }
 }
 
@@ -141,13 +128,12 @@ impl Handler<Message> for ChatSession {
 impl ChatSession {
     pub fn new(
         addr: Addr<ChatServer>,
-        framed: actix::io::FramedWrite<ChatResponse, WriteHalf<TcpStream>, ChatCodec>,
+        framed: actix::io::FramedWrite<ChatResponse, WriteHalf<Connection>, ChatCodec>,
     ) -> ChatSession {
         ChatSession {
             id: 0,
             addr,
             hb: Instant::now(),
-            room: "main".to_owned(),
             framed,
         }
     }
@@ -177,20 +163,63 @@ impl ChatSession {
 
 /// Define TCP server that will accept incoming TCP connection and create
 /// chat actors.
-pub fn tcp_server(_s: &str, server: Addr<ChatServer>) {
+///
+/// Each connection starts with a handshake that settles its framing, after the
+/// TLS handshake if `tls` is given. Request frames larger than `max_frame_size`
+/// are refused.
+///
+/// Returns the address that the server listens on, which tells callers the
+/// port picked if `addr` has port 0.
+pub async fn tcp_server(
+    addr: impl ToSocketAddrs,
+    server: Addr<ChatServer>,
+    max_frame_size: usize,
+    tls: Option<TlsAcceptor>,
+) -> io::Result<net::SocketAddr> {
     // Create server listener
-    let addr = net::SocketAddr::from_str("127.0.0.1:12345").unwrap();
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
 
     spawn(async move {
-        let listener = TcpListener::bind(&addr).await.unwrap();
-
-        while let Ok((stream, _)) = listener.accept().await {
+        while let Ok((stream, peer)) = listener.accept().await {
             let server = server.clone();
-            ChatSession::create(|ctx| {
-                let (r, w) = split(stream);
-                ChatSession::add_stream(FramedRead::new(r, ChatCodec), ctx);
-                ChatSession::new(server, actix::io::FramedWrite::new(w, ChatCodec, ctx))
+            let tls = tls.clone();
+
+            // handshakes are done apart from the accept loop, so a slow client
+            // does not hold up others
+            spawn(async move {
+                let handshake = async {
+                    let mut stream: Connection = match tls {
+                        Some(tls) => Box::new(tls.accept(stream).await?),
+                        None => Box::new(stream),
+                    };
+                    let options = codec::accept(&mut stream, max_frame_size).await?;
+                    Ok::<_, io::Error>((stream, options))
+                };
+                let (stream, options) =
+                    match tokio::time::timeout(codec::HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(handshake)) => handshake,
+                        Ok(Err(err)) => {
+                            log::warn!("handshake with {peer} failed: {err}");
+                            return;
+                        }
+                        Err(_) => {
+                            log::warn!("handshake with {peer} timed out");
+                            return;
+                        }
+                    };
+
+                ChatSession::create(|ctx| {
+                    let (r, w) = split(stream);
+                    ChatSession::add_stream(FramedRead::new(r, ChatCodec::new(options)), ctx);
+                    ChatSession::new(
+                        server,
+                        actix::io::FramedWrite::new(w, ChatCodec::new(options), ctx),
+                    )
+                });
             });
         }
     });
+
+    Ok(local_addr)
 }
@@ -0,0 +1,154 @@
+//! TLS for TCP chat connections, using rustls.
+//!
+//! The server terminates TLS if it is given a certificate and key, and also requires clients to
+//! present a certificate if it is given a CA to check them against. Certificates and keys are PEM
+//! files, loaded as in the `https-tls/rustls` example.
+
+#![allow(dead_code)]
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustls::{
+    ClientConfig, RootCertStore, ServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Environment variable holding the path of the server's certificate chain.
+pub const CERT_VAR: &str = "CHAT_TLS_CERT";
+
+/// Environment variable holding the path of the server's private key.
+pub const KEY_VAR: &str = "CHAT_TLS_KEY";
+
+/// Environment variable holding the path of the CA that client certificates must be signed by.
+pub const CLIENT_CA_VAR: &str = "CHAT_TLS_CLIENT_CA";
+
+/// A connection, with or without TLS.
+pub trait Io: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Io for T {}
+
+pub type Connection = Box<dyn Io>;
+
+/// Makes the crypto provider of the `https-tls` examples the default.
+fn install_provider() {
+    // errors if a provider is installed already, which is just as good
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+}
+
+fn invalid_data(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {err}", path.display()),
+    )
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|err| invalid_data(path, err))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| invalid_data(path, err))?;
+
+    if certs.is_empty() {
+        return Err(invalid_data(path, "no certificates found"));
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|err| invalid_data(path, err))
+}
+
+fn load_roots(path: &Path) -> io::Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|err| invalid_data(path, err))?;
+    }
+
+    Ok(Arc::new(roots))
+}
+
+/// Loads the server's certificate chain and key. If `client_ca` is given, clients have to present
+/// a certificate signed by it.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<ServerConfig> {
+    install_provider();
+
+    let builder = ServerConfig::builder();
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder(load_roots(ca)?)
+                .build()
+                .map_err(|err| invalid_data(ca, err))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|err| invalid_data(key, err))
+}
+
+/// Returns an acceptor for the certificate and key in [`CERT_VAR`] and [`KEY_VAR`], checking
+/// client certificates against [`CLIENT_CA_VAR`] if it is set, or `None` if TLS is not
+/// configured.
+pub fn acceptor_from_env() -> io::Result<Option<TlsAcceptor>> {
+    let (cert, key) = match (env::var_os(CERT_VAR), env::var_os(KEY_VAR)) {
+        (Some(cert), Some(key)) => (PathBuf::from(cert), PathBuf::from(key)),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{CERT_VAR} and {KEY_VAR} must be set together"),
+            ));
+        }
+    };
+    let client_ca = env::var_os(CLIENT_CA_VAR).map(PathBuf::from);
+
+    let config = server_config(&cert, &key, client_ca.as_deref())?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Trusts servers with a certificate signed by `ca`. If `identity` is given, its certificate chain
+/// and key are presented to servers that ask for a client certificate.
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> io::Result<ClientConfig> {
+    install_provider();
+
+    let builder = ClientConfig::builder().with_root_certificates(load_roots(ca)?);
+
+    match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|err| invalid_data(key, err)),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Starts TLS on `stream`, checking that the server's certificate is valid for `domain`.
+pub async fn connect(
+    config: ClientConfig,
+    domain: &str,
+    stream: TcpStream,
+) -> io::Result<Connection> {
+    let domain = ServerName::try_from(domain.to_owned())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(domain, stream)
+        .await?;
+
+    Ok(Box::new(stream))
+}
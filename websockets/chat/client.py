@@ -3,21 +3,93 @@
 
 import argparse
 import asyncio
+import getpass
+import json
 import sys
 from contextlib import suppress
+from datetime import datetime
 
 import aiohttp
 
 
+MODERATION = {
+    "/invite": "Invite",
+    "/kick": "Kick",
+    "/ban": "Ban",
+    "/unban": "Unban",
+    "/mute": "Mute",
+    "/unmute": "Unmute",
+}
+
+
+def parse_command(line: str) -> dict:
+    """Turns a line typed by the user into a request of the chat protocol."""
+    text = line.strip()
+
+    if not text.startswith("/"):
+        return {"cmd": "Message", "data": text}
+
+    cmd, _, arg = text.partition(" ")
+    arg = arg.strip()
+    first, _, second = arg.partition(" ")
+    second = second.strip()
+
+    if cmd == "/list":
+        return {"cmd": "List"}
+    if cmd == "/public":
+        return {"cmd": "Moderate", "data": {"SetAccess": "Open"}}
+    if cmd == "/private":
+        return {"cmd": "Moderate", "data": {"SetAccess": "InviteOnly"}}
+
+    if not arg:
+        raise ValueError("argument is required")
+
+    if cmd == "/join":
+        return {"cmd": "Join", "data": {"room": first, "password": second or None}}
+    if cmd == "/msg" and second:
+        return {"cmd": "Direct", "data": {"to": first, "text": second}}
+    if cmd == "/history":
+        return {"cmd": "History", "data": {"after": int(arg)}}
+    if cmd == "/password":
+        return {"cmd": "Moderate", "data": {"SetAccess": {"Password": arg}}}
+    if cmd in MODERATION:
+        return {"cmd": "Moderate", "data": {MODERATION[cmd]: arg}}
+
+    raise ValueError(f"invalid command: {text}")
+
+
+def show_response(res: dict) -> None:
+    """Prints a response of the chat server."""
+    cmd, data = res["cmd"], res.get("data")
+
+    def time(timestamp: int) -> str:
+        return datetime.fromtimestamp(timestamp / 1000).strftime("%H:%M:%S")
+
+    if cmd == "Message":
+        where = f"#{data['room']} [{data['id']}]" if data["room"] else "private"
+        print(f"{time(data['timestamp'])} {where} {data['sender']}: {data['text']}")
+    elif cmd == "Notice":
+        print(f"{time(data['timestamp'])} {data['text']}")
+    elif cmd == "Rooms":
+        print("Rooms:", ", ".join(data))
+    elif cmd == "Joined":
+        print("Joined", data)
+    elif cmd == "Name":
+        print("Your name is", data)
+    elif cmd == "Error":
+        print("!!!", data)
+
+
 async def start_client(url: str) -> None:
     name = input("Please enter your name: ")
+    password = getpass.getpass("Password: ")
 
     async def dispatch(ws: aiohttp.ClientWebSocketResponse) -> None:
         while True:
             msg = await ws.receive()
 
             if msg.type == aiohttp.WSMsgType.TEXT:
-                print("Text: ", msg.data.strip())
+                show_response(json.loads(msg.data))
             elif msg.type == aiohttp.WSMsgType.BINARY:
                 print("Binary: ", msg.data)
             elif msg.type == aiohttp.WSMsgType.PING:
@@ -35,16 +107,27 @@ async def start_client(url: str) -> None:
                 break
 
     async with aiohttp.ClientSession() as session:
-        async with session.ws_connect(url, autoclose=False, autoping=False) as ws:
+        login = url.rsplit("/", 1)[0] + "/login"
+        async with session.post(login, json={"name": name, "password": password}) as res:
+            res.raise_for_status()
+            token = (await res.json())["token"]
+
+        headers = {"Authorization": f"Bearer {token}"}
+        async with session.ws_connect(
+            url, headers=headers, autoclose=False, autoping=False
+        ) as ws:
             # send request
             dispatch_task = asyncio.create_task(dispatch(ws))
 
             # Exit with Ctrl+D
             while line := await asyncio.to_thread(sys.stdin.readline):
-                if line.startswith("/"):
-                    await ws.send_str(line)
-                else:
-                    await ws.send_str(name + ": " + line)
+                if not line.strip():
+                    continue
+
+                try:
+                    await ws.send_json(parse_command(line))
+                except ValueError as err:
+                    print("!!!", err)
 
             dispatch_task.cancel()
             with suppress(asyncio.CancelledError):
@@ -71,8 +154,11 @@ if __name__ == "__main__":
 
     print("""
     /list 	list all available rooms
-    /join name 	join room, if room does not exist, create new one
-    /name name 	set session name
+    /join name [password] 	join room, if room does not exist, create new one
+    /msg user message 	send a direct message to a user
+    /history id 	replay the messages of the current room sent after message id
+    /invite, /kick, /ban, /unban, /mute, /unmute user 	moderate the current room
+    /public, /private, /password secret 	set who may join the current room
     some message 	just string, send message to all peers in same room
     ctrl-D to exit
     """)
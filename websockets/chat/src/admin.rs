@@ -0,0 +1,373 @@
+//! HTTP API for administrators to inspect and manage the live rooms of the chat server.
+//!
+//! Every endpoint requires an [`Admin`], a logged in user listed in the admins file. Rooms and
+//! sessions are those of this node only.
+
+use std::fmt;
+
+use actix::Addr;
+use actix_web::{Error, HttpResponse, ResponseError, error, http::StatusCode, web};
+use serde::Deserialize;
+
+use crate::{
+    auth::Admin,
+    server::{self, ChatServer},
+};
+
+/// Reason that the chat server refused an admin request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminError {
+    UnknownRoom,
+    UnknownSession,
+
+    /// The main room cannot be closed or renamed.
+    MainRoom,
+
+    /// A room cannot be renamed to that of another room.
+    RoomExists,
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::UnknownRoom => "no such room",
+            Self::UnknownSession => "no such session",
+            Self::MainRoom => "the main room cannot be closed or renamed",
+            Self::RoomExists => "a room with that name already exists",
+        })
+    }
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::UnknownRoom | Self::UnknownSession => StatusCode::NOT_FOUND,
+            Self::MainRoom | Self::RoomExists => StatusCode::CONFLICT,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Broadcast {
+    /// Room to send the notice to, or every session if `None`.
+    room: Option<String>,
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Rename {
+    to: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Kick {
+    reason: Option<String>,
+}
+
+/// Registers the admin endpoints, which are meant to be mounted under `/admin`.
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.route("/rooms", web::get().to(list_rooms))
+        .route("/rooms/{room}", web::delete().to(close_room))
+        .route("/rooms/{room}/sessions", web::get().to(room_sessions))
+        .route("/rooms/{room}/rename", web::post().to(rename_room))
+        .route("/broadcast", web::post().to(broadcast))
+        .route("/sessions/{id}", web::delete().to(kick_session));
+}
+
+/// Lists rooms with the number of sessions and users in each.
+async fn list_rooms(_: Admin, srv: web::Data<Addr<ChatServer>>) -> Result<HttpResponse, Error> {
+    let rooms = srv
+        .send(server::RoomStats)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(rooms))
+}
+
+/// Lists the sessions in a room.
+async fn room_sessions(
+    _: Admin,
+    room: web::Path<String>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    let sessions = srv
+        .send(server::RoomSessions {
+            room: room.into_inner(),
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+/// Sends a notice to a room, or to every session.
+async fn broadcast(
+    Admin(admin): Admin,
+    body: web::Json<Broadcast>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    let Broadcast { room, text } = body.into_inner();
+    log::info!(
+        "{admin} broadcast to {}: {text}",
+        room.as_deref().unwrap_or("everyone")
+    );
+
+    srv.send(server::SystemBroadcast { room, text })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Disconnects a session, telling its client why if `reason` is given.
+async fn kick_session(
+    Admin(admin): Admin,
+    id: web::Path<u64>,
+    query: web::Query<Kick>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    let reason = query
+        .into_inner()
+        .reason
+        .unwrap_or_else(|| "kicked by an administrator".to_owned());
+    log::info!("{admin} kicked session {id}: {reason}");
+
+    srv.send(server::KickSession { id, reason })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Closes a room, moving its sessions back to the main room.
+async fn close_room(
+    Admin(admin): Admin,
+    room: web::Path<String>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    let room = room.into_inner();
+    log::info!("{admin} closed room {room}");
+
+    srv.send(server::CloseRoom { room })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Renames a room.
+async fn rename_room(
+    Admin(admin): Admin,
+    room: web::Path<String>,
+    body: web::Json<Rename>,
+    srv: web::Data<Addr<ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    let (room, to) = (room.into_inner(), body.into_inner().to);
+    log::info!("{admin} renamed room {room} to {to}");
+
+    srv.send(server::RenameRoom { room, to })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, atomic::AtomicUsize},
+        time::Duration,
+    };
+
+    use actix::prelude::*;
+    use actix_web::{App, http::header, test};
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        auth::{self, Accounts, Admins, Tokens},
+        fanout::Local,
+        history::{History, HistoryStore},
+        protocol::ChatResponse,
+        queue::{QueueConfig, QueueMetrics, QueueReceiver},
+    };
+
+    /// Stands in for a WebSocket session, passing on why it was kicked
+    struct Probe(mpsc::UnboundedSender<String>);
+
+    impl Actor for Probe {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<server::Kicked> for Probe {
+        type Result = ();
+
+        fn handle(&mut self, msg: server::Kicked, _: &mut Context<Self>) {
+            let _ = self.0.send(msg.reason);
+        }
+    }
+
+    /// Waits for a response to the session that `matches` accepts
+    async fn expect(rx: &mut QueueReceiver<ChatResponse>, matches: impl Fn(&ChatResponse) -> bool) {
+        loop {
+            let res = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+
+            if matches(&res) {
+                return;
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn admins_manage_rooms_and_sessions() {
+        let server = ChatServer::new(
+            Arc::new(AtomicUsize::new(0)),
+            HistoryStore::start(History::open_in_memory(10).unwrap()),
+            0,
+            Box::new(Local),
+            QueueConfig::default(),
+            Arc::new(QueueMetrics::default()),
+        )
+        .start();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(server.clone()))
+                .app_data(web::Data::new(Accounts::load("users.txt").unwrap()))
+                .app_data(web::Data::new(Tokens::default()))
+                .app_data(web::Data::new(Admins::new(["alice"])))
+                .route("/login", web::post().to(auth::login))
+                .service(web::scope("/admin").configure(config)),
+        )
+        .await;
+
+        let login = async |name: &str, password: &str| {
+            let req = test::TestRequest::post()
+                .uri("/login")
+                .set_json(json!({ "name": name, "password": password }))
+                .to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            format!("Bearer {}", body["token"].as_str().unwrap())
+        };
+        let alice = login("alice", "wonderland").await;
+        let bob = login("bob", "builder").await;
+
+        let req = test::TestRequest::get().uri("/admin/rooms").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/rooms")
+            .insert_header((header::AUTHORIZATION, bob))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let (kicked_tx, mut kicked_rx) = mpsc::unbounded_channel();
+        let (id, mut rx) = server
+            .send(server::Connect {
+                user: "bob".to_owned(),
+                addr: Probe(kicked_tx).start().recipient(),
+            })
+            .await
+            .unwrap();
+        server
+            .send(server::Join {
+                id,
+                name: "lobby".to_owned(),
+                password: None,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let admin = |req: test::TestRequest| {
+            req.insert_header((header::AUTHORIZATION, alice.clone()))
+                .to_request()
+        };
+
+        let req = admin(test::TestRequest::get().uri("/admin/rooms"));
+        let rooms: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            rooms,
+            json!([
+                { "name": "lobby", "sessions": 1, "users": 1 },
+                { "name": "main", "sessions": 0, "users": 0 },
+            ])
+        );
+
+        let req = admin(test::TestRequest::get().uri("/admin/rooms/lobby/sessions"));
+        let sessions: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(sessions, json!([{ "id": id, "user": "bob" }]));
+
+        let req = admin(
+            test::TestRequest::post()
+                .uri("/admin/rooms/lobby/rename")
+                .set_json(json!({ "to": "hall" })),
+        );
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        expect(&mut rx, |res| {
+            *res == ChatResponse::Joined("hall".to_owned())
+        })
+        .await;
+
+        let req = admin(
+            test::TestRequest::post()
+                .uri("/admin/broadcast")
+                .set_json(json!({ "room": "hall", "text": "restarting soon" })),
+        );
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        expect(
+            &mut rx,
+            |res| matches!(res, ChatResponse::Notice(notice) if notice.text == "restarting soon"),
+        )
+        .await;
+
+        let req = admin(test::TestRequest::delete().uri("/admin/rooms/main"));
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CONFLICT
+        );
+
+        let req = admin(test::TestRequest::delete().uri("/admin/rooms/hall"));
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        expect(&mut rx, |res| {
+            *res == ChatResponse::Joined("main".to_owned())
+        })
+        .await;
+
+        let req = admin(test::TestRequest::get().uri("/admin/rooms/hall/sessions"));
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let req =
+            admin(test::TestRequest::delete().uri(&format!("/admin/sessions/{id}?reason=spam")));
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(kicked_rx.recv().await.as_deref(), Some("spam"));
+
+        let req = admin(test::TestRequest::delete().uri(&format!("/admin/sessions/{}", id ^ 1)));
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! Authentication of chat users.
+//!
+//! Users log in with a name and password at `/login`. The user is then remembered in the session
+//! cookie, which browsers send with the WebSocket handshake, and a bearer token is returned for
+//! clients that cannot keep cookies.
+//!
+//! Users listed in [`Admins`] may also use the admin API.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+    sync::RwLock,
+};
+
+use actix_session::{Session, SessionExt as _};
+use actix_web::{
+    Error, FromRequest, HttpRequest, HttpResponse, dev::Payload, error, http::header, web,
+};
+use rand::Rng as _;
+use serde::{Deserialize, Serialize};
+
+/// Session key under which the logged in user is stored.
+const SESSION_USER: &str = "user";
+
+/// Known users and their passwords.
+#[derive(Debug)]
+pub struct Accounts {
+    passwords: HashMap<String, String>,
+}
+
+impl Accounts {
+    /// Loads accounts from a file with one `name:password` pair per line.
+    ///
+    /// Passwords are kept in plain text, which is only suitable for a demo.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let passwords = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                line.split_once(':')
+                    .map(|(name, password)| (name.to_owned(), password.to_owned()))
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "expected name:password")
+                    })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self { passwords })
+    }
+
+    fn verify(&self, name: &str, password: &str) -> bool {
+        self.passwords.get(name).is_some_and(|pw| pw == password)
+    }
+}
+
+/// Users that may use the admin API.
+#[derive(Debug, Default)]
+pub struct Admins {
+    names: HashSet<String>,
+}
+
+impl Admins {
+    pub fn new<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            names: names.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Loads admins from a file with one user name per line.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let names = fs::read_to_string(path)?;
+
+        Ok(Self::new(
+            names
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#')),
+        ))
+    }
+
+    fn contains(&self, user: &str) -> bool {
+        self.names.contains(user)
+    }
+}
+
+/// Bearer tokens handed out at login, mapped to the user they were issued to.
+#[derive(Debug, Default)]
+pub struct Tokens {
+    users: RwLock<HashMap<String, String>>,
+}
+
+impl Tokens {
+    fn issue(&self, user: &str) -> String {
+        let token = rand::rng()
+            .random::<[u8; 32]>()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+
+        self.users
+            .write()
+            .unwrap()
+            .insert(token.clone(), user.to_owned());
+
+        token
+    }
+
+    fn user(&self, token: &str) -> Option<String> {
+        self.users.read().unwrap().get(token).cloned()
+    }
+
+    fn revoke(&self, token: &str) {
+        self.users.write().unwrap().remove(token);
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// An authenticated user, taken from the bearer token or, failing that, the session cookie.
+#[derive(Debug, Clone)]
+pub struct User(pub String);
+
+impl FromRequest for User {
+    type Error = Error;
+    type Future = std::future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let from_token = bearer_token(req).and_then(|token| {
+            req.app_data::<web::Data<Tokens>>()
+                .and_then(|tokens| tokens.user(token))
+        });
+
+        let user = match from_token {
+            Some(user) => Some(user),
+            None => req.get_session().get::<String>(SESSION_USER).ok().flatten(),
+        };
+
+        std::future::ready(
+            user.map(User)
+                .ok_or_else(|| error::ErrorUnauthorized("login required")),
+        )
+    }
+}
+
+/// An authenticated user that is listed in [`Admins`].
+#[derive(Debug, Clone)]
+pub struct Admin(pub String);
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = std::future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let admin = User::from_request(req, payload)
+            .into_inner()
+            .and_then(|User(user)| {
+                let is_admin = req
+                    .app_data::<web::Data<Admins>>()
+                    .is_some_and(|admins| admins.contains(&user));
+
+                if is_admin {
+                    Ok(Admin(user))
+                } else {
+                    Err(error::ErrorForbidden("only administrators can do that"))
+                }
+            });
+
+        std::future::ready(admin)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Credentials {
+    name: String,
+    password: String,
+}
+
+#[derive(Debug, Serialize)]
+struct LoginResponse {
+    user: String,
+    token: String,
+}
+
+/// Logs a user in, setting the session cookie and returning a bearer token.
+pub async fn login(
+    credentials: web::Json<Credentials>,
+    session: Session,
+    accounts: web::Data<Accounts>,
+    tokens: web::Data<Tokens>,
+) -> Result<HttpResponse, Error> {
+    let Credentials { name, password } = credentials.into_inner();
+
+    if !accounts.verify(&name, &password) {
+        return Err(error::ErrorUnauthorized("invalid name or password"));
+    }
+
+    session.renew();
+    session.insert(SESSION_USER, &name)?;
+
+    let token = tokens.issue(&name);
+    Ok(HttpResponse::Ok().json(LoginResponse { user: name, token }))
+}
+
+/// Logs a user out, clearing the session cookie and revoking the bearer token used, if any.
+pub async fn logout(req: HttpRequest, session: Session, tokens: web::Data<Tokens>) -> HttpResponse {
+    if let Some(token) = bearer_token(&req) {
+        tokens.revoke(token);
+    }
+
+    session.purge();
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_session::{SessionMiddleware, storage::CookieSessionStore};
+    use actix_web::{App, cookie::Key, http::StatusCode, test};
+
+    use super::*;
+
+    async fn whoami(user: User) -> String {
+        user.0
+    }
+
+    #[actix_web::test]
+    async fn authenticates_with_cookie_or_token() {
+        let accounts = Accounts {
+            passwords: HashMap::from([("alice".to_owned(), "secret".to_owned())]),
+        };
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(accounts))
+                .app_data(web::Data::new(Tokens::default()))
+                .route("/login", web::post().to(login))
+                .route("/whoami", web::get().to(whoami))
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                )),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/whoami").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_json(serde_json::json!({ "name": "alice", "password": "wrong" }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_json(serde_json::json!({ "name": "alice", "password": "secret" }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let body: serde_json::Value = test::read_body_json(res).await;
+        let token = body["token"].as_str().unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/whoami")
+            .cookie(cookie)
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "alice");
+
+        let req = test::TestRequest::get()
+            .uri("/whoami")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "alice");
+    }
+}
@@ -0,0 +1,358 @@
+//! Bounded per-room message history, persisted to SQLite so that it survives restarts.
+//!
+//! [`HistoryStore`] runs the history on a thread of its own, so that the chat server is not held
+//! up by disk I/O.
+
+use std::{path::Path, sync::Mutex};
+
+use actix::prelude::*;
+use rusqlite::{Connection, Row, params};
+
+use crate::protocol::{self, ChatMessage};
+
+/// Keeps the last `limit` messages of each room.
+///
+/// Message IDs are handed out by the database, unless the chat is shared with other nodes, which
+/// number messages between them. Either way they increase with each message and are never
+/// reused, so clients can ask for everything after the last one they saw.
+#[derive(Debug)]
+pub struct History {
+    conn: Connection,
+    limit: usize,
+}
+
+impl History {
+    /// Opens (or creates) the history database at `path`.
+    pub fn open(path: impl AsRef<Path>, limit: usize) -> rusqlite::Result<Self> {
+        Self::init(Connection::open(path)?, limit)
+    }
+
+    /// Creates a history that is kept in memory only.
+    #[cfg(test)]
+    pub fn open_in_memory(limit: usize) -> rusqlite::Result<Self> {
+        Self::init(Connection::open_in_memory()?, limit)
+    }
+
+    fn init(conn: Connection, limit: usize) -> rusqlite::Result<Self> {
+        // AUTOINCREMENT stops IDs of trimmed messages from being handed out again
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                room TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                text TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room, id);",
+        )?;
+
+        Ok(Self { conn, limit })
+    }
+
+    /// Stores a message sent to `room` by `sender`, dropping the room's oldest messages beyond the
+    /// limit.
+    ///
+    /// Returns the stored message, with its new ID.
+    pub fn append(&self, room: &str, sender: &str, text: &str) -> rusqlite::Result<ChatMessage> {
+        let timestamp = protocol::now();
+
+        self.conn.execute(
+            "INSERT INTO messages (room, sender, timestamp, text) VALUES (?1, ?2, ?3, ?4)",
+            params![room, sender, timestamp, text],
+        )?;
+        let id = self.conn.last_insert_rowid() as u64;
+        self.trim(room)?;
+
+        Ok(ChatMessage {
+            id,
+            timestamp,
+            sender: sender.to_owned(),
+            room: Some(room.to_owned()),
+            text: text.to_owned(),
+        })
+    }
+
+    /// Stores a message that was numbered elsewhere, e.g. one sent by another node, dropping the
+    /// room's oldest messages beyond the limit.
+    ///
+    /// Messages that are stored already, or were not sent to a room, are ignored.
+    pub fn insert(&self, message: &ChatMessage) -> rusqlite::Result<()> {
+        let Some(room) = &message.room else {
+            return Ok(());
+        };
+
+        // nodes may share a database, in which case the sender stored it
+        self.conn.execute(
+            "INSERT OR IGNORE INTO messages (id, room, sender, timestamp, text)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                message.id,
+                room,
+                message.sender,
+                message.timestamp,
+                message.text
+            ],
+        )?;
+
+        self.trim(room)
+    }
+
+    /// Drops the oldest messages of `room` beyond the limit.
+    fn trim(&self, room: &str) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "DELETE FROM messages WHERE room = ?1 AND id <= (
+                SELECT id FROM messages WHERE room = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2
+            )",
+            params![room, self.limit],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the last `count` messages sent to `room`, oldest first.
+    pub fn recent(&self, room: &str, count: usize) -> rusqlite::Result<Vec<ChatMessage>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, timestamp, sender, room, text FROM (
+                SELECT * FROM messages WHERE room = ?1 ORDER BY id DESC LIMIT ?2
+            ) ORDER BY id",
+        )?;
+
+        stmt.query_map(params![room, count], message_from_row)?
+            .collect()
+    }
+
+    /// Returns the stored messages of `room` with an ID greater than `after`, oldest first.
+    pub fn after(&self, room: &str, after: u64) -> rusqlite::Result<Vec<ChatMessage>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, timestamp, sender, room, text FROM messages
+            WHERE room = ?1 AND id > ?2 ORDER BY id",
+        )?;
+
+        stmt.query_map(params![room, after], message_from_row)?
+            .collect()
+    }
+
+    /// Moves the stored messages of room `from` to room `to`.
+    pub fn rename_room(&self, from: &str, to: &str) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE messages SET room = ?2 WHERE room = ?1",
+            params![from, to],
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Actor that owns the [`History`] and runs its queries on a dedicated thread.
+///
+/// Requests are handled one at a time, in the order they are sent, so messages are stored in
+/// the order the chat server receives them.
+pub struct HistoryStore {
+    history: History,
+}
+
+impl HistoryStore {
+    /// Starts the store on its own thread.
+    pub fn start(history: History) -> Addr<Self> {
+        // the factory is only called once, since there is a single thread
+        let history = Mutex::new(Some(history));
+
+        SyncArbiter::start(1, move || Self {
+            history: history
+                .lock()
+                .unwrap()
+                .take()
+                .expect("history store should only be started once"),
+        })
+    }
+}
+
+impl Actor for HistoryStore {
+    type Context = SyncContext<Self>;
+}
+
+/// Store a message sent to a room, returning it with its new ID
+#[derive(Message)]
+#[rtype(result = "rusqlite::Result<ChatMessage>")]
+pub struct Append {
+    pub room: String,
+    pub sender: String,
+    pub text: String,
+}
+
+/// Store a message that was numbered elsewhere
+#[derive(Message)]
+#[rtype(result = "rusqlite::Result<()>")]
+pub struct Insert(pub ChatMessage);
+
+/// Get the last `count` messages sent to a room
+#[derive(Message)]
+#[rtype(result = "rusqlite::Result<Vec<ChatMessage>>")]
+pub struct Recent {
+    pub room: String,
+    pub count: usize,
+}
+
+/// Get the messages of a room with an ID greater than `after`
+#[derive(Message)]
+#[rtype(result = "rusqlite::Result<Vec<ChatMessage>>")]
+pub struct After {
+    pub room: String,
+    pub after: u64,
+}
+
+/// Move the messages of a room to another
+#[derive(Message)]
+#[rtype(result = "rusqlite::Result<()>")]
+pub struct RenameRoom {
+    pub from: String,
+    pub to: String,
+}
+
+impl Handler<Append> for HistoryStore {
+    type Result = rusqlite::Result<ChatMessage>;
+
+    fn handle(&mut self, msg: Append, _: &mut SyncContext<Self>) -> Self::Result {
+        self.history.append(&msg.room, &msg.sender, &msg.text)
+    }
+}
+
+impl Handler<Insert> for HistoryStore {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: Insert, _: &mut SyncContext<Self>) -> Self::Result {
+        self.history.insert(&msg.0)
+    }
+}
+
+impl Handler<Recent> for HistoryStore {
+    type Result = rusqlite::Result<Vec<ChatMessage>>;
+
+    fn handle(&mut self, msg: Recent, _: &mut SyncContext<Self>) -> Self::Result {
+        self.history.recent(&msg.room, msg.count)
+    }
+}
+
+impl Handler<After> for HistoryStore {
+    type Result = rusqlite::Result<Vec<ChatMessage>>;
+
+    fn handle(&mut self, msg: After, _: &mut SyncContext<Self>) -> Self::Result {
+        self.history.after(&msg.room, msg.after)
+    }
+}
+
+impl Handler<RenameRoom> for HistoryStore {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: RenameRoom, _: &mut SyncContext<Self>) -> Self::Result {
+        self.history.rename_room(&msg.from, &msg.to)
+    }
+}
+
+fn message_from_row(row: &Row<'_>) -> rusqlite::Result<ChatMessage> {
+    Ok(ChatMessage {
+        id: row.get(0)?,
+        timestamp: row.get(1)?,
+        sender: row.get(2)?,
+        room: row.get(3)?,
+        text: row.get(4)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(messages: &[ChatMessage]) -> Vec<&str> {
+        messages.iter().map(|msg| msg.text.as_str()).collect()
+    }
+
+    #[test]
+    fn keeps_last_messages_of_each_room() {
+        let history = History::open_in_memory(3).unwrap();
+
+        for i in 0..5 {
+            history
+                .append("main", "alice", &format!("main {i}"))
+                .unwrap();
+        }
+        history.append("other", "alice", "other 0").unwrap();
+
+        assert_eq!(
+            texts(&history.recent("main", 10).unwrap()),
+            ["main 2", "main 3", "main 4"]
+        );
+        assert_eq!(
+            texts(&history.recent("main", 2).unwrap()),
+            ["main 3", "main 4"]
+        );
+        assert_eq!(texts(&history.recent("other", 10).unwrap()), ["other 0"]);
+        assert!(history.recent("empty", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn replays_messages_after_id() {
+        let history = History::open_in_memory(10).unwrap();
+
+        let first = history.append("main", "alice", "one").unwrap().id;
+        history.append("other", "bob", "elsewhere").unwrap();
+        let second = history.append("main", "bob", "two").unwrap().id;
+        history.append("main", "alice", "three").unwrap();
+
+        assert_eq!(
+            texts(&history.after("main", first).unwrap()),
+            ["two", "three"]
+        );
+        let after_second = history.after("main", second).unwrap();
+        assert_eq!(after_second[0].sender, "alice");
+        assert_eq!(after_second[0].room.as_deref(), Some("main"));
+        assert_eq!(texts(&after_second), ["three"]);
+        assert_eq!(texts(&history.after("main", 0).unwrap()).len(), 3);
+    }
+
+    #[test]
+    fn stores_messages_numbered_elsewhere() {
+        let history = History::open_in_memory(2).unwrap();
+
+        for id in [7, 9, 9, 12] {
+            let message = ChatMessage::new(id, "bob", Some("main"), format!("remote {id}"));
+            history.insert(&message).unwrap();
+        }
+        history
+            .insert(&ChatMessage::new(13, "bob", None, "private"))
+            .unwrap();
+
+        let recent = history.recent("main", 10).unwrap();
+        assert_eq!(texts(&recent), ["remote 9", "remote 12"]);
+        assert!(history.append("main", "alice", "local").unwrap().id > 12);
+    }
+
+    #[actix_web::test]
+    async fn store_handles_requests_in_order() {
+        let store = HistoryStore::start(History::open_in_memory(10).unwrap());
+
+        let appends = (0..5).map(|i| {
+            store.send(Append {
+                room: "main".to_owned(),
+                sender: "alice".to_owned(),
+                text: format!("message {i}"),
+            })
+        });
+        let ids = futures_util::future::join_all(appends)
+            .await
+            .into_iter()
+            .map(|res| res.unwrap().unwrap().id)
+            .collect::<Vec<_>>();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let recent = store
+            .send(Recent {
+                room: "main".to_owned(),
+                count: 2,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(texts(&recent), ["message 3", "message 4"]);
+    }
+}
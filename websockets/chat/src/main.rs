@@ -8,35 +8,79 @@ use std::{
 
 use actix::*;
 use actix_files::{Files, NamedFile};
+use actix_session::{SessionMiddleware, storage::CookieSessionStore};
 use actix_web::{
-    App, Error, HttpRequest, HttpResponse, HttpServer, Responder, middleware::Logger, web,
+    App, Error, HttpRequest, HttpResponse, HttpServer, Responder, cookie::Key, middleware::Logger,
+    web,
 };
 use actix_web_actors::ws;
 
+mod admin;
+mod auth;
+mod history;
 mod server;
 mod session;
+mod shutdown;
+
+use websocket_chat_common::{fanout, filter, limits, permissions, protocol, queue};
+
+/// File that room history is stored in
+const HISTORY_DB: &str = "chat-history.db";
+
+/// Number of messages kept for each room
+const HISTORY_LIMIT: usize = 100;
+
+/// Number of recent messages sent to a session when it joins a room
+const JOIN_REPLAY: usize = 20;
+
+/// File with the words that are masked in messages, one per line
+const BLOCKED_WORDS_FILE: &str = "blocked-words.txt";
+
+/// File with the `name:password` pairs of users that may log in
+const USERS_FILE: &str = "users.txt";
+
+/// File with the names of the users that may use the admin API, one per line
+const ADMINS_FILE: &str = "admins.txt";
 
 async fn index() -> impl Responder {
     NamedFile::open_async("./static/index.html").await.unwrap()
 }
 
 /// Entry point for our websocket route
+///
+/// Only logged in users may connect.
 async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
+    user: auth::User,
     srv: web::Data<Addr<server::ChatServer>>,
+    shutdown: web::Data<shutdown::Shutdown>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(
-        session::WsChatSession {
-            id: 0,
-            hb: Instant::now(),
-            room: "main".to_owned(),
-            name: None,
-            addr: srv.get_ref().clone(),
-        },
-        &req,
-        stream,
-    )
+    let limit_config = limits::LimitConfig::default();
+    let session = session::WsChatSession {
+        id: 0,
+        hb: Instant::now(),
+        user: user.0,
+        addr: srv.get_ref().clone(),
+        limits: limits::SessionLimits::new(limit_config),
+        shutdown: shutdown.guard(),
+    };
+
+    // longer frames are refused by the codec, before they reach the session
+    ws::WsResponseBuilder::new(session, &req, stream)
+        .frame_size(limit_config.max_frame_len)
+        .start()
+}
+
+/// Has the chat server close the queues of all sessions once `shutdown` is
+/// signalled, so that sessions close after sending what is left in them
+fn close_sessions_on_shutdown(shutdown: &shutdown::Shutdown, server: Addr<server::ChatServer>) {
+    let mut guard = shutdown.guard();
+
+    actix_web::rt::spawn(async move {
+        guard.signalled().await;
+        server.do_send(server::CloseSessions);
+    });
 }
 
 /// Displays state
@@ -45,6 +89,11 @@ async fn get_count(count: web::Data<AtomicUsize>) -> impl Responder {
     format!("Visitors: {current_count}")
 }
 
+/// Displays depths of session queues, in the Prometheus text format
+async fn metrics(queue_metrics: web::Data<queue::QueueMetrics>) -> impl Responder {
+    queue_metrics.to_string()
+}
+
 // the actor-based WebSocket examples REQUIRE `actix_web::main` for actor support
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -54,23 +103,219 @@ async fn main() -> std::io::Result<()> {
     // keep a count of the number of visitors
     let app_state = Arc::new(AtomicUsize::new(0));
 
+    let history =
+        history::History::open(HISTORY_DB, HISTORY_LIMIT).map_err(std::io::Error::other)?;
+    let history = history::HistoryStore::start(history);
+
+    let accounts = web::Data::new(auth::Accounts::load(USERS_FILE)?);
+    let tokens = web::Data::new(auth::Tokens::default());
+    let admins = web::Data::new(auth::Admins::load(ADMINS_FILE)?);
+
+    // sessions, and so logins, do not survive a restart
+    let secret_key = Key::generate();
+
     // start chat server actor
-    let server = server::ChatServer::new(app_state.clone()).start();
+    // shares rooms with other nodes if `REDIS_URL` is set
+    let fanout = fanout::from_env().await?;
+
+    let queue_config = queue::QueueConfig::from_env()?;
+    let queue_metrics = Arc::new(queue::QueueMetrics::default());
+
+    let server = server::ChatServer::new(
+        app_state.clone(),
+        history,
+        JOIN_REPLAY,
+        fanout,
+        queue_config,
+        queue_metrics.clone(),
+    )
+    .with_filter(filter::Filters::standard(BLOCKED_WORDS_FILE)?)
+    .start();
+
+    // sessions are closed with `1001 Going Away` on SIGINT or SIGTERM, once
+    // they have sent what is queued for them
+    let shutdown = shutdown::Shutdown::default();
+    close_sessions_on_shutdown(&shutdown, server.clone());
+    let app_shutdown = web::Data::new(shutdown.clone());
 
     log::info!("starting HTTP server at http://localhost:8080");
 
-    HttpServer::new(move || {
+    let http_server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::from(app_state.clone()))
+            .app_data(web::Data::from(queue_metrics.clone()))
             .app_data(web::Data::new(server.clone()))
+            .app_data(accounts.clone())
+            .app_data(tokens.clone())
+            .app_data(admins.clone())
+            .app_data(app_shutdown.clone())
             .service(web::resource("/").to(index))
             .route("/count", web::get().to(get_count))
+            .route("/metrics", web::get().to(metrics))
+            .route("/login", web::post().to(auth::login))
+            .route("/logout", web::post().to(auth::logout))
             .route("/ws", web::get().to(chat_route))
+            .service(web::scope("/admin").configure(admin::config))
             .service(Files::new("/static", "./static"))
+            .wrap(
+                SessionMiddleware::builder(CookieSessionStore::default(), secret_key.clone())
+                    .cookie_secure(false)
+                    .build(),
+            )
             .wrap(Logger::default())
     })
     .workers(2)
+    .disable_signals()
+    .shutdown_timeout(shutdown::DRAIN_TIMEOUT.as_secs())
     .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .run();
+
+    shutdown::run(http_server, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_codec::Framed;
+    use awc::{
+        BoxedSocket,
+        ws::{self, CloseCode, Frame},
+    };
+    use futures_util::{SinkExt as _, StreamExt as _};
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::protocol::ChatResponse;
+
+    fn chat_server() -> Addr<server::ChatServer> {
+        server::ChatServer::new(
+            Arc::new(AtomicUsize::new(0)),
+            history::HistoryStore::start(history::History::open_in_memory(10).unwrap()),
+            0,
+            Box::new(fanout::Local),
+            queue::QueueConfig::default(),
+            Arc::new(queue::QueueMetrics::default()),
+        )
+        .start()
+    }
+
+    /// Serves the chat and connects to it as alice
+    async fn connect(
+        server: Addr<server::ChatServer>,
+        shutdown: shutdown::Shutdown,
+    ) -> (actix_test::TestServer, Framed<BoxedSocket, ws::Codec>) {
+        let shutdown = web::Data::new(shutdown);
+
+        let srv = actix_test::start(move || {
+            App::new()
+                .app_data(web::Data::new(server.clone()))
+                .app_data(web::Data::new(auth::Accounts::load(USERS_FILE).unwrap()))
+                .app_data(web::Data::new(auth::Tokens::default()))
+                .app_data(shutdown.clone())
+                .route("/login", web::post().to(auth::login))
+                .route("/ws", web::get().to(chat_route))
+        });
+
+        let mut res = srv
+            .post("/login")
+            .send_json(&serde_json::json!({ "name": "alice", "password": "wonderland" }))
+            .await
+            .unwrap();
+        let body: serde_json::Value = res.json().await.unwrap();
+        let token = body["token"].as_str().unwrap();
+
+        let (_, ws) = awc::Client::new()
+            .ws(srv.url("/ws"))
+            .bearer_auth(token)
+            .connect()
+            .await
+            .unwrap();
+
+        (srv, ws)
+    }
+
+    /// Waits for the next error sent to the client
+    async fn next_error(ws: &mut Framed<BoxedSocket, ws::Codec>) -> String {
+        loop {
+            let frame = timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+
+            if let Frame::Text(text) = frame.unwrap().unwrap() {
+                if let ChatResponse::Error(err) = serde_json::from_slice(&text).unwrap() {
+                    return err;
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn long_and_binary_frames_are_refused() {
+        let (_srv, mut ws) = connect(chat_server(), shutdown::Shutdown::default()).await;
+        let max = limits::LimitConfig::default().max_frame_len;
+
+        // refused by the codec, without closing the connection
+        ws.send(ws::Message::Text("x".repeat(max + 1).into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_error(&mut ws).await,
+            format!("message is too long, the limit is {max} bytes")
+        );
+
+        ws.send(ws::Message::Binary(vec![1].into())).await.unwrap();
+        assert_eq!(
+            next_error(&mut ws).await,
+            "requests must be sent as text frames"
+        );
+
+        // binary frames are limited like text frames, so the third violation mutes
+        ws.send(ws::Message::Binary(vec![0; max + 1].into()))
+            .await
+            .unwrap();
+        assert!(next_error(&mut ws).await.starts_with("message is too long"));
+        ws.send(ws::Message::Binary(vec![0; max + 1].into()))
+            .await
+            .unwrap();
+        assert!(next_error(&mut ws).await.starts_with("you are muted"));
+    }
+
+    #[actix_web::test]
+    async fn sessions_flush_and_go_away_on_shutdown() {
+        let server = chat_server();
+
+        let shutdown = shutdown::Shutdown::default();
+        close_sessions_on_shutdown(&shutdown, server.clone());
+        let (_srv, mut ws) = connect(server.clone(), shutdown.clone()).await;
+
+        // queued before the server shuts down, so sent before the close frame
+        server
+            .send(server::SystemBroadcast {
+                room: None,
+                text: "last words".to_owned(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let draining = tokio::spawn(async move { shutdown.drain(Duration::from_secs(5)).await });
+
+        let mut notices = Vec::new();
+        let reason = loop {
+            let frame = timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+
+            match frame.unwrap().unwrap() {
+                Frame::Text(text) => {
+                    if let ChatResponse::Notice(notice) = serde_json::from_slice(&text).unwrap() {
+                        notices.push(notice.text);
+                    }
+                }
+                Frame::Close(reason) => break reason.unwrap(),
+                _ => {}
+            }
+        };
+
+        assert_eq!(notices.last().map(String::as_str), Some("last words"));
+        assert_eq!(reason.code, CloseCode::Away);
+        assert_eq!(reason.description, Some(shutdown::reconnect_hint()));
+        assert_eq!(draining.await.unwrap(), 0);
+    }
 }
@@ -1,9 +1,35 @@
-//! `ChatServer` is an actor. It maintains a list of connection client sessions.
-//! It also manages available rooms. Peers send messages to other peers in same
-//! room through `ChatServer`.
+//! `ChatServer` is an actor. It maintains a list of connection client sessions
+//! and the users they belong to. It also manages available rooms. Peers send
+//! messages to other peers in same room through `ChatServer`.
+//!
+//! Who may join and talk in a room is decided by [`Permissions`], which the
+//! owner of each room controls.
+//!
+//! A user may be connected several times, e.g. from multiple browser tabs. They
+//! are announced as online when their first session connects and as offline
+//! when their last one disconnects.
+//!
+//! Sessions are sent [`ChatResponse`]s. Room messages are numbered by the
+//! [`HistoryStore`] that they are stored in, which runs on a thread of its own.
+//!
+//! What is sent to a room is also published through a [`Fanout`] backend, and
+//! what other nodes publish is delivered to the sessions of this one, so that
+//! several servers can share rooms. Shared rooms' messages are numbered by the
+//! backend instead, and changes to their permissions are published too.
+//!
+//! Sessions are sent responses through bounded queues rather than their
+//! mailboxes, so that a client that stops reading cannot use up the server's
+//! memory. What happens once a queue is full is set by its [`QueueConfig`].
+//!
+//! Messages pass through a [`MessageFilter`] before they are sent, which may
+//! rewrite or reject them.
+//!
+//! Administrators can inspect rooms and sessions, and close, rename or
+//! broadcast to rooms and kick sessions, through the [admin API](crate::admin).
 
 use std::{
     collections::{HashMap, HashSet},
+    io,
     sync::{
         Arc,
         atomic::{AtomicUsize, Ordering},
@@ -12,19 +38,38 @@ use std::{
 
 use actix::prelude::*;
 use rand::Rng as _;
+use serde::Serialize;
 
-/// Chat server sends this messages to session
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct Message(pub String);
+use crate::{
+    admin::AdminError,
+    fanout::{Event, Fanout},
+    filter::{Filters, MessageFilter},
+    history::{self, HistoryStore},
+    permissions::{MAIN_ROOM, Moderation, Permissions, RoomError},
+    protocol::{ChatMessage, ChatResponse},
+    queue::{self, QueueConfig, QueueMetrics, QueueReceiver, QueueSender},
+};
 
 /// Message for chat server communications
 ///
-/// New chat session is created
+/// New chat session is created. Returns the session ID and the queue of
+/// responses for the session.
 #[derive(Message)]
-#[rtype(u64)]
+#[rtype(result = "(u64, QueueReceiver<ChatResponse>)")]
 pub struct Connect {
-    pub addr: Recipient<Message>,
+    /// Authenticated user the session belongs to
+    pub user: String,
+
+    /// Address of the session, for telling it that it was kicked
+    pub addr: Recipient<Kicked>,
+}
+
+/// Chat server sends this to a session that an administrator kicked, which
+/// closes its connection
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Kicked {
+    pub reason: String,
 }
 
 /// Session is disconnected
@@ -34,7 +79,7 @@ pub struct Disconnect {
     pub id: u64,
 }
 
-/// Send message to specific room
+/// Send message to the room the session is in
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ClientMessage {
@@ -42,8 +87,27 @@ pub struct ClientMessage {
     pub id: u64,
     /// Peer message
     pub msg: String,
-    /// Room name
-    pub room: String,
+}
+
+/// Send a private message to every session of a user
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Direct {
+    /// Id of the sending client session
+    pub id: u64,
+    /// Receiving user
+    pub to: String,
+    /// Peer message
+    pub msg: String,
+}
+
+/// Moderate the room the session is in, which only its owner may do
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Moderate {
+    /// Id of the client session
+    pub id: u64,
+    pub action: Moderation,
 }
 
 /// List of available rooms
@@ -55,52 +119,331 @@ impl actix::Message for ListRooms {
 
 /// Join room, if room does not exists create new one.
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<(), RoomError>")]
 pub struct Join {
     /// Client ID
     pub id: u64,
 
     /// Room name
     pub name: String,
+
+    /// Password, for password-protected rooms
+    pub password: Option<String>,
+}
+
+/// Send the messages of a room after the given message ID to a session
+///
+/// Used by clients that reconnect to catch up on what they missed.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Replay {
+    /// Client ID
+    pub id: u64,
+
+    /// ID of the last message the client has seen
+    pub after: u64,
+}
+
+/// Rooms of this node, with how many sessions and users are in each
+pub struct RoomStats;
+
+impl actix::Message for RoomStats {
+    type Result = Vec<RoomInfo>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub sessions: usize,
+    pub users: usize,
+}
+
+/// Sessions of this node in a room
+#[derive(Message)]
+#[rtype(result = "Result<Vec<SessionInfo>, AdminError>")]
+pub struct RoomSessions {
+    pub room: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub user: String,
+}
+
+/// Send a notice from the system to a room, or to every session of this node
+#[derive(Message)]
+#[rtype(result = "Result<(), AdminError>")]
+pub struct SystemBroadcast {
+    pub room: Option<String>,
+    pub text: String,
+}
+
+/// Disconnect a session
+#[derive(Message)]
+#[rtype(result = "Result<(), AdminError>")]
+pub struct KickSession {
+    pub id: u64,
+    pub reason: String,
+}
+
+/// Close a room, moving its sessions back to the main room
+#[derive(Message)]
+#[rtype(result = "Result<(), AdminError>")]
+pub struct CloseRoom {
+    pub room: String,
+}
+
+/// Rename a room, keeping its sessions, permissions and history
+#[derive(Message)]
+#[rtype(result = "Result<(), AdminError>")]
+pub struct RenameRoom {
+    pub room: String,
+    pub to: String,
+}
+
+/// Drop the queues of all sessions, which close once they have sent what is
+/// left in them. Used when the server shuts down.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseSessions;
+
+/// A connected session
+#[derive(Debug)]
+struct Session {
+    tx: QueueSender<ChatResponse>,
+    user: String,
+    addr: Recipient<Kicked>,
 }
 
 /// `ChatServer` manages chat rooms and responsible for coordinating chat session.
 ///
 /// Implementation is very naïve.
-#[derive(Debug)]
 pub struct ChatServer {
-    sessions: HashMap<u64, Recipient<Message>>,
+    sessions: HashMap<u64, Session>,
+    /// Sessions of each online user
+    users: HashMap<String, HashSet<u64>>,
     rooms: HashMap<String, HashSet<u64>>,
+    permissions: Permissions,
     visitor_count: Arc<AtomicUsize>,
+    history: Addr<HistoryStore>,
+    /// Number of recent messages sent to sessions that join a room
+    join_replay: usize,
+    /// Carries room traffic to and from other nodes
+    fanout: Box<dyn Fanout>,
+    /// Size and overflow policy of session queues
+    queue_config: QueueConfig,
+    queue_metrics: Arc<QueueMetrics>,
+    /// Decides whether and how messages are sent
+    filter: Box<dyn MessageFilter>,
 }
 
 impl ChatServer {
-    pub fn new(visitor_count: Arc<AtomicUsize>) -> ChatServer {
+    pub fn new(
+        visitor_count: Arc<AtomicUsize>,
+        history: Addr<HistoryStore>,
+        join_replay: usize,
+        fanout: Box<dyn Fanout>,
+        queue_config: QueueConfig,
+        queue_metrics: Arc<QueueMetrics>,
+    ) -> ChatServer {
         // default room
         let mut rooms = HashMap::new();
-        rooms.insert("main".to_owned(), HashSet::new());
+        rooms.insert(MAIN_ROOM.to_owned(), HashSet::new());
+        fanout.add_room(MAIN_ROOM);
 
         ChatServer {
             sessions: HashMap::new(),
+            users: HashMap::new(),
             rooms,
+            permissions: Permissions::default(),
             visitor_count,
+            history,
+            join_replay,
+            fanout,
+            queue_config,
+            queue_metrics,
+            filter: Box::new(Filters::default()),
         }
     }
+
+    /// Passes messages through `filter` before they are sent
+    pub fn with_filter(mut self, filter: impl MessageFilter + 'static) -> Self {
+        self.filter = Box::new(filter);
+        self
+    }
 }
 
 impl ChatServer {
-    /// Send message to all users in the room
-    fn send_message(&self, room: &str, message: &str, skip_id: u64) {
+    /// Send message to all users in the room, on this node and others
+    fn send_message(&self, room: &str, message: &ChatResponse, skip_id: u64) {
+        self.fanout.publish(room, message);
+        self.deliver(room, message, skip_id);
+    }
+
+    /// Send message to the users in the room that are connected to this node
+    fn deliver(&self, room: &str, message: &ChatResponse, skip_id: u64) {
         if let Some(sessions) = self.rooms.get(room) {
             for id in sessions {
                 if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        addr.do_send(Message(message.to_owned()));
+                    if let Some(session) = self.sessions.get(id) {
+                        // errors if the session is gone, or fell too far
+                        // behind and is being disconnected
+                        let _ = session.tx.send(message.clone());
                     }
                 }
             }
         }
     }
+
+    /// Send a notice about a room to all users in it
+    fn send_notice(&self, room: &str, text: String, skip_id: u64) {
+        self.send_message(room, &ChatResponse::notice(Some(room), text), skip_id);
+    }
+
+    /// Send message to a single session
+    fn send_to(&self, id: u64, message: ChatResponse) {
+        if let Some(session) = self.sessions.get(&id) {
+            let _ = session.tx.send(message);
+        }
+    }
+
+    /// Returns the user of a session and the room it is in
+    fn user_and_room(&self, id: u64) -> Option<(String, String)> {
+        let user = self.sessions.get(&id)?.user.clone();
+        let room = self
+            .rooms
+            .iter()
+            .find_map(|(name, sessions)| sessions.contains(&id).then(|| name.clone()))?;
+
+        Some((user, room))
+    }
+
+    /// Tell other nodes about a change to the permissions of a room
+    fn publish_permissions(&self, room: &str) {
+        self.fanout.publish_update(&self.permissions.update(room));
+    }
+
+    /// Move the sessions of `user` in `room` back to the main room, e.g.
+    /// once they are kicked
+    fn remove_from_room(&mut self, room: &str, user: &str, ctx: &mut Context<Self>) {
+        let removed = self.rooms.get(room).map_or_else(Vec::new, |sessions| {
+            sessions
+                .iter()
+                .copied()
+                .filter(|id| self.sessions.get(id).is_some_and(|s| s.user == user))
+                .collect()
+        });
+
+        for removed_id in removed {
+            if let Some(sessions) = self.rooms.get_mut(room) {
+                sessions.remove(&removed_id);
+            }
+            let removal = format!("you were removed from {room}");
+            self.send_to(removed_id, ChatResponse::notice(Some(room), removal));
+            self.send_to(removed_id, ChatResponse::Joined(MAIN_ROOM.to_owned()));
+            self.join_room(removed_id, user, MAIN_ROOM, ctx);
+        }
+    }
+
+    /// Store a message numbered elsewhere in the history
+    fn store(&self, message: ChatMessage, ctx: &mut Context<Self>) {
+        self.history
+            .send(history::Insert(message))
+            .into_actor(self)
+            .map(|res, _, _| match res {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => log::error!("failed to store message: {err}"),
+                Err(err) => log::error!("history store is unavailable: {err}"),
+            })
+            .spawn(ctx);
+    }
+
+    /// Send a notice to every session, in any room
+    fn broadcast(&self, text: String) {
+        let notice = ChatResponse::notice(None, text);
+
+        for session in self.sessions.values() {
+            let _ = session.tx.send(notice.clone());
+        }
+    }
+
+    /// Whether any session of `user` is in `room`
+    fn user_in_room(&self, user: &str, room: &str) -> bool {
+        self.rooms.get(room).is_some_and(|sessions| {
+            sessions
+                .iter()
+                .any(|id| self.sessions.get(id).is_some_and(|s| s.user == user))
+        })
+    }
+
+    /// Remove session from all rooms, telling the rest of each room if the
+    /// user has no other session left in it
+    fn leave_rooms(&mut self, id: u64, user: &str) {
+        let mut rooms = Vec::new();
+
+        for (name, sessions) in &mut self.rooms {
+            if sessions.remove(&id) {
+                rooms.push(name.to_owned());
+            }
+        }
+
+        for room in rooms {
+            if !self.user_in_room(user, &room) {
+                self.send_notice(&room, format!("{user} left"), 0);
+            }
+        }
+    }
+
+    /// Add session to a room, telling the rest of the room if the user was not
+    /// already in it
+    fn join_room(&mut self, id: u64, user: &str, room: &str, ctx: &mut Context<Self>) {
+        if !self.user_in_room(user, room) {
+            self.send_notice(room, format!("{user} joined"), id);
+        }
+
+        if !self.rooms.contains_key(room) {
+            self.fanout.add_room(room);
+        }
+        self.rooms.entry(room.to_owned()).or_default().insert(id);
+
+        let recent = history::Recent {
+            room: room.to_owned(),
+            count: self.join_replay,
+        };
+        self.send_history(id, recent, ctx);
+    }
+
+    /// Send the stored messages returned by `query` to a single session
+    fn send_history<M>(&self, id: u64, query: M, ctx: &mut Context<Self>)
+    where
+        M: Message<Result = rusqlite::Result<Vec<ChatMessage>>> + Send + 'static,
+        HistoryStore: Handler<M>,
+    {
+        self.history
+            .send(query)
+            .into_actor(self)
+            .map(move |res, act, _| {
+                let messages = match res {
+                    Ok(Ok(messages)) => messages,
+                    Ok(Err(err)) => {
+                        log::error!("failed to read message history: {err}");
+                        return;
+                    }
+                    Err(err) => {
+                        log::error!("history store is unavailable: {err}");
+                        return;
+                    }
+                };
+
+                if let Some(session) = act.sessions.get(&id) {
+                    for message in messages {
+                        let _ = session.tx.send(ChatResponse::Message(message));
+                    }
+                }
+            })
+            .spawn(ctx);
+    }
 }
 
 /// Make actor from `ChatServer`
@@ -108,32 +451,75 @@ impl Actor for ChatServer {
     /// We are going to use simple Context, we just need ability to communicate
     /// with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.add_stream(self.fanout.events());
+    }
+}
+
+/// Handler for room traffic and permission changes published by other nodes.
+///
+/// Their messages are stored in this node's history too, so that they are replayed.
+impl StreamHandler<Event> for ChatServer {
+    fn handle(&mut self, event: Event, ctx: &mut Context<Self>) {
+        match event {
+            Event::Room { room, response } => {
+                if let ChatResponse::Message(message) = &response {
+                    self.store(message.clone(), ctx);
+                }
+                self.deliver(&room, &response, 0);
+            }
+
+            Event::Permissions(update) => {
+                self.permissions.apply(&update);
+                if let Some(user) = &update.removed {
+                    self.remove_from_room(&update.room, user, ctx);
+                }
+            }
+        }
+    }
 }
 
 /// Handler for Connect message.
 ///
 /// Register new session and assign unique id to this session
 impl Handler<Connect> for ChatServer {
-    type Result = u64;
+    type Result = MessageResult<Connect>;
 
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        println!("Someone joined");
-
-        // notify all users in same room
-        self.send_message("main", "Someone joined", 0);
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> Self::Result {
+        let Connect { user, addr } = msg;
+        println!("{user} connected");
 
         // register session with random id
         let id = rand::rng().random::<u64>();
-        self.sessions.insert(id, msg.addr);
+        let sessions = self.users.entry(user.clone()).or_default();
+        let first_session = sessions.is_empty();
+        sessions.insert(id);
+        let (tx, rx) = queue::queue(self.queue_config, self.queue_metrics.clone());
+        self.sessions.insert(
+            id,
+            Session {
+                tx,
+                user: user.clone(),
+                addr,
+            },
+        );
+
+        if first_session {
+            self.broadcast(format!("{user} is online"));
+        }
+        self.send_to(id, ChatResponse::Name(user.clone()));
 
         // auto join session to main room
-        self.rooms.entry("main".to_owned()).or_default().insert(id);
+        self.join_room(id, &user, MAIN_ROOM, ctx);
 
-        let count = self.visitor_count.fetch_add(1, Ordering::SeqCst);
-        self.send_message("main", &format!("Total visitors {count}"), 0);
+        if first_session {
+            let count = self.visitor_count.fetch_add(1, Ordering::SeqCst);
+            self.send_notice(MAIN_ROOM, format!("Total visitors {count}"), 0);
+        }
 
         // send id back
-        id
+        MessageResult((id, rx))
     }
 }
 
@@ -142,23 +528,23 @@ impl Handler<Disconnect> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        println!("Someone disconnected");
+        // remove address
+        let Some(Session { user, .. }) = self.sessions.remove(&msg.id) else {
+            return;
+        };
+        println!("{user} disconnected");
 
-        let mut rooms: Vec<String> = Vec::new();
+        self.leave_rooms(msg.id, &user);
 
-        // remove address
-        if self.sessions.remove(&msg.id).is_some() {
-            // remove session from all rooms
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
-                }
+        // announce the user as offline once their last session is gone
+        if let Some(sessions) = self.users.get_mut(&user) {
+            sessions.remove(&msg.id);
+
+            if sessions.is_empty() {
+                self.users.remove(&user);
+                self.broadcast(format!("{user} is offline"));
             }
         }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
-        }
     }
 }
 
@@ -166,14 +552,142 @@ impl Handler<Disconnect> for ChatServer {
 impl Handler<ClientMessage> for ChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        self.send_message(&msg.room, msg.msg.as_str(), msg.id);
+    fn handle(&mut self, msg: ClientMessage, ctx: &mut Context<Self>) {
+        let Some((user, room)) = self.user_and_room(msg.id) else {
+            return;
+        };
+
+        if let Err(err) = self.permissions.check_send(&room, &user) {
+            self.send_to(msg.id, ChatResponse::error(err));
+            return;
+        }
+
+        let text = match self.filter.apply(&user, Some(&room), msg.msg) {
+            Ok(text) => text,
+            Err(reason) => {
+                self.send_to(msg.id, ChatResponse::error(reason));
+                return;
+            }
+        };
+
+        // shared chats are numbered by the fanout backend, others by the history
+        let message_id = self.fanout.message_id();
+        let store = self.history.clone();
+        let sent_to = room.clone();
+
+        let stored = async move {
+            let message = match message_id.await? {
+                Some(id) => {
+                    let message = ChatMessage::new(id, user, Some(&sent_to), text);
+                    store
+                        .send(history::Insert(message.clone()))
+                        .await
+                        .map_err(io::Error::other)?
+                        .map_err(io::Error::other)?;
+                    message
+                }
+                None => {
+                    let append = history::Append {
+                        room: sent_to,
+                        sender: user,
+                        text,
+                    };
+                    store
+                        .send(append)
+                        .await
+                        .map_err(io::Error::other)?
+                        .map_err(io::Error::other)?
+                }
+            };
+
+            io::Result::Ok(message)
+        };
+
+        // the sender gets the message back too, so that it learns its ID
+        stored
+            .into_actor(self)
+            .map(move |res, act, _| match res {
+                Ok(message) => act.send_message(&room, &ChatResponse::Message(message), 0),
+                Err(err) => {
+                    log::error!("failed to send message: {err}");
+                    act.send_to(msg.id, ChatResponse::error(RoomError::NotSent));
+                }
+            })
+            .spawn(ctx);
+    }
+}
+
+/// Handler for `Direct` message.
+impl Handler<Direct> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Direct, _: &mut Context<Self>) {
+        let Some(from) = self.sessions.get(&msg.id).map(|s| s.user.clone()) else {
+            return;
+        };
+
+        if !self.users.contains_key(&msg.to) {
+            self.send_to(msg.id, ChatResponse::error(RoomError::UnknownUser));
+            return;
+        }
+
+        let text = match self.filter.apply(&from, None, msg.msg) {
+            Ok(text) => text,
+            Err(reason) => {
+                self.send_to(msg.id, ChatResponse::error(reason));
+                return;
+            }
+        };
+
+        // private messages are not stored, so are not numbered
+        let message = ChatMessage::new(0, from, None, text);
+
+        for id in self.users.get(&msg.to).into_iter().flatten() {
+            self.send_to(*id, ChatResponse::Message(message.clone()));
+        }
+    }
+}
+
+/// Handler for `Moderate` message.
+///
+/// Users who are kicked or banned are moved back to the main room, on other
+/// nodes too.
+impl Handler<Moderate> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Moderate, ctx: &mut Context<Self>) {
+        let Moderate { id, action } = msg;
+        let Some((user, room)) = self.user_and_room(id) else {
+            return;
+        };
+
+        if let Err(err) = self.permissions.moderate(&room, &user, &action) {
+            self.send_to(id, ChatResponse::error(err));
+            return;
+        }
+        let update = self.permissions.update(&room).removing(action.removes());
+        self.fanout.publish_update(&update);
+
+        if let Moderation::Invite(invited) = &action {
+            for id in self.users.get(invited).into_iter().flatten() {
+                let invite = format!("{user} invited you to {room}");
+                self.send_to(*id, ChatResponse::notice(Some(&room), invite));
+            }
+        }
+
+        if let Some(target) = action.removes() {
+            self.remove_from_room(&room, target, ctx);
+        }
+
+        self.send_notice(&room, action.announcement(), 0);
     }
 }
 
 /// Handler for `ListRooms` message.
+///
+/// Lists the rooms of other nodes too.
 impl Handler<ListRooms> for ChatServer {
-    type Result = MessageResult<ListRooms>;
+    type Result = ResponseFuture<Vec<String>>;
 
     fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
         let mut rooms = Vec::new();
@@ -182,32 +696,408 @@ impl Handler<ListRooms> for ChatServer {
             rooms.push(key.to_owned())
         }
 
-        MessageResult(rooms)
+        self.fanout.rooms(rooms)
     }
 }
 
 /// Join room, send disconnect message to old room
 /// send join message to new room
 impl Handler<Join> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: Join, ctx: &mut Context<Self>) -> Self::Result {
+        let Join { id, name, password } = msg;
+
+        let Some(user) = self.sessions.get(&id).map(|s| s.user.clone()) else {
+            return Ok(());
+        };
+
+        if self.permissions.join(&name, &user, password.as_deref())? {
+            self.publish_permissions(&name);
+        }
+
+        self.leave_rooms(id, &user);
+        self.join_room(id, &user, &name, ctx);
+        Ok(())
+    }
+}
+
+/// Handler for `Replay` message.
+impl Handler<Replay> for ChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
-        let Join { id, name } = msg;
-        let mut rooms = Vec::new();
+    fn handle(&mut self, msg: Replay, ctx: &mut Context<Self>) {
+        if let Some((_, room)) = self.user_and_room(msg.id) {
+            let after = history::After {
+                room,
+                after: msg.after,
+            };
+            self.send_history(msg.id, after, ctx);
+        }
+    }
+}
 
-        // remove session from all rooms
-        for (n, sessions) in &mut self.rooms {
-            if sessions.remove(&id) {
-                rooms.push(n.to_owned());
-            }
+/// Handler for `RoomStats` message.
+impl Handler<RoomStats> for ChatServer {
+    type Result = MessageResult<RoomStats>;
+
+    fn handle(&mut self, _: RoomStats, _: &mut Context<Self>) -> Self::Result {
+        let mut rooms = self
+            .rooms
+            .iter()
+            .map(|(name, sessions)| {
+                let users = sessions
+                    .iter()
+                    .filter_map(|id| self.sessions.get(id))
+                    .map(|session| session.user.as_str())
+                    .collect::<HashSet<_>>();
+
+                RoomInfo {
+                    name: name.clone(),
+                    sessions: sessions.len(),
+                    users: users.len(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        MessageResult(rooms)
+    }
+}
+
+/// Handler for `RoomSessions` message.
+impl Handler<RoomSessions> for ChatServer {
+    type Result = Result<Vec<SessionInfo>, AdminError>;
+
+    fn handle(&mut self, msg: RoomSessions, _: &mut Context<Self>) -> Self::Result {
+        let sessions = self.rooms.get(&msg.room).ok_or(AdminError::UnknownRoom)?;
+
+        let mut sessions = sessions
+            .iter()
+            .filter_map(|id| {
+                let session = self.sessions.get(id)?;
+                Some(SessionInfo {
+                    id: *id,
+                    user: session.user.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        sessions.sort_by(|a, b| a.user.cmp(&b.user).then(a.id.cmp(&b.id)));
+        Ok(sessions)
+    }
+}
+
+/// Handler for `SystemBroadcast` message.
+///
+/// A notice to a room reaches other nodes too, while one to every session only
+/// reaches those of this node.
+impl Handler<SystemBroadcast> for ChatServer {
+    type Result = Result<(), AdminError>;
+
+    fn handle(&mut self, msg: SystemBroadcast, _: &mut Context<Self>) -> Self::Result {
+        match msg.room {
+            Some(room) if !self.rooms.contains_key(&room) => return Err(AdminError::UnknownRoom),
+            Some(room) => self.send_notice(&room, msg.text, 0),
+            None => self.broadcast(msg.text),
         }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
+
+        Ok(())
+    }
+}
+
+/// Handler for `KickSession` message.
+///
+/// The session closes its connection, and then disconnects as usual.
+impl Handler<KickSession> for ChatServer {
+    type Result = Result<(), AdminError>;
+
+    fn handle(&mut self, msg: KickSession, _: &mut Context<Self>) -> Self::Result {
+        let session = self
+            .sessions
+            .get(&msg.id)
+            .ok_or(AdminError::UnknownSession)?;
+        session.addr.do_send(Kicked { reason: msg.reason });
+        Ok(())
+    }
+}
+
+/// Handler for `CloseRoom` message.
+///
+/// The room's history is kept, and is replayed if the room is joined again.
+impl Handler<CloseRoom> for ChatServer {
+    type Result = Result<(), AdminError>;
+
+    fn handle(&mut self, msg: CloseRoom, ctx: &mut Context<Self>) -> Self::Result {
+        let CloseRoom { room } = msg;
+
+        if room == MAIN_ROOM {
+            return Err(AdminError::MainRoom);
+        }
+        let sessions = self.rooms.remove(&room).ok_or(AdminError::UnknownRoom)?;
+        self.permissions.remove_room(&room);
+        self.publish_permissions(&room);
+        self.fanout.remove_room(&room);
+
+        for id in sessions {
+            let Some(user) = self.sessions.get(&id).map(|s| s.user.clone()) else {
+                continue;
+            };
+
+            let closed = format!("{room} was closed by an administrator");
+            self.send_to(id, ChatResponse::notice(Some(&room), closed));
+            self.send_to(id, ChatResponse::Joined(MAIN_ROOM.to_owned()));
+            self.join_room(id, &user, MAIN_ROOM, ctx);
+        }
+
+        Ok(())
+    }
+}
+
+/// Handler for `RenameRoom` message.
+///
+/// The sessions in the room are told they are now in the renamed room.
+impl Handler<RenameRoom> for ChatServer {
+    type Result = Result<(), AdminError>;
+
+    fn handle(&mut self, msg: RenameRoom, ctx: &mut Context<Self>) -> Self::Result {
+        let RenameRoom { room, to } = msg;
+
+        if room == MAIN_ROOM || to == MAIN_ROOM {
+            return Err(AdminError::MainRoom);
+        }
+        if self.rooms.contains_key(&to) {
+            return Err(AdminError::RoomExists);
+        }
+        let sessions = self.rooms.remove(&room).ok_or(AdminError::UnknownRoom)?;
+
+        let rename = history::RenameRoom {
+            from: room.clone(),
+            to: to.clone(),
+        };
+        self.history
+            .send(rename)
+            .into_actor(self)
+            .map(|res, _, _| match res {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => log::error!("failed to move message history: {err}"),
+                Err(err) => log::error!("history store is unavailable: {err}"),
+            })
+            .spawn(ctx);
+        self.permissions.rename_room(&room, &to);
+        self.publish_permissions(&room);
+        self.publish_permissions(&to);
+        self.fanout.remove_room(&room);
+        self.fanout.add_room(&to);
+
+        for id in &sessions {
+            self.send_to(*id, ChatResponse::Joined(to.clone()));
+        }
+        self.rooms.insert(to.clone(), sessions);
+        self.send_notice(&to, format!("{room} was renamed to {to}"), 0);
+
+        Ok(())
+    }
+}
+
+/// Handler for `CloseSessions` message.
+///
+/// Users are not announced as offline, since everyone is being disconnected.
+impl Handler<CloseSessions> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, _: CloseSessions, _: &mut Context<Self>) {
+        self.sessions.clear();
+        self.users.clear();
+
+        for sessions in self.rooms.values_mut() {
+            sessions.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        fanout::{Hub, Local},
+        history::History,
+    };
+
+    /// Stands in for a WebSocket session
+    struct Probe;
+
+    impl Actor for Probe {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Kicked> for Probe {
+        type Result = ();
+
+        fn handle(&mut self, _: Kicked, _: &mut Context<Self>) {}
+    }
+
+    async fn connect(
+        server: &Addr<ChatServer>,
+        user: &str,
+        room: &str,
+    ) -> (u64, QueueReceiver<ChatResponse>) {
+        let (id, rx) = server
+            .send(Connect {
+                user: user.to_owned(),
+                addr: Probe.start().recipient(),
+            })
+            .await
+            .unwrap();
+        server
+            .send(Join {
+                id,
+                name: room.to_owned(),
+                password: None,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        (id, rx)
+    }
+
+    /// Takes responses to the session up to and including the first that `matches` accepts
+    async fn take_until(
+        rx: &mut QueueReceiver<ChatResponse>,
+        matches: impl Fn(&ChatResponse) -> bool,
+    ) -> Vec<ChatResponse> {
+        let mut taken = Vec::new();
+        while !taken.last().is_some_and(&matches) {
+            let res = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            taken.push(res);
         }
 
-        self.rooms.entry(name.clone()).or_default().insert(id);
+        taken
+    }
+
+    fn server(history: Addr<HistoryStore>, fanout: Box<dyn Fanout>) -> Addr<ChatServer> {
+        ChatServer::new(
+            Arc::new(AtomicUsize::new(0)),
+            history,
+            0,
+            fanout,
+            QueueConfig::default(),
+            Arc::new(QueueMetrics::default()),
+        )
+        .start()
+    }
+
+    fn notice(text: &str) -> impl Fn(&ChatResponse) -> bool + '_ {
+        move |res| matches!(res, ChatResponse::Notice(notice) if notice.text == text)
+    }
+
+    #[actix_web::test]
+    async fn removed_users_are_told_they_joined_the_main_room() {
+        let history = HistoryStore::start(History::open_in_memory(10).unwrap());
+        let server = server(history, Box::new(Local));
+
+        let (alice, _alice_rx) = connect(&server, "alice", "lobby").await;
+
+        for action in [Moderation::Kick, Moderation::Ban] {
+            let (_, mut bob_rx) = connect(&server, "bob", "lobby").await;
+
+            server
+                .send(Moderate {
+                    id: alice,
+                    action: action("bob".to_owned()),
+                })
+                .await
+                .unwrap();
+
+            let frames = take_until(&mut bob_rx, |res| {
+                *res == ChatResponse::Joined(MAIN_ROOM.to_owned())
+            })
+            .await;
+            assert!(frames.iter().any(notice("you were removed from lobby")));
+
+            server
+                .send(Moderate {
+                    id: alice,
+                    action: Moderation::Unban("bob".to_owned()),
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    #[actix_web::test]
+    async fn nodes_share_messages_and_permissions() {
+        let hub = Hub::default();
+        let history_b = HistoryStore::start(History::open_in_memory(10).unwrap());
+        let server_a = server(
+            HistoryStore::start(History::open_in_memory(10).unwrap()),
+            Box::new(hub.node("a")),
+        );
+        let server_b = server(history_b.clone(), Box::new(hub.node("b")));
+
+        let (bob, mut bob_rx) = connect(&server_b, "bob", MAIN_ROOM).await;
+        let (alice, mut alice_rx) = connect(&server_a, "alice", "lobby").await;
+
+        // node b has applied alice's ownership of the room once she has left the main room
+        take_until(&mut bob_rx, notice("alice left")).await;
+        server_b
+            .send(Join {
+                id: bob,
+                name: "lobby".to_owned(),
+                password: None,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        take_until(&mut alice_rx, notice("bob joined")).await;
+
+        server_a
+            .send(ClientMessage {
+                id: alice,
+                msg: "hello from a".to_owned(),
+            })
+            .await
+            .unwrap();
+        let frames = take_until(&mut bob_rx, |res| matches!(res, ChatResponse::Message(_))).await;
+        let Some(ChatResponse::Message(message)) = frames.last() else {
+            unreachable!();
+        };
+        assert_eq!(message.id, 1);
+
+        let recent = history_b
+            .send(history::Recent {
+                room: "lobby".to_owned(),
+                count: 10,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(recent, std::slice::from_ref(message));
+
+        server_a
+            .send(Moderate {
+                id: alice,
+                action: Moderation::Ban("bob".to_owned()),
+            })
+            .await
+            .unwrap();
+        let frames = take_until(&mut bob_rx, |res| {
+            *res == ChatResponse::Joined(MAIN_ROOM.to_owned())
+        })
+        .await;
+        assert!(frames.iter().any(notice("you were removed from lobby")));
 
-        self.send_message(&name, "Someone connected", id);
+        let rejoin = Join {
+            id: bob,
+            name: "lobby".to_owned(),
+            password: None,
+        };
+        assert_eq!(server_b.send(rejoin).await.unwrap(), Err(RoomError::Banned));
     }
 }
@@ -1,9 +1,13 @@
 use std::time::{Duration, Instant};
 
 use actix::prelude::*;
-use actix_web_actors::ws;
+use actix_web_actors::ws::{self, CloseCode, CloseReason};
 
-use crate::server;
+use crate::{
+    limits::SessionLimits,
+    protocol::{ChatRequest, ChatResponse},
+    server, shutdown,
+};
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -20,14 +24,17 @@ pub struct WsChatSession {
     /// otherwise we drop connection.
     pub hb: Instant,
 
-    /// joined room
-    pub room: String,
-
-    /// peer name
-    pub name: Option<String>,
+    /// authenticated user
+    pub user: String,
 
     /// Chat server
     pub addr: Addr<server::ChatServer>,
+
+    /// How long and how frequent the client's requests may be
+    pub limits: SessionLimits,
+
+    /// Tells the session whether the server is shutting down
+    pub shutdown: shutdown::Guard,
 }
 
 impl WsChatSession {
@@ -54,6 +61,92 @@ impl WsChatSession {
             ctx.ping(b"");
         });
     }
+
+    /// Forwards a request of the peer to the chat server
+    fn handle_request(&mut self, req: ChatRequest, ctx: &mut ws::WebsocketContext<Self>) {
+        // muted clients may do anything but talk
+        if matches!(req, ChatRequest::Message(_) | ChatRequest::Direct { .. }) {
+            if let Err(err) = self.limits.check_send(Instant::now()) {
+                send(ctx, &ChatResponse::error(err));
+                return;
+            }
+        }
+
+        match req {
+            ChatRequest::List => {
+                // Send ListRooms message to chat server and wait for
+                // response
+                println!("List rooms");
+                self.addr
+                    .send(server::ListRooms)
+                    .into_actor(self)
+                    .then(|res, _, ctx| {
+                        match res {
+                            Ok(rooms) => send(ctx, &ChatResponse::Rooms(rooms)),
+                            _ => println!("Something is wrong"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+                // .wait(ctx) pauses all events in context,
+                // so actor wont receive any new messages until it get list
+                // of rooms back
+            }
+            ChatRequest::Join { room, password } => self
+                .addr
+                .send(server::Join {
+                    id: self.id,
+                    name: room.clone(),
+                    password,
+                })
+                .into_actor(self)
+                .then(|res, _, ctx| {
+                    match res {
+                        Ok(Ok(())) => send(ctx, &ChatResponse::Joined(room)),
+                        Ok(Err(err)) => send(ctx, &ChatResponse::error(err)),
+                        _ => println!("Something is wrong"),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ChatRequest::Name(_) => {
+                send(ctx, &ChatResponse::error("name is taken from login"));
+            }
+            ChatRequest::Message(msg) => {
+                // send message to chat server
+                self.addr
+                    .do_send(server::ClientMessage { id: self.id, msg })
+            }
+            ChatRequest::Direct { to, text } => self.addr.do_send(server::Direct {
+                id: self.id,
+                to,
+                msg: text,
+            }),
+            ChatRequest::Moderate(action) => self.addr.do_send(server::Moderate {
+                id: self.id,
+                action,
+            }),
+            ChatRequest::History { after } => {
+                self.addr.do_send(server::Replay { id: self.id, after })
+            }
+            ChatRequest::Typing(_)
+            | ChatRequest::Read { .. }
+            | ChatRequest::Away(_)
+            | ChatRequest::Members => send(
+                ctx,
+                &ChatResponse::error(
+                    "typing, read receipts and presence are not supported by this server",
+                ),
+            ),
+            ChatRequest::Ping => self.hb = Instant::now(),
+        }
+    }
+}
+
+/// Sends a response to the peer as a JSON text frame
+fn send(ctx: &mut ws::WebsocketContext<WsChatSession>, res: &ChatResponse) {
+    // unwrap: responses are plain data that always serializes
+    ctx.text(serde_json::to_string(res).unwrap());
 }
 
 impl Actor for WsChatSession {
@@ -70,15 +163,21 @@ impl Actor for WsChatSession {
         // before processing any other events.
         // HttpContext::state() is instance of WsChatSessionState, state is shared
         // across all routes within application
-        let addr = ctx.address();
         self.addr
             .send(server::Connect {
-                addr: addr.recipient(),
+                user: self.user.clone(),
+                addr: ctx.address().recipient(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok((id, rx)) => {
+                        act.id = id;
+
+                        // the queue is only read while the client keeps up
+                        // with what is written to it
+                        ctx.add_stream(rx.into_stream());
+                    }
                     // something is wrong with chat server
                     _ => ctx.stop(),
                 }
@@ -94,12 +193,46 @@ impl Actor for WsChatSession {
     }
 }
 
-/// Handle messages from chat server, we simply send it to peer websocket
-impl Handler<server::Message> for WsChatSession {
+/// Handle responses queued by chat server, we simply send them to peer websocket
+impl StreamHandler<ChatResponse> for WsChatSession {
+    fn handle(&mut self, res: ChatResponse, ctx: &mut Self::Context) {
+        send(ctx, &res);
+    }
+
+    /// The queue is closed early if the client fell too far behind, or once
+    /// everything queued has been sent when the server shuts down
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        let reason = if self.shutdown.is_shutting_down() {
+            CloseReason {
+                code: CloseCode::Away,
+                description: Some(shutdown::reconnect_hint()),
+            }
+        } else {
+            println!("Websocket Client not keeping up, disconnecting!");
+
+            CloseReason {
+                code: CloseCode::Again,
+                description: Some("not keeping up with messages".to_owned()),
+            }
+        };
+
+        ctx.close(Some(reason));
+        ctx.stop();
+    }
+}
+
+/// Handle being kicked by an administrator
+impl Handler<server::Kicked> for WsChatSession {
     type Result = ();
 
-    fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+    fn handle(&mut self, msg: server::Kicked, ctx: &mut Self::Context) {
+        println!("Websocket Client kicked: {}", msg.reason);
+
+        ctx.close(Some(CloseReason {
+            code: CloseCode::Policy,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
     }
 }
 
@@ -107,6 +240,11 @@ impl Handler<server::Message> for WsChatSession {
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
+            Err(ws::ProtocolError::Overflow) => {
+                let err = self.limits.refuse_too_long(Instant::now());
+                send(ctx, &ChatResponse::error(err));
+                return;
+            }
             Err(_) => {
                 ctx.stop();
                 return;
@@ -124,71 +262,23 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                 self.hb = Instant::now();
             }
             ws::Message::Text(text) => {
-                let m = text.trim();
-                // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            println!("List rooms");
-                            self.addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
-                                        }
-                                        _ => println!("Something is wrong"),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) pauses all events in context,
-                            // so actor wont receive any new messages until it get list
-                            // of rooms back
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                v[1].clone_into(&mut self.room);
-                                self.addr.do_send(server::Join {
-                                    id: self.id,
-                                    name: self.room.clone(),
-                                });
-
-                                ctx.text("joined");
-                            } else {
-                                ctx.text("!!! room name is required");
-                            }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {m:?}")),
-                    }
-                } else {
-                    let msg = if let Some(ref name) = self.name {
-                        format!("{name}: {m}")
-                    } else {
-                        m.to_owned()
-                    };
-                    // send message to chat server
-                    self.addr.do_send(server::ClientMessage {
-                        id: self.id,
-                        msg,
-                        room: self.room.clone(),
-                    })
+                if let Err(err) = self.limits.check_frame(text.len(), Instant::now()) {
+                    send(ctx, &ChatResponse::error(err));
+                    return;
                 }
+
+                match serde_json::from_str(&text) {
+                    Ok(req) => self.handle_request(req, ctx),
+                    Err(err) => send(ctx, &ChatResponse::error(format!("invalid request: {err}"))),
+                }
+            }
+            ws::Message::Binary(bin) => {
+                let err = match self.limits.check_frame(bin.len(), Instant::now()) {
+                    Err(err) => err.to_string(),
+                    Ok(()) => "requests must be sent as text frames".to_owned(),
+                };
+                send(ctx, &ChatResponse::error(err));
             }
-            ws::Message::Binary(_) => println!("Unexpected binary"),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
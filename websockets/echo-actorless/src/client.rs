@@ -1,12 +1,13 @@
 //! Simple websocket client.
+//!
+//! Reconnects whenever the connection drops. If an argument is given, it is sent each time the
+//! client connects, before anything typed while it was disconnected.
 
-use std::{io, thread};
+use std::{env, io, thread};
 
-use actix_web::web::Bytes;
-use awc::ws;
-use futures_util::{SinkExt as _, StreamExt as _};
-use tokio::{select, sync::mpsc};
-use tokio_stream::wrappers::UnboundedReceiverStream;
+mod reconnect;
+
+use self::reconnect::{Client, Event, Outgoing};
 
 #[actix_web::main]
 async fn main() {
@@ -14,59 +15,44 @@ async fn main() {
 
     log::info!("starting echo WebSocket client");
 
-    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-    let mut cmd_rx = UnboundedReceiverStream::new(cmd_rx);
+    let on_connect = env::args().nth(1);
+
+    let (tx, mut events) = Client::new("ws://127.0.0.1:8080/ws")
+        .on_connect(move || on_connect.iter().cloned().map(Outgoing::Text).collect())
+        .start();
 
     // run blocking terminal input reader on separate thread
     let input_thread = thread::spawn(move || {
         loop {
             let mut cmd = String::with_capacity(32);
 
-            if io::stdin().read_line(&mut cmd).is_err() {
-                log::error!("error reading line");
-                return;
+            match io::stdin().read_line(&mut cmd) {
+                // end of input stops the client
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(_) => {
+                    log::error!("error reading line");
+                    return;
+                }
             }
 
-            cmd_tx.send(cmd).unwrap();
-        }
-    });
-
-    let (res, mut ws) = awc::Client::new()
-        .ws("ws://127.0.0.1:8080/ws")
-        .connect()
-        .await
-        .unwrap();
-
-    log::debug!("response: {res:?}");
-    log::info!("connected; server will echo messages sent");
-
-    loop {
-        select! {
-            Some(msg) = ws.next() => {
-                match msg {
-                    Ok(ws::Frame::Text(txt)) => {
-                        // log echoed messages from server
-                        log::info!("Server: {txt:?}")
-                    }
-
-                    Ok(ws::Frame::Ping(_)) => {
-                        // respond to ping probes
-                        ws.send(ws::Message::Pong(Bytes::new())).await.unwrap();
-                    }
-
-                    _ => {}
-                }
+            if cmd.trim().is_empty() {
+                continue;
             }
 
-            Some(cmd) = cmd_rx.next() => {
-                if cmd.is_empty() {
-                    continue;
-                }
+            // buffered by the client while it is disconnected
+            tx.text(cmd);
+        }
+    });
 
-                ws.send(ws::Message::Text(cmd.into())).await.unwrap();
-            }
+    while let Some(event) = events.recv().await {
+        match event {
+            Event::Connected => log::info!("connected; server will echo messages sent"),
+            Event::Disconnected(reason) => log::warn!("disconnected: {reason}"),
 
-            else => break
+            // log echoed messages from server
+            Event::Text(txt) => log::info!("Server: {txt:?}"),
+            Event::Binary(bin) => log::info!("Server: {bin:?}"),
         }
     }
 
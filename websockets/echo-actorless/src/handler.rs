@@ -1,13 +1,15 @@
 use std::time::{Duration, Instant};
 
 use actix_web::web;
-use actix_ws::Message;
+use actix_ws::{CloseCode, CloseReason, Message};
 use futures_util::{
     StreamExt as _,
     future::{self, Either},
 };
 use tokio::{pin, select, sync::broadcast, time::interval};
 
+use crate::shutdown::{self, Guard};
+
 /// How often heartbeat pings are sent.
 ///
 /// Should be half (or less) of the acceptable client timeout.
@@ -16,11 +18,22 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout.
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Close reason sent to clients when the server shuts down.
+fn going_away() -> CloseReason {
+    CloseReason {
+        code: CloseCode::Away,
+        description: Some(shutdown::reconnect_hint()),
+    }
+}
+
 /// Echo text & binary messages received from the client, respond to ping messages, and monitor
 /// connection health to detect network issues and free up resources.
+///
+/// The connection is closed with `1001 Going Away` when the server shuts down.
 pub async fn echo_heartbeat_ws(
     mut session: actix_ws::Session,
     mut msg_stream: actix_ws::MessageStream,
+    mut shutdown: Guard,
 ) {
     log::info!("connected");
 
@@ -30,12 +43,14 @@ pub async fn echo_heartbeat_ws(
     let reason = loop {
         // create "next client timeout check" future
         let tick = interval.tick();
+        let signalled = shutdown.signalled();
         // required for select()
-        pin!(tick);
+        pin!(tick, signalled);
 
-        // waits for either `msg_stream` to receive a message from the client or the heartbeat
-        // interval timer to tick, yielding the value of whichever one is ready first
-        match future::select(msg_stream.next(), tick).await {
+        // waits for either `msg_stream` to receive a message from the client, the heartbeat
+        // interval timer to tick or the server to shut down, yielding the value of whichever one
+        // is ready first
+        match future::select(msg_stream.next(), future::select(tick, signalled)).await {
             // received message from WebSocket client
             Either::Left((Some(Ok(msg)), _)) => {
                 log::debug!("msg: {msg:?}");
@@ -81,7 +96,7 @@ pub async fn echo_heartbeat_ws(
             Either::Left((None, _)) => break None,
 
             // heartbeat interval ticked
-            Either::Right((_inst, _)) => {
+            Either::Right((Either::Left((_inst, _)), _)) => {
                 // if no heartbeat ping/pong received recently, close the connection
                 if Instant::now().duration_since(last_heartbeat) > CLIENT_TIMEOUT {
                     log::info!(
@@ -94,6 +109,9 @@ pub async fn echo_heartbeat_ws(
                 // send heartbeat ping
                 let _ = session.ping(b"").await;
             }
+
+            // server is shutting down
+            Either::Right((Either::Right(((), _)), _)) => break Some(going_away()),
         }
     };
 
@@ -110,11 +128,20 @@ pub async fn echo_heartbeat_ws(
 /// connections die or network issues arise.
 ///
 /// See [`echo_heartbeat_ws`] for a more realistic implementation.
-pub async fn echo_ws(mut session: actix_ws::Session, mut msg_stream: actix_ws::MessageStream) {
+pub async fn echo_ws(
+    mut session: actix_ws::Session,
+    mut msg_stream: actix_ws::MessageStream,
+    mut shutdown: Guard,
+) {
     log::info!("connected");
 
     let close_reason = loop {
-        match msg_stream.next().await {
+        let msg = select! {
+            msg = msg_stream.next() => msg,
+            () = shutdown.signalled() => break Some(going_away()),
+        };
+
+        match msg {
             Some(Ok(msg)) => {
                 log::debug!("msg: {msg:?}");
 
@@ -163,6 +190,7 @@ pub async fn broadcast_ws(
     mut session: actix_ws::Session,
     mut msg_stream: actix_ws::MessageStream,
     mut rx: broadcast::Receiver<web::Bytes>,
+    mut shutdown: Guard,
 ) {
     log::info!("connected");
 
@@ -171,8 +199,8 @@ pub async fn broadcast_ws(
 
     let reason = loop {
         // waits for either `msg_stream` to receive a message from the client, the broadcast channel
-        // to send a message, or the heartbeat interval timer to tick, yielding the value of
-        // whichever one is ready first
+        // to send a message, the heartbeat interval timer to tick or the server to shut down,
+        // yielding the value of whichever one is ready first
         select! {
             broadcast_msg = rx.recv() => {
                 let msg = match broadcast_msg {
@@ -207,6 +235,8 @@ pub async fn broadcast_ws(
                 let _ = session.ping(b"").await;
             },
 
+            () = shutdown.signalled() => break Some(going_away()),
+
             msg = msg_stream.next() => {
                 let msg = match msg {
                     // received message from WebSocket client
@@ -11,17 +11,28 @@ use actix_web::{
 use tokio::sync::broadcast;
 
 mod handler;
+mod shutdown;
+
+use self::shutdown::Shutdown;
 
 async fn index() -> impl Responder {
     NamedFile::open_async("./static/index.html").await.unwrap()
 }
 
 /// Handshake and start WebSocket handler with heartbeats.
-async fn echo_heartbeat_ws(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+async fn echo_heartbeat_ws(
+    req: HttpRequest,
+    stream: web::Payload,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, Error> {
     let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
 
     // spawn websocket handler (and don't await it) so that the response is returned immediately
-    rt::spawn(handler::echo_heartbeat_ws(session, msg_stream));
+    rt::spawn(handler::echo_heartbeat_ws(
+        session,
+        msg_stream,
+        shutdown.guard(),
+    ));
 
     Ok(res)
 }
@@ -31,11 +42,15 @@ async fn echo_heartbeat_ws(req: HttpRequest, stream: web::Payload) -> Result<Htt
 /// This example is just for simple demonstration. In reality, you likely want to include
 /// some handling of heartbeats for connection health tracking to free up server resources when
 /// connections die or network issues arise.
-async fn echo_ws(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+async fn echo_ws(
+    req: HttpRequest,
+    stream: web::Payload,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, Error> {
     let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
 
     // spawn websocket handler (and don't await it) so that the response is returned immediately
-    rt::spawn(handler::echo_ws(session, msg_stream));
+    rt::spawn(handler::echo_ws(session, msg_stream, shutdown.guard()));
 
     Ok(res)
 }
@@ -56,11 +71,17 @@ async fn broadcast_ws(
     req: HttpRequest,
     stream: web::Payload,
     tx: web::Data<broadcast::Sender<web::Bytes>>,
+    shutdown: web::Data<Shutdown>,
 ) -> Result<HttpResponse, Error> {
     let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
 
     // spawn websocket handler (and don't await it) so that the response is returned immediately
-    rt::spawn(handler::broadcast_ws(session, msg_stream, tx.subscribe()));
+    rt::spawn(handler::broadcast_ws(
+        session,
+        msg_stream,
+        tx.subscribe(),
+        shutdown.guard(),
+    ));
 
     Ok(res)
 }
@@ -73,8 +94,13 @@ async fn main() -> io::Result<()> {
 
     let (tx, _) = broadcast::channel::<web::Bytes>(128);
 
-    HttpServer::new(move || {
+    // sessions are closed with `1001 Going Away` on SIGINT or SIGTERM
+    let shutdown = Shutdown::default();
+    let app_shutdown = shutdown.clone();
+
+    let server = HttpServer::new(move || {
         App::new()
+            .app_data(web::Data::new(app_shutdown.clone()))
             // WebSocket UI HTML file
             .service(web::resource("/").to(index))
             // websocket routes
@@ -88,7 +114,61 @@ async fn main() -> io::Result<()> {
             .wrap(middleware::Logger::default())
     })
     .workers(2)
+    .disable_signals()
+    .shutdown_timeout(shutdown::DRAIN_TIMEOUT.as_secs())
     .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .run();
+
+    shutdown::run(server, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use awc::ws::{CloseCode, Frame};
+    use futures_util::StreamExt as _;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn clients_are_told_the_server_is_going_away() {
+        let shutdown = Shutdown::default();
+        let app_shutdown = shutdown.clone();
+
+        let srv = actix_test::start(move || {
+            App::new()
+                .app_data(web::Data::new(app_shutdown.clone()))
+                .service(web::resource("/ws").route(web::get().to(echo_heartbeat_ws)))
+                .service(web::resource("/ws-basic").route(web::get().to(echo_ws)))
+        });
+
+        let mut clients = Vec::new();
+        for path in ["/ws", "/ws-basic"] {
+            let (_, ws) = awc::Client::new()
+                .ws(srv.url(path))
+                .connect()
+                .await
+                .unwrap();
+            clients.push(ws);
+        }
+
+        let draining = tokio::spawn(async move { shutdown.drain(Duration::from_secs(5)).await });
+
+        for mut ws in clients {
+            let reason = loop {
+                let frame = timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+
+                if let Frame::Close(reason) = frame.unwrap().unwrap() {
+                    break reason.unwrap();
+                }
+            };
+
+            assert_eq!(reason.code, CloseCode::Away);
+            assert_eq!(reason.description, Some(shutdown::reconnect_hint()));
+        }
+
+        assert_eq!(draining.await.unwrap(), 0);
+    }
 }
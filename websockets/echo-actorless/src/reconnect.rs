@@ -0,0 +1,395 @@
+//! WebSocket client that reconnects by itself, shared by the echo examples' clients.
+//!
+//! [`Client::start`] keeps a connection open in the background. When the connection drops, or the
+//! server stops answering heartbeats for [`CLIENT_TIMEOUT`], the client reconnects after an
+//! exponential backoff with jitter. Each time it connects, it first sends what its on-connect
+//! hook returns, e.g. to re-join a room, and then whatever was sent while it was disconnected.
+
+// not every client uses every part of this module
+#![allow(dead_code)]
+
+use std::{collections::VecDeque, fmt, time::Duration};
+
+use actix_web::web::Bytes;
+use awc::ws;
+use futures_util::{Sink, SinkExt as _, Stream, StreamExt as _};
+use rand::Rng as _;
+use tokio::{
+    select,
+    sync::mpsc,
+    time::{Instant, interval_at, sleep},
+};
+
+/// How often heartbeat pings are sent, as by the server.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the server may stay silent before it is taken for dead, as it does with clients.
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of messages kept while disconnected. The oldest are dropped beyond that.
+const BUFFER_CAPACITY: usize = 256;
+
+/// A data message for the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outgoing {
+    Text(String),
+    Binary(Bytes),
+}
+
+impl From<Outgoing> for ws::Message {
+    fn from(msg: Outgoing) -> Self {
+        match msg {
+            Outgoing::Text(text) => ws::Message::Text(text.into()),
+            Outgoing::Binary(bin) => ws::Message::Binary(bin),
+        }
+    }
+}
+
+/// What happened to the connection, or what the server sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Connected,
+
+    /// The connection was lost, for the given reason. The client will reconnect.
+    Disconnected(String),
+
+    Text(String),
+    Binary(Bytes),
+}
+
+/// Builder and handle of a reconnecting client.
+pub struct Client {
+    url: String,
+    on_connect: Box<dyn FnMut() -> Vec<Outgoing>>,
+    min_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Client {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            on_connect: Box::new(Vec::new),
+            min_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+
+    /// Sets a hook that is run each time the client connects, returning the messages to send
+    /// before any others.
+    pub fn on_connect(mut self, hook: impl FnMut() -> Vec<Outgoing> + 'static) -> Self {
+        self.on_connect = Box::new(hook);
+        self
+    }
+
+    /// Sets the shortest and longest wait before reconnecting.
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Starts connecting on the current thread's runtime.
+    ///
+    /// Returns a sender for messages and a receiver of events. The client stops once every sender
+    /// is dropped.
+    pub fn start(self) -> (Sender, mpsc::UnboundedReceiver<Event>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+
+        let driver = Driver {
+            client: self,
+            rx,
+            events: events_tx,
+            buffer: VecDeque::new(),
+        };
+        actix_web::rt::spawn(driver.run());
+
+        (Sender(tx), events_rx)
+    }
+}
+
+/// Sends messages to the server, or buffers them until the client is connected again.
+#[derive(Debug, Clone)]
+pub struct Sender(mpsc::UnboundedSender<Outgoing>);
+
+impl Sender {
+    pub fn send(&self, msg: Outgoing) {
+        // the client only stops once all senders are gone
+        let _ = self.0.send(msg);
+    }
+
+    pub fn text(&self, text: impl Into<String>) {
+        self.send(Outgoing::Text(text.into()));
+    }
+}
+
+/// How a connection ended.
+enum Ended {
+    /// Every sender was dropped.
+    Stopped,
+
+    /// The connection was lost. The server may have said how long to wait before reconnecting.
+    Lost {
+        reason: String,
+        retry_after: Option<Duration>,
+    },
+}
+
+struct Driver {
+    client: Client,
+    rx: mpsc::UnboundedReceiver<Outgoing>,
+    events: mpsc::UnboundedSender<Event>,
+    buffer: VecDeque<Outgoing>,
+}
+
+impl Driver {
+    async fn run(mut self) {
+        let mut attempt = 0;
+
+        loop {
+            let mut retry_after = None;
+
+            match awc::Client::new().ws(&self.client.url).connect().await {
+                Ok((_, ws)) => {
+                    attempt = 0;
+                    let _ = self.events.send(Event::Connected);
+
+                    match self.connection(ws).await {
+                        Ended::Stopped => return,
+                        Ended::Lost {
+                            reason,
+                            retry_after: hint,
+                        } => {
+                            let _ = self.events.send(Event::Disconnected(reason));
+                            retry_after = hint;
+                        }
+                    }
+                }
+                Err(err) => log::warn!("failed to connect to {}: {err}", self.client.url),
+            }
+
+            let delay = retry_after.unwrap_or_else(|| {
+                backoff(attempt, self.client.min_backoff, self.client.max_backoff)
+            });
+            attempt += 1;
+            log::info!("reconnecting in {delay:?}");
+
+            if !self.wait(delay).await {
+                return;
+            }
+        }
+    }
+
+    /// Buffers messages for `delay`. Returns false if every sender was dropped.
+    async fn wait(&mut self, delay: Duration) -> bool {
+        let delay = sleep(delay);
+        tokio::pin!(delay);
+
+        loop {
+            select! {
+                () = &mut delay => return true,
+
+                msg = self.rx.recv() => match msg {
+                    Some(msg) => self.buffer(msg),
+                    None => return false,
+                },
+            }
+        }
+    }
+
+    fn buffer(&mut self, msg: Outgoing) {
+        if self.buffer.len() >= BUFFER_CAPACITY {
+            log::warn!("send buffer is full, dropping the oldest message");
+            self.buffer.pop_front();
+        }
+
+        self.buffer.push_back(msg);
+    }
+
+    async fn connection<S, E>(&mut self, mut ws: S) -> Ended
+    where
+        S: Stream<Item = Result<ws::Frame, E>> + Sink<ws::Message, Error = E> + Unpin,
+        E: fmt::Display,
+    {
+        let lost = |reason: String| Ended::Lost {
+            reason,
+            retry_after: None,
+        };
+
+        for msg in (self.client.on_connect)() {
+            if let Err(err) = ws.send(msg.into()).await {
+                return lost(err.to_string());
+            }
+        }
+
+        while let Some(msg) = self.buffer.pop_front() {
+            if let Err(err) = ws.send(msg.clone().into()).await {
+                self.buffer.push_front(msg);
+                return lost(err.to_string());
+            }
+        }
+
+        let mut last_heard = Instant::now();
+        let mut heartbeat = interval_at(last_heard + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+
+        loop {
+            select! {
+                frame = ws.next() => {
+                    let frame = match frame {
+                        Some(Ok(frame)) => frame,
+                        Some(Err(err)) => return lost(err.to_string()),
+                        None => return lost("connection closed".to_owned()),
+                    };
+                    last_heard = Instant::now();
+
+                    match frame {
+                        ws::Frame::Text(text) => {
+                            let text = String::from_utf8_lossy(&text).into_owned();
+                            let _ = self.events.send(Event::Text(text));
+                        }
+                        ws::Frame::Binary(bin) => {
+                            let _ = self.events.send(Event::Binary(bin));
+                        }
+                        ws::Frame::Ping(bytes) => {
+                            let _ = ws.send(ws::Message::Pong(bytes)).await;
+                        }
+                        ws::Frame::Close(reason) => {
+                            let _ = ws.close().await;
+
+                            return Ended::Lost {
+                                retry_after: reason
+                                    .as_ref()
+                                    .and_then(|reason| reason.description.as_deref())
+                                    .and_then(reconnect_hint),
+                                reason: match reason {
+                                    Some(ws::CloseReason { code, description }) => format!(
+                                        "server closed the connection ({}): {}",
+                                        u16::from(code),
+                                        description.unwrap_or_default()
+                                    ),
+                                    None => "server closed the connection".to_owned(),
+                                },
+                            };
+                        }
+                        ws::Frame::Pong(_) | ws::Frame::Continuation(_) => {}
+                    }
+                }
+
+                msg = self.rx.recv() => {
+                    let Some(msg) = msg else {
+                        let _ = ws.send(ws::Message::Close(Some(ws::CloseCode::Normal.into()))).await;
+                        return Ended::Stopped;
+                    };
+
+                    if let Err(err) = ws.send(msg.clone().into()).await {
+                        self.buffer(msg);
+                        return lost(err.to_string());
+                    }
+                }
+
+                _ = heartbeat.tick() => {
+                    if last_heard.elapsed() > CLIENT_TIMEOUT {
+                        return lost(format!("server has not been heard from in {CLIENT_TIMEOUT:?}"));
+                    }
+
+                    let _ = ws.send(ws::Message::Ping(Bytes::new())).await;
+                }
+            }
+        }
+    }
+}
+
+/// Returns how long to wait before the `attempt`th reconnection, counting from 0.
+///
+/// The wait doubles with each attempt up to `max`, and is picked at random from its upper half,
+/// so that clients dropped at once do not all reconnect at once.
+fn backoff(attempt: u32, min: Duration, max: Duration) -> Duration {
+    let ceiling = min.saturating_mul(2u32.saturating_pow(attempt)).min(max);
+    rand::rng().random_range(ceiling / 2..=ceiling)
+}
+
+/// Reads the wait from a close description like `server shutting down, reconnect in 2s`.
+fn reconnect_hint(description: &str) -> Option<Duration> {
+    let (_, secs) = description.rsplit_once("reconnect in ")?;
+    let secs = secs.strip_suffix('s')?.parse().ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpRequest, HttpResponse, rt, web};
+
+    use super::*;
+
+    #[test]
+    fn backoff_grows_with_jitter_up_to_max() {
+        let (min, max) = (Duration::from_millis(100), Duration::from_secs(1));
+
+        for _ in 0..100 {
+            let first = backoff(0, min, max);
+            assert!(first >= min / 2 && first <= min);
+
+            let third = backoff(2, min, max);
+            assert!(third >= min * 2 && third <= min * 4);
+
+            let late = backoff(30, min, max);
+            assert!(late >= max / 2 && late <= max);
+        }
+
+        assert_eq!(
+            reconnect_hint("server shutting down, reconnect in 2s"),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(reconnect_hint("bye"), None);
+    }
+
+    /// Echoes text messages, but drops the connection when told to
+    async fn flaky_echo(req: HttpRequest, body: web::Payload) -> actix_web::Result<HttpResponse> {
+        let (res, mut session, mut msg_stream) = actix_ws::handle(&req, body)?;
+
+        rt::spawn(async move {
+            while let Some(Ok(msg)) = msg_stream.next().await {
+                match msg {
+                    actix_ws::Message::Text(text) if text == "drop" => break,
+                    actix_ws::Message::Text(text) => {
+                        let _ = session.text(text).await;
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        Ok(res)
+    }
+
+    async fn next(events: &mut mpsc::UnboundedReceiver<Event>) -> Event {
+        tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn reconnects_reruns_hook_and_flushes_buffer() {
+        let srv = actix_test::start(|| App::new().route("/ws", web::get().to(flaky_echo)));
+
+        let (tx, mut events) = Client::new(srv.url("/ws").replace("http", "ws"))
+            .on_connect(|| vec![Outgoing::Text("hello".to_owned())])
+            .backoff(Duration::from_millis(50), Duration::from_millis(100))
+            .start();
+
+        assert_eq!(next(&mut events).await, Event::Connected);
+        assert_eq!(next(&mut events).await, Event::Text("hello".to_owned()));
+
+        tx.text("drop");
+        assert!(matches!(next(&mut events).await, Event::Disconnected(_)));
+
+        // sent while disconnected
+        tx.text("buffered");
+
+        assert_eq!(next(&mut events).await, Event::Connected);
+        assert_eq!(next(&mut events).await, Event::Text("hello".to_owned()));
+        assert_eq!(next(&mut events).await, Event::Text("buffered".to_owned()));
+    }
+}
@@ -0,0 +1,153 @@
+//! Graceful shutdown of WebSocket sessions, shared by the echo and chat examples.
+//!
+//! Actix Web stops on SIGINT or SIGTERM by dropping the open connections once its workers time
+//! out, so WebSocket clients never learn why they were disconnected. [`run`] takes over the
+//! signals instead. It stops accepting connections, tells every session holding a [`Guard`] to
+//! close with `1001 Going Away` and a [`reconnect_hint`], and waits up to [`DRAIN_TIMEOUT`] for
+//! them to finish sending before the server stops.
+
+// not every example uses every part of this module
+#![allow(dead_code)]
+
+use std::{io, sync::Arc, time::Duration};
+
+use actix_web::{dev::Server, rt};
+use tokio::sync::watch;
+
+/// How long sessions are given to flush what they have queued and close.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long clients are asked to wait before reconnecting.
+pub const RECONNECT_AFTER: Duration = Duration::from_secs(2);
+
+/// Description sent with the `1001 Going Away` close frame.
+pub fn reconnect_hint() -> String {
+    format!(
+        "server shutting down, reconnect in {}s",
+        RECONNECT_AFTER.as_secs()
+    )
+}
+
+/// Tells sessions that the server is shutting down, and waits for them to close.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            tx: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Returns a guard for a new session, which counts as live until the guard is dropped.
+    pub fn guard(&self) -> Guard {
+        Guard {
+            rx: self.tx.subscribe(),
+        }
+    }
+
+    /// Signals every session to close, and waits up to `timeout` for their guards to be dropped.
+    ///
+    /// Returns the number of sessions still open.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.tx.send_replace(true);
+
+        let _ = tokio::time::timeout(timeout, self.tx.closed()).await;
+        self.tx.receiver_count()
+    }
+}
+
+/// Held by a live session, which it tells when the server is shutting down.
+#[derive(Debug, Clone)]
+pub struct Guard {
+    rx: watch::Receiver<bool>,
+}
+
+impl Guard {
+    pub fn is_shutting_down(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Waits until the server is shutting down, which may have happened already.
+    pub async fn signalled(&mut self) {
+        // the sender is only dropped once nobody can be told about the shutdown any more
+        let _ = self.rx.wait_for(|shutting_down| *shutting_down).await;
+    }
+}
+
+/// Runs `server` until SIGINT or SIGTERM, then drains the sessions of `shutdown` before stopping
+/// it gracefully.
+///
+/// The server has to be built with `disable_signals()`, or it stops on the signal by itself.
+pub async fn run(server: Server, shutdown: Shutdown) -> io::Result<()> {
+    let handle = server.handle();
+
+    tokio::spawn(async move {
+        stop_signal().await;
+        log::info!("shutting down, closing WebSocket sessions");
+
+        // connections that are open already are served until the server stops
+        handle.pause().await;
+
+        let left = shutdown.drain(DRAIN_TIMEOUT).await;
+        if left > 0 {
+            log::warn!("{left} sessions did not close within {DRAIN_TIMEOUT:?}");
+        }
+
+        handle.stop(true).await;
+    });
+
+    server.await
+}
+
+async fn stop_signal() {
+    #[cfg(unix)]
+    {
+        use rt::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = rt::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(err) => {
+                log::error!("failed to listen for SIGTERM: {err}");
+                let _ = rt::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = rt::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_waits_for_guards() {
+        let shutdown = Shutdown::default();
+
+        let mut slow = shutdown.guard();
+        assert!(!slow.is_shutting_down());
+        let closes = tokio::spawn(async move {
+            slow.signalled().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        });
+        let stuck = shutdown.guard();
+
+        assert_eq!(shutdown.drain(Duration::from_millis(200)).await, 1);
+        assert!(closes.is_finished());
+        assert!(stuck.is_shutting_down());
+
+        drop(stuck);
+        assert_eq!(shutdown.drain(Duration::from_millis(200)).await, 0);
+    }
+}
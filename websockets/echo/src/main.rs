@@ -5,25 +5,59 @@
 use actix_files::NamedFile;
 use actix_web::{App, Error, HttpRequest, HttpResponse, HttpServer, Responder, middleware, web};
 use ractor::Actor;
+use tokio::select;
 
 mod server;
-use self::server::{MyWebSocket, WsMessage};
+mod shutdown;
+use self::{
+    server::{MyWebSocket, WsMessage},
+    shutdown::Shutdown,
+};
 
 async fn index() -> impl Responder {
     NamedFile::open_async("./static/index.html").await.unwrap()
 }
 
 /// WebSocket handshake and start `MyWebSocket` actor.
-async fn echo_ws(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+///
+/// The actor is told when the server shuts down, and the session counts as
+/// live until the client has closed its side of the connection.
+async fn echo_ws(
+    req: HttpRequest,
+    stream: web::Payload,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, Error> {
     let (res, session, stream) = actix_ws::handle(&req, stream)?;
+    let mut shutdown = shutdown.guard();
 
     let (actor, _handle) = Actor::spawn(None, MyWebSocket, session).await.unwrap();
 
     actix_web::rt::spawn(async move {
         let mut stream = stream.aggregate_continuations();
 
-        while let Some(Ok(msg)) = stream.recv().await {
-            actor.send_message(WsMessage::Ws(msg)).unwrap();
+        // the server may be shutting down already by the time the loop starts
+        let mut told = false;
+
+        loop {
+            select! {
+                msg = stream.recv() => match msg {
+                    // errors once the actor has stopped, e.g. after closing
+                    // the connection on shutdown
+                    Some(Ok(msg)) => {
+                        if actor.send_message(WsMessage::Ws(msg)).is_err() {
+                            break;
+                        }
+                    }
+                    _ => break,
+                },
+
+                () = shutdown.signalled(), if !told => {
+                    told = true;
+
+                    // errors if the actor has stopped already
+                    let _ = actor.send_message(WsMessage::GoingAway);
+                }
+            }
         }
     });
 
@@ -37,8 +71,13 @@ async fn main() -> std::io::Result<()> {
 
     log::info!("starting HTTP server at http://localhost:8080");
 
-    HttpServer::new(|| {
+    // sessions are closed with `1001 Going Away` on SIGINT or SIGTERM
+    let shutdown = Shutdown::default();
+    let app_shutdown = shutdown.clone();
+
+    let server = HttpServer::new(move || {
         App::new()
+            .app_data(web::Data::new(app_shutdown.clone()))
             // WebSocket UI HTML file
             .service(web::resource("/").to(index))
             // websocket route
@@ -47,7 +86,54 @@ async fn main() -> std::io::Result<()> {
             .wrap(middleware::Logger::default())
     })
     .workers(2)
+    .disable_signals()
+    .shutdown_timeout(shutdown::DRAIN_TIMEOUT.as_secs())
     .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .run();
+
+    shutdown::run(server, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use awc::ws::{CloseCode, Frame};
+    use futures_util::StreamExt as _;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn clients_are_told_the_server_is_going_away() {
+        let shutdown = Shutdown::default();
+        let app_shutdown = shutdown.clone();
+
+        let srv = actix_test::start(move || {
+            App::new()
+                .app_data(web::Data::new(app_shutdown.clone()))
+                .service(web::resource("/ws").route(web::get().to(echo_ws)))
+        });
+
+        let (_, mut ws) = awc::Client::new()
+            .ws(srv.url("/ws"))
+            .connect()
+            .await
+            .unwrap();
+
+        let draining = tokio::spawn(async move { shutdown.drain(Duration::from_secs(5)).await });
+
+        let reason = loop {
+            let frame = timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+
+            if let Frame::Close(reason) = frame.unwrap().unwrap() {
+                break reason.unwrap();
+            }
+        };
+        drop(ws);
+
+        assert_eq!(reason.code, CloseCode::Away);
+        assert_eq!(reason.description, Some(shutdown::reconnect_hint()));
+        assert_eq!(draining.await.unwrap(), 0);
+    }
 }
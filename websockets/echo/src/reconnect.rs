@@ -0,0 +1 @@
+../../echo-actorless/src/reconnect.rs
\ No newline at end of file
@@ -1,8 +1,10 @@
 use std::time::{Duration, Instant};
 
-use actix_ws::AggregatedMessage;
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason};
 use ractor::{ActorProcessingErr, ActorRef};
 
+use crate::shutdown;
+
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
@@ -13,6 +15,8 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 pub(crate) enum WsMessage {
     Ws(actix_ws::AggregatedMessage),
     Hb,
+    /// The server is shutting down
+    GoingAway,
 }
 
 /// websocket connection is long running connection, it easier
@@ -105,6 +109,16 @@ impl ractor::Actor for MyWebSocket {
             WsMessage::Ws(msg) => {
                 self.handle_ws_msg(msg, state, myself).await?;
             }
+
+            WsMessage::GoingAway => {
+                let reason = CloseReason {
+                    code: CloseCode::Away,
+                    description: Some(shutdown::reconnect_hint()),
+                };
+
+                let _ = state.1.clone().close(Some(reason)).await;
+                myself.stop(None);
+            }
         }
 
         Ok(())
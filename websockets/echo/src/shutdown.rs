@@ -0,0 +1 @@
+../../echo-actorless/src/shutdown.rs
\ No newline at end of file